@@ -0,0 +1,1662 @@
+// Audio input and output: devices, WAV files, the shared sample ring and stream health
+
+use crate::dsp::{
+    self, hum_notches, Biquad, Decimator, HighPass, LinearResampler, SignalGenerator, TestSignal, ToneGenerator,
+};
+use crate::export::{OutputNames, SkipLog};
+use crate::options::Options;
+use anyhow::{anyhow, bail, Result};
+use cpal::traits::*;
+use realfft::RealToComplex;
+use std::{
+    fs::File,
+    io::{stdin, BufWriter, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Falling further behind a piped producer than this restarts the pacing clock
+const MAX_STDIN_LAG: Duration = Duration::from_millis(200);
+// Longest the playback ring may grow to hold an --output-delay
+const MAX_RING_SECS: u32 = 10;
+// Input a recording's writer thread may fall behind by before losing any, how often it
+// catches up, and the samples it copies out of the ring at a time
+const RECORD_RING_SECS: u32 = 2;
+const RECORD_DRAIN_INTERVAL: Duration = Duration::from_millis(50);
+const RECORD_CHUNK_SAMPLES: usize = 4096;
+// Rate --test-signal is generated at without --sample-rate
+pub const DEFAULT_TEST_SIGNAL_RATE: u32 = 48000;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChannelSelect {
+    Mix,
+    Index(usize),
+    // Half the sum and half the difference of the first two channels
+    Mid,
+    Side,
+}
+
+impl ChannelSelect {
+    // The one sample this selection takes from an interleaved frame
+    fn pick(self, frame: &[f32]) -> f32 {
+        match self {
+            Self::Mix => frame.iter().sum::<f32>() / frame.len() as f32,
+            Self::Index(idx) => frame[idx],
+            Self::Mid => (frame[0] + frame[1]) / 2.0,
+            Self::Side => (frame[0] - frame[1]) / 2.0,
+        }
+    }
+}
+
+impl FromStr for ChannelSelect {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "mix" => Ok(Self::Mix),
+            "l" | "left" => Ok(Self::Index(0)),
+            "r" | "right" => Ok(Self::Index(1)),
+            other => other.parse().map(Self::Index)
+                .map_err(|_| anyhow!("Unknown channel '{}', expected L, R, mix or a channel index", s)),
+        }
+    }
+}
+
+// Whether the display follows one stream or shows left and right side by side
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChannelLayout {
+    Mono,
+    Stereo,
+}
+
+impl FromStr for ChannelLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "mono" => Ok(Self::Mono),
+            "stereo" => Ok(Self::Stereo),
+            _ => bail!("Unknown channel layout '{}', expected mono or stereo", s),
+        }
+    }
+}
+
+// What the two stereo panels show: the channels themselves, or their mid (L+R) and side (L-R)
+#[derive(Clone, Copy, PartialEq)]
+pub enum StereoMode {
+    LeftRight,
+    MidSide,
+}
+
+impl StereoMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::LeftRight => "lr",
+            Self::MidSide => "mid-side",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::LeftRight => "L/R",
+            Self::MidSide => "M/S",
+        }
+    }
+
+    fn channels(self) -> [ChannelSelect; 2] {
+        match self {
+            Self::LeftRight => [ChannelSelect::Index(0), ChannelSelect::Index(1)],
+            Self::MidSide => [ChannelSelect::Mid, ChannelSelect::Side],
+        }
+    }
+}
+
+impl FromStr for StereoMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "lr" | "l-r" | "left-right" => Ok(Self::LeftRight),
+            "mid-side" | "ms" | "m-s" => Ok(Self::MidSide),
+            _ => bail!("Unknown stereo mode '{}', expected mid-side or lr", s),
+        }
+    }
+}
+
+pub enum DeviceSelector {
+    Index(usize),
+    Name(String),
+}
+
+impl DeviceSelector {
+    pub fn resolve(&self, devices: &[cpal::Device]) -> Option<usize> {
+        match self {
+            Self::Index(idx) => (*idx < devices.len()).then_some(*idx),
+            Self::Name(pattern) => {
+                let pattern = pattern.to_lowercase();
+                devices.iter().position(|device| {
+                    device.name().map(|name| name.to_lowercase().contains(&pattern)).unwrap_or(false)
+                })
+            }
+        }
+    }
+}
+
+// A bare number selects by index, anything else by name
+impl FromStr for DeviceSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(s.parse().map(Self::Index).unwrap_or_else(|_| Self::Name(s.to_string())))
+    }
+}
+
+impl std::fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Index(idx) => write!(f, "index {}", idx),
+            Self::Name(pattern) => write!(f, "name '{}'", pattern),
+        }
+    }
+}
+
+// Lock-free single-producer/single-consumer ring: only the input callback advances
+// `write_pos` and only the output callback advances `read_pos`, so neither ever waits on the
+// other or on the render thread. FFT snapshots read behind `write_pos` without touching either
+// index. Both positions count samples since creation; samples are stored as f32 bits.
+pub struct AudioBuffer {
+    samples: Box<[AtomicU32]>,
+    write_pos: AtomicU64,
+    read_pos: AtomicU64,
+    // Samples playback stays behind the writer, for --output-delay
+    delay: u64,
+}
+
+impl AudioBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_delay(capacity, 0)
+    }
+
+    // The ring must hold the delay on top of whatever the readers need behind the writer
+    pub fn with_delay(capacity: usize, delay: usize) -> Self {
+        Self {
+            samples: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+            write_pos: AtomicU64::new(0),
+            read_pos: AtomicU64::new(0),
+            delay: delay as u64,
+        }
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.samples.len() as u64
+    }
+
+    pub fn delay(&self) -> u64 {
+        self.delay
+    }
+
+    fn slot(&self, pos: u64) -> &AtomicU32 {
+        &self.samples[(pos % self.capacity()) as usize]
+    }
+
+    // Returns false on overrun, when the oldest unread sample had to be overwritten; the
+    // reader then skips ahead to the oldest sample still held
+    pub fn push(&self, sample: f32) -> bool {
+        let pos = self.write_pos.load(Ordering::Relaxed);
+        self.slot(pos).store(sample.to_bits(), Ordering::Relaxed);
+        self.write_pos.store(pos + 1, Ordering::Release);
+        pos + 1 - self.read_pos.load(Ordering::Relaxed) <= self.capacity()
+    }
+
+    pub fn pop(&self) -> Option<f32> {
+        let mut pos = self.read_pos.load(Ordering::Relaxed);
+        loop {
+            let written = self.write_pos.load(Ordering::Acquire);
+            pos = pos.max(written.saturating_sub(self.capacity()));
+            if pos >= written.saturating_sub(self.delay) {
+                self.read_pos.store(pos, Ordering::Relaxed);
+                return None;
+            }
+            let sample = f32::from_bits(self.slot(pos).load(Ordering::Relaxed));
+            // If the writer lapped this slot while we read it, skip ahead and try again
+            if self.write_pos.load(Ordering::Acquire) <= pos + self.capacity() {
+                self.read_pos.store(pos + 1, Ordering::Relaxed);
+                return Some(sample);
+            }
+        }
+    }
+
+    // Total samples ever pushed, the position analysis windows are measured against
+    pub fn written(&self) -> u64 {
+        self.write_pos.load(Ordering::Acquire)
+    }
+
+    // Copies the `out.len()` samples before position `end`, oldest first, without moving
+    // the playback read position. Samples not yet written or already overwritten, including
+    // any the writer laps during the copy, read as silence.
+    pub fn window_ending(&self, end: u64, out: &mut [f32]) {
+        let written = self.written();
+        let start = end as i64 - out.len() as i64;
+        for (i, slot) in out.iter_mut().enumerate() {
+            let pos = start + i as i64;
+            let held = pos >= 0 && (pos as u64) < written;
+            *slot = if held { f32::from_bits(self.slot(pos as u64).load(Ordering::Relaxed)) } else { 0.0 };
+        }
+        let oldest = self.written().saturating_sub(self.capacity()) as i64;
+        let lapped = (oldest - start).clamp(0, out.len() as i64) as usize;
+        out[..lapped].fill(0.0);
+    }
+}
+
+// Samples copied out of a ring as it fills, from a fixed position on, so they can still be
+// read long after the ring has overwritten them. Stops taking more at `limit` samples.
+pub struct Tape {
+    start: u64,
+    samples: Vec<f32>,
+    limit: usize,
+}
+
+impl Tape {
+    pub fn new(start: u64, limit: usize) -> Self {
+        Self { start, samples: Vec::new(), limit }
+    }
+
+    pub fn written(&self) -> u64 {
+        self.start + self.samples.len() as u64
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.samples.len() >= self.limit
+    }
+
+    // Takes whatever `ring` has gained since the last call; anything it already lost reads as silence
+    pub fn follow(&mut self, ring: &AudioBuffer) {
+        let taken = self.samples.len();
+        let new = ring.written().saturating_sub(self.written()).min((self.limit - taken) as u64);
+        self.samples.resize(taken + new as usize, 0.0);
+        ring.window_ending(self.written(), &mut self.samples[taken..]);
+    }
+
+    // Same as `AudioBuffer::window_ending`, with anything outside the tape as silence
+    pub fn window_ending(&self, end: u64, out: &mut [f32]) {
+        let start = end as i64 - out.len() as i64;
+        for (i, slot) in out.iter_mut().enumerate() {
+            let pos = start + i as i64 - self.start as i64;
+            *slot = usize::try_from(pos).ok().and_then(|pos| self.samples.get(pos)).copied().unwrap_or(0.0);
+        }
+    }
+}
+
+// Most windows one frame will analyze before skipping ahead, so a stalled frame loop
+// can't leave a backlog
+const MAX_HOPS_PER_FRAME: u64 = 16;
+
+// Steps analysis windows through the ring a fixed hop apart, so with overlap consecutive
+// spectra share samples. Without it each frame analyzes whatever is newest.
+pub struct HopCursor {
+    hop: Option<u64>,
+    window: u64,
+    // How far behind the newest sample a window may still end: a frame's worth of hops,
+    // or less if older samples are already gone from the ring
+    reach: u64,
+    next_end: u64,
+}
+
+impl HopCursor {
+    pub fn new(fft_size: usize, overlap: Option<f32>, capacity: u64) -> Self {
+        let hop = overlap.map(|overlap| ((fft_size as f32 * (1.0 - overlap)).round() as u64).max(1));
+        let reach = (hop.unwrap_or(0) * MAX_HOPS_PER_FRAME).min(capacity.saturating_sub(fft_size as u64));
+        Self { hop, window: fft_size as u64, reach, next_end: 0 }
+    }
+
+    // End positions of the windows due now that `written` samples are in the ring
+    pub fn due(&mut self, written: u64) -> impl Iterator<Item = u64> {
+        let (first, hop, count) = match self.hop {
+            None => (written, 0, 1),
+            Some(hop) => {
+                if written > self.next_end + self.reach {
+                    self.next_end = written - self.reach;
+                }
+                let first = self.next_end.max(hop);
+                let count = if written < first { 0 } else { (written - first) / hop + 1 };
+                self.next_end = first + count * hop;
+                (first, hop, count)
+            }
+        };
+        (0..count).map(move |i| first + i * hop)
+    }
+
+    // Drops the backlog, e.g. after a pause, so analysis resumes at live audio
+    pub fn skip_to(&mut self, written: u64) {
+        self.next_end = written;
+    }
+
+    // End of the window one hop past the last, or a whole window past it without overlap,
+    // for stepping a paused view; None until it has all been written
+    pub fn step(&mut self, written: u64) -> Option<u64> {
+        let end = self.next_end + self.hop.unwrap_or(self.window);
+        (end <= written).then(|| {
+            self.next_end = end;
+            end
+        })
+    }
+}
+
+// What a listing reports about one input device; the index is what --device takes
+pub struct DeviceInfo {
+    pub index: usize,
+    pub name: String,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    pub formats: Vec<cpal::SampleFormat>,
+    pub ranges: Vec<cpal::SupportedStreamConfigRange>,
+}
+
+impl DeviceInfo {
+    // None for devices that cannot even report a name
+    fn probe(index: usize, device: &cpal::Device) -> Option<Self> {
+        let name = device.name().ok()?;
+        let config = device.default_input_config().ok();
+        let ranges: Vec<_> = device.supported_input_configs().into_iter().flatten().collect();
+        let mut formats = Vec::new();
+        for range in &ranges {
+            if !formats.contains(&range.sample_format()) {
+                formats.push(range.sample_format());
+            }
+        }
+        Some(Self {
+            index,
+            name,
+            sample_rate: config.as_ref().map(|config| config.sample_rate().0),
+            channels: config.as_ref().map(|config| config.channels()),
+            formats,
+            ranges,
+        })
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "index": self.index,
+            "name": self.name,
+            "default_sample_rate": self.sample_rate,
+            "channels": self.channels,
+            "formats": self.formats.iter().map(|format| format.to_string()).collect::<Vec<_>>(),
+            "configs": self.ranges.iter().map(|range| serde_json::json!({
+                "channels": range.channels(),
+                "min_sample_rate": range.min_sample_rate().0,
+                "max_sample_rate": range.max_sample_rate().0,
+                "format": range.sample_format().to_string(),
+            })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+impl std::fmt::Display for DeviceInfo {
+    // e.g. "0. USB Audio (48000 Hz, 2 ch, i16/f32)", then one indented line per supported range
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}. {}", self.index, self.name)?;
+        let mut details = Vec::new();
+        if let Some(rate) = self.sample_rate {
+            details.push(format!("{} Hz", rate));
+        }
+        if let Some(channels) = self.channels {
+            details.push(format!("{} ch", channels));
+        }
+        if !self.formats.is_empty() {
+            details.push(self.formats.iter().map(|format| format.to_string()).collect::<Vec<_>>().join("/"));
+        }
+        if !details.is_empty() {
+            write!(f, " ({})", details.join(", "))?;
+        }
+        for range in &self.ranges {
+            let (min, max) = (range.min_sample_rate().0, range.max_sample_rate().0);
+            let rates = if min == max { format!("{} Hz", min) } else { format!("{}-{} Hz", min, max) };
+            write!(f, "\n     {} ch, {}, {}", range.channels(), rates, range.sample_format())?;
+        }
+        Ok(())
+    }
+}
+
+pub fn describe_devices(devices: &[cpal::Device]) -> Vec<DeviceInfo> {
+    devices.iter().enumerate().filter_map(|(idx, device)| DeviceInfo::probe(idx, device)).collect()
+}
+
+pub fn input_devices() -> Result<Vec<cpal::Device>> {
+    Ok(cpal::default_host().input_devices()?.collect())
+}
+
+pub fn list_devices() -> Result<Vec<cpal::Device>> {
+    let device_list = input_devices()?;
+    println!("Available input devices:\n----------------------");
+    for info in describe_devices(&device_list) {
+        println!("{}", info);
+    }
+    Ok(device_list)
+}
+
+pub fn get_user_device_choice(max: usize) -> Result<usize> {
+    loop {
+        println!("\nSelect device number (0-{}): ", max - 1);
+        let mut input = String::new();
+        if stdin().read_line(&mut input)? == 0 {
+            bail!("No device selected before input ended; pass --device or --device-name to choose one");
+        }
+        if let Ok(num) = input.trim().parse() {
+            if num < max {
+                return Ok(num);
+            }
+        }
+        println!("Invalid selection, try again");
+    }
+}
+
+type WavFileWriter = hound::WavWriter<BufWriter<File>>;
+
+// The input callback's side of a recording: while a take runs it pushes the raw interleaved
+// input into a ring of its own, and never waits on the file or on the UI
+pub struct RecordTap {
+    ring: AudioBuffer,
+    recording: AtomicBool,
+    channels: u64,
+}
+
+impl RecordTap {
+    fn push(&self, data: &[f32]) {
+        if self.recording.load(Ordering::Relaxed) {
+            for &sample in data {
+                self.ring.push(sample);
+            }
+        }
+    }
+
+    // Samples pushed, less any of a frame the callback is still part way through
+    fn frames_end(&self) -> u64 {
+        let written = self.ring.written();
+        written - written % self.channels
+    }
+}
+
+// The file side of a take: a thread draining the tap into the WAV file, up to where the
+// take is told to end
+struct TakeWriter {
+    end: Arc<AtomicU64>,
+    flush: Arc<AtomicBool>,
+    thread: thread::JoinHandle<Result<()>>,
+}
+
+// WAV recording of the raw input, fed from the input callback through a RecordTap
+pub struct Recorder {
+    // The requested path, or the kind of file to name each take after
+    base_path: Option<PathBuf>,
+    names: OutputNames,
+    kind: String,
+    spec: hound::WavSpec,
+    takes: usize,
+    tap: Arc<RecordTap>,
+    writer: Option<TakeWriter>,
+}
+
+impl Recorder {
+    pub fn new(base_path: Option<PathBuf>, names: OutputNames, kind: &str, sample_rate: u32, channels: u16) -> Self {
+        let ring_samples = RECORD_RING_SECS as usize * sample_rate as usize * channels as usize;
+        Self {
+            base_path: base_path.map(|path| names.expand(&path)),
+            names,
+            kind: kind.to_string(),
+            spec: hound::WavSpec {
+                channels,
+                sample_rate,
+                bits_per_sample: 32,
+                sample_format: hound::SampleFormat::Float,
+            },
+            takes: 0,
+            tap: Arc::new(RecordTap {
+                ring: AudioBuffer::new(ring_samples.max(1)),
+                recording: AtomicBool::new(false),
+                channels: channels.max(1) as u64,
+            }),
+            writer: None,
+        }
+    }
+
+    pub fn tap(&self) -> Arc<RecordTap> {
+        Arc::clone(&self.tap)
+    }
+
+    // The first take uses the requested path, later ones get a numeric suffix; without one
+    // each take is named as it starts
+    pub fn take_path(&self) -> PathBuf {
+        let Some(base_path) = &self.base_path else { return self.names.path(&self.kind, "wav") };
+        if self.takes == 0 {
+            return base_path.clone();
+        }
+        let stem = base_path.file_stem().and_then(|s| s.to_str()).unwrap_or("capture");
+        let ext = base_path.extension().and_then(|s| s.to_str()).unwrap_or("wav");
+        base_path.with_file_name(format!("{}-{}.{}", stem, self.takes + 1, ext))
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        let path = self.take_path();
+        let writer = hound::WavWriter::create(&path, self.spec)
+            .map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e))?;
+        self.tap.recording.store(true, Ordering::Relaxed);
+        let start = self.tap.frames_end();
+        let end = Arc::new(AtomicU64::new(u64::MAX));
+        let flush = Arc::new(AtomicBool::new(false));
+        let (tap, thread_end, thread_flush) = (Arc::clone(&self.tap), Arc::clone(&end), Arc::clone(&flush));
+        let thread = thread::spawn(move || drain_take(&tap, writer, start, &thread_end, &thread_flush));
+        self.writer = Some(TakeWriter { end, flush, thread });
+        self.takes += 1;
+        Ok(())
+    }
+
+    // The take ends at the last whole frame pushed by now; its thread writes out the rest and
+    // finishes the file before this returns, with any error it met on the way
+    pub fn stop(&mut self) -> Result<()> {
+        let Some(writer) = self.writer.take() else { return Ok(()) };
+        self.tap.recording.store(false, Ordering::Relaxed);
+        writer.end.store(self.tap.frames_end(), Ordering::Release);
+        writer.thread.join().map_err(|_| anyhow!("The recording thread panicked"))?
+    }
+
+    pub fn toggle(&mut self) -> Result<()> {
+        if self.is_recording() { self.stop() } else { self.start() }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.tap.recording.load(Ordering::Relaxed)
+    }
+
+    // Has the header rewritten so the file is valid up to this point, once the writer thread
+    // next wakes
+    pub fn flush(&mut self) {
+        if let Some(writer) = &self.writer {
+            writer.flush.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+// Writes the tap from `pos` on until the take's end is set and reached. Falling a whole ring
+// behind loses what was overwritten; the file carries on from the oldest frame still held.
+fn drain_take(tap: &RecordTap, mut writer: WavFileWriter, mut pos: u64, end: &AtomicU64, flush: &AtomicBool)
+    -> Result<()> {
+    let mut chunk = vec![0.0; RECORD_CHUNK_SAMPLES];
+    loop {
+        let take_end = end.load(Ordering::Acquire);
+        let until = take_end.min(tap.frames_end());
+        pos = pos.max(until.saturating_sub(tap.ring.capacity()));
+        while pos < until {
+            let len = ((until - pos) as usize).min(chunk.len());
+            tap.ring.window_ending(pos + len as u64, &mut chunk[..len]);
+            for &sample in &chunk[..len] {
+                writer.write_sample(sample)?;
+            }
+            pos += len as u64;
+        }
+        if flush.swap(false, Ordering::Relaxed) {
+            writer.flush()?;
+        }
+        if pos >= take_end {
+            break;
+        }
+        thread::sleep(RECORD_DRAIN_INTERVAL);
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+// Takes the device a selector points at out of a listing; `kind` names it in the error
+pub fn select_device(mut devices: Vec<cpal::Device>, selector: &DeviceSelector, kind: &str) -> Result<cpal::Device> {
+    let idx = selector.resolve(&devices).ok_or_else(|| anyhow!("No {} device matches {}", kind, selector))?;
+    Ok(devices.swap_remove(idx))
+}
+
+fn output_device(selector: Option<&DeviceSelector>) -> Result<cpal::Device> {
+    let host = cpal::default_host();
+    match selector {
+        Some(selector) => select_device(host.output_devices()?.collect(), selector, "output"),
+        None => host.default_output_device()
+            .ok_or_else(|| anyhow!("No output device available (use --no-output to capture without playback)")),
+    }
+}
+
+// Headless machines and containers often have capture hardware but nothing to play on.
+// ALSA names a default device even with no card behind it, so it has to have a config too.
+pub fn has_default_output() -> bool {
+    cpal::default_host().default_output_device().is_some_and(|device| device.default_output_config().is_ok())
+}
+
+// Names the device that failed and what usually fixes it, instead of a bare backend error
+fn open_error(kind: &str, device: &cpal::Device, err: impl std::fmt::Display) -> anyhow::Error {
+    let name = device.name().unwrap_or_else(|_| "unknown device".to_string());
+    let alternatives = match kind {
+        "input" => "choose another with --device or --device-name",
+        "loopback" => "capture a device of your choosing with --device",
+        _ => "choose another with --output-device, or use --no-output to capture without playback",
+    };
+    anyhow!("Failed to open {} device '{}': {}. Check that it is connected and not held by another program, or {}",
+            kind, name, err, alternatives)
+}
+
+// What the system is playing, for --loopback. PulseAudio and PipeWire list a monitor of each
+// output as an input device, and ALSA's snd-aloop a loopback card; failing those, WASAPI can
+// capture the default output directly.
+pub fn loopback_source(sample_rate: Option<u32>) -> Result<SampleSource> {
+    let host = cpal::default_host();
+    let monitor = host.input_devices()?.find(|device| device.name().is_ok_and(|name| {
+        let name = name.to_lowercase();
+        name.contains("monitor") || name.contains("loopback")
+    }));
+    if let Some(device) = monitor {
+        return Ok(SampleSource::device(device, sample_rate));
+    }
+    if host.id().name() == "WASAPI" {
+        if let Some(device) = host.default_output_device() {
+            if sample_rate.is_some() {
+                eprintln!("Warning: loopback capture runs at the output's own rate; ignoring --sample-rate");
+            }
+            return Ok(SampleSource::Loopback(device));
+        }
+    }
+    bail!("No loopback source found on {}. Enable a monitor source (PulseAudio or PipeWire) or load snd-aloop \
+           (ALSA), or on macOS install a loopback driver, then pick it with --device",
+          host.id().name())
+}
+
+pub fn find_input_device(name: &str) -> Result<cpal::Device> {
+    let host = cpal::default_host();
+    host.input_devices()?
+        .find(|device| device.name().map(|n| n == name).unwrap_or(false))
+        .ok_or_else(|| anyhow!("Input device '{}' not found", name))
+}
+
+// Liveness of a running pipeline, updated from the audio callbacks
+pub struct PipelineHealth {
+    started: Instant,
+    last_input_ms: AtomicU64,
+    stream_errors: AtomicUsize,
+    ended: AtomicBool,
+    overrun_samples: AtomicU64,
+    underrun_samples: AtomicU64,
+    // Callbacks can't print into the alternate screen, so the UI shows this instead
+    last_error: Mutex<Option<String>>,
+    disconnected: AtomicBool,
+    // Frames in the latest block the input delivered, which is what the driver settled on
+    block_frames: AtomicUsize,
+    // Input frames --skip-silence passed over
+    skipped_frames: AtomicU64,
+}
+
+impl PipelineHealth {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_input_ms: AtomicU64::new(0),
+            stream_errors: AtomicUsize::new(0),
+            ended: AtomicBool::new(false),
+            overrun_samples: AtomicU64::new(0),
+            underrun_samples: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+            disconnected: AtomicBool::new(false),
+            block_frames: AtomicUsize::new(0),
+            skipped_frames: AtomicU64::new(0),
+        }
+    }
+
+    pub fn mark_input(&self) {
+        self.last_input_ms.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, direction: &str, err: &cpal::StreamError) {
+        self.stream_errors.fetch_add(1, Ordering::Relaxed);
+        if let cpal::StreamError::DeviceNotAvailable = err {
+            self.disconnected.store(true, Ordering::Relaxed);
+        }
+        *self.last_error.lock().unwrap() = Some(format!("{} stream: {}", direction, err));
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    // A vanished device never comes back on its own stream, so this always forces a rebuild
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Relaxed)
+    }
+
+    // Input samples dropped because playback fell a whole ring behind
+    pub fn record_overrun(&self, samples: u64) {
+        if samples > 0 {
+            self.overrun_samples.fetch_add(samples, Ordering::Relaxed);
+        }
+    }
+
+    // Output samples padded with silence because the ring ran dry
+    pub fn record_underrun(&self, samples: u64) {
+        if samples > 0 {
+            self.underrun_samples.fetch_add(samples, Ordering::Relaxed);
+        }
+    }
+
+    pub fn overruns(&self) -> u64 {
+        self.overrun_samples.load(Ordering::Relaxed)
+    }
+
+    pub fn underruns(&self) -> u64 {
+        self.underrun_samples.load(Ordering::Relaxed)
+    }
+
+    fn record_skip(&self, frames: u64) {
+        self.skipped_frames.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    pub fn skipped_frames(&self) -> u64 {
+        self.skipped_frames.load(Ordering::Relaxed)
+    }
+
+    fn record_block(&self, frames: usize) {
+        self.block_frames.store(frames, Ordering::Relaxed);
+    }
+
+    // None until the first block arrives
+    pub fn block_frames(&self) -> Option<usize> {
+        Some(self.block_frames.load(Ordering::Relaxed)).filter(|&frames| frames > 0)
+    }
+
+    // Set by sources that can run dry, such as a pipe reaching EOF
+    pub fn mark_ended(&self) {
+        self.ended.store(true, Ordering::Relaxed);
+    }
+
+    pub fn has_ended(&self) -> bool {
+        self.ended.load(Ordering::Relaxed)
+    }
+
+    pub fn input_age(&self) -> Duration {
+        let last_input = Duration::from_millis(self.last_input_ms.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last_input)
+    }
+
+    pub fn is_healthy(&self, options: &Options) -> bool {
+        self.stream_errors.load(Ordering::Relaxed) < options.max_stream_errors
+            && self.input_age() < options.stall_timeout
+    }
+}
+
+// Decides when an unhealthy pipeline has to be torn down and rebuilt
+#[derive(Default)]
+pub struct Watchdog {
+    unhealthy_since: Option<Instant>,
+}
+
+impl Watchdog {
+    pub fn should_restart(&mut self, health: &PipelineHealth, options: &Options) -> bool {
+        if health.is_disconnected() {
+            return true;
+        }
+        if !options.auto_restart || health.is_healthy(options) {
+            self.unhealthy_since = None;
+            return false;
+        }
+        let since = *self.unhealthy_since.get_or_insert_with(Instant::now);
+        since.elapsed() >= options.restart_after
+    }
+}
+
+// Raw input level since the UI last looked, across all channels and before any filtering.
+// Magnitudes are never negative, so their f32 bits order like the values and the peak can
+// use fetch_max; the sum of squares travels as f64 bits.
+#[derive(Default)]
+pub struct InputMeter {
+    peak: AtomicU32,
+    sum_squares: AtomicU64,
+    samples: AtomicU64,
+    clipped: AtomicBool,
+}
+
+impl InputMeter {
+    fn add(&self, data: &[f32]) {
+        let peak = data.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        let sum: f64 = data.iter().map(|&x| x as f64 * x as f64).sum();
+        self.peak.fetch_max(peak.to_bits(), Ordering::Relaxed);
+        let _ = self.sum_squares.fetch_update(Ordering::Relaxed, Ordering::Relaxed,
+                                              |bits| Some((f64::from_bits(bits) + sum).to_bits()));
+        self.samples.fetch_add(data.len() as u64, Ordering::Relaxed);
+        if peak >= 1.0 {
+            self.clipped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Peak and RMS since the last call, and whether any sample reached full scale
+    pub fn take(&self) -> (f32, f32, bool) {
+        let peak = f32::from_bits(self.peak.swap(0, Ordering::Relaxed));
+        let sum = f64::from_bits(self.sum_squares.swap(0, Ordering::Relaxed));
+        let samples = self.samples.swap(0, Ordering::Relaxed);
+        let rms = if samples > 0 { (sum / samples as f64).sqrt() as f32 } else { 0.0 };
+        (peak, rms, self.clipped.swap(false, Ordering::Relaxed))
+    }
+}
+
+// One mono stream derived from the input: a channel selection, its filters and its ring
+#[derive(Clone)]
+struct Tap {
+    channel: ChannelSelect,
+    buffer: Arc<AudioBuffer>,
+    decimator: Option<Decimator>,
+    high_pass: Option<HighPass>,
+    notches: Vec<Biquad>,
+    // Only the ring that playback drains can overrun; the others are read by peeking
+    playback: bool,
+}
+
+// Everything the input side does with a block of samples, wherever they come from
+#[derive(Clone)]
+struct InputSink {
+    taps: Vec<Tap>,
+    record: Arc<RecordTap>,
+    health: Arc<PipelineHealth>,
+    meter: Arc<InputMeter>,
+    channels: usize,
+    notch_enabled: Arc<AtomicBool>,
+    // Only the file and pipe feeders check this; a device can't be read ahead of time
+    silence: Option<SilenceSkip>,
+}
+
+impl InputSink {
+    // How the feeders hand over a chunk. Silence being skipped never reaches `consume`, and
+    // the return tells the feeder so, since there is nothing to wait for either.
+    fn feed(&mut self, chunk: &[f32]) -> bool {
+        let frames = (chunk.len() / self.channels) as u64;
+        if let Some(silence) = &mut self.silence {
+            if !silence.admit(chunk, frames, &self.health) {
+                // Still input as far as the watchdog is concerned
+                self.health.mark_input();
+                return false;
+            }
+        }
+        self.consume(chunk);
+        true
+    }
+
+    // Logs a stretch of silence still being skipped when the input runs out
+    fn end_input(&mut self) {
+        if let Some(silence) = &mut self.silence {
+            silence.end_run();
+        }
+    }
+
+    // Interleaved frames are reduced to each tap's channel or their mono mix, then
+    // decimated and filtered; the recording keeps the raw input
+    fn consume(&mut self, data: &[f32]) {
+        self.meter.add(data);
+        let notch_enabled = self.notch_enabled.load(Ordering::Relaxed);
+        let mut overruns = 0;
+        for tap in &mut self.taps {
+            let buffer = &tap.buffer;
+            for frame in data.chunks_exact(self.channels) {
+                let mut sample = tap.channel.pick(frame);
+                if let Some(decimator) = &mut tap.decimator {
+                    match decimator.process(sample) {
+                        Some(decimated) => sample = decimated,
+                        None => continue,
+                    }
+                }
+                if let Some(filter) = &mut tap.high_pass {
+                    sample = filter.process(sample);
+                }
+                // The notches always run so toggling them never restarts their state
+                let notched = tap.notches.iter_mut().fold(sample, |x, notch| notch.process(x));
+                if notch_enabled {
+                    sample = notched;
+                }
+                if !buffer.push(sample) && tap.playback {
+                    overruns += 1;
+                }
+            }
+        }
+        self.health.record_overrun(overruns);
+        self.record.push(data);
+        self.health.record_block(data.len() / self.channels);
+        self.health.mark_input();
+    }
+}
+
+// --skip-silence: once a file or pipe has stayed below the threshold for the minimum stretch,
+// the rest of the quiet is passed over. Shorter gaps play through as they are, so pauses
+// between words don't turn choppy.
+#[derive(Clone)]
+struct SilenceSkip {
+    // RMS out of full scale, over a whole chunk and all its channels
+    threshold: f32,
+    min_frames: u64,
+    sample_rate: u32,
+    log: Option<Arc<SkipLog>>,
+    // Frames read so far, those in the current quiet run, and where and how much of it was skipped
+    position: u64,
+    quiet_frames: u64,
+    skip_start: u64,
+    skipped_frames: u64,
+}
+
+impl SilenceSkip {
+    fn new(threshold_db: f32, min_secs: f32, sample_rate: u32, log: Option<Arc<SkipLog>>) -> Self {
+        Self {
+            threshold: 10f32.powf(threshold_db / 20.0),
+            min_frames: (min_secs * sample_rate as f32).round() as u64,
+            sample_rate,
+            log,
+            position: 0,
+            quiet_frames: 0,
+            skip_start: 0,
+            skipped_frames: 0,
+        }
+    }
+
+    // Whether a chunk of `frames` interleaved frames is let through
+    fn admit(&mut self, chunk: &[f32], frames: u64, health: &PipelineHealth) -> bool {
+        let start = self.position;
+        self.position += frames;
+        let rms = (chunk.iter().map(|s| s * s).sum::<f32>() / chunk.len().max(1) as f32).sqrt();
+        if rms >= self.threshold {
+            self.end_run();
+            return true;
+        }
+        self.quiet_frames += frames;
+        if self.quiet_frames <= self.min_frames {
+            return true;
+        }
+        if self.skipped_frames == 0 {
+            self.skip_start = start;
+        }
+        self.skipped_frames += frames;
+        health.record_skip(frames);
+        false
+    }
+
+    fn end_run(&mut self) {
+        if let Some(log) = self.log.as_ref().filter(|_| self.skipped_frames > 0) {
+            let rate = self.sample_rate as f64;
+            log.log(self.skip_start as f64 / rate, self.skipped_frames as f64 / rate);
+        }
+        self.quiet_frames = 0;
+        self.skipped_frames = 0;
+    }
+}
+
+pub enum SampleSource {
+    // A rate other than the default is opened from whichever supported range covers it, and
+    // a buffer size other than the driver's as a fixed one
+    Device { device: cpal::Device, sample_rate: Option<u32>, buffer_size: Option<u32> },
+    File(PathBuf),
+    // Raw interleaved little-endian f32 at a rate given on the command line
+    Stdin { sample_rate: u32, channels: u16 },
+    // An output device captured as an input, on hosts whose backend can (WASAPI loopback)
+    Loopback(cpal::Device),
+    // Mono samples synthesized here, for trying the pipeline without any hardware
+    TestSignal { signal: TestSignal, sample_rate: u32 },
+}
+
+// Keeps the input side alive; dropping it stops the samples
+pub enum InputHandle {
+    Stream { stream: cpal::Stream },
+    File { feeder: FileFeeder },
+}
+
+pub struct FileFeeder {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for FileFeeder {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl SampleSource {
+    // A rate the device can't run at falls back to its default with a warning, printed here
+    // while the terminal is still in its normal mode
+    pub fn device(device: cpal::Device, sample_rate: Option<u32>) -> Self {
+        let sample_rate = sample_rate.filter(|&rate| {
+            let Ok(default) = device.default_input_config() else { return true };
+            let supported = rate == default.sample_rate().0 || config_with_rate(&device, &default, rate).is_some();
+            if !supported {
+                eprintln!("Warning: {} does not support {} Hz, using its default {} Hz",
+                          device.name().unwrap_or_else(|_| "the input device".to_string()), rate,
+                          default.sample_rate().0);
+            }
+            supported
+        });
+        Self::Device { device, sample_rate, buffer_size: None }
+    }
+
+    // Only a capture device takes a buffer size; the other sources keep their own blocks
+    pub fn with_buffer_size(self, frames: Option<u32>) -> Self {
+        match self {
+            Self::Device { device, sample_rate, .. } => Self::Device { device, sample_rate, buffer_size: frames },
+            other => other,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Self::Device { device, .. } => device.name().unwrap_or_else(|_| "unknown device".to_string()),
+            Self::File(path) => path.display().to_string(),
+            Self::Stdin { .. } => "stdin".to_string(),
+            Self::Loopback(device) => {
+                format!("{} (loopback)", device.name().unwrap_or_else(|_| "unknown device".to_string()))
+            }
+            Self::TestSignal { signal, .. } => signal.name(),
+        }
+    }
+
+    // Sample rate and channel count of the samples this source produces
+    pub fn format(&self) -> Result<(u32, u16)> {
+        match self {
+            Self::Device { device, sample_rate, .. } => {
+                let config = input_config(device, *sample_rate)?;
+                Ok((config.sample_rate().0, config.channels()))
+            }
+            Self::File(path) => {
+                let spec = open_wav(path)?.spec();
+                Ok((spec.sample_rate, spec.channels))
+            }
+            Self::Stdin { sample_rate, channels } => Ok((*sample_rate, *channels)),
+            Self::Loopback(device) => {
+                let config = device.default_output_config().map_err(|e| open_error("loopback", device, e))?;
+                Ok((config.sample_rate().0, config.channels()))
+            }
+            Self::TestSignal { sample_rate, .. } => Ok((*sample_rate, 1)),
+        }
+    }
+
+    // Fresh handle to the same source, used when the pipeline is rebuilt
+    pub fn reopen(&self) -> Result<Self> {
+        match self {
+            Self::Device { device, sample_rate, buffer_size } => {
+                let device = find_input_device(&device.name()?)?;
+                Ok(Self::Device { device, sample_rate: *sample_rate, buffer_size: *buffer_size })
+            }
+            Self::File(path) => Ok(Self::File(path.clone())),
+            Self::Stdin { sample_rate, channels } => Ok(Self::Stdin { sample_rate: *sample_rate, channels: *channels }),
+            Self::Loopback(_) => loopback_source(None),
+            Self::TestSignal { signal, sample_rate } => {
+                Ok(Self::TestSignal { signal: signal.clone(), sample_rate: *sample_rate })
+            }
+        }
+    }
+
+    fn start(&self, sink: InputSink) -> Result<InputHandle> {
+        match self {
+            Self::Device { device, sample_rate, buffer_size } => {
+                let config = input_config(device, *sample_rate)?;
+                let stream = open_input_stream(device, config, *buffer_size, sink)
+                    .map_err(|e| open_error("input", device, e))?;
+                Ok(InputHandle::Stream { stream })
+            }
+            Self::File(path) => {
+                let reader = open_wav(path)?;
+                let stop = Arc::new(AtomicBool::new(false));
+                let thread_stop = Arc::clone(&stop);
+                let thread = thread::spawn(move || feed_wav(reader, sink, thread_stop));
+                Ok(InputHandle::File { feeder: FileFeeder { stop, thread: Some(thread) } })
+            }
+            Self::Stdin { sample_rate, channels } => {
+                let stop = Arc::new(AtomicBool::new(false));
+                let thread_stop = Arc::clone(&stop);
+                let (sample_rate, channels) = (*sample_rate, *channels as usize);
+                thread::spawn(move || feed_stdin(sink, thread_stop, sample_rate, channels));
+                // A read blocked on the pipe can't be interrupted, so the thread is left to
+                // notice the stop flag after its next read instead of being joined
+                Ok(InputHandle::File { feeder: FileFeeder { stop, thread: None } })
+            }
+            Self::Loopback(device) => {
+                let config = device.default_output_config().map_err(|e| open_error("loopback", device, e))?;
+                let stream = open_input_stream(device, config, None, sink)
+                    .map_err(|e| open_error("loopback", device, e))?;
+                Ok(InputHandle::Stream { stream })
+            }
+            Self::TestSignal { signal, sample_rate } => {
+                let generator = SignalGenerator::new(signal.clone(), *sample_rate);
+                let stop = Arc::new(AtomicBool::new(false));
+                let thread_stop = Arc::clone(&stop);
+                let sample_rate = *sample_rate;
+                let thread = thread::spawn(move || feed_test_signal(generator, sink, thread_stop, sample_rate));
+                Ok(InputHandle::File { feeder: FileFeeder { stop, thread: Some(thread) } })
+            }
+        }
+    }
+}
+
+fn report_input_error(health: &PipelineHealth, err: cpal::StreamError) {
+    health.record_error("Input", &err);
+}
+
+// The default input config, or for another rate the supported range covering it, preferring
+// ranges with the default's sample format and channel count
+fn input_config(device: &cpal::Device, sample_rate: Option<u32>) -> Result<cpal::SupportedStreamConfig> {
+    let default = device.default_input_config().map_err(|e| open_error("input", device, e))?;
+    let Some(rate) = sample_rate.filter(|&rate| rate != default.sample_rate().0) else { return Ok(default) };
+    config_with_rate(device, &default, rate)
+        .ok_or_else(|| anyhow!("Input device '{}' does not support {} Hz; --list-devices shows the rates it does",
+                               device.name().unwrap_or_else(|_| "unknown device".to_string()), rate))
+}
+
+fn config_with_rate(device: &cpal::Device, default: &cpal::SupportedStreamConfig, rate: u32)
+                    -> Option<cpal::SupportedStreamConfig> {
+    device.supported_input_configs().ok()?
+        .filter(|range| (range.min_sample_rate().0..=range.max_sample_rate().0).contains(&rate))
+        .max_by_key(|range| (range.sample_format() == default.sample_format(), range.channels() == default.channels()))
+        .map(|range| range.with_sample_rate(cpal::SampleRate(rate)))
+}
+
+// Opens the stream in the given config, converting any sample format to f32. A buffer size
+// is checked against the range the device reports, when it reports one.
+fn open_input_stream(device: &cpal::Device, config: cpal::SupportedStreamConfig, buffer_size: Option<u32>,
+                     mut sink: InputSink) -> Result<cpal::Stream> {
+    let sample_format = config.sample_format();
+    let mut stream_config = config.config();
+    if let Some(frames) = buffer_size {
+        if let cpal::SupportedBufferSize::Range { min, max } = *config.buffer_size() {
+            if !(min..=max).contains(&frames) {
+                bail!("--buffer-size {} is outside the {} to {} frames the device supports", frames, min, max);
+            }
+        }
+        stream_config.buffer_size = cpal::BufferSize::Fixed(frames);
+    }
+    let config = stream_config;
+    let stream = match sample_format {
+        cpal::SampleFormat::F32 => {
+            let error_health = Arc::clone(&sink.health);
+            device.build_input_stream(
+                &config,
+                move |data: &[f32], _: &cpal::InputCallbackInfo| sink.consume(data),
+                move |err| report_input_error(&error_health, err),
+                None,
+            )?
+        }
+        cpal::SampleFormat::I16 => build_converting_input_stream::<i16>(device, &config, sink)?,
+        cpal::SampleFormat::U16 => build_converting_input_stream::<u16>(device, &config, sink)?,
+        cpal::SampleFormat::I32 => build_converting_input_stream::<i32>(device, &config, sink)?,
+        cpal::SampleFormat::U8 => build_converting_input_stream::<u8>(device, &config, sink)?,
+        cpal::SampleFormat::F64 => build_converting_input_stream::<f64>(device, &config, sink)?,
+        other => bail!("Unsupported input sample format {:?}", other),
+    };
+    stream.play()?;
+    Ok(stream)
+}
+
+// Integer and f64 devices are normalized to f32 before reaching the sink
+fn build_converting_input_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, mut sink: InputSink) -> Result<cpal::Stream>
+where
+    T: cpal::SizedSample,
+    f32: cpal::FromSample<T>,
+{
+    let error_health = Arc::clone(&sink.health);
+    let mut converted = Vec::new();
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            converted.clear();
+            converted.extend(data.iter().map(|&sample| sample.to_sample::<f32>()));
+            sink.consume(&converted);
+        },
+        move |err| report_input_error(&error_health, err),
+        None,
+    )?;
+    Ok(stream)
+}
+
+fn open_wav(path: &Path) -> Result<hound::WavReader<std::io::BufReader<File>>> {
+    hound::WavReader::open(path).map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))
+}
+
+// Streams a WAV file into the sink in 10 ms chunks, paced to real time less any silence skipped
+fn feed_wav(mut reader: hound::WavReader<std::io::BufReader<File>>, mut sink: InputSink, stop: Arc<AtomicBool>) {
+    let spec = reader.spec();
+    let channels = spec.channels as usize;
+    let chunk_len = (spec.sample_rate as usize / 100).max(1) * channels;
+    let int_scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+    let mut samples: Box<dyn Iterator<Item = f32>> = match spec.sample_format {
+        hound::SampleFormat::Float => Box::new(reader.samples::<f32>().map_while(Result::ok)),
+        hound::SampleFormat::Int => Box::new(reader.samples::<i32>().map_while(Result::ok)
+            .map(move |s| s as f32 * int_scale)),
+    };
+
+    let started = Instant::now();
+    let mut fed_frames = 0u64;
+    let mut chunk = Vec::with_capacity(chunk_len);
+    while !stop.load(Ordering::Relaxed) {
+        chunk.clear();
+        chunk.extend(samples.by_ref().take(chunk_len));
+        if chunk.is_empty() {
+            break;
+        }
+        if sink.feed(&chunk) {
+            fed_frames += (chunk.len() / channels) as u64;
+        }
+
+        let due = Duration::from_secs_f64(fed_frames as f64 / spec.sample_rate as f64);
+        if let Some(wait) = due.checked_sub(started.elapsed()) {
+            thread::sleep(wait);
+        }
+    }
+    sink.end_input();
+}
+
+// Reads raw f32 frames from stdin in 10 ms chunks. A producer faster than real time
+// (a file piped in) is slowed down; one that stalls is not caught up in a burst afterwards.
+fn feed_stdin(mut sink: InputSink, stop: Arc<AtomicBool>, sample_rate: u32, channels: usize) {
+    let frame_bytes = channels * std::mem::size_of::<f32>();
+    let chunk_frames = (sample_rate as usize / 100).max(1);
+    let mut bytes = vec![0u8; chunk_frames * frame_bytes];
+    let mut chunk = Vec::with_capacity(chunk_frames * channels);
+    let mut input = stdin().lock();
+
+    let mut started = Instant::now();
+    let mut fed_frames = 0u64;
+    while !stop.load(Ordering::Relaxed) {
+        // read_exact would drop a partial final chunk, so fill by hand until EOF
+        let mut filled = 0;
+        while filled < bytes.len() {
+            match input.read(&mut bytes[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+        let whole = filled - filled % frame_bytes;
+        if whole == 0 {
+            break;
+        }
+        chunk.clear();
+        chunk.extend(bytes[..whole].chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())));
+        if sink.feed(&chunk) {
+            fed_frames += (whole / frame_bytes) as u64;
+        }
+        if filled < bytes.len() {
+            break;
+        }
+
+        let due = Duration::from_secs_f64(fed_frames as f64 / sample_rate as f64);
+        let elapsed = started.elapsed();
+        match due.checked_sub(elapsed) {
+            Some(wait) => thread::sleep(wait),
+            None if elapsed - due > MAX_STDIN_LAG => {
+                started = Instant::now();
+                fed_frames = 0;
+            }
+            None => (),
+        }
+    }
+    sink.end_input();
+    sink.health.mark_ended();
+}
+
+// Generates --test-signal in 10 ms chunks, paced to real time, until stopped
+fn feed_test_signal(mut generator: SignalGenerator, mut sink: InputSink, stop: Arc<AtomicBool>, sample_rate: u32) {
+    let chunk_frames = (sample_rate as usize / 100).max(1);
+    let mut chunk = Vec::with_capacity(chunk_frames);
+    let started = Instant::now();
+    let mut fed_frames = 0u64;
+    while !stop.load(Ordering::Relaxed) {
+        chunk.clear();
+        chunk.extend((0..chunk_frames).map(|_| generator.next_sample()));
+        sink.feed(&chunk);
+        fed_frames += chunk_frames as u64;
+
+        let due = Duration::from_secs_f64(fed_frames as f64 / sample_rate as f64);
+        if let Some(wait) = due.checked_sub(started.elapsed()) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+// Tone the output plays instead of the input, shared with the output callback; the f32s
+// travel as their bits
+#[derive(Default)]
+struct ToneControl {
+    enabled: AtomicBool,
+    freq: AtomicU32,
+    level: AtomicU32,
+}
+
+// Playback volume and left/right balance, which leave the analysis untouched
+struct OutputMix {
+    gain: AtomicU32,
+    balance: AtomicU32,
+}
+
+impl OutputMix {
+    fn new() -> Self {
+        Self { gain: AtomicU32::new(1f32.to_bits()), balance: AtomicU32::new(0f32.to_bits()) }
+    }
+
+    // The side the balance leans away from is turned down; a centered balance leaves both
+    // at the full gain
+    fn channel_gains(&self) -> (f32, f32) {
+        let gain = f32::from_bits(self.gain.load(Ordering::Relaxed));
+        let balance = f32::from_bits(self.balance.load(Ordering::Relaxed));
+        (gain * (1.0 - balance).min(1.0), gain * (1.0 + balance).min(1.0))
+    }
+}
+
+pub struct Pipeline {
+    pub input_buffer: Arc<AudioBuffer>,
+    // Left and right rings for the stereo view, on top of the playback ring
+    pub stereo_buffers: Option<[Arc<AudioBuffer>; 2]>,
+    pub health: Arc<PipelineHealth>,
+    pub meter: Arc<InputMeter>,
+    pub fft: Arc<dyn RealToComplex<f32>>,
+    pub fft_size: usize,
+    pub sample_rate: u32,
+    // Rate of the samples as captured, before any --decimate
+    pub input_rate: u32,
+    muted: Arc<AtomicBool>,
+    notch_enabled: Arc<AtomicBool>,
+    tone: Arc<ToneControl>,
+    mix: Arc<OutputMix>,
+    // Both taken by `shutdown`
+    input: Option<InputHandle>,
+    output_stream: Option<cpal::Stream>,
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl Pipeline {
+    // Ordered teardown, in place of whatever order the fields happen to drop in: both streams
+    // are paused before either goes, so neither callback runs while the other is torn down,
+    // and the feeder thread is joined before the recorder it writes into can be finished.
+    // Dropping the pipeline runs it too, so an error unwinding past one still tears it down
+    // in order; once done, it does nothing.
+    pub fn shutdown(&mut self) {
+        if let Some(stream) = &self.output_stream {
+            let _ = stream.pause();
+        }
+        match self.input.take() {
+            Some(InputHandle::Stream { stream }) => {
+                let _ = stream.pause();
+                drop(stream);
+            }
+            Some(InputHandle::File { feeder }) => drop(feeder),
+            None => (),
+        }
+        drop(self.output_stream.take());
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    pub fn set_notch(&self, enabled: bool) {
+        self.notch_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    // Without a frequency the tone fades out on its last pitch
+    pub fn set_tone(&self, enabled: bool, freq: Option<f32>, volume: f32) {
+        self.tone.enabled.store(enabled, Ordering::Relaxed);
+        if let Some(freq) = freq {
+            self.tone.freq.store(freq.to_bits(), Ordering::Relaxed);
+        }
+        let level = if enabled && freq.is_some() { volume } else { 0.0 };
+        self.tone.level.store(level.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_output_mix(&self, gain_db: f32, balance: f32) {
+        self.mix.gain.store(10f32.powf(gain_db / 20.0).to_bits(), Ordering::Relaxed);
+        self.mix.balance.store(balance.to_bits(), Ordering::Relaxed);
+    }
+}
+
+// The rate everything after the input runs at: the input's, divided by --decimate
+pub fn analysis_rate(input_rate: u32, decimate: usize) -> Result<u32> {
+    if !input_rate.is_multiple_of(decimate as u32) {
+        bail!("--decimate {} does not divide the {} Hz input rate", decimate, input_rate);
+    }
+    Ok(input_rate / decimate as u32)
+}
+
+// `skip_log` outlives the pipeline, like the recorder, so a rebuild keeps appending to it
+pub fn build_pipeline(source: &SampleSource, options: &Options, recorder: &Recorder,
+                      skip_log: Option<&Arc<SkipLog>>) -> Result<Pipeline> {
+    let fft_size = options.fft_size;
+    let (input_rate, channels) = source.format()?;
+    let sample_rate = analysis_rate(input_rate, options.decimate)?;
+    if let ChannelSelect::Index(idx) = options.channel {
+        if idx >= channels as usize {
+            bail!("Channel {} requested but {} only has {} channel(s)", idx, source.name(), channels);
+        }
+    }
+
+    let fft = dsp::plan_fft(fft_size);
+
+    let health = Arc::new(PipelineHealth::new());
+
+    // Shared ring between the input, output and FFT readers
+    // Room for a window plus a frame's worth of overlapped hops behind it
+    let capacity = (2 * fft_size).max(sample_rate as usize / 4);
+    let new_buffer = || Arc::new(AudioBuffer::new(capacity));
+    // Playback reads the same ring, so --output-delay grows it by the delay
+    let delay = (options.output_delay * sample_rate as f32 / 1000.0).round() as usize;
+    if capacity + delay > MAX_RING_SECS as usize * sample_rate as usize {
+        let max_ms = (MAX_RING_SECS as usize * sample_rate as usize - capacity) * 1000 / sample_rate as usize;
+        bail!("--output-delay {} ms does not fit the {} s ring at {} Hz; use at most {} ms",
+              options.output_delay, MAX_RING_SECS, sample_rate, max_ms);
+    }
+    let input_buffer = Arc::new(AudioBuffer::with_delay(capacity + delay, delay));
+    let stereo_buffers = (options.channel_layout == ChannelLayout::Stereo && channels >= 2)
+        .then(|| [new_buffer(), new_buffer()]);
+
+    let tap = |channel, buffer: &Arc<AudioBuffer>, playback| Tap {
+        channel,
+        buffer: Arc::clone(buffer),
+        decimator: (options.decimate > 1).then(|| Decimator::new(options.decimate, input_rate)),
+        high_pass: options.hpf.map(|cutoff| HighPass::new(cutoff, sample_rate)),
+        notches: options.notch.map_or_else(Vec::new, |mains| hum_notches(mains, sample_rate)),
+        playback,
+    };
+    let mut taps = vec![tap(options.channel, &input_buffer, options.output)];
+    if let Some([left, right]) = &stereo_buffers {
+        let [first, second] = options.stereo_mode.channels();
+        taps.push(tap(first, left, false));
+        taps.push(tap(second, right, false));
+    }
+
+    let notch_enabled = Arc::new(AtomicBool::new(options.notch.is_some()));
+    let meter = Arc::new(InputMeter::default());
+    let input = source.start(InputSink {
+        taps,
+        record: recorder.tap(),
+        health: Arc::clone(&health),
+        meter: Arc::clone(&meter),
+        channels: channels as usize,
+        notch_enabled: Arc::clone(&notch_enabled),
+        silence: options.skip_silence.map(|threshold_db| {
+            SilenceSkip::new(threshold_db, options.skip_silence_min, input_rate, skip_log.cloned())
+        }),
+    })?;
+
+    let muted = Arc::new(AtomicBool::new(false));
+    let tone = Arc::new(ToneControl::default());
+    let mix = Arc::new(OutputMix::new());
+    let output_stream = if options.output {
+        let device = output_device(options.output_device.as_ref())?;
+        Some(build_output_stream(&device, sample_rate, Arc::clone(&input_buffer), Arc::clone(&muted),
+                                 Arc::clone(&tone), Arc::clone(&mix), Arc::clone(&health))
+            .map_err(|e| open_error("output", &device, e))?)
+    } else {
+        None
+    };
+
+    Ok(Pipeline {
+        input_buffer,
+        stereo_buffers,
+        health,
+        meter,
+        fft,
+        fft_size,
+        sample_rate,
+        input_rate,
+        muted,
+        notch_enabled,
+        tone,
+        mix,
+        input: Some(input),
+        output_stream,
+    })
+}
+
+// Plays the ring back on `output_device`, converted from the input rate so the ring drains
+// exactly as fast as it fills; muting or playing the tone still drains it so switching back
+// resumes with live audio
+fn build_output_stream(output_device: &cpal::Device, input_rate: u32, buffer: Arc<AudioBuffer>,
+                       muted: Arc<AtomicBool>, tone: Arc<ToneControl>, mix: Arc<OutputMix>,
+                       health: Arc<PipelineHealth>)
+    -> Result<cpal::Stream> {
+    let output_config = output_device.default_output_config()?;
+
+    let output_channels = output_config.channels() as usize;
+    let mut resampler = LinearResampler::new(input_rate, output_config.sample_rate().0);
+    let mut generator = ToneGenerator::new(output_config.sample_rate().0);
+    let error_health = Arc::clone(&health);
+    let output_stream = output_device.build_output_stream(
+        &output_config.config(),
+        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            let muted = muted.load(Ordering::Relaxed);
+            let tone_enabled = tone.enabled.load(Ordering::Relaxed);
+            let tone_freq = f32::from_bits(tone.freq.load(Ordering::Relaxed));
+            let tone_level = f32::from_bits(tone.level.load(Ordering::Relaxed));
+            let (left, right) = mix.channel_gains();
+
+            // The ring is mono, so every output channel gets the same sample, with the balance
+            // applied to the first two; pad with silence on underrun
+            let mut underruns = 0;
+            for frame in data.chunks_mut(output_channels) {
+                let sample = resampler.next_sample(|| {
+                    let sample = buffer.pop();
+                    // Waiting out the delay at the start is not running dry
+                    underruns += (sample.is_none() && buffer.written() > buffer.delay()) as u64;
+                    sample
+                });
+                let input = if tone_enabled { 0.0 } else { sample };
+                let sample = input + generator.next_sample(tone_freq, tone_level);
+                let sample = if muted { 0.0 } else { sample };
+                frame.fill(sample * left.max(right));
+                if let [first, second, ..] = frame {
+                    (*first, *second) = (sample * left, sample * right);
+                }
+            }
+            health.record_underrun(underruns);
+        },
+        move |err| error_health.record_error("Output", &err),
+        None,
+    )?;
+    output_stream.play()?;
+    Ok(output_stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_reports_overrun_and_underrun() {
+        let ring = AudioBuffer::new(4);
+        assert_eq!(ring.pop(), None);
+        assert!((0..4).all(|i| ring.push(i as f32)));
+        assert!(!ring.push(4.0));
+        // The dropped sample is the oldest one
+        assert_eq!(ring.pop(), Some(1.0));
+
+        // A delayed ring holds back the newest samples from playback
+        let delayed = AudioBuffer::with_delay(8, 3);
+        (0..3).for_each(|i| assert!(delayed.push(i as f32)));
+        assert_eq!(delayed.pop(), None);
+        assert!(delayed.push(3.0));
+        assert_eq!((delayed.pop(), delayed.pop()), (Some(0.0), None));
+
+        let health = PipelineHealth::new();
+        health.record_overrun(3);
+        health.record_overrun(0);
+        health.record_underrun(2);
+        assert_eq!((health.overruns(), health.underruns()), (3, 2));
+    }
+
+    #[test]
+    fn stereo_modes_pick_the_channels_or_their_sum_and_difference() {
+        let frame = [0.75, 0.25, -1.0];
+        let pick = |mode: StereoMode| mode.channels().map(|channel| channel.pick(&frame));
+        assert_eq!(pick(StereoMode::LeftRight), [0.75, 0.25]);
+        assert_eq!(pick(StereoMode::MidSide), [0.5, 0.25]);
+        assert_eq!(ChannelSelect::Mix.pick(&frame), 0.0);
+        assert!(matches!("ms".parse(), Ok(StereoMode::MidSide)));
+    }
+
+    #[test]
+    fn silence_plays_for_the_minimum_then_is_skipped_until_the_signal_returns() {
+        // 10-frame chunks at 100 Hz, so 0.2 s of quiet is two chunks
+        let health = PipelineHealth::new();
+        let mut silence = SilenceSkip::new(-40.0, 0.2, 100, None);
+        let (quiet, loud) = ([0.001; 10], [0.5; 10]);
+        let admitted: Vec<bool> = [&loud, &quiet, &quiet, &quiet, &quiet, &loud, &quiet, &loud].iter()
+            .map(|chunk| silence.admit(&chunk[..], 10, &health))
+            .collect();
+        assert_eq!(admitted, [true, true, true, false, false, true, true, true]);
+        assert_eq!(health.skipped_frames(), 20);
+    }
+
+    #[test]
+    fn overlapped_windows_advance_by_the_hop() {
+        let mut hops = HopCursor::new(8, Some(0.75), 24);
+        assert_eq!(hops.due(1).count(), 0);
+        assert_eq!(hops.due(9).collect::<Vec<_>>(), [2, 4, 6, 8]);
+        assert_eq!(hops.due(11).collect::<Vec<_>>(), [10]);
+        // A long stall only catches up on what the ring still holds
+        assert_eq!(hops.due(1000).next(), Some(984));
+        // Steps go one hop at a time, and never past the newest sample
+        hops.skip_to(990);
+        assert_eq!(hops.step(1000), Some(992));
+        assert_eq!(hops.step(1000), Some(994));
+        assert_eq!(hops.step(995), None);
+        assert_eq!(hops.step(996), Some(996));
+
+        let ring = AudioBuffer::new(8);
+        (0..10).for_each(|i| { ring.push(i as f32); });
+        let mut window = [0.0; 4];
+        ring.window_ending(6, &mut window);
+        assert_eq!(window, [2.0, 3.0, 4.0, 5.0]);
+        ring.window_ending(12, &mut window);
+        assert_eq!(window, [8.0, 9.0, 0.0, 0.0]);
+
+        // A tape keeps what the ring goes on to overwrite
+        let mut tape = Tape::new(6, 8);
+        tape.follow(&ring);
+        (10..16).for_each(|i| { ring.push(i as f32); });
+        tape.follow(&ring);
+        assert_eq!(tape.written(), 14);
+        tape.window_ending(8, &mut window);
+        assert_eq!(window, [0.0, 0.0, 6.0, 7.0]);
+        tape.window_ending(14, &mut window);
+        assert_eq!(window, [10.0, 11.0, 12.0, 13.0]);
+    }
+}
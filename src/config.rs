@@ -0,0 +1,284 @@
+// Settings file: defaults for the command-line flags, and a snapshot of the live view
+
+use crate::view::{
+    Band, Colormap, GateMode, LevelUnit, Quantity, TimeCompression, TraceStyle, ViewState, DEFAULT_CLASSIC_THRESHOLDS,
+    DEFAULT_GAIN_STEP_DB, DEFAULT_ONSET_THRESHOLD, DEFAULT_PHASE_GATE_DB, DEFAULT_STEP,
+};
+use anyhow::{anyhow, bail, Result};
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use toml::{Table, Value};
+
+// $XDG_CONFIG_HOME/qmx-capture/config.toml, falling back to ~/.config
+pub fn config_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("qmx-capture").join("config.toml"))
+}
+
+// A missing file is an empty table, not an error
+fn read_table(path: &Path) -> Result<Table> {
+    match fs::read_to_string(path) {
+        Ok(text) => text.parse().map_err(|e| anyhow!("{}", e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Table::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Config entries as flag/value pairs: `fft-size = 4096` reads like `--fft-size 4096` and
+// `ascii = true` like `--ascii`. An unreadable file only costs its defaults.
+pub fn load_args() -> Vec<(String, Vec<String>)> {
+    let Some(path) = config_path() else { return Vec::new() };
+    let table = match read_table(&path) {
+        Ok(table) => table,
+        Err(e) => {
+            eprintln!("Ignoring config {}: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+
+    table.into_iter().filter_map(|(key, value)| {
+        let flag = format!("--{}", key);
+        match value {
+            Value::Boolean(true) => Some((flag, Vec::new())),
+            Value::Boolean(false) => None,
+            Value::String(s) => Some((flag, vec![s])),
+            Value::Integer(i) => Some((flag, vec![i.to_string()])),
+            Value::Float(f) => Some((flag, vec![f.to_string()])),
+            other => {
+                eprintln!("Ignoring config entry {}: unsupported value {}", key, other);
+                None
+            }
+        }
+    }).collect()
+}
+
+// A band plan is a list of `[[band]]` tables, each with a name and start and end in Hz
+pub fn load_bands(path: &Path) -> Result<Vec<Band>> {
+    let text = fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+    let table: Table = text.parse().map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    let Some(Value::Array(entries)) = table.get("band") else {
+        bail!("{} has no [[band]] entries", path.display());
+    };
+    let freq = |entry: &Table, key: &str| match entry.get(key) {
+        Some(Value::Integer(i)) => Some(*i as f32),
+        Some(Value::Float(f)) => Some(*f as f32),
+        _ => None,
+    };
+    entries.iter().enumerate().map(|(i, entry)| {
+        let parsed = entry.as_table().and_then(|entry| {
+            let name = entry.get("name")?.as_str()?.to_string();
+            Some(Band { name, start: freq(entry, "start")?, end: freq(entry, "end")? })
+        });
+        match parsed {
+            Some(band) if band.start >= 0.0 && band.start < band.end => Ok(band),
+            Some(band) => bail!("{}: band '{}' must start at or above 0 Hz and below its end", path.display(), band.name),
+            None => bail!("{}: band {} needs a name, start and end", path.display(), i + 1),
+        }
+    }).collect()
+}
+
+// A target response for --reference-curve: one frequency in Hz and magnitude per line,
+// split by a comma or spaces, in rising frequency. Blank lines and # comments are skipped.
+pub fn load_reference_curve(path: &Path) -> Result<Vec<(f32, f32)>> {
+    let text = fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+    let mut curve: Vec<(f32, f32)> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<f32> = line.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|field| !field.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map_err(|_| anyhow!("{}: line {} is not a frequency and magnitude", path.display(), i + 1))?;
+        let &[freq, magnitude] = fields.as_slice() else {
+            bail!("{}: line {} needs a frequency and a magnitude", path.display(), i + 1);
+        };
+        let valid = freq.is_finite() && magnitude.is_finite() && freq >= 0.0 && magnitude >= 0.0;
+        if !valid || curve.last().is_some_and(|&(last, _)| freq <= last) {
+            bail!("{}: line {} must have a frequency above the one before and a magnitude of 0 or more",
+                  path.display(), i + 1);
+        }
+        curve.push((freq, magnitude));
+    }
+    if curve.len() < 2 {
+        bail!("{} needs at least two points", path.display());
+    }
+    Ok(curve)
+}
+
+// Three decimals is plenty for gain and zoom and keeps f32 noise out of the file
+fn rounded(value: f32) -> Value {
+    Value::Float((value as f64 * 1000.0).round() / 1000.0)
+}
+
+// Writes the live view settings and device into the config, keeping any other keys
+pub fn save(state: &ViewState, device_name: Option<&str>) -> Result<PathBuf> {
+    let path = config_path().ok_or_else(|| anyhow!("No home directory to keep a config in"))?;
+    let mut table = read_table(&path)?;
+
+    table.insert("gain".into(), rounded(state.gain_db()));
+    table.insert("zoom".into(), rounded(state.freq_zoom));
+    table.insert("fmin".into(), rounded(state.fmin));
+    table.insert("fft-size".into(), Value::Integer((state.fft_size / state.zero_pad) as i64));
+    table.insert("window".into(), Value::String(state.window.name().to_lowercase()));
+    table.insert("scale".into(), Value::String(state.scale.name().into()));
+    table.insert("db-floor".into(), rounded(state.db_floor));
+    table.insert("weighting".into(), Value::String(state.weighting.name().to_lowercase()));
+    table.insert("freq-axis".into(), Value::String(state.freq_axis.name().into()));
+    table.insert("scroll".into(), Value::String(state.scroll.name().into()));
+    table.insert("layout".into(), Value::String(state.layout.name().into()));
+    table.insert("colormap".into(), Value::String(state.colormap.name().into()));
+    table.insert("waterfall-char".into(), Value::String(state.waterfall_glyph.name()));
+    table.insert("average".into(), rounded(state.average_alpha));
+    table.insert("fps".into(), Value::Integer(state.fps as i64));
+    table.insert("waterfall-scale".into(), rounded(state.waterfall_scale));
+    table.insert("waterfall-gamma".into(), rounded(state.waterfall_gamma));
+    for (key, enabled) in [("bars", state.bars), ("braille", state.braille), ("auto-contrast", state.auto_contrast),
+                           ("envelope", state.envelope.enabled), ("session-max", state.session_max_enabled),
+                           ("follow-peak", state.follow_peak), ("phase-panel", state.phase_panel),
+                           ("onsets", state.onset_threshold.is_some()),
+                           ("invert", state.invert)] {
+        if enabled {
+            table.insert(key.into(), Value::Boolean(true));
+        } else {
+            table.remove(key);
+        }
+    }
+    if state.persistence {
+        table.insert("persistence".into(), rounded(state.persistence_decay));
+    } else {
+        table.remove("persistence");
+    }
+    if state.compand {
+        table.insert("compand".into(), Value::String(state.compander.name()));
+    } else {
+        table.remove("compand");
+    }
+    match state.colormap {
+        Colormap::Classic(thresholds) if thresholds != DEFAULT_CLASSIC_THRESHOLDS => {
+            table.insert("classic-thresholds".into(), Value::String(thresholds.name()));
+        }
+        _ => {
+            table.remove("classic-thresholds");
+        }
+    }
+    if state.gate == GateMode::Level {
+        table.insert("gate".into(), rounded(state.gate_db));
+    } else {
+        table.remove("gate");
+    }
+    if state.level_unit == LevelUnit::Db {
+        table.remove("cal-offset");
+        table.remove("cal-unit");
+    } else {
+        table.insert("cal-offset".into(), rounded(state.cal_offset_db));
+        table.insert("cal-unit".into(), Value::String(state.level_unit.name().into()));
+    }
+    for (key, value) in [("output-gain", state.output_gain_db), ("balance", state.balance)] {
+        if value != 0.0 {
+            table.insert(key.into(), rounded(value));
+        } else {
+            table.remove(key);
+        }
+    }
+    match state.trace_color {
+        Some(color) => {
+            table.insert("trace-color".into(), Value::String(color.name()));
+        }
+        None => {
+            table.remove("trace-color");
+        }
+    }
+    if state.trace_style != TraceStyle::Line {
+        table.insert("trace-style".into(), Value::String(state.trace_style.name().into()));
+    } else {
+        table.remove("trace-style");
+    }
+    match state.level_range {
+        Some(range) => {
+            table.insert("level-range".into(), Value::String(range.name()));
+        }
+        None => {
+            table.remove("level-range");
+        }
+    }
+    if state.waterfall_smooth > 1 {
+        table.insert("waterfall-smooth".into(), Value::Integer(state.waterfall_smooth as i64));
+    } else {
+        table.remove("waterfall-smooth");
+    }
+    if state.zero_pad > 1 {
+        table.insert("zero-pad".into(), Value::Integer(state.zero_pad as i64));
+    } else {
+        table.remove("zero-pad");
+    }
+    if state.quantity != Quantity::Magnitude {
+        table.insert("quantity".into(), Value::String(state.quantity.name().to_lowercase()));
+    } else {
+        table.remove("quantity");
+    }
+    if state.time_compress > 1 {
+        table.insert("time-compress".into(), Value::Integer(state.time_compress as i64));
+    } else {
+        table.remove("time-compress");
+    }
+    if state.time_compress_mode != TimeCompression::Max {
+        table.insert("time-compress-mode".into(), Value::String(state.time_compress_mode.name().into()));
+    } else {
+        table.remove("time-compress-mode");
+    }
+    match state.onset_threshold {
+        Some(threshold) if threshold != DEFAULT_ONSET_THRESHOLD => {
+            table.insert("onset-threshold".into(), rounded(threshold));
+        }
+        _ => {
+            table.remove("onset-threshold");
+        }
+    }
+    if state.phase_gate_db != DEFAULT_PHASE_GATE_DB {
+        table.insert("phase-gate".into(), rounded(state.phase_gate_db));
+    } else {
+        table.remove("phase-gate");
+    }
+    match state.waterfall_fps {
+        Some(fps) => {
+            table.insert("waterfall-fps".into(), Value::Integer(fps as i64));
+        }
+        None => {
+            table.remove("waterfall-fps");
+        }
+    }
+    if state.step != DEFAULT_STEP {
+        table.insert("step".into(), rounded(state.step));
+    } else {
+        table.remove("step");
+    }
+    if state.gain_step_db != DEFAULT_GAIN_STEP_DB {
+        table.insert("gain-step".into(), rounded(state.gain_step_db));
+    } else {
+        table.remove("gain-step");
+    }
+    if state.bandwidth {
+        table.insert("bandwidth".into(), rounded(state.bandwidth_db));
+    } else {
+        table.remove("bandwidth");
+    }
+    if let Some(name) = device_name {
+        table.remove("device");
+        table.insert("device-name".into(), Value::String(name.into()));
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, table.to_string())?;
+    Ok(path)
+}
@@ -0,0 +1,1336 @@
+// Signal processing: analysis windows, the FFT and turning its output into magnitudes
+
+use anyhow::{anyhow, bail, Result};
+use realfft::{num_complex::Complex, ComplexToReal, RealFftPlanner, RealToComplex};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+pub const BASE_GAIN: f32 = 10.0;
+const NOTCH_HARMONICS: usize = 3;
+const NOTCH_Q: f32 = 20.0;
+// Time the tone generator takes to glide to a new frequency or level
+const TONE_GLIDE_SECS: f32 = 0.01;
+// THD counts harmonics up to this order, each over its bin and this many either side so
+// window leakage stays with the harmonic
+const THD_HARMONICS: usize = 10;
+const THD_HALF_WIDTH_BINS: usize = 3;
+// Section Qs of an 8th-order Butterworth lowpass, and its cutoff as a fraction of the
+// decimated rate: 80% of the new Nyquist, leaving the rest for the roll-off
+const DECIMATE_QS: [f32; 4] = [0.5098, 0.6013, 0.9000, 2.5629];
+const DECIMATE_CUTOFF: f32 = 0.4;
+// Power floor for spectral flatness, relative to the mean power (-120 dB)
+const FLATNESS_FLOOR: f64 = 1e-12;
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+// Peak level of --test-signal, -6 dBFS, shared between the tones of a sum. A sweep takes
+// 10 s from end to end unless the spec says otherwise.
+const TEST_SIGNAL_LEVEL: f32 = 0.5;
+const DEFAULT_SWEEP_SECS: f32 = 10.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Window {
+    Hann,
+    Hamming,
+    Blackman,
+    Rectangular,
+}
+
+impl Window {
+    // Each window is a sum of cosines, a0 - a1 cos x + a2 cos 2x over one period
+    fn cosine_terms(self) -> (f32, f32, f32) {
+        match self {
+            Self::Hann => (0.5, 0.5, 0.0),
+            Self::Hamming => (0.54, 0.46, 0.0),
+            Self::Blackman => (0.42, 0.5, 0.08),
+            Self::Rectangular => (1.0, 0.0, 0.0),
+        }
+    }
+
+    pub fn coefficients(self, size: usize) -> Vec<f32> {
+        let (a0, a1, a2) = self.cosine_terms();
+        (0..size)
+            .map(|i| {
+                let x = 2.0 * std::f32::consts::PI * i as f32 / size as f32;
+                a0 - a1 * x.cos() + a2 * (2.0 * x).cos()
+            })
+            .collect()
+    }
+
+    // Mean coefficient: how much of a sine's amplitude survives the window
+    pub fn coherent_gain(self) -> f32 {
+        self.cosine_terms().0
+    }
+
+    // Equivalent noise bandwidth in bins, the mean square coefficient over the squared mean:
+    // the width of the ideal filter that would pass as much noise as one windowed bin
+    pub fn noise_bandwidth(self) -> f32 {
+        let (a0, a1, a2) = self.cosine_terms();
+        (a0 * a0 + (a1 * a1 + a2 * a2) / 2.0) / (a0 * a0)
+    }
+
+    // Coefficients over the last size / zero_pad samples, zero before them: the FFT then runs
+    // over the padded length while only the captured samples are windowed
+    pub fn padded_coefficients(self, size: usize, zero_pad: usize) -> Vec<f32> {
+        let captured = size / zero_pad;
+        let mut coefficients = vec![0.0; size - captured];
+        coefficients.extend(self.coefficients(captured));
+        coefficients
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            Self::Hann => Self::Hamming,
+            Self::Hamming => Self::Blackman,
+            Self::Blackman => Self::Rectangular,
+            Self::Rectangular => Self::Hann,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Hann => "Hann",
+            Self::Hamming => "Hamming",
+            Self::Blackman => "Blackman",
+            Self::Rectangular => "Rectangular",
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "hann" => Ok(Self::Hann),
+            "hamming" => Ok(Self::Hamming),
+            "blackman" => Ok(Self::Blackman),
+            "rectangular" | "rect" | "none" => Ok(Self::Rectangular),
+            _ => bail!("Unknown window '{}', expected hann, hamming, blackman or rectangular", s),
+        }
+    }
+}
+
+// Frequency weighting curves of IEC 61672, applied to magnitudes as amplitude factors
+#[derive(Clone, Copy, PartialEq)]
+pub enum Weighting {
+    None,
+    A,
+    C,
+}
+
+impl Weighting {
+    // Normalized to 1 at 1 kHz, the reference point of both curves
+    pub fn factor(self, freq: f32) -> f32 {
+        let response = |f: f64| {
+            let f2 = f * f;
+            let (p1, p2, p3, p4) = (20.6f64.powi(2), 107.7f64.powi(2), 737.9f64.powi(2), 12194f64.powi(2));
+            match self {
+                Self::None => 1.0,
+                Self::A => p4 * f2 * f2 / ((f2 + p1) * ((f2 + p2) * (f2 + p3)).sqrt() * (f2 + p4)),
+                Self::C => p4 * f2 / ((f2 + p1) * (f2 + p4)),
+            }
+        };
+        (response(freq as f64) / response(1000.0)) as f32
+    }
+
+    // One factor per FFT bin, computed once per sample rate
+    pub fn table(self, sample_rate: u32, fft_size: usize) -> Vec<f32> {
+        let bin_width = sample_rate as f32 / fft_size as f32;
+        (0..fft_size / 2).map(|i| self.factor(i as f32 * bin_width)).collect()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::A => "A",
+            Self::C => "C",
+        }
+    }
+}
+
+impl FromStr for Weighting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "none" | "z" | "flat" => Ok(Self::None),
+            "a" => Ok(Self::A),
+            "c" => Ok(Self::C),
+            _ => bail!("Unknown weighting '{}', expected a, c or none", s),
+        }
+    }
+}
+
+// Nearest equal-tempered note to a frequency, and how many cents it is off
+#[derive(Clone, Copy, Debug)]
+pub struct Note {
+    pub midi: i32,
+    pub cents: f32,
+}
+
+impl Note {
+    pub fn nearest(freq: f32) -> Self {
+        let exact = 69.0 + 12.0 * (freq / 440.0).log2();
+        let midi = exact.round();
+        Self { midi: midi as i32, cents: (exact - midi) * 100.0 }
+    }
+
+    // e.g. "A4" for 440 Hz
+    pub fn name(&self) -> String {
+        format!("{}{}", NOTE_NAMES[self.midi.rem_euclid(12) as usize], self.midi.div_euclid(12) - 1)
+    }
+}
+
+pub fn plan_fft(size: usize) -> Arc<dyn RealToComplex<f32>> {
+    RealFftPlanner::new().plan_fft_forward(size)
+}
+
+// Working memory for `compute_spectrum_into`, sized for one FFT plan
+pub struct SpectrumBuffers {
+    input: Vec<f32>,
+    output: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    // `display_tilt` of each output bin, with DC at zero so it drops out of the spectrum
+    tilt: Vec<f32>,
+}
+
+impl SpectrumBuffers {
+    pub fn new(fft: &dyn RealToComplex<f32>) -> Self {
+        let output = fft.make_output_vec();
+        let tilt = (0..output.len()).map(|i| if i == 0 { 0.0 } else { display_tilt(i as f32) }).collect();
+        Self { input: fft.make_input_vec(), output, scratch: fft.make_scratch_vec(), tilt }
+    }
+
+    // Phase in radians of each bin of the last `compute_spectrum_into`, as far as `phases` reaches
+    pub fn phases_into(&self, phases: &mut [f32]) {
+        for (phase, x) in phases.iter_mut().zip(&self.output) {
+            *phase = x.arg();
+        }
+    }
+}
+
+// Everything built for one FFT size: the plan, its working memory and the window tables used with it
+pub struct FftSetup {
+    pub fft: Arc<dyn RealToComplex<f32>>,
+    pub buffers: SpectrumBuffers,
+    // The FFT length over the samples captured for it, 1 without --zero-pad
+    pub zero_pad: usize,
+    windows: Vec<(Window, Arc<[f32]>)>,
+}
+
+impl FftSetup {
+    pub fn new(fft: Arc<dyn RealToComplex<f32>>, zero_pad: usize) -> Self {
+        Self { buffers: SpectrumBuffers::new(&*fft), fft, zero_pad, windows: Vec::new() }
+    }
+
+    // Coefficients for `window` at this size, computed the first time each window is asked for
+    pub fn window(&mut self, window: Window) -> Arc<[f32]> {
+        if let Some((_, coefficients)) = self.windows.iter().find(|(w, _)| *w == window) {
+            return Arc::clone(coefficients);
+        }
+        let coefficients: Arc<[f32]> = window.padded_coefficients(self.fft.len(), self.zero_pad).into();
+        self.windows.push((window, Arc::clone(&coefficients)));
+        coefficients
+    }
+}
+
+// Setups for the sizes used so far, so switching back to a size builds and allocates nothing
+pub struct FftCache {
+    planner: RealFftPlanner<f32>,
+    setups: HashMap<usize, FftSetup>,
+    zero_pad: usize,
+}
+
+impl FftCache {
+    pub fn new(zero_pad: usize) -> Self {
+        Self { planner: RealFftPlanner::new(), setups: HashMap::new(), zero_pad }
+    }
+
+    pub fn take(&mut self, size: usize) -> FftSetup {
+        match self.setups.remove(&size) {
+            Some(setup) => setup,
+            None => FftSetup::new(self.planner.plan_fft_forward(size), self.zero_pad),
+        }
+    }
+
+    // Hands a setup back once it is no longer in use
+    pub fn store(&mut self, setup: FftSetup) {
+        self.setups.insert(setup.fft.len(), setup);
+    }
+}
+
+// Windowed magnitude spectrum of `samples`, fft_size/2 bins with DC zeroed
+pub fn compute_spectrum(samples: &[f32], window: &[f32], fft: &dyn RealToComplex<f32>, gain: f32) -> Result<Vec<f32>> {
+    let mut spectrum = vec![0.0; fft.len() / 2];
+    compute_spectrum_into(samples, window, fft, gain, &mut SpectrumBuffers::new(fft), &mut spectrum)?;
+    Ok(spectrum)
+}
+
+// Same as `compute_spectrum` without allocating, for the per-frame loop. Higher bins get a
+// gentle boost so the treble stays visible next to the bass
+pub fn compute_spectrum_into(samples: &[f32], window: &[f32], fft: &dyn RealToComplex<f32>, gain: f32,
+                             buffers: &mut SpectrumBuffers, spectrum: &mut [f32]) -> Result<()> {
+    compute_spectrum_timed(samples, window, fft, gain, buffers, spectrum, None)
+}
+
+// Time spent in each step of `compute_spectrum_timed`, added up over however many calls
+#[derive(Default, Clone, Copy)]
+pub struct StageTimes {
+    pub window: Duration,
+    pub fft: Duration,
+    pub magnitude: Duration,
+}
+
+// `compute_spectrum_into` that also adds its step timings to `times`; without them it
+// doesn't read the clock at all
+pub fn compute_spectrum_timed(samples: &[f32], window: &[f32], fft: &dyn RealToComplex<f32>, gain: f32,
+                              buffers: &mut SpectrumBuffers, spectrum: &mut [f32], mut times: Option<&mut StageTimes>)
+                              -> Result<()> {
+    let mut lap = times.is_some().then(Instant::now);
+    let mut split = |stage: fn(&mut StageTimes) -> &mut Duration| {
+        if let (Some(times), Some(lap)) = (times.as_deref_mut(), lap.as_mut()) {
+            let now = Instant::now();
+            *stage(times) += now - *lap;
+            *lap = now;
+        }
+    };
+
+    let scale = gain * BASE_GAIN;
+    for ((slot, &sample), &coefficient) in buffers.input.iter_mut().zip(samples).zip(window) {
+        *slot = sample * coefficient * scale;
+    }
+    split(|times| &mut times.window);
+
+    // The R2C transform yields fft_size/2 + 1 bins; the Nyquist bin is not displayed
+    fft.process_with_scratch(&mut buffers.input, &mut buffers.output, &mut buffers.scratch)
+        .map_err(|e| anyhow!("FFT failed: {}", e))?;
+    split(|times| &mut times.fft);
+
+    magnitudes_into(&buffers.output, &buffers.tilt, spectrum);
+    split(|times| &mut times.magnitude);
+    Ok(())
+}
+
+// Bins per step of `magnitudes_into`: 8 f32 lanes is one AVX register, two SSE or NEON ones
+const MAGNITUDE_LANES: usize = 8;
+
+// `|x| * tilt` for each bin. Fixed-size chunks give the compiler straight-line code it turns
+// into vector square roots and multiplies; the tail, if any, goes one bin at a time.
+fn magnitudes_into(output: &[Complex<f32>], tilt: &[f32], spectrum: &mut [f32]) {
+    let bins = spectrum.len().min(output.len());
+    let (spectrum, output, tilt) = (&mut spectrum[..bins], &output[..bins], &tilt[..bins]);
+    let mut chunks = spectrum.chunks_exact_mut(MAGNITUDE_LANES);
+    let lanes = output.chunks_exact(MAGNITUDE_LANES).zip(tilt.chunks_exact(MAGNITUDE_LANES));
+    for (mags, (xs, tilts)) in (&mut chunks).zip(lanes) {
+        let mags: &mut [f32; MAGNITUDE_LANES] = mags.try_into().unwrap();
+        let xs: &[Complex<f32>; MAGNITUDE_LANES] = xs.try_into().unwrap();
+        let tilts: &[f32; MAGNITUDE_LANES] = tilts.try_into().unwrap();
+        for ((mag, x), tilt) in mags.iter_mut().zip(xs).zip(tilts) {
+            *mag = (x.re * x.re + x.im * x.im).sqrt() * tilt;
+        }
+    }
+    let done = bins - chunks.into_remainder().len();
+    for ((mag, x), tilt) in spectrum[done..].iter_mut().zip(&output[done..]).zip(&tilt[done..]) {
+        *mag = x.norm_sqr().sqrt() * tilt;
+    }
+}
+
+// Goertzel filter: the magnitude at exactly one frequency, scaled like a `compute_spectrum`
+// bin including its display tilt so the two read the same on screen
+pub fn goertzel(samples: &[f32], window: &[f32], freq: f32, sample_rate: u32, gain: f32) -> f32 {
+    let omega = 2.0 * std::f64::consts::PI * freq as f64 / sample_rate as f64;
+    let coeff = 2.0 * omega.cos();
+    let (mut s1, mut s2) = (0.0f64, 0.0f64);
+    for (&sample, &coefficient) in samples.iter().zip(window) {
+        let s0 = (sample * coefficient) as f64 + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+    }
+    let magnitude = (s1 * s1 + s2 * s2 - coeff * s1 * s2).max(0.0).sqrt() as f32;
+    let bin = freq * samples.len() as f32 / sample_rate as f32;
+    magnitude * gain * BASE_GAIN * display_tilt(bin)
+}
+
+// Where the true peak lies relative to the loudest of three neighboring bins, in bins: the
+// vertex of the parabola through their log magnitudes, which a smooth window makes close to
+// a parabola itself. Within half a bin either way; 0 when a neighbor is empty.
+pub fn parabolic_offset(left: f32, center: f32, right: f32) -> f32 {
+    if left <= 0.0 || center <= 0.0 || right <= 0.0 {
+        return 0.0;
+    }
+    let (left, center, right) = (left.ln(), center.ln(), right.ln());
+    let curvature = left - 2.0 * center + right;
+    if curvature >= 0.0 {
+        return 0.0;
+    }
+    (0.5 * (left - right) / curvature).clamp(-0.5, 0.5)
+}
+
+// The treble boost `compute_spectrum` gives each bin
+pub fn display_tilt(bin: f32) -> f32 {
+    (1.0 + bin / 100.0).log10()
+}
+
+// A level with the display tilt already out, such as a `band_levels` one, as the amplitude
+// of the sine it holds over full scale: the gain and the window's coherent gain taken back
+// out too. `captured` is the samples the window spans, without the zero padding.
+pub fn sine_amplitude(level: f32, captured: usize, window: Window, gain: f32) -> f32 {
+    level / (gain * BASE_GAIN) * 2.0 / (captured as f32 * window.coherent_gain())
+}
+
+// `sine_amplitude` of one `compute_spectrum` bin, whose display tilt comes out first
+pub fn bin_sine_amplitude(magnitude: f32, bin: f32, captured: usize, window: Window, gain: f32) -> f32 {
+    sine_amplitude(magnitude / display_tilt(bin).max(f32::MIN_POSITIVE), captured, window, gain)
+}
+
+// Total harmonic distortion of a `compute_spectrum` spectrum as a ratio: the RMS of
+// harmonics 2f and up over the fundamental's, with the display tilt taken back out.
+// Harmonics above Nyquist are left out; a fundamental too low for its harmonics to be
+// told apart, or with no energy, gives None.
+pub fn thd(spectrum: &[f32], fundamental: f32, sample_rate: u32) -> Option<f32> {
+    let bin_width = sample_rate as f32 / (spectrum.len() * 2) as f32;
+    let band_power = |center: usize| -> f32 {
+        let start = center.saturating_sub(THD_HALF_WIDTH_BINS).max(1);
+        let bins = start..(center + THD_HALF_WIDTH_BINS + 1).min(spectrum.len());
+        bins.map(|i| (spectrum[i] / display_tilt(i as f32)).powi(2)).sum()
+    };
+    let center = (fundamental / bin_width).round() as usize;
+    if center <= 2 * THD_HALF_WIDTH_BINS || center >= spectrum.len() {
+        return None;
+    }
+    let fundamental_power = band_power(center);
+    if fundamental_power <= 0.0 {
+        return None;
+    }
+    let harmonic_power: f32 = (2..=THD_HARMONICS)
+        .map(|order| (order as f32 * fundamental / bin_width).round() as usize)
+        .take_while(|&bin| bin < spectrum.len())
+        .map(band_power)
+        .sum();
+    Some((harmonic_power / fundamental_power).sqrt())
+}
+
+// Spectral flatness of a `compute_spectrum` spectrum: the geometric over the arithmetic
+// mean of the bin powers, from 0 for a pure tone to 1 for white noise. DC is left out
+// and empty bins are floored just above zero so one of them cannot pull the log mean to
+// minus infinity; a spectrum with no energy gives None.
+pub fn spectral_flatness(spectrum: &[f32]) -> Option<f32> {
+    let powers: Vec<f64> = spectrum.iter().enumerate().skip(1)
+        .map(|(i, &mag)| (mag.max(0.0) / display_tilt(i as f32)).powi(2) as f64)
+        .collect();
+    let mean = powers.iter().sum::<f64>() / powers.len() as f64;
+    if powers.is_empty() || mean <= 0.0 {
+        return None;
+    }
+    let floor = mean * FLATNESS_FLOOR;
+    let log_mean = powers.iter().map(|&power| power.max(floor).ln()).sum::<f64>() / powers.len() as f64;
+    Some((log_mean.exp() / mean).min(1.0) as f32)
+}
+
+// Spectral flux of a `compute_spectrum` spectrum against the one before it: the summed rise
+// of each bin over the summed larger of its two levels, from 0 for a steady signal to 1 for
+// one out of silence. DC is left out; None without a previous spectrum of the same size or
+// with neither holding any energy.
+pub fn spectral_flux(previous: &[f32], spectrum: &[f32]) -> Option<f32> {
+    if previous.len() != spectrum.len() {
+        return None;
+    }
+    let (rise, total) = previous.iter().zip(spectrum).skip(1)
+        .fold((0.0, 0.0), |(rise, total), (&before, &now)| (rise + (now - before).max(0.0), total + now.max(before)));
+    (total > 0.0).then(|| rise / total)
+}
+
+// Triangular moving average over `width` neighboring bins, an odd count, weighted 1, 2, ..
+// up to the middle and back down. Near the ends the weights that fall off are left out of
+// the total, so an edge bin isn't pulled toward zero.
+pub fn triangular_smooth(values: &[f32], width: usize) -> Vec<f32> {
+    let half = (width / 2) as isize;
+    (0..values.len() as isize).map(|i| {
+        let (sum, total) = (-half..=half).filter_map(|k| {
+            let value = values.get(usize::try_from(i + k).ok()?)?;
+            let weight = (half + 1 - k.abs()) as f32;
+            Some((value * weight, weight))
+        }).fold((0.0, 0.0), |(sum, total), (value, weight)| (sum + value, total + weight));
+        sum / total
+    }).collect()
+}
+
+// Bins holding the greatest value within `reach` bins either side, strongest first. The DC
+// bin is left out, and of a run of equal values only the first counts.
+pub fn local_peaks(values: &[f32], reach: usize) -> Vec<usize> {
+    let mut peaks: Vec<usize> = (1..values.len()).filter(|&i| {
+        let around = i.saturating_sub(reach).max(1)..(i + reach + 1).min(values.len());
+        values[i] > 0.0 && around.into_iter().all(|j| values[j] < values[i] || (values[j] == values[i] && j >= i))
+    }).collect();
+    peaks.sort_by(|&a, &b| values[b].total_cmp(&values[a]));
+    peaks
+}
+
+// ISO 266 nominal centers, for labels; the edges come from the exact base-2 centers
+const OCTAVE_CENTERS: [f32; 10] = [31.5, 63.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0];
+const THIRD_OCTAVE_CENTERS: [f32; 30] = [
+    25.0, 31.5, 40.0, 50.0, 63.0, 80.0, 100.0, 125.0, 160.0, 200.0, 250.0, 315.0, 400.0, 500.0, 630.0,
+    800.0, 1000.0, 1250.0, 1600.0, 2000.0, 2500.0, 3150.0, 4000.0, 5000.0, 6300.0, 8000.0, 10000.0, 12500.0,
+    16000.0, 20000.0,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BandFraction {
+    Octave,
+    ThirdOctave,
+}
+
+impl BandFraction {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Octave => "octave",
+            Self::ThirdOctave => "third-octave",
+        }
+    }
+
+    // Nominal centers, how many bands below 1 kHz the first one sits, and bands per octave
+    fn centers(self) -> (&'static [f32], i32, i32) {
+        match self {
+            Self::Octave => (&OCTAVE_CENTERS, 5, 1),
+            Self::ThirdOctave => (&THIRD_OCTAVE_CENTERS, 16, 3),
+        }
+    }
+}
+
+impl FromStr for BandFraction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "octave" | "1/1" => Ok(Self::Octave),
+            "third-octave" | "third" | "1/3" => Ok(Self::ThirdOctave),
+            _ => bail!("Unknown band fraction '{}', expected octave or third-octave", s),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OctaveBand {
+    pub center: f32,
+    pub low: f32,
+    pub high: f32,
+}
+
+// The bands that start below Nyquist, each spanning half a band either side of 1 kHz times
+// a power of two (of the cube root of two for third octaves)
+pub fn octave_bands(fraction: BandFraction, sample_rate: u32) -> Vec<OctaveBand> {
+    let nyquist = sample_rate as f32 / 2.0;
+    let (centers, below, per_octave) = fraction.centers();
+    let half_band = 2f32.powf(0.5 / per_octave as f32);
+    centers.iter().zip(-below..).map(|(&center, band)| {
+        let exact = 1000.0 * 2f32.powf(band as f32 / per_octave as f32);
+        OctaveBand { center, low: exact / half_band, high: exact * half_band }
+    }).take_while(|band| band.low < nyquist).collect()
+}
+
+// The energy of the bins of a `compute_spectrum` spectrum in each band, summed rather than
+// averaged since a band is a filter, as a magnitude with the display tilt taken back out.
+// Dividing by the window's noise bandwidth, in bins of this spectrum, makes a tone read its
+// own magnitude whatever the window and FFT size. A low band narrower than the bin spacing
+// can hold no bin at all, and is None.
+pub fn band_levels(spectrum: &[f32], sample_rate: u32, bands: &[OctaveBand], noise_bandwidth: f32)
+    -> Vec<Option<f32>> {
+    let bin_hz = sample_rate as f32 / (2 * spectrum.len().max(1)) as f32;
+    bands.iter().map(|band| {
+        let bins = (band.low / bin_hz).ceil() as usize..((band.high / bin_hz).ceil() as usize).min(spectrum.len());
+        let bins = bins.start.max(1)..bins.end;
+        (!bins.is_empty()).then(|| {
+            let power: f32 = bins.map(|i| (spectrum[i] / display_tilt(i as f32)).powi(2)).sum();
+            (power / noise_bandwidth).sqrt()
+        })
+    }).collect()
+}
+
+// Below this normalized correlation the two channels are taken to be unrelated
+const DELAY_MIN_CORRELATION: f32 = 0.2;
+
+// Time offset between two channels, from the strongest peak of their cross-correlation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Delay {
+    // Positive when the right channel is the later one
+    pub samples: i64,
+    // Normalized correlation at that lag; negative when one channel is inverted
+    pub correlation: f32,
+}
+
+// Cross-correlation through the FFT: both windows are zero-padded to twice their length, so
+// the circular correlation of the transform doesn't wrap, and L·conj(R) is transformed back
+pub struct DelayEstimator {
+    size: usize,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    padded: Vec<f32>,
+    left: Vec<Complex<f32>>,
+    right: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    correlation: Vec<f32>,
+}
+
+impl DelayEstimator {
+    pub fn new(size: usize) -> Self {
+        let mut planner = RealFftPlanner::new();
+        let forward = planner.plan_fft_forward(size * 2);
+        let inverse = planner.plan_fft_inverse(size * 2);
+        let scratch = vec![Complex::default(); forward.get_scratch_len().max(inverse.get_scratch_len())];
+        Self {
+            size,
+            padded: forward.make_input_vec(),
+            left: forward.make_output_vec(),
+            right: forward.make_output_vec(),
+            correlation: inverse.make_output_vec(),
+            scratch,
+            forward,
+            inverse,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Delay of `right` behind `left`, both `size` samples long. Silence in either channel, or
+    // nothing that lines up, gives None.
+    pub fn estimate(&mut self, left: &[f32], right: &[f32]) -> Result<Option<Delay>> {
+        let energy = |samples: &[f32]| samples.iter().map(|&x| x as f64 * x as f64).sum::<f64>();
+        let norm = (energy(left) * energy(right)).sqrt();
+        if norm == 0.0 {
+            return Ok(None);
+        }
+        for (samples, spectrum) in [(left, &mut self.left), (right, &mut self.right)] {
+            self.padded.fill(0.0);
+            self.padded[..samples.len()].copy_from_slice(samples);
+            self.forward.process_with_scratch(&mut self.padded, spectrum, &mut self.scratch)
+                .map_err(|e| anyhow!("FFT failed: {}", e))?;
+        }
+        for (l, r) in self.left.iter_mut().zip(&self.right) {
+            *l *= r.conj();
+        }
+        // Rounding can leave DC and Nyquist a hair off the real axis, which the inverse rejects
+        let last = self.left.len() - 1;
+        self.left[0].im = 0.0;
+        self.left[last].im = 0.0;
+        self.inverse.process_with_scratch(&mut self.left, &mut self.correlation, &mut self.scratch)
+            .map_err(|e| anyhow!("FFT failed: {}", e))?;
+
+        // Index k holds sum(left[n + k] * right[n]), so a later right channel peaks at a negative k
+        let padded_len = self.correlation.len();
+        let (index, &peak) = self.correlation.iter().enumerate()
+            .max_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+            .unwrap();
+        let correlation = (peak as f64 / padded_len as f64 / norm) as f32;
+        if correlation.abs() < DELAY_MIN_CORRELATION {
+            return Ok(None);
+        }
+        let lag = if index < padded_len / 2 { index as i64 } else { index as i64 - padded_len as i64 };
+        Ok(Some(Delay { samples: -lag, correlation }))
+    }
+}
+
+// First-order DC blocker: y[n] = x[n] - x[n-1] + r * y[n-1], with r set by the cutoff.
+// Runs on the continuous sample stream so there are no per-frame transients
+#[derive(Clone)]
+pub struct HighPass {
+    r: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl HighPass {
+    pub fn new(cutoff: f32, sample_rate: u32) -> Self {
+        let r = (-2.0 * std::f32::consts::PI * cutoff / sample_rate as f32).exp();
+        Self { r, prev_input: 0.0, prev_output: 0.0 }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let output = sample - self.prev_input + self.r * self.prev_output;
+        self.prev_input = sample;
+        self.prev_output = output;
+        output
+    }
+}
+
+// Direct form I biquad section, kept in f64: low notches sit so close to DC that f32
+// coefficients lose most of their depth
+#[derive(Clone)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    // RBJ cookbook notch at `freq` with quality factor `q`
+    pub fn notch(freq: f32, q: f32, sample_rate: u32) -> Self {
+        let w0 = 2.0 * std::f64::consts::PI * freq as f64 / sample_rate as f64;
+        let alpha = w0.sin() / (2.0 * q as f64);
+        let a0 = 1.0 + alpha;
+        let cos = w0.cos();
+        Self {
+            b: [1.0 / a0, -2.0 * cos / a0, 1.0 / a0],
+            a: [-2.0 * cos / a0, (1.0 - alpha) / a0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    // RBJ cookbook lowpass at `freq` with quality factor `q`
+    pub fn lowpass(freq: f32, q: f32, sample_rate: u32) -> Self {
+        let w0 = 2.0 * std::f64::consts::PI * freq as f64 / sample_rate as f64;
+        let alpha = w0.sin() / (2.0 * q as f64);
+        let a0 = 1.0 + alpha;
+        let cos = w0.cos();
+        let b = (1.0 - cos) / 2.0 / a0;
+        Self {
+            b: [b, 2.0 * b, b],
+            a: [-2.0 * cos / a0, (1.0 - alpha) / a0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let sample = sample as f64;
+        let output = self.b[0] * sample + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+        self.x = [sample, self.x[0]];
+        self.y = [output, self.y[0]];
+        output as f32
+    }
+}
+
+// Notches at a mains frequency and its first harmonics, skipping any past Nyquist
+pub fn hum_notches(mains: f32, sample_rate: u32) -> Vec<Biquad> {
+    (1..=NOTCH_HARMONICS)
+        .map(|harmonic| mains * harmonic as f32)
+        .filter(|&freq| freq < sample_rate as f32 / 2.0)
+        .map(|freq| Biquad::notch(freq, NOTCH_Q, sample_rate))
+        .collect()
+}
+
+// Lowpass then keep every `factor`th sample, so a fast input can be analyzed at a lower
+// rate without what lies above the new Nyquist folding back into it
+#[derive(Clone)]
+pub struct Decimator {
+    factor: usize,
+    filters: Vec<Biquad>,
+    phase: usize,
+}
+
+impl Decimator {
+    pub fn new(factor: usize, sample_rate: u32) -> Self {
+        let cutoff = DECIMATE_CUTOFF * sample_rate as f32 / factor as f32;
+        let filters = DECIMATE_QS.iter().map(|&q| Biquad::lowpass(cutoff, q, sample_rate)).collect();
+        Self { factor, filters, phase: 0 }
+    }
+
+    // Every input sample goes through the filter; only one in `factor` comes out
+    pub fn process(&mut self, sample: f32) -> Option<f32> {
+        let filtered = self.filters.iter_mut().fold(sample, |x, filter| filter.process(x));
+        self.phase = (self.phase + 1) % self.factor;
+        (self.phase == 0).then_some(filtered)
+    }
+}
+
+// Linear-interpolating rate converter for the playback path, one output sample at a time
+pub struct LinearResampler {
+    step: f64,
+    phase: f64,
+    prev: f32,
+    next: f32,
+}
+
+impl LinearResampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
+        Self { step: input_rate as f64 / output_rate as f64, phase: 1.0, prev: 0.0, next: 0.0 }
+    }
+
+    // Pulls as many input samples as the rate ratio calls for; a source that runs dry
+    // reads as silence
+    pub fn next_sample(&mut self, mut source: impl FnMut() -> Option<f32>) -> f32 {
+        while self.phase >= 1.0 {
+            self.prev = self.next;
+            self.next = source().unwrap_or(0.0);
+            self.phase -= 1.0;
+        }
+        let sample = self.prev + (self.next - self.prev) * self.phase as f32;
+        self.phase += self.step;
+        sample
+    }
+}
+
+// Sine oscillator for the playback tone. The phase carries over when the frequency changes
+// and both frequency and level glide toward their targets, so retuning never clicks.
+pub struct ToneGenerator {
+    sample_rate: f32,
+    glide: f32,
+    phase: f32,
+    freq: f32,
+    amplitude: f32,
+}
+
+impl ToneGenerator {
+    pub fn new(sample_rate: u32) -> Self {
+        let glide = 1.0 - (-1.0 / (TONE_GLIDE_SECS * sample_rate as f32)).exp();
+        Self { sample_rate: sample_rate as f32, glide, phase: 0.0, freq: 0.0, amplitude: 0.0 }
+    }
+
+    pub fn next_sample(&mut self, freq: f32, amplitude: f32) -> f32 {
+        // Fading in from silence there is no old pitch worth gliding from
+        if self.amplitude < 1e-4 {
+            self.freq = freq;
+        }
+        self.freq += (freq - self.freq) * self.glide;
+        self.amplitude += (amplitude - self.amplitude) * self.glide;
+        let sample = (self.phase * std::f32::consts::TAU).sin() * self.amplitude;
+        self.phase = (self.phase + self.freq / self.sample_rate).fract();
+        sample
+    }
+}
+
+// What --test-signal synthesizes: a sine or a sum of them, a logarithmic sweep that starts
+// over at the bottom each time it reaches the top, or noise
+#[derive(Clone, PartialEq, Debug)]
+pub enum TestSignal {
+    Tones(Vec<f32>),
+    Sweep { start: f32, end: f32, secs: f32 },
+    Noise(NoiseColor),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NoiseColor {
+    White,
+    Pink,
+}
+
+impl TestSignal {
+    pub fn name(&self) -> String {
+        match self {
+            Self::Tones(freqs) => {
+                let freqs: Vec<String> = freqs.iter().map(f32::to_string).collect();
+                format!("sine:{}", freqs.join("+"))
+            }
+            Self::Sweep { start, end, secs } => format!("sweep:{}-{}:{}", start, end, secs),
+            Self::Noise(NoiseColor::White) => "noise:white".to_string(),
+            Self::Noise(NoiseColor::Pink) => "noise:pink".to_string(),
+        }
+    }
+
+    // The highest frequency the signal holds, which has to stay below Nyquist
+    pub fn max_freq(&self) -> Option<f32> {
+        match self {
+            Self::Tones(freqs) => freqs.iter().copied().reduce(f32::max),
+            Self::Sweep { end, .. } => Some(*end),
+            Self::Noise(_) => None,
+        }
+    }
+}
+
+impl FromStr for TestSignal {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, parameter) = s.split_once(':').unwrap_or((s, ""));
+        let freq = |text: &str| match text.trim().parse::<f32>() {
+            Ok(freq) if freq > 0.0 && freq.is_finite() => Ok(freq),
+            _ => Err(anyhow!("'{}' in test signal '{}' is not a positive frequency in Hz", text, s)),
+        };
+        match kind.trim().to_lowercase().as_str() {
+            "sine" => Ok(Self::Tones(parameter.split('+').map(freq).collect::<Result<_>>()?)),
+            "sweep" => {
+                let (range, secs) = match parameter.split_once(':') {
+                    Some((range, secs)) => (range, secs.trim().parse::<f32>()
+                        .map_err(|_| anyhow!("Sweep length '{}' is not a number of seconds", secs))?),
+                    None => (parameter, DEFAULT_SWEEP_SECS),
+                };
+                let (start, end) = range.split_once('-')
+                    .ok_or_else(|| anyhow!("A sweep needs its range as START-END in Hz, got '{}'", range))?;
+                let (start, end) = (freq(start)?, freq(end)?);
+                if start >= end {
+                    bail!("A sweep must rise, from {} Hz to above it, got {} Hz", start, end);
+                }
+                if secs <= 0.0 || !secs.is_finite() {
+                    bail!("A sweep must take a positive number of seconds, got {}", secs);
+                }
+                Ok(Self::Sweep { start, end, secs })
+            }
+            "noise" => match parameter.trim().to_lowercase().as_str() {
+                "" | "white" => Ok(Self::Noise(NoiseColor::White)),
+                "pink" => Ok(Self::Noise(NoiseColor::Pink)),
+                _ => bail!("Unknown noise '{}', expected white or pink", parameter),
+            },
+            _ => bail!("Unknown test signal '{}', expected sine:F[+F...], sweep:START-END[:SECS] or noise:white|pink",
+                       s),
+        }
+    }
+}
+
+// Produces a TestSignal one sample at a time. Each tone keeps its own phase and the sweep
+// integrates its frequency, so nothing jumps when the sweep starts over.
+pub struct SignalGenerator {
+    signal: TestSignal,
+    sample_rate: f32,
+    phases: Vec<f32>,
+    elapsed: f32,
+    noise: u32,
+    // Paul Kellet's three-pole filter that turns white noise pink
+    pink: [f32; 3],
+}
+
+impl SignalGenerator {
+    pub fn new(signal: TestSignal, sample_rate: u32) -> Self {
+        let voices = if let TestSignal::Tones(freqs) = &signal { freqs.len() } else { 1 };
+        Self { signal, sample_rate: sample_rate as f32, phases: vec![0.0; voices], elapsed: 0.0, noise: 0x2545_f491,
+               pink: [0.0; 3] }
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        match &self.signal {
+            TestSignal::Tones(freqs) => {
+                let level = TEST_SIGNAL_LEVEL / freqs.len() as f32;
+                freqs.iter().zip(&mut self.phases).map(|(freq, phase)| {
+                    let sample = (*phase * std::f32::consts::TAU).sin();
+                    *phase = (*phase + freq / self.sample_rate).fract();
+                    sample * level
+                }).sum()
+            }
+            &TestSignal::Sweep { start, end, secs } => {
+                let freq = start * (end / start).powf(self.elapsed / secs);
+                self.elapsed = (self.elapsed + 1.0 / self.sample_rate) % secs;
+                let sample = (self.phases[0] * std::f32::consts::TAU).sin();
+                self.phases[0] = (self.phases[0] + freq / self.sample_rate).fract();
+                sample * TEST_SIGNAL_LEVEL
+            }
+            TestSignal::Noise(color) => {
+                // xorshift again, for the same reason as the benchmark's
+                self.noise ^= self.noise << 13;
+                self.noise ^= self.noise >> 17;
+                self.noise ^= self.noise << 5;
+                let white = self.noise as f32 / u32::MAX as f32 * 2.0 - 1.0;
+                match color {
+                    NoiseColor::White => white * TEST_SIGNAL_LEVEL,
+                    NoiseColor::Pink => {
+                        let [b0, b1, b2] = &mut self.pink;
+                        *b0 = 0.99765 * *b0 + white * 0.0990460;
+                        *b1 = 0.96300 * *b1 + white * 0.2965164;
+                        *b2 = 0.57000 * *b2 + white * 1.0526913;
+                        // The filter's gain is about 3, so this lands near the white noise's level
+                        (*b0 + *b1 + *b2 + white * 0.1848) * TEST_SIGNAL_LEVEL / 3.0
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct BenchResult {
+    pub frames: usize,
+    pub elapsed: Duration,
+}
+
+impl BenchResult {
+    pub fn frames_per_sec(&self) -> f64 {
+        self.frames as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn micros_per_frame(&self) -> f64 {
+        self.elapsed.as_secs_f64() * 1e6 / self.frames as f64
+    }
+}
+
+// Times `compute_spectrum_into` on white noise, exactly as the display loop calls it
+pub fn benchmark(fft_size: usize, window: Window, iterations: usize) -> Result<BenchResult> {
+    let fft = plan_fft(fft_size);
+    let coefficients = window.coefficients(fft_size);
+    let mut buffers = SpectrumBuffers::new(&*fft);
+    let mut spectrum = vec![0.0; fft_size / 2];
+
+    // xorshift keeps the input deterministic without pulling in a RNG crate
+    let mut state = 0x2545_f491_u32;
+    let samples: Vec<f32> = (0..fft_size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as f32 / u32::MAX as f32 * 2.0 - 1.0
+        })
+        .collect();
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        compute_spectrum_into(&samples, &coefficients, &*fft, 1.0, &mut buffers, &mut spectrum)?;
+        std::hint::black_box(&spectrum);
+    }
+    Ok(BenchResult { frames: iterations, elapsed: start.elapsed() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+
+    fn sine(freq: f32, len: usize) -> Vec<f32> {
+        (0..len).map(|i| (2.0 * std::f32::consts::PI * freq * i as f32 / SAMPLE_RATE).sin()).collect()
+    }
+
+    fn peak_bin(spectrum: &[f32]) -> usize {
+        spectrum.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1)).unwrap().0
+    }
+
+    #[test]
+    fn sine_peaks_at_its_bin() {
+        let size = 1024;
+        let fft = plan_fft(size);
+        let bin_width = SAMPLE_RATE / size as f32;
+        for window in [Window::Hann, Window::Hamming, Window::Blackman, Window::Rectangular] {
+            let spectrum = compute_spectrum(&sine(50.0 * bin_width, size), &window.coefficients(size), &*fft, 1.0)
+                .unwrap();
+            assert_eq!(spectrum.len(), size / 2);
+            assert_eq!(peak_bin(&spectrum), 50, "{} window", window.name());
+        }
+    }
+
+    #[test]
+    fn window_gains_match_their_coefficients() {
+        let size = 1024;
+        for window in [Window::Hann, Window::Hamming, Window::Blackman, Window::Rectangular] {
+            let coefficients = window.coefficients(size);
+            let sum: f32 = coefficients.iter().sum();
+            let square_sum: f32 = coefficients.iter().map(|c| c * c).sum();
+            assert!((sum / size as f32 - window.coherent_gain()).abs() < 1e-4, "{} window", window.name());
+            let enbw = size as f32 * square_sum / (sum * sum);
+            assert!((enbw - window.noise_bandwidth()).abs() < 1e-4, "{} window", window.name());
+        }
+        assert_eq!(Window::Hann.noise_bandwidth(), 1.5);
+    }
+
+    #[test]
+    fn zero_padding_places_a_peak_between_bins() {
+        let (size, zero_pad) = (256, 4);
+        let freq = 10.25 * SAMPLE_RATE / size as f32;
+        let plain = compute_spectrum(&sine(freq, size), &Window::Hann.coefficients(size), &*plan_fft(size), 1.0);
+        assert_eq!(peak_bin(&plain.unwrap()), 10);
+
+        let padded_size = size * zero_pad;
+        let window = Window::Hann.padded_coefficients(padded_size, zero_pad);
+        assert!(window[..padded_size - size].iter().all(|&c| c == 0.0));
+        let padded = compute_spectrum(&sine(freq, padded_size), &window, &*plan_fft(padded_size), 1.0).unwrap();
+        assert_eq!(padded.len(), padded_size / 2);
+        assert_eq!(peak_bin(&padded), 41);
+    }
+
+    #[test]
+    fn parabolic_offset_finds_a_tone_between_bins() {
+        let size = 2048;
+        let bin_width = SAMPLE_RATE / size as f32;
+        for bins in [100.0, 100.2, 100.5, 100.8] {
+            let spectrum = compute_spectrum(&sine(bins * bin_width, size), &Window::Hann.coefficients(size),
+                                            &*plan_fft(size), 1.0).unwrap();
+            let peak = peak_bin(&spectrum);
+            let estimate = peak as f32 + parabolic_offset(spectrum[peak - 1], spectrum[peak], spectrum[peak + 1]);
+            assert!((estimate - bins).abs() < 0.03, "{} bins estimated as {}", bins, estimate);
+        }
+        assert_eq!(parabolic_offset(0.0, 1.0, 0.5), 0.0);
+    }
+
+    #[test]
+    fn off_bin_sine_peaks_at_nearest_bin() {
+        let size = 2048;
+        let fft = plan_fft(size);
+        let spectrum = compute_spectrum(&sine(1000.0, size), &Window::Hann.coefficients(size), &*fft, 1.0).unwrap();
+        let expected = (1000.0 / (SAMPLE_RATE / size as f32)).round() as usize;
+        assert_eq!(peak_bin(&spectrum), expected);
+    }
+
+    #[test]
+    fn silence_and_dc_give_an_empty_spectrum() {
+        let size = 256;
+        let fft = plan_fft(size);
+        let window = Window::Rectangular.coefficients(size);
+        assert!(compute_spectrum(&vec![0.0; size], &window, &*fft, 1.0).unwrap().iter().all(|&m| m == 0.0));
+        let dc = compute_spectrum(&vec![0.5; size], &window, &*fft, 1.0).unwrap();
+        assert!(dc.iter().all(|&m| m < 1e-3), "DC leaked into {:?}", peak_bin(&dc));
+    }
+
+    #[test]
+    fn high_pass_removes_dc_but_passes_tones() {
+        let mut filter = HighPass::new(20.0, SAMPLE_RATE as u32);
+        let settled = (0..48000).map(|_| filter.process(0.5)).last().unwrap();
+        assert!(settled.abs() < 1e-3, "DC left: {}", settled);
+
+        let mut filter = HighPass::new(20.0, SAMPLE_RATE as u32);
+        let tone: Vec<f32> = sine(1000.0, 48000).into_iter().map(|x| filter.process(x)).collect();
+        let peak = tone[24000..].iter().fold(0.0f32, |max, &x| max.max(x.abs()));
+        assert!((peak - 1.0).abs() < 0.01, "1 kHz peak {}", peak);
+    }
+
+    #[test]
+    fn hum_notches_cut_mains_and_spare_the_rest() {
+        let rms_after = |freq: f32| {
+            let mut notches = hum_notches(50.0, SAMPLE_RATE as u32);
+            let filtered: Vec<f32> = sine(freq, 48000).into_iter()
+                .map(|x| notches.iter_mut().fold(x, |x, notch| notch.process(x)))
+                .collect();
+            (filtered[24000..].iter().map(|x| x * x).sum::<f32>() / 24000.0).sqrt()
+        };
+        for hum in [50.0, 100.0, 150.0] {
+            assert!(rms_after(hum) < 0.01, "{} Hz not notched", hum);
+        }
+        assert!((rms_after(1000.0) - 0.5f32.sqrt()).abs() < 0.01);
+    }
+
+    #[test]
+    fn decimator_passes_the_low_band_and_rejects_aliases() {
+        // 48 kHz down to 12 kHz: 1 kHz stays, 10 kHz would fold to 2 kHz
+        let rms_after = |freq: f32| {
+            let mut decimator = Decimator::new(4, SAMPLE_RATE as u32);
+            let output: Vec<f32> = sine(freq, 48000).into_iter().filter_map(|x| decimator.process(x)).collect();
+            assert_eq!(output.len(), 12000);
+            (output[6000..].iter().map(|x| x * x).sum::<f32>() / 6000.0).sqrt()
+        };
+        assert!((rms_after(1000.0) - 0.5f32.sqrt()).abs() < 0.01);
+        assert!(rms_after(10000.0) < 0.005);
+    }
+
+    #[test]
+    fn resampler_keeps_duration_and_interpolates() {
+        let input: Vec<f32> = (0..441).map(|i| i as f32).collect();
+        let mut source = input.iter().copied();
+        let mut resampler = LinearResampler::new(44100, 48000);
+        let output: Vec<f32> = (0..480).map(|_| resampler.next_sample(|| source.next())).collect();
+        assert!(source.next().is_none(), "input should be used up at the same pace");
+        // One sample of latency: output i sits at input position i * step - 1
+        for (i, &sample) in output.iter().enumerate().skip(2) {
+            let expected = (i as f64 * 44100.0 / 48000.0) as f32 - 1.0;
+            assert!((sample - expected).abs() < 1e-3, "sample {}: {} vs {}", i, sample, expected);
+        }
+    }
+
+    #[test]
+    fn gain_scales_magnitudes() {
+        let size = 512;
+        let fft = plan_fft(size);
+        let window = Window::Hann.coefficients(size);
+        let samples = sine(3000.0, size);
+        let unity = compute_spectrum(&samples, &window, &*fft, 1.0).unwrap();
+        let doubled = compute_spectrum(&samples, &window, &*fft, 2.0).unwrap();
+        let bin = peak_bin(&unity);
+        assert!((doubled[bin] / unity[bin] - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn weighting_matches_reference_levels() {
+        let db = |weighting: Weighting, freq: f32| 20.0 * weighting.factor(freq).log10();
+        assert!(db(Weighting::A, 1000.0).abs() < 1e-3);
+        assert!((db(Weighting::A, 100.0) + 19.1).abs() < 0.1);
+        assert!((db(Weighting::A, 10000.0) + 2.5).abs() < 0.1);
+        assert!((db(Weighting::C, 100.0) + 0.3).abs() < 0.1);
+        assert_eq!(Weighting::None.factor(50.0), 1.0);
+    }
+
+    #[test]
+    fn goertzel_agrees_with_the_fft_bin() {
+        let size = 1024;
+        let fft = plan_fft(size);
+        let window = Window::Hann.coefficients(size);
+        let freq = 64.0 * SAMPLE_RATE / size as f32;
+        let samples = sine(freq, size);
+        let spectrum = compute_spectrum(&samples, &window, &*fft, 1.0).unwrap();
+        let level = goertzel(&samples, &window, freq, SAMPLE_RATE as u32, 1.0);
+        assert!((level / spectrum[64] - 1.0).abs() < 1e-3);
+        assert!(goertzel(&samples, &window, freq * 3.0, SAMPLE_RATE as u32, 1.0) < level * 1e-3);
+    }
+
+    #[test]
+    fn thd_measures_added_harmonics() {
+        let size = 4096;
+        let fft = plan_fft(size);
+        let freq = 1000.0;
+        // 1% second and 0.5% third harmonic
+        let samples: Vec<f32> = sine(freq, size).iter().zip(sine(2.0 * freq, size)).zip(sine(3.0 * freq, size))
+            .map(|((f, h2), h3)| f + 0.01 * h2 + 0.005 * h3)
+            .collect();
+        let spectrum = compute_spectrum(&samples, &Window::Blackman.coefficients(size), &*fft, 1.0).unwrap();
+        let expected = (0.01f32.powi(2) + 0.005f32.powi(2)).sqrt();
+        assert!((thd(&spectrum, freq, SAMPLE_RATE as u32).unwrap() / expected - 1.0).abs() < 0.05);
+        assert!(thd(&spectrum, 20.0, SAMPLE_RATE as u32).is_none());
+    }
+
+    #[test]
+    fn flux_rises_only_at_onsets() {
+        let size = 4096;
+        let fft = plan_fft(size);
+        let window = Window::Hann.coefficients(size);
+        let tone = compute_spectrum(&sine(1000.0, size), &window, &*fft, 1.0).unwrap();
+        let silence = vec![0.0; size / 2];
+        assert!(spectral_flux(&tone, &tone).unwrap() < 1e-6);
+        assert!((spectral_flux(&silence, &tone).unwrap() - 1.0).abs() < 1e-6);
+        assert_eq!(spectral_flux(&tone, &silence), Some(0.0));
+        assert!(spectral_flux(&silence, &silence).is_none());
+        assert!(spectral_flux(&tone[..size / 4], &tone).is_none());
+    }
+
+    #[test]
+    fn triangular_smoothing_spreads_a_spike_and_keeps_its_total() {
+        let mut spike = vec![0.0; 9];
+        spike[4] = 9.0;
+        assert_eq!(triangular_smooth(&spike, 1), spike);
+        assert_eq!(triangular_smooth(&spike, 5), [0.0, 0.0, 1.0, 2.0, 3.0, 2.0, 1.0, 0.0, 0.0]);
+        // At the edge only the weights that land on bins count
+        assert_eq!(triangular_smooth(&[3.0, 0.0, 0.0], 3), [2.0, 0.75, 0.0]);
+    }
+
+    #[test]
+    fn local_peaks_skip_dc_and_neighbors_of_stronger_bins() {
+        let values = [5.0, 0.0, 1.0, 3.0, 1.0, 0.0, 0.0, 2.0, 2.0, 0.0];
+        assert_eq!(local_peaks(&values, 2), [3, 7]);
+        // A bin two away from a stronger one only counts once the reach no longer spans it
+        assert_eq!(local_peaks(&[0.0, 4.0, 0.0, 1.0], 2), [1]);
+        assert_eq!(local_peaks(&[0.0, 4.0, 0.0, 1.0], 1), [1, 3]);
+    }
+
+    #[test]
+    fn octave_bands_stop_at_nyquist_and_sum_their_bins_energy() {
+        let bands = octave_bands(BandFraction::Octave, 8000);
+        assert_eq!(bands.len(), 8);
+        assert_eq!(bands[5].center, 1000.0);
+        assert!((bands[5].low - 707.1).abs() < 0.1 && (bands[5].high - 1414.2).abs() < 0.1);
+        // 10 Hz bins: the 1 kHz band holds 71 through 141, the 31.5 Hz band only 3 and 4
+        let tilted: Vec<f32> = (0..400).map(|i| display_tilt(i as f32)).collect();
+        let levels = band_levels(&tilted, 8000, &bands, 1.0);
+        assert!((levels[5].unwrap() - 71f32.sqrt()).abs() < 1e-4);
+        assert!((levels[0].unwrap() - 2f32.sqrt()).abs() < 1e-4);
+        assert_eq!(band_levels(&tilted[..4], 8000, &bands, 1.0)[0], None);
+
+        // A tone's band reads its own magnitude at any FFT size, once the window's spread is taken out
+        for size in [1024, 4096] {
+            let samples: Vec<f32> = (0..size)
+                .map(|i| (std::f32::consts::TAU * 1000.0 * i as f32 / 8000.0).sin())
+                .collect();
+            let fft = plan_fft(size);
+            let spectrum = compute_spectrum(&samples, &Window::Hann.coefficients(size), &*fft, 1.0).unwrap();
+            let bin = size / 8;
+            let level = band_levels(&spectrum, 8000, &bands, Window::Hann.noise_bandwidth())[5].unwrap();
+            let tone = spectrum[bin] / display_tilt(bin as f32);
+            assert!((level / tone - 1.0).abs() < 0.01, "{} against {} at {}", level, tone, size);
+        }
+
+        let thirds = octave_bands(BandFraction::ThirdOctave, 48000);
+        assert_eq!(thirds.len(), 30);
+        assert_eq!(thirds[16].center, 1000.0);
+        assert!((thirds[16].high - thirds[17].low).abs() < 0.01);
+        assert!((thirds[17].low - 1122.5).abs() < 0.1);
+    }
+
+    #[test]
+    fn flatness_tells_tones_from_noise() {
+        let size = 4096;
+        let fft = plan_fft(size);
+        let window = Window::Hann.coefficients(size);
+        let tone = compute_spectrum(&sine(1000.0, size), &window, &*fft, 1.0).unwrap();
+        assert!(spectral_flatness(&tone).unwrap() < 0.01);
+
+        // Tilt-corrected flat spectrum, as white noise averages out to
+        let flat: Vec<f32> = (0..size / 2).map(|i| display_tilt(i as f32)).collect();
+        assert!((spectral_flatness(&flat).unwrap() - 1.0).abs() < 1e-4);
+        assert!(spectral_flatness(&vec![0.0; size / 2]).is_none());
+    }
+
+    #[test]
+    fn notes_round_to_the_nearest_semitone() {
+        let a4 = Note::nearest(440.0);
+        assert_eq!((a4.name().as_str(), a4.cents.round()), ("A4", 0.0));
+
+        // 12 cents sharp of A4, and 49 cents flat of C4 still rounding up to it
+        let sharp = Note::nearest(440.0 * 2f32.powf(12.0 / 1200.0));
+        assert_eq!((sharp.name().as_str(), sharp.cents.round()), ("A4", 12.0));
+        let flat = Note::nearest(261.63 * 2f32.powf(-49.0 / 1200.0));
+        assert_eq!((flat.name().as_str(), flat.cents.round()), ("C4", -49.0));
+    }
+
+    #[test]
+    fn tone_stays_continuous_across_a_frequency_jump() {
+        let mut tone = ToneGenerator::new(48000);
+        let mut samples: Vec<f32> = (0..4800).map(|_| tone.next_sample(440.0, 0.5)).collect();
+        samples.extend((0..4800).map(|_| tone.next_sample(880.0, 0.5)));
+        // A 880 Hz sine at this level moves at most about 0.06 per sample
+        let largest_step = samples.windows(2).map(|pair| (pair[1] - pair[0]).abs()).fold(0.0, f32::max);
+        assert!(largest_step < 0.06, "step {}", largest_step);
+    }
+
+    #[test]
+    fn vectorized_magnitudes_match_the_scalar_reference() {
+        // 21 bins: two full chunks and a tail
+        let output: Vec<Complex<f32>> = (0..22).map(|i| Complex::new(i as f32 * 0.7 - 3.0, (i as f32).sin())).collect();
+        let tilt: Vec<f32> = (0..22).map(|i| if i == 0 { 0.0 } else { display_tilt(i as f32) }).collect();
+        let mut spectrum = vec![0.0; 21];
+        magnitudes_into(&output, &tilt, &mut spectrum);
+        for (i, &mag) in spectrum.iter().enumerate() {
+            let reference = if i == 0 { 0.0 } else { output[i].norm() * display_tilt(i as f32) };
+            assert!((mag - reference).abs() <= reference * 1e-6, "bin {}: {} vs {}", i, mag, reference);
+        }
+    }
+
+    #[test]
+    fn delay_finds_the_offset_between_channels() {
+        let size = 1024;
+        let noise: Vec<f32> = (0..size + 64).map(|i| ((i * 7919) % 211) as f32 / 105.0 - 1.0).collect();
+        let mut estimator = DelayEstimator::new(size);
+        let left = &noise[64..];
+        let late = &noise[64 - 37..size + 64 - 37];
+        let delay = estimator.estimate(left, late).unwrap().unwrap();
+        assert_eq!(delay.samples, 37);
+        assert!(delay.correlation > 0.9, "correlation {}", delay.correlation);
+
+        let inverted: Vec<f32> = left.iter().map(|x| -x).collect();
+        let delay = estimator.estimate(&inverted, left).unwrap().unwrap();
+        assert_eq!(delay.samples, 0);
+        assert!(delay.correlation < -0.9);
+        assert_eq!(estimator.estimate(left, &vec![0.0; size]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_signals_parse_and_put_their_tones_in_the_right_bins() {
+        assert_eq!("sine:1000+3000".parse::<TestSignal>().unwrap(), TestSignal::Tones(vec![1000.0, 3000.0]));
+        assert_eq!("sweep:20-20000".parse::<TestSignal>().unwrap().max_freq(), Some(20000.0));
+        assert!("sweep:500-100".parse::<TestSignal>().is_err());
+        assert!("sine:".parse::<TestSignal>().is_err());
+
+        let size = 4096;
+        let mut generator = SignalGenerator::new("sine:1500+6000".parse().unwrap(), 48000);
+        let samples: Vec<f32> = (0..size).map(|_| generator.next_sample()).collect();
+        assert!(samples.iter().all(|s| s.abs() <= TEST_SIGNAL_LEVEL));
+        let fft = plan_fft(size);
+        let spectrum = compute_spectrum(&samples, &Window::Hann.coefficients(size), &*fft, 1.0).unwrap();
+        let bin = |freq: f32| (freq * size as f32 / 48000.0).round() as usize;
+        let loud = spectrum[bin(1500.0)].min(spectrum[bin(6000.0)]);
+        assert!(spectrum.iter().enumerate().all(|(i, &mag)| {
+            [bin(1500.0), bin(6000.0)].iter().any(|&b| i.abs_diff(b) <= 2) || mag < loud / 100.0
+        }));
+    }
+
+    #[test]
+    fn cached_setups_are_reused_per_size() {
+        let mut cache = FftCache::new(1);
+        let mut setup = cache.take(1024);
+        let (fft, window) = (Arc::clone(&setup.fft), setup.window(Window::Hann));
+        assert!(Arc::ptr_eq(&window, &setup.window(Window::Hann)));
+        cache.store(setup);
+        let other = cache.take(2048);
+        cache.store(other);
+
+        let mut again = cache.take(1024);
+        assert!(Arc::ptr_eq(&fft, &again.fft));
+        assert!(Arc::ptr_eq(&window, &again.window(Window::Hann)));
+        assert_eq!(again.window(Window::Blackman).len(), 1024);
+    }
+}
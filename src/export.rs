@@ -0,0 +1,612 @@
+// Saving what is on screen for offline use: PNG snapshots, SVG spectrum plots, marker lists,
+// NumPy spectrograms, state dumps, CSV spectrum logs, event, onset and skipped silence logs and
+// stage timing logs
+
+use crate::config::config_path;
+use crate::dsp::{bin_sine_amplitude, parabolic_offset, spectral_flux, Window};
+use crate::keys::BINDINGS;
+use crate::view::{format_freq_tick, nice_step, GateMode, Quantity, Scale, ViewState};
+use anyhow::{anyhow, Result};
+use std::{
+    fmt::Write,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write as _},
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Frames queued for the CSV thread before new ones are dropped
+const CSV_QUEUE_FRAMES: usize = 64;
+// Timings queued for the profile thread; a frame has a handful of stages
+const PROFILE_QUEUE_SAMPLES: usize = 1024;
+// SVG canvas and the margins around its plot area, in user units
+const SVG_WIDTH: f32 = 800.0;
+const SVG_HEIGHT: f32 = 450.0;
+const SVG_MARGIN_LEFT: f32 = 70.0;
+const SVG_MARGIN_RIGHT: f32 = 20.0;
+const SVG_MARGIN_TOP: f32 = 55.0;
+const SVG_MARGIN_BOTTOM: f32 = 50.0;
+const SVG_FREQ_TICKS: usize = 10;
+const SVG_LEVEL_TICKS: usize = 6;
+const NPY_MAGIC: &[u8; 6] = b"\x93NUMPY";
+// Height of the session maximum strip under a PNG's waterfall, after a one-row gap
+const PNG_SESSION_MAX_ROWS: usize = 8;
+
+// Per-frame spectra written as wide CSV rows (`time_s, <one column per bin>`) on a
+// background thread, so a slow disk costs frames in the file rather than on screen.
+// With `session_max` a closing row labeled `max` holds each bin's highest logged value.
+pub struct CsvLogger {
+    sender: mpsc::SyncSender<(f64, Vec<f32>)>,
+    writer: thread::JoinHandle<Result<()>>,
+    started: Instant,
+}
+
+impl CsvLogger {
+    pub fn create(path: &Path, sample_rate: u32, bins: usize, fft_size: usize, session_max: bool) -> Result<Self> {
+        let mut csv = csv::Writer::from_path(path)
+            .map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e))?;
+        let bin_width = sample_rate as f32 / fft_size as f32;
+        let mut header = vec!["time_s".to_string()];
+        header.extend((0..bins).map(|i| format!("{:.2}", i as f32 * bin_width)));
+        csv.write_record(&header)?;
+
+        let (sender, receiver) = mpsc::sync_channel::<(f64, Vec<f32>)>(CSV_QUEUE_FRAMES);
+        let writer = thread::spawn(move || -> Result<()> {
+            let mut record = Vec::with_capacity(bins + 1);
+            let mut max = vec![0.0f32; bins];
+            for (time, spectrum) in receiver {
+                record.clear();
+                record.push(format!("{:.4}", time));
+                record.extend(spectrum.iter().map(|mag| mag.to_string()));
+                csv.write_record(&record)?;
+                for (max, &mag) in max.iter_mut().zip(&spectrum) {
+                    *max = max.max(mag);
+                }
+            }
+            if session_max {
+                record.clear();
+                record.push("max".to_string());
+                record.extend(max.iter().map(|mag| mag.to_string()));
+                csv.write_record(&record)?;
+            }
+            csv.flush()?;
+            Ok(())
+        });
+        Ok(Self { sender, writer, started: Instant::now() })
+    }
+
+    pub fn log(&self, spectrum: &[f32]) {
+        let _ = self.sender.try_send((self.started.elapsed().as_secs_f64(), spectrum.to_vec()));
+    }
+
+    // Drains the queue and reports any write error from the thread
+    pub fn finish(self) -> Result<()> {
+        drop(self.sender);
+        self.writer.join().map_err(|_| anyhow!("CSV writer thread panicked"))?
+    }
+}
+
+// Signal activity as tab-separated lines appended to a file: UTC time, frequency and level
+// in dB below full scale of each peak that rises past the threshold. Levels are read with
+// the gain, display tilt and window's coherent gain taken out, so a tone crosses at the same
+// level at any frequency. An event lasts until
+// the peak has stayed under the threshold for the debounce time, so a signal that fades for
+// a moment is still one entry. Each line is flushed as it is written, for `tail -f`.
+pub struct EventLog {
+    state: Mutex<EventState>,
+    // Samples each window spans, without the zero padding, and the width of a bin
+    captured: usize,
+    bin_width: f32,
+    threshold_db: f32,
+    debounce: Duration,
+}
+
+struct EventState {
+    out: BufWriter<File>,
+    // When the peak was last over the threshold, while an event lasts
+    last_above: Option<Instant>,
+    // The first write that failed, kept for `finish` so logging never stalls the analysis
+    error: Option<std::io::Error>,
+}
+
+impl EventLog {
+    pub fn create(path: &Path, sample_rate: u32, fft_size: usize, zero_pad: usize, threshold_db: f32,
+                  debounce: Duration) -> Result<Self> {
+        let out = open_log(path, "# time\tfreq_hz\tlevel_db")?;
+        Ok(Self {
+            state: Mutex::new(EventState { out, last_above: None, error: None }),
+            captured: fft_size / zero_pad,
+            bin_width: sample_rate as f32 / fft_size as f32,
+            threshold_db,
+            debounce,
+        })
+    }
+
+    // `spectrum` analyzed through `window` and scaled by `gain`; the peak is its loudest bin past
+    // DC against full scale
+    pub fn check(&self, spectrum: &[f32], gain: f32, window: Window) {
+        let amplitude = |(bin, &magnitude): (usize, &f32)| {
+            (bin, bin_sine_amplitude(magnitude, bin as f32, self.captured, window, gain))
+        };
+        let loudest = spectrum.iter().enumerate().skip(1).map(amplitude).max_by(|a, b| a.1.total_cmp(&b.1));
+        let Some((bin, amplitude)) = loudest else {
+            return;
+        };
+        let magnitude = spectrum[bin];
+        let level_db = 20.0 * amplitude.log10();
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if level_db < self.threshold_db {
+            if state.last_above.is_some_and(|last| now - last >= self.debounce) {
+                state.last_above = None;
+            }
+            return;
+        }
+        if state.last_above.replace(now).is_some() || state.error.is_some() {
+            return;
+        }
+        let offset = match spectrum.get(bin + 1) {
+            Some(&right) if bin > 1 => parabolic_offset(spectrum[bin - 1], magnitude, right),
+            _ => 0.0,
+        };
+        let freq = (bin as f32 + offset) * self.bin_width;
+        let time = utc_timestamp(SystemTime::now());
+        let written = writeln!(state.out, "{}\t{:.1}\t{:.1}", time, freq, level_db).and_then(|_| state.out.flush());
+        state.error = written.err();
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self.state.into_inner().unwrap().error {
+            Some(e) => Err(anyhow!("Event log write failed: {}", e)),
+            None => Ok(()),
+        }
+    }
+}
+
+// Spectral flux as tab-separated lines appended to a file: UTC time and the flux of each
+// frame that rises past the threshold, once per rise. Flushed as written, like the event log.
+pub struct OnsetLog {
+    state: Mutex<OnsetState>,
+    threshold: f32,
+}
+
+struct OnsetState {
+    out: BufWriter<File>,
+    previous: Vec<f32>,
+    above: bool,
+    error: Option<std::io::Error>,
+}
+
+impl OnsetLog {
+    pub fn create(path: &Path, threshold: f32) -> Result<Self> {
+        let out = open_log(path, "# time\tflux")?;
+        Ok(Self { state: Mutex::new(OnsetState { out, previous: Vec::new(), above: false, error: None }), threshold })
+    }
+
+    // Flux is a ratio, so `spectrum` may carry any gain
+    pub fn check(&self, spectrum: &[f32]) {
+        let mut state = self.state.lock().unwrap();
+        let flux = spectral_flux(&state.previous, spectrum);
+        state.previous.clear();
+        state.previous.extend_from_slice(spectrum);
+        let Some(flux) = flux.filter(|&flux| flux >= self.threshold) else {
+            state.above = false;
+            return;
+        };
+        if std::mem::replace(&mut state.above, true) || state.error.is_some() {
+            return;
+        }
+        let time = utc_timestamp(SystemTime::now());
+        let written = writeln!(state.out, "{}\t{:.3}", time, flux).and_then(|_| state.out.flush());
+        state.error = written.err();
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self.state.into_inner().unwrap().error {
+            Some(e) => Err(anyhow!("Onset log write failed: {}", e)),
+            None => Ok(()),
+        }
+    }
+}
+
+// Stretches of silence --skip-silence passed over, as tab-separated lines appended to a file:
+// where each starts in the input and how long it ran, both in seconds. Written by the thread
+// reading the input, so errors wait for `finish` like the onset log's.
+pub struct SkipLog {
+    state: Mutex<(BufWriter<File>, Option<std::io::Error>)>,
+}
+
+impl SkipLog {
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Self { state: Mutex::new((open_log(path, "# position\tskipped")?, None)) })
+    }
+
+    pub fn log(&self, position_secs: f64, skipped_secs: f64) {
+        let (out, error) = &mut *self.state.lock().unwrap();
+        if error.is_none() {
+            *error = writeln!(out, "{:.3}\t{:.3}", position_secs, skipped_secs).and_then(|_| out.flush()).err();
+        }
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        match self.state.lock().unwrap().1.take() {
+            Some(e) => Err(anyhow!("Skipped silence log write failed: {}", e)),
+            None => Ok(()),
+        }
+    }
+}
+
+// A log opened for appending, with `header` written first if the file is new
+fn open_log(path: &Path, header: &str) -> Result<BufWriter<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)
+        .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+    let fresh = file.metadata()?.len() == 0;
+    let mut out = BufWriter::new(file);
+    if fresh {
+        writeln!(out, "{}", header)?;
+        out.flush()?;
+    }
+    Ok(out)
+}
+
+// ISO 8601 in UTC to the millisecond, from the days since 1970 by the civil calendar
+pub fn utc_timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, day_secs) = ((secs / 86_400) as i64, secs % 86_400);
+    let shifted = days + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, day_secs / 3600, day_secs / 60 % 60,
+            day_secs % 60, since.subsec_millis())
+}
+
+// Stage timings as folded stacks, one `thread;stage nanoseconds` line per stage per frame,
+// written on a background thread. Tools that read folded stacks (flamegraph.pl, inferno)
+// add up the lines themselves, and so does a one-line awk script.
+pub struct ProfileLog {
+    sender: mpsc::SyncSender<(&'static str, Duration)>,
+    writer: thread::JoinHandle<Result<()>>,
+}
+
+impl ProfileLog {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e))?;
+        let mut out = BufWriter::new(file);
+        let (sender, receiver) = mpsc::sync_channel::<(&'static str, Duration)>(PROFILE_QUEUE_SAMPLES);
+        let writer = thread::spawn(move || -> Result<()> {
+            for (stack, elapsed) in receiver {
+                writeln!(out, "{} {}", stack, elapsed.as_nanos())?;
+            }
+            out.flush()?;
+            Ok(())
+        });
+        Ok(Self { sender, writer })
+    }
+
+    pub fn record(&self, stack: &'static str, elapsed: Duration) {
+        let _ = self.sender.try_send((stack, elapsed));
+    }
+
+    // Drains the queue and reports any write error from the thread
+    pub fn finish(self) -> Result<()> {
+        drop(self.sender);
+        self.writer.join().map_err(|_| anyhow!("Profile writer thread panicked"))?
+    }
+}
+
+// Default for --output-prefix: the kind of file and when it was written
+pub const DEFAULT_OUTPUT_PREFIX: &str = "{kind}-{timestamp}";
+
+// How exported files are named: a template whose {kind}, {timestamp}, {device} and
+// {samplerate} are filled in as each file is written. A template without {kind} gets it on
+// the end, so a waterfall and its markers never share a name, and a name already taken
+// gets a number after it instead of being overwritten.
+#[derive(Clone, Debug)]
+pub struct OutputNames {
+    template: String,
+    device: String,
+    sample_rate: u32,
+}
+
+impl OutputNames {
+    // Characters of the device name that would be awkward in a file name become underscores
+    pub fn new(template: &str, device: &str, sample_rate: u32) -> Self {
+        let device = device.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '.') { c } else { '_' })
+            .collect();
+        Self { template: template.to_string(), device, sample_rate }
+    }
+
+    // A path no file has yet for a new `kind` file
+    pub fn path(&self, kind: &str, extension: &str) -> PathBuf {
+        let stem = match self.template.contains("{kind}") {
+            true => self.fill(&self.template.replace("{kind}", kind)),
+            false => format!("{}-{}", self.fill(&self.template), kind),
+        };
+        let mut path = PathBuf::from(format!("{}.{}", stem, extension));
+        for n in 2.. {
+            if !path.exists() {
+                break;
+            }
+            path = PathBuf::from(format!("{}-{}.{}", stem, n, extension));
+        }
+        path
+    }
+
+    // A path given on the command line, with any tokens in it filled in
+    pub fn expand(&self, path: &Path) -> PathBuf {
+        PathBuf::from(self.fill(&path.to_string_lossy()))
+    }
+
+    fn fill(&self, template: &str) -> String {
+        // The ISO 8601 time without the separators a file name can't always carry
+        let time: String = utc_timestamp(SystemTime::now())[..19].chars().filter(|c| !matches!(c, '-' | ':')).collect();
+        template.replace("{timestamp}", &format!("{}Z", time))
+            .replace("{device}", &self.device)
+            .replace("{samplerate}", &self.sample_rate.to_string())
+    }
+}
+
+// One pixel per visible bin and one row per history line, ordered like the screen, with
+// the session maximum as a strip along the bottom while it is shown
+pub fn export_png(state: &ViewState, sample_rate: u32) -> Result<PathBuf> {
+    let (min_freq, max_freq) = state.freq_range(sample_rate);
+    let bin_width = sample_rate as f32 / state.fft_size as f32;
+    let width = ((max_freq - min_freq) / bin_width).round().max(1.0) as usize;
+    let strip_rows = if state.session_max_enabled { 1 + PNG_SESSION_MAX_ROWS } else { 0 };
+
+    let mut image = image::RgbImage::new(width as u32, (state.visible_lines + strip_rows) as u32);
+    for row in 0..state.visible_lines {
+        let index = state.history_index(row);
+        let points = &state.waterfall_line(&state.waterfall_data[index]);
+        let phases = state.waterfall_phase.get(index).map_or(&[][..], Vec::as_slice);
+        for x in 0..width {
+            let magnitude = state.column_magnitude(points, x, width, sample_rate).unwrap_or(0.0);
+            let phase = state.column_phase(points, phases, x, width, sample_rate);
+            let (r, g, b) = state.waterfall_rgb(state.waterfall_intensity(magnitude), phase);
+            image.put_pixel(x as u32, row as u32, image::Rgb([r, g, b]));
+        }
+    }
+    if strip_rows > 0 {
+        for x in 0..width {
+            let max = state.column_session_max(x, width, sample_rate).unwrap_or(0.0);
+            let (r, g, b) = state.waterfall_rgb(state.waterfall_intensity(max), None);
+            for row in state.visible_lines + 1..state.visible_lines + strip_rows {
+                image.put_pixel(x as u32, row as u32, image::Rgb([r, g, b]));
+            }
+        }
+    }
+
+    let path = state.output_names.path("waterfall", "png");
+    image.save(&path).map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+// The whole waterfall history as a little-endian f32 NumPy array of shape
+// [history, fft_size / 2], oldest line first, with a JSON file of the same name beside it
+// giving the sample rate, FFT size and each column's frequency
+pub fn export_npy(state: &ViewState, sample_rate: u32, path: Option<&Path>) -> Result<PathBuf> {
+    let path = path.map_or_else(|| state.output_names.path("waterfall", "npy"), Path::to_path_buf);
+    let bins = state.fft_size / 2;
+    // Rows never written yet would only pad the array with zeros
+    let rows = state.filled_lines();
+    let size = state.waterfall_data.len();
+
+    // Version 1.0 header: magic, version, then a dict padded so the data starts 64-byte aligned
+    let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}", rows, bins);
+    let unpadded = NPY_MAGIC.len() + 4 + header.len() + 1;
+    header.push_str(&" ".repeat(unpadded.next_multiple_of(64) - unpadded));
+    header.push('\n');
+    let mut out = Vec::with_capacity(NPY_MAGIC.len() + 4 + header.len() + rows * bins * 4);
+    out.extend_from_slice(NPY_MAGIC);
+    out.extend_from_slice(&[1, 0]);
+    out.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    out.extend_from_slice(header.as_bytes());
+    // Oldest first, ending with the newest line just before `current_line`
+    for i in 0..rows {
+        let line = &state.waterfall_data[(state.current_line + size - rows + i) % size];
+        for &(_, magnitude) in line {
+            out.extend_from_slice(&magnitude.to_le_bytes());
+        }
+    }
+    fs::write(&path, out).map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))?;
+
+    let bin_width = sample_rate as f32 / state.fft_size as f32;
+    let sidecar = serde_json::json!({
+        "sample_rate": sample_rate,
+        "fft_size": state.fft_size,
+        "shape": [rows, bins],
+        "order": "oldest first",
+        "freqs_hz": (0..bins).map(|i| i as f32 * bin_width).collect::<Vec<_>>(),
+    });
+    let sidecar_path = path.with_extension("json");
+    fs::write(&sidecar_path, sidecar.to_string())
+        .map_err(|e| anyhow!("Failed to write {}: {}", sidecar_path.display(), e))?;
+    Ok(path)
+}
+
+// Markers as tab-separated text: number, frequency, time before the newest line and level
+pub fn export_annotations(state: &ViewState, sample_rate: u32) -> Result<PathBuf> {
+    let line_secs = state.line_time().as_secs_f64();
+    let mut text = String::from("# marker\tfreq_hz\ttime_s\tlevel\n");
+    for (i, annotation) in state.annotations.iter().enumerate() {
+        let age = state.annotation_age(annotation) as f64 * line_secs;
+        let level = state.format_level(state.annotation_magnitude(annotation, sample_rate));
+        writeln!(text, "{}\t{:.2}\t{:.3}\t{}", i + 1, annotation.freq, -age, level)?;
+    }
+    let path = state.output_names.path("markers", "txt");
+    fs::write(&path, text).map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+// Everything that shapes the display, then the key bindings, as text for a bug report. Unlike
+// the saved config it is meant to be read, not loaded back.
+pub fn export_state(state: &ViewState, sample_rate: u32, device_name: Option<&str>) -> Result<PathBuf> {
+    let on = |enabled: bool| if enabled { "on" } else { "off" };
+    let (min_freq, max_freq) = state.freq_range(sample_rate);
+    let mut text = format!("# qmx-capture {} state\n", env!("CARGO_PKG_VERSION"));
+    let mut field = |name: &str, value: String| writeln!(text, "{:<18} {}", format!("{}:", name), value);
+    field("device", device_name.or(state.input_label.as_deref()).unwrap_or("(not a device)").to_string())?;
+    field("sample rate", format!("{} Hz", sample_rate))?;
+    field("fft size", state.fft_size.to_string())?;
+    field("window", state.window.name().to_string())?;
+    field("scale", state.scale_label())?;
+    field("quantity", state.quantity.name().to_string())?;
+    field("db floor", format!("{:.1} dB", state.db_floor))?;
+    field("weighting", state.weighting.name().to_string())?;
+    field("level unit", format!("{} (offset {:+.1} dB)", state.level_unit.label(), state.cal_offset_db))?;
+    field("level range", state.level_range.map_or("auto".to_string(), |range| range.name()))?;
+    field("gain", format!("{:+.2} dB, AGC {}", state.gain_db(), on(state.agc.enabled)))?;
+    field("step", format!("{} dB, {:.2}x", state.gain_step_db, state.step))?;
+    field("zoom", format!("{:.3}x, showing {:.1} to {:.1} Hz", state.freq_zoom, min_freq, max_freq))?;
+    field("freq axis", state.freq_axis.name().to_string())?;
+    field("layout", state.layout.name().to_string())?;
+    field("scroll", state.scroll.name().to_string())?;
+    field("colormap", format!("{}, inverted {}", state.colormap.name(), on(state.invert)))?;
+    field("waterfall char", state.waterfall_glyph.name())?;
+    field("contrast", format!("{:.2}x, auto {}", state.waterfall_scale, on(state.auto_contrast)))?;
+    field("gamma", format!("{:.2}", state.waterfall_gamma))?;
+    field("compand", format!("{}, {}", on(state.compand), state.compander.name()))?;
+    field("waterfall smooth", format!("{} bin(s)", state.waterfall_smooth))?;
+    field("average", format!("{:.2}", state.average_alpha))?;
+    field("follow peak", format!("{}, smoothing {:.2}", on(state.follow_peak), state.follow_smoothing))?;
+    field("phase panel", format!("{}, gate {:.1} dB", on(state.phase_panel), state.phase_gate_db))?;
+    field("onsets", match state.onset_threshold {
+        Some(threshold) => format!("on, flux {:.2}", threshold),
+        None => "off".into(),
+    })?;
+    field("envelope", format!("{}, attack {:.2}, release {:.2}", on(state.envelope.enabled), state.envelope.attack,
+                              state.envelope.release))?;
+    field("persistence", format!("{} ({:.2})", on(state.persistence), state.persistence_decay))?;
+    field("fps", state.fps.to_string())?;
+    field("waterfall fps", state.waterfall_fps.map_or("every frame".into(), |fps| fps.to_string()))?;
+    field("time compress", format!("{} frame(s) per line, {}", state.time_compress, state.time_compress_mode.name()))?;
+    field("spectrum trace", if state.braille { "braille" } else if state.bars { "bars" } else { "line" }.to_string())?;
+    field("peak hold", format!("{}, decay {:.2}", on(state.peak_hold_enabled), state.peak_decay))?;
+    field("session max", on(state.session_max_enabled).to_string())?;
+    field("references", format!("A {}, B {}, showing {}", on(state.reference.is_some()), on(state.reference_b.is_some()),
+                                state.comparison.name()))?;
+    field("gate", match state.gate {
+        GateMode::Off => "off".to_string(),
+        GateMode::Level => format!("below {:.1} dB", state.gate_db),
+        GateMode::Reference => format!("{:.1} dB above the reference", state.gate_margin_db),
+    })?;
+    field("notch", state.notch.map_or("none".to_string(), |freq| format!("{} Hz, {}", freq, on(state.notch_enabled))))?;
+    field("bandwidth", format!("{}, -{} dB", on(state.bandwidth), state.bandwidth_db))?;
+    field("thd", on(state.thd).to_string())?;
+    field("scope", format!("{}, trigger {}", on(state.scope), on(state.scope_trigger)))?;
+    field("x-y", on(state.xy).to_string())?;
+    field("band analysis", state.band_analysis.map_or("off", |fraction| fraction.name()).to_string())?;
+    field("reference curve", match state.reference_curve.len() {
+        0 => "none".to_string(),
+        points => format!("{} points", points),
+    })?;
+    field("peak tracks", match state.track_peaks {
+        0 => "off".to_string(),
+        count => format!("{} of up to {}, within {:.1} Hz", state.peak_tracks.len(), count, state.track_tolerance),
+    })?;
+    field("stereo", format!("{}, {}", on(state.right.is_some()), state.stereo_mode.name()))?;
+    field("paused", on(state.paused).to_string())?;
+    field("waterfall held", on(state.waterfall_held).to_string())?;
+    field("playback", format!("{}, muted {}, {:+.1} dB, balance {:+.2}, delay {:.0} ms", on(state.output),
+                              on(state.muted), state.output_gain_db, state.balance, state.output_delay_ms))?;
+    field("tone", format!("{}, volume {:.2}", on(state.tone), state.tone_volume))?;
+    field("recording", on(state.recording).to_string())?;
+    field("markers", state.annotations.len().to_string())?;
+    field("restarts", state.restarts.to_string())?;
+    field("xruns", format!("{} overruns, {} underruns", state.overruns, state.underruns))?;
+    field("silence skip", match state.skip_silence {
+        Some((threshold, min_secs)) => format!("below {:.1} dB after {:.2} s, {:.1} s skipped", threshold, min_secs,
+                                               state.skipped_secs),
+        None => "off".to_string(),
+    })?;
+    field("config", config_path().map_or("(no home directory)".to_string(), |path| path.display().to_string()))?;
+
+    text.push_str("\n# keys\n");
+    for binding in BINDINGS {
+        writeln!(text, "{:<12} {}", binding.key_label(), binding.description)?;
+    }
+    let path = state.output_names.path("state", "txt");
+    fs::write(&path, text).map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+// The latest spectrum as a line plot over the on-screen frequency range, axis and scale
+pub fn export_svg(state: &ViewState, sample_rate: u32) -> Result<PathBuf> {
+    let (min_freq, max_freq) = state.freq_range(sample_rate);
+    let (left, right) = (state.axis_position(min_freq.max(f32::MIN_POSITIVE)), state.axis_position(max_freq));
+    let points: Vec<(f32, f32)> = state.shown_line().iter()
+        .skip(1)
+        .filter(|&&(freq, _)| (min_freq..=max_freq).contains(&freq))
+        .map(|&(freq, magnitude)| (state.axis_position(freq), state.display_value(magnitude, freq, sample_rate)))
+        .collect();
+    let highest = points.iter().map(|&(_, value)| value).fold(f32::MIN, f32::max);
+    let (bottom, top, level_title) = match (state.scale, state.quantity) {
+        (Scale::Db, Quantity::Magnitude) => (state.db_floor, highest.max(0.0), "Level (dB)".to_string()),
+        (Scale::Db, quantity) => (state.display_floor_db(sample_rate), highest.max(state.display_floor_db(sample_rate)),
+                                  format!("{} ({})", quantity.name(), state.quantity_unit())),
+        (Scale::Linear, quantity) => (0.0, highest.max(f32::MIN_POSITIVE), quantity.name().to_string()),
+    };
+    let level_step = nice_step((top - bottom) / SVG_LEVEL_TICKS as f32);
+    let top = (top / level_step).ceil() * level_step;
+
+    let plot_width = SVG_WIDTH - SVG_MARGIN_LEFT - SVG_MARGIN_RIGHT;
+    let plot_height = SVG_HEIGHT - SVG_MARGIN_TOP - SVG_MARGIN_BOTTOM;
+    let x_of = |position: f32| SVG_MARGIN_LEFT + (position - left) / (right - left) * plot_width;
+    let y_of = |value: f32| SVG_MARGIN_TOP + (1.0 - (value.clamp(bottom, top) - bottom) / (top - bottom)) * plot_height;
+    let plot_bottom = SVG_MARGIN_TOP + plot_height;
+
+    let mut svg = String::new();
+    write!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}""#,
+           w = SVG_WIDTH, h = SVG_HEIGHT)?;
+    writeln!(svg, r#" font-family="sans-serif" font-size="12">"#)?;
+    writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#)?;
+    writeln!(svg, r#"<text x="{}" y="22" text-anchor="middle" font-size="16">Spectrum, {:.0} Hz - {:.0} Hz</text>"#,
+             SVG_WIDTH / 2.0, min_freq, max_freq)?;
+    let settings = format!("Scale: {} | Weighting: {} | Window: {} | FFT {} at {} Hz", state.scale_label(),
+                           state.weighting.name(), state.window.name(), state.fft_size, sample_rate);
+    writeln!(svg, r##"<text x="{}" y="40" text-anchor="middle" fill="#555">{}</text>"##, SVG_WIDTH / 2.0, settings)?;
+
+    // Grid and tick labels
+    for freq in state.freq_ticks(sample_rate, SVG_FREQ_TICKS) {
+        let x = x_of(state.axis_position(freq));
+        writeln!(svg, r##"<line x1="{x:.1}" y1="{}" x2="{x:.1}" y2="{plot_bottom:.1}" stroke="#ddd"/>"##,
+                 SVG_MARGIN_TOP)?;
+        writeln!(svg, r#"<text x="{x:.1}" y="{:.1}" text-anchor="middle">{}</text>"#,
+                 plot_bottom + 16.0, format_freq_tick(freq))?;
+    }
+    let first_level = (bottom / level_step).ceil() as i32;
+    for level in (first_level..).map(|k| k as f32 * level_step).take_while(|&level| level <= top) {
+        let y = y_of(level);
+        writeln!(svg, r##"<line x1="{}" y1="{y:.1}" x2="{:.1}" y2="{y:.1}" stroke="#ddd"/>"##,
+                 SVG_MARGIN_LEFT, SVG_MARGIN_LEFT + plot_width)?;
+        writeln!(svg, r#"<text x="{:.1}" y="{:.1}" text-anchor="end">{}</text>"#,
+                 SVG_MARGIN_LEFT - 6.0, y + 4.0, level)?;
+    }
+    writeln!(svg, r#"<rect x="{}" y="{}" width="{}" height="{}" fill="none" stroke="black"/>"#,
+             SVG_MARGIN_LEFT, SVG_MARGIN_TOP, plot_width, plot_height)?;
+    writeln!(svg, r#"<text x="{:.1}" y="{:.1}" text-anchor="middle">Frequency (Hz)</text>"#,
+             SVG_MARGIN_LEFT + plot_width / 2.0, SVG_HEIGHT - 10.0)?;
+    writeln!(svg, r#"<text transform="translate(18 {:.1}) rotate(-90)" text-anchor="middle">{}</text>"#,
+             SVG_MARGIN_TOP + plot_height / 2.0, level_title)?;
+
+    let mut polyline = String::new();
+    for &(position, value) in &points {
+        write!(polyline, "{:.1},{:.1} ", x_of(position), y_of(value))?;
+    }
+    writeln!(svg, r##"<polyline fill="none" stroke="#1f77b4" stroke-width="1.2" points="{}"/>"##, polyline.trim_end())?;
+    writeln!(svg, "</svg>")?;
+
+    let path = state.output_names.path("spectrum", "svg");
+    fs::write(&path, svg).map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))?;
+    Ok(path)
+}
@@ -0,0 +1,227 @@
+// Key bindings: one table drives both the event loop and the help overlay
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Quit,
+    ToggleHelp,
+    ToggleDiagnostics,
+    GainUp,
+    GainDown,
+    ToggleAgc,
+    ZoomIn,
+    ZoomOut,
+    PanLeft,
+    PanRight,
+    ResetView,
+    ZoomOutFully,
+    TogglePause,
+    FpsDown,
+    FpsUp,
+    ToggleNotch,
+    ToggleMute,
+    TogglePeakHold,
+    ResetPeakHold,
+    ToggleSessionMax,
+    ResetSessionMax,
+    ToggleRecording,
+    ExportPng,
+    ExportSvg,
+    CursorLeft,
+    CursorRight,
+    CursorLeftFast,
+    CursorRightFast,
+    CursorUp,
+    CursorDown,
+    CaptureReference,
+    ClearReference,
+    CycleGate,
+    GateDown,
+    GateUp,
+    SmoothMore,
+    SmoothLess,
+    ToggleFreqAxis,
+    NextWindow,
+    FftSmaller,
+    FftLarger,
+    SaveConfig,
+    ContrastUp,
+    ContrastDown,
+    ToggleAutoContrast,
+    AutoRange,
+    BrightnessUp,
+    BrightnessDown,
+    ToggleThd,
+    CycleLayout,
+    TogglePersistence,
+    ToggleBandwidth,
+    ToggleScope,
+    ToggleScopeTrigger,
+    ToggleXy,
+    HistoryBack,
+    HistoryForward,
+    SeekBack,
+    SeekForward,
+    ToggleTone,
+    ToneQuieter,
+    ToneLouder,
+    OutputQuieter,
+    OutputLouder,
+    BalanceLeft,
+    BalanceRight,
+    Annotate,
+    ClearAnnotations,
+    ExportAnnotations,
+    ResetClip,
+    ToggleWaterfallHold,
+    CycleStep,
+    ExportNpy,
+    ToggleLevelLock,
+    NextInput,
+    ExportState,
+    CaptureReferenceB,
+    CycleComparison,
+    CompressTimeMore,
+    CompressTimeLess,
+    CycleQuantity,
+    ToggleFollowPeak,
+    TogglePhasePanel,
+    ToggleInvert,
+    ToggleCompand,
+    ToggleMarquee,
+}
+
+pub struct Binding {
+    pub code: KeyCode,
+    // Shift is only checked for non-character keys; characters already carry their case
+    pub modifiers: KeyModifiers,
+    pub label: &'static str,
+    pub action: Action,
+    pub description: &'static str,
+}
+
+const fn key(c: char, action: Action, description: &'static str) -> Binding {
+    Binding { code: KeyCode::Char(c), modifiers: KeyModifiers::NONE, label: "", action, description }
+}
+
+const fn special(code: KeyCode, modifiers: KeyModifiers, label: &'static str, action: Action,
+                 description: &'static str) -> Binding {
+    Binding { code, modifiers, label, action, description }
+}
+
+pub const BINDINGS: &[Binding] = &[
+    key('q', Action::Quit, "Quit"),
+    // Raw mode turns Ctrl-C into a key press instead of SIGINT
+    special(KeyCode::Char('c'), KeyModifiers::CONTROL, "Ctrl+C", Action::Quit, "Quit"),
+    key('?', Action::ToggleHelp, "Show or hide this help"),
+    key('d', Action::ToggleDiagnostics, "Show or hide frame timings"),
+    key('+', Action::GainUp, "Increase gain"),
+    key('-', Action::GainDown, "Decrease gain"),
+    key('a', Action::ToggleAgc, "Toggle automatic gain"),
+    key('w', Action::ZoomIn, "Zoom in"),
+    key('s', Action::ZoomOut, "Zoom out"),
+    key('F', Action::CycleStep, "Cycle the gain and zoom step: fine, normal, coarse"),
+    key('h', Action::PanLeft, "Pan toward lower frequencies"),
+    key('l', Action::PanRight, "Pan toward higher frequencies"),
+    key('0', Action::ResetView, "Reset zoom and pan"),
+    key('=', Action::ToggleMarquee, "Show the whole band over the waterfall while zoomed; click it to move there"),
+    key('|', Action::ToggleFollowPeak, "Keep the zoomed band centered on the strongest peak"),
+    special(KeyCode::Esc, KeyModifiers::NONE, "Esc", Action::ZoomOutFully, "Zoom all the way out (or right-click)"),
+    special(KeyCode::Char(' '), KeyModifiers::NONE, "Space", Action::TogglePause, "Pause or resume the display"),
+    key('H', Action::ToggleWaterfallHold, "Hold the waterfall while the spectrum keeps updating"),
+    key(':', Action::CompressTimeMore, "Fold one more frame into each waterfall line, for a longer span"),
+    key(';', Action::CompressTimeLess, "Fold one frame fewer into each waterfall line, for more detail"),
+    key('[', Action::FpsDown, "Lower the frame rate"),
+    key(']', Action::FpsUp, "Raise the frame rate"),
+    key('n', Action::ToggleNotch, "Toggle the hum notch (with --notch)"),
+    key('m', Action::ToggleMute, "Mute or unmute playback"),
+    key('t', Action::ToggleTone, "Play a tone at the peak instead of the input"),
+    key('{', Action::ToneQuieter, "Lower the tone volume"),
+    key('}', Action::ToneLouder, "Raise the tone volume"),
+    key('i', Action::OutputQuieter, "Lower the playback volume"),
+    key('I', Action::OutputLouder, "Raise the playback volume"),
+    key('(', Action::BalanceLeft, "Shift the playback balance left"),
+    key(')', Action::BalanceRight, "Shift the playback balance right"),
+    key('p', Action::TogglePeakHold, "Toggle peak hold"),
+    key('P', Action::ResetPeakHold, "Reset held peaks"),
+    key('M', Action::ToggleSessionMax, "Show or hide the session maximum"),
+    key('U', Action::ResetSessionMax, "Reset the session maximum"),
+    key('K', Action::ResetClip, "Reset the input clip indicator"),
+    key('o', Action::ToggleRecording, "Start or stop recording"),
+    key('S', Action::ExportPng, "Save the waterfall as PNG"),
+    key('V', Action::ExportSvg, "Save the spectrum as SVG"),
+    key('N', Action::ExportNpy, "Save the waterfall history as a NumPy array"),
+    special(KeyCode::Left, KeyModifiers::NONE, "Left", Action::CursorLeft, "Move the cursor left"),
+    special(KeyCode::Right, KeyModifiers::NONE, "Right", Action::CursorRight, "Move the cursor right"),
+    special(KeyCode::Left, KeyModifiers::SHIFT, "Shift+Left", Action::CursorLeftFast, "Move the cursor 10 columns left"),
+    special(KeyCode::Right, KeyModifiers::SHIFT, "Shift+Right", Action::CursorRightFast, "Move the cursor 10 columns right"),
+    special(KeyCode::Up, KeyModifiers::NONE, "Up", Action::CursorUp,
+            "Show the waterfall row above in the spectrum (or click a row)"),
+    special(KeyCode::Down, KeyModifiers::NONE, "Down", Action::CursorDown,
+            "Show the row below; past the newest row the spectrum is live again"),
+    key('r', Action::CaptureReference, "Capture a reference spectrum"),
+    key('#', Action::CaptureReferenceB, "Capture reference B to compare against the first"),
+    key('/', Action::CycleComparison, "Cycle live minus reference, live, reference B minus the first"),
+    key('R', Action::ClearReference, "Clear the references"),
+    key('y', Action::Annotate, "Mark the cursor where it is loudest, or click, while paused"),
+    key('Y', Action::ClearAnnotations, "Remove all markers"),
+    key('D', Action::ExportAnnotations, "Save the markers to a text file"),
+    key('u', Action::ExportState, "Save the view settings and these keys to a text file, for bug reports"),
+    key('G', Action::CycleGate, "Cycle the waterfall gate: off, level, above the reference"),
+    key('j', Action::GateDown, "Lower the gate threshold"),
+    key('J', Action::GateUp, "Raise the gate threshold"),
+    key('e', Action::SmoothMore, "Smooth the average more"),
+    key('E', Action::SmoothLess, "Smooth the average less"),
+    key('z', Action::ToggleLevelLock, "Lock the chart's level range where it is, or fit it again"),
+    key('x', Action::ToggleFreqAxis, "Toggle linear/log frequency axis"),
+    key('!', Action::TogglePhasePanel, "Split the spectrum into magnitude above and phase below"),
+    key('Q', Action::CycleQuantity, "Cycle the spectrum level: magnitude, power, power spectral density"),
+    key('f', Action::NextWindow, "Cycle the FFT window"),
+    key('<', Action::FftSmaller, "Halve the FFT size"),
+    key('>', Action::FftLarger, "Double the FFT size"),
+    key('C', Action::SaveConfig, "Save settings to the config file"),
+    key('c', Action::ContrastUp, "Raise waterfall contrast"),
+    key('v', Action::ContrastDown, "Lower waterfall contrast"),
+    key('~', Action::ToggleInvert, "Run the waterfall colors backward, for a light background"),
+    key('^', Action::ToggleCompand, "Compand drawn levels, lifting weak signals (curve set by --compand)"),
+    key('A', Action::ToggleAutoContrast, "Toggle automatic waterfall contrast"),
+    key('k', Action::AutoRange, "Set gain and contrast from the next 2 s of signal"),
+    key('b', Action::BrightnessUp, "Brighten weak signals in the waterfall"),
+    key('B', Action::BrightnessDown, "Darken weak signals in the waterfall"),
+    key('T', Action::ToggleThd, "Show or hide the THD readout"),
+    key('g', Action::TogglePersistence, "Toggle waterfall persistence"),
+    key('W', Action::ToggleBandwidth, "Show or hide the peak bandwidth markers"),
+    key('O', Action::ToggleScope, "Show the waveform in place of the spectrum"),
+    key('Z', Action::ToggleScopeTrigger, "Start the waveform on a rising zero crossing"),
+    key('X', Action::ToggleXy, "Plot left against right (with --channels stereo)"),
+    key('L', Action::CycleLayout, "Cycle spectrum and waterfall, spectrum only, waterfall only"),
+    special(KeyCode::Tab, KeyModifiers::NONE, "Tab", Action::NextInput, "Show the next input (with several --device)"),
+    special(KeyCode::PageUp, KeyModifiers::NONE, "PageUp", Action::HistoryBack, "Scroll the waterfall back in time"),
+    special(KeyCode::PageDown, KeyModifiers::NONE, "PageDown", Action::HistoryForward,
+            "Scroll the waterfall toward the newest line"),
+    key(',', Action::SeekBack, "Seek back 5 s (with --replay)"),
+    key('.', Action::SeekForward, "Seek forward 5 s (with --replay); while paused, analyze the next frame"),
+];
+
+impl Binding {
+    pub fn matches(&self, event: &KeyEvent) -> bool {
+        let checked = match self.code {
+            KeyCode::Char(_) => KeyModifiers::CONTROL,
+            _ => KeyModifiers::SHIFT | KeyModifiers::CONTROL,
+        };
+        event.code == self.code && event.modifiers & checked == self.modifiers
+    }
+
+    // Plain character keys are labeled by the character itself
+    pub fn key_label(&self) -> String {
+        match self.code {
+            KeyCode::Char(c) if self.label.is_empty() => c.to_string(),
+            _ => self.label.to_string(),
+        }
+    }
+}
+
+pub fn action_for(event: &KeyEvent) -> Option<Action> {
+    BINDINGS.iter().find(|binding| binding.matches(event)).map(|binding| binding.action)
+}
@@ -0,0 +1,13 @@
+// Library side of qmx-capture: everything the terminal binary is built from
+
+pub mod audio;
+pub mod config;
+pub mod dsp;
+pub mod export;
+pub mod keys;
+pub mod morse;
+pub mod net;
+pub mod options;
+pub mod render;
+pub mod session;
+pub mod view;
@@ -1,403 +1,1545 @@
-use anyhow::Result;
-use cpal::traits::*;
-use rustfft::{FftPlanner, num_complex::Complex};
-use textplots::{Chart, Plot, Shape};
+use anyhow::{anyhow, bail, Result};
 use crossterm::{
-    execute, queue,
-    terminal::{size, enable_raw_mode, disable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
-    cursor::{MoveTo, Hide, Show},
-    style::{Color, SetForegroundColor, SetBackgroundColor, ResetColor},
-    event::{self, Event, KeyCode},
+    event::{self, Event, MouseButton, MouseEvent, MouseEventKind},
+    queue,
+    style::{ResetColor, SetBackgroundColor, SetForegroundColor},
+    terminal::size,
 };
+use qmx_capture::{
+    audio::{
+        analysis_rate, build_pipeline, describe_devices, get_user_device_choice, has_default_output,
+        input_devices, list_devices, loopback_source, select_device, HopCursor, Pipeline, PipelineHealth, Recorder,
+        AudioBuffer, SampleSource, Tape, Watchdog, DEFAULT_TEST_SIGNAL_RATE,
+    },
+    config,
+    dsp::{self, compute_spectrum_into, compute_spectrum_timed, goertzel, BandFraction, Delay, DelayEstimator, FftCache,
+          FftSetup, SpectrumBuffers, StageTimes, Window},
+    export::{export_annotations, export_npy, export_png, export_state, export_svg, CsvLogger, EventLog,
+             utc_timestamp, OnsetLog, OutputNames, ProfileLog, SkipLog},
+    keys::{action_for, Action},
+    net::{IpcSender, UdpReceiver, UdpSender},
+    options::{help_text, Options, MAX_FFT_SIZE, MIN_FFT_SIZE},
+    render::{
+        check_terminal, install_interrupt_handler, install_panic_hook, interrupted, Layout, Renderer, MIN_TERMINAL_ROWS,
+    },
+    session::{Session, SessionWriter},
+    view::{Calibration, FrameTiming, LayoutMode, ViewState, MIN_FPS},
+};
+use realfft::RealToComplex;
 use std::{
-    sync::{Arc, Mutex},
-    io::{stdout, Write, Stdout, stdin},
-    time::{Duration, Instant},
+    io::{stdin, stdout, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-const FFT_SIZE: usize = 2048;
-const TARGET_FPS: u64 = 30;
-const BASE_GAIN: f32 = 10.0;
+const FPS_STEP: u64 = 5;
+const TONE_VOLUME_STEP: f32 = 0.05;
+const OUTPUT_GAIN_STEP_DB: f32 = 1.0;
+const BALANCE_STEP: f32 = 0.1;
+const GATE_STEP_DB: f32 = 3.0;
+const REPLAY_SEEK_STEP: Duration = Duration::from_secs(5);
+// Bumped whenever a --json-out field changes meaning or goes away
+const JSON_SCHEMA_VERSION: u32 = 1;
+// Analyzed hops waiting for the view before new ones are dropped
+const ANALYSIS_QUEUE_FRAMES: usize = 64;
+// How often the analysis thread looks for new hops with --overlap
+const ANALYSIS_POLL_INTERVAL: Duration = Duration::from_millis(2);
+// Audio kept from the start of a pause for stepping through it
+const STEP_TAPE_SECS: u32 = 30;
 
-#[derive(Clone)]
-struct AudioBuffer {
-    samples: Vec<f32>,
-    position: usize,
+// Where frames go: the local terminal, a remote viewer over UDP, clients of a Unix socket,
+// or JSON or --tape lines on stdout
+enum Frontend {
+    Terminal { state: Box<ViewState>, renderer: Renderer },
+    Udp(UdpSender),
+    Ipc(IpcSender),
+    Json(Box<ViewState>),
+    Tape(Box<ViewState>),
 }
 
-#[derive(Clone)]
-struct ViewState {
-    gain: f32,
-    freq_zoom: f32,
-    waterfall_data: Vec<Vec<(f32, f32)>>,
-    current_line: usize,
-    history_size: usize,
+// Per-frame spectrum outputs besides the screen
+struct SpectrumLogs {
+    csv: Option<CsvLogger>,
+    events: Option<EventLog>,
+    onsets: Option<OnsetLog>,
+    session: Option<SessionWriter>,
+    // Stage timings rather than spectra, but written on the same schedule
+    profile: Option<ProfileLog>,
+    // Written by the input feeder, which holds its own handle
+    skips: Option<Arc<SkipLog>>,
 }
 
-impl ViewState {
-    fn new(history_size: usize) -> Self {
-        Self {
-            gain: 5.0,
-            freq_zoom: 1.0,
-            waterfall_data: vec![vec![(0.0, 0.0); FFT_SIZE/2]; history_size],
-            current_line: 0,
-            history_size,
+impl SpectrumLogs {
+    // `spectrum` includes `gain`; the CSV keeps it while sessions store unit gain
+    fn log(&self, spectrum: &[f32], gain: f32, window: Window) {
+        if let Some(csv) = &self.csv {
+            csv.log(spectrum);
+        }
+        if let Some(session) = &self.session {
+            session.log(spectrum, gain);
+        }
+        if let Some(events) = &self.events {
+            events.check(spectrum, gain, window);
+        }
+        if let Some(onsets) = &self.onsets {
+            onsets.check(spectrum);
         }
     }
 
-    fn add_spectrum(&mut self, spectrum: Vec<f32>, sample_rate: u32) {
-        self.waterfall_data[self.current_line] = spectrum.iter()
-            .enumerate()
-            .map(|(i, &mag)| {
-                let freq = i as f32 * sample_rate as f32 / FFT_SIZE as f32;
-                (freq, mag)
-            })
-            .collect();
-        self.current_line = (self.current_line + 1) % self.history_size;
+    fn finish(self) -> Result<()> {
+        if let Some(csv) = self.csv {
+            csv.finish()?;
+        }
+        if let Some(session) = self.session {
+            session.finish()?;
+        }
+        if let Some(events) = self.events {
+            events.finish()?;
+        }
+        if let Some(skips) = self.skips {
+            skips.finish()?;
+        }
+        if let Some(onsets) = self.onsets {
+            onsets.finish()?;
+        }
+        if let Some(profile) = self.profile {
+            profile.finish()?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Clone, PartialEq)]
-struct ScreenCell {
-    char: char,
-    fg_color: Option<Color>,
-    bg_color: Option<Color>,
-}
-
-impl Default for ScreenCell {
-    fn default() -> Self {
-        Self {
-            char: ' ',
-            fg_color: None,
-            bg_color: None,
-        }
-    }
+enum RunOutcome {
+    Quit,
+    Restart,
+    NextInput,
 }
 
-struct ScreenBuffer {
-    cells: Vec<Vec<ScreenCell>>,
-    width: usize,
-    height: usize,
+// An input of a comma-separated --device that is off screen. Its streams keep capturing, and
+// recording if asked to, while only the input on screen is analyzed; Tab swaps it in.
+struct StandbyInput {
+    source: SampleSource,
+    pipeline: Pipeline,
+    recorder: Recorder,
+    state: Box<ViewState>,
+    device_name: Option<String>,
+    sample_rate: u32,
 }
 
-impl ScreenBuffer {
-    fn new(width: usize, height: usize) -> Self {
-        Self {
-            cells: vec![vec![ScreenCell::default(); width]; height],
-            width,
-            height,
+// Keys that only touch the view, shared by local capture and the UDP viewer
+fn apply_view_action(action: Action, state: &mut ViewState, renderer: &Renderer, sample_rate: u32) {
+    let width = renderer.waterfall_width(state.panels());
+    match action {
+        Action::ToggleHelp => state.show_help = !state.show_help,
+        Action::ToggleDiagnostics => state.diagnostics.enabled = !state.diagnostics.enabled,
+        Action::GainUp => state.adjust_gain(state.gain_step_db),
+        Action::GainDown => state.adjust_gain(-state.gain_step_db),
+        Action::ToggleAgc => state.agc.enabled = !state.agc.enabled,
+        Action::ZoomIn => state.adjust_zoom(state.step, width, sample_rate),
+        Action::ZoomOut => state.adjust_zoom(1.0 / state.step, width, sample_rate),
+        Action::CycleStep => {
+            state.cycle_step();
+            state.notify(format!("Gain and zoom step: {} dB, {:.2}x", state.gain_step_db, state.step));
+        }
+        Action::PanLeft => state.pan(-0.1, sample_rate),
+        Action::PanRight => state.pan(0.1, sample_rate),
+        Action::ResetView => state.reset_view(),
+        Action::ZoomOutFully => state.zoom_out(),
+        Action::TogglePause => state.paused = !state.paused,
+        Action::ToggleWaterfallHold => state.waterfall_held = !state.waterfall_held,
+        Action::FpsDown => state.adjust_fps(-(FPS_STEP as i64)),
+        Action::FpsUp => state.adjust_fps(FPS_STEP as i64),
+        Action::CompressTimeMore => state.adjust_time_compress(1),
+        Action::CompressTimeLess => state.adjust_time_compress(-1),
+        Action::TogglePeakHold => state.peak_hold_enabled = !state.peak_hold_enabled,
+        Action::ResetPeakHold => state.reset_peak_hold(),
+        Action::ToggleSessionMax => state.session_max_enabled = !state.session_max_enabled,
+        Action::ResetSessionMax => state.reset_session_max(),
+        Action::ResetClip => state.input_clipped = false,
+        Action::ExportPng => match export_png(state, sample_rate) {
+            Ok(path) => state.notify(format!("Saved {}", path.display())),
+            Err(e) => state.notify(format!("PNG export failed: {}", e)),
+        },
+        Action::ExportSvg => match export_svg(state, sample_rate) {
+            Ok(path) => state.notify(format!("Saved {}", path.display())),
+            Err(e) => state.notify(format!("SVG export failed: {}", e)),
+        },
+        Action::ToggleLevelLock if state.level_range.is_some() => {
+            state.level_range = None;
+            state.notify("Chart levels fit the signal again".into());
+        }
+        Action::ToggleLevelLock => match renderer.fitted_range() {
+            Some(range) => {
+                state.level_range = Some(range);
+                state.notify(format!("Chart levels locked to {:.1} - {:.1}", range.bottom, range.top));
+            }
+            None => state.notify("No chart levels to lock yet".into()),
+        },
+        Action::ExportNpy => match export_npy(state, sample_rate, None) {
+            Ok(path) => state.notify(format!("Saved {}", path.display())),
+            Err(e) => state.notify(format!("NumPy export failed: {}", e)),
+        },
+        Action::CursorLeft => state.move_cursor(-1, width),
+        Action::CursorRight => state.move_cursor(1, width),
+        Action::CursorLeftFast => state.move_cursor(-10, width),
+        Action::CursorRightFast => state.move_cursor(10, width),
+        Action::CursorUp => state.move_cursor_row(-1),
+        Action::CursorDown => state.move_cursor_row(1),
+        Action::CaptureReference => state.capture_reference(),
+        Action::Annotate if !state.paused => state.notify("Pause first to place markers".into()),
+        Action::Annotate => state.annotate_cursor(width, sample_rate),
+        Action::ClearAnnotations => state.annotations.clear(),
+        Action::ExportAnnotations if state.annotations.is_empty() => state.notify("No markers to save".into()),
+        Action::ExportAnnotations => match export_annotations(state, sample_rate) {
+            Ok(path) => state.notify(format!("Saved {}", path.display())),
+            Err(e) => state.notify(format!("Marker export failed: {}", e)),
+        },
+        Action::ExportState => dump_state(state, sample_rate, None),
+        Action::CaptureReferenceB if state.reference.is_none() => {
+            state.notify("Capture the first reference with r before reference B".into())
+        }
+        Action::CaptureReferenceB => state.capture_reference_b(),
+        Action::CycleComparison if state.reference.is_none() => state.notify("No reference to compare with".into()),
+        Action::CycleComparison => state.cycle_comparison(),
+        Action::ClearReference => state.clear_references(),
+        Action::CycleGate => state.cycle_gate(),
+        Action::GateDown => state.adjust_gate(-GATE_STEP_DB),
+        Action::GateUp => state.adjust_gate(GATE_STEP_DB),
+        Action::SmoothMore => state.adjust_average(0.8),
+        Action::SmoothLess => state.adjust_average(1.25),
+        Action::ToggleFreqAxis => state.freq_axis = state.freq_axis.toggle(),
+        Action::CycleQuantity => state.cycle_quantity(),
+        Action::ToggleFollowPeak => state.toggle_follow_peak(),
+        Action::ToggleMarquee => state.marquee = !state.marquee,
+        Action::TogglePhasePanel => state.toggle_phase_panel(),
+        Action::ToggleInvert => state.toggle_invert(),
+        Action::ToggleCompand => state.compand = !state.compand,
+        Action::ContrastUp => state.adjust_waterfall_scale(1.25),
+        Action::ContrastDown => state.adjust_waterfall_scale(1.0 / 1.25),
+        Action::ToggleAutoContrast => state.auto_contrast = !state.auto_contrast,
+        Action::AutoRange => state.calibration = Some(Calibration::default()),
+        Action::BrightnessUp => state.adjust_waterfall_gamma(1.0 / 1.25),
+        Action::BrightnessDown => state.adjust_waterfall_gamma(1.25),
+        Action::ToggleThd => {
+            state.thd = !state.thd;
+            state.thd_ratio = None;
         }
+        Action::TogglePersistence => state.persistence = !state.persistence,
+        Action::ToggleBandwidth => state.bandwidth = !state.bandwidth,
+        // Local capture handles these; frames from the network or a session carry no samples
+        Action::FftSmaller | Action::FftLarger => state.notify("The FFT size comes with the frames".into()),
+        Action::ToggleScope | Action::ToggleScopeTrigger | Action::ToggleXy => {
+            state.notify("The scope needs live input".into())
+        }
+        Action::CycleLayout => {
+            state.layout = state.layout.next();
+            let (width, height) = renderer.size();
+            fit_history(state, width, height);
+        }
+        Action::HistoryBack => state.scroll_history(state.visible_lines as isize),
+        Action::HistoryForward => state.scroll_history(-(state.visible_lines as isize)),
+        Action::ToneQuieter => state.adjust_tone_volume(-TONE_VOLUME_STEP),
+        Action::ToneLouder => state.adjust_tone_volume(TONE_VOLUME_STEP),
+        Action::OutputQuieter => state.adjust_output_gain(-OUTPUT_GAIN_STEP_DB),
+        Action::OutputLouder => state.adjust_output_gain(OUTPUT_GAIN_STEP_DB),
+        Action::BalanceLeft => state.adjust_balance(-BALANCE_STEP),
+        Action::BalanceRight => state.adjust_balance(BALANCE_STEP),
+        _ => (),
     }
+}
 
-    fn clear(&mut self) {
-        for row in &mut self.cells {
-            for cell in row {
-                *cell = ScreenCell::default();
+// A click places the cursor and a drag across a panel zooms to the band it covers
+fn apply_mouse(mouse: MouseEvent, state: &mut ViewState, renderer: &Renderer, sample_rate: u32) {
+    if let (MouseEventKind::Down(MouseButton::Left), Some(column)) =
+        (mouse.kind, renderer.marquee_column(state, mouse.column as usize, mouse.row as usize)) {
+        state.center_on_marquee(column, renderer.waterfall_width(state.panels()), sample_rate);
+        return;
+    }
+    let column = renderer.panel_column(state.panels(), mouse.column as usize, mouse.row as usize);
+    match mouse.kind {
+        MouseEventKind::Down(MouseButton::Left) => state.selection = column.map(|column| (column, column)),
+        MouseEventKind::Drag(MouseButton::Left) => {
+            if let (Some(selection), Some(column)) = (&mut state.selection, column) {
+                selection.1 = column;
             }
         }
+        MouseEventKind::Up(MouseButton::Left) => match state.selection.take() {
+            // Paused, a click on the waterfall drops a marker instead of moving the cursor; running,
+            // it also picks the row the spectrum shows
+            Some((start, end)) if start == column.unwrap_or(end) => {
+                match renderer.waterfall_row(state.panels(), state.layout, mouse.row as usize) {
+                    Some(row) if state.paused => {
+                        state.annotate(start, row, renderer.waterfall_width(state.panels()), sample_rate)
+                    }
+                    row => {
+                        state.cursor = Some(start);
+                        state.cursor_row = row.or(state.cursor_row);
+                    }
+                }
+            }
+            Some((start, end)) => {
+                let width = renderer.waterfall_width(state.panels());
+                state.zoom_to_columns(start, column.unwrap_or(end), width, sample_rate);
+            }
+            None => (),
+        },
+        MouseEventKind::Down(MouseButton::Right) => state.zoom_out(),
+        _ => (),
     }
 }
 
-struct Renderer {
-    stdout: Stdout,
-    front_buffer: ScreenBuffer,
-    back_buffer: ScreenBuffer,
+fn save_config(state: &mut ViewState, device_name: Option<&str>) {
+    match config::save(state, device_name) {
+        Ok(path) => state.notify(format!("Saved {}", path.display())),
+        Err(e) => state.notify(format!("Config save failed: {}", e)),
+    }
+}
+
+fn dump_state(state: &mut ViewState, sample_rate: u32, device_name: Option<&str>) {
+    match export_state(state, sample_rate, device_name) {
+        Ok(path) => state.notify(format!("Saved {}", path.display())),
+        Err(e) => state.notify(format!("State dump failed: {}", e)),
+    }
 }
 
-impl Renderer {
-    fn new() -> Result<Self> {
-        let mut stdout = stdout();
-        execute!(stdout, EnterAlternateScreen, Hide)?;
-        enable_raw_mode()?;
-        let (term_width, term_height) = size()?;
+fn resize(state: &mut ViewState, renderer: &mut Renderer, width: u16, height: u16) -> Result<()> {
+    renderer.resize(width as usize, height as usize)?;
+    fit_history(state, width as usize, height as usize);
+    Ok(())
+}
 
-        Ok(Self {
-            stdout,
-            front_buffer: ScreenBuffer::new(term_width as usize, term_height as usize),
-            back_buffer: ScreenBuffer::new(term_width as usize, term_height as usize),
-        })
+// A hidden waterfall keeps its history for when it comes back
+fn fit_history(state: &mut ViewState, width: usize, height: usize) {
+    if let Some(layout) = Layout::new(width, height, state.panels(), state.layout) {
+        if layout.history_rows > 0 {
+            state.fit_history_rows(layout.history_rows);
+        }
     }
+}
 
-    fn write_str_at(&mut self, x: usize, y: usize, s: &str) {
-        let cells = &mut self.back_buffer.cells[y];
-        for (i, c) in s.chars().enumerate() {
-            if x + i >= self.back_buffer.width {
-                break;
-            }
-            cells[x + i].char = c;
+// Everything sized by the FFT, switched as a whole so no frame mixes two sizes
+struct Analysis {
+    fft_size: usize,
+    setup: FftSetup,
+    window_type: Window,
+    window: Arc<[f32]>,
+    // Scratch buffers reused by every frame
+    samples: Vec<f32>,
+    spectrum: Vec<f32>,
+    right_spectrum: Vec<f32>,
+    phase: Vec<f32>,
+    hops: HopCursor,
+}
+
+impl Analysis {
+    fn new(mut setup: FftSetup, window: Window, overlap: Option<f32>, capacity: u64) -> Self {
+        let fft_size = setup.fft.len();
+        Self {
+            fft_size,
+            window_type: window,
+            window: setup.window(window),
+            samples: vec![0.0; fft_size],
+            spectrum: vec![0.0; fft_size / 2],
+            right_spectrum: vec![0.0; fft_size / 2],
+            phase: vec![0.0; fft_size / 2],
+            // Hops step through the captured samples, not the padding
+            hops: HopCursor::new(fft_size / setup.zero_pad, overlap, capacity),
+            setup,
         }
     }
 
-    fn set_cell(&mut self, x: usize, y: usize, cell: ScreenCell) {
-        if x < self.back_buffer.width && y < self.back_buffer.height {
-            self.back_buffer.cells[y][x] = cell;
+    // Hands the current setup back to `cache` and picks up the one for `size`, resizing the
+    // frame buffers in place
+    fn switch_size(&mut self, cache: &mut FftCache, size: usize, window: Window, overlap: Option<f32>, capacity: u64) {
+        let mut setup = cache.take(size);
+        self.window_type = window;
+        self.window = setup.window(window);
+        cache.store(std::mem::replace(&mut self.setup, setup));
+        self.fft_size = size;
+        self.samples.resize(size, 0.0);
+        for bins in [&mut self.spectrum, &mut self.right_spectrum, &mut self.phase] {
+            bins.resize(size / 2, 0.0);
         }
+        self.hops = HopCursor::new(size / self.setup.zero_pad, overlap, capacity);
     }
 
-    fn render(&mut self, state: &ViewState, sample_rate: u32) -> Result<()> {
-        self.back_buffer.clear();
+    // Ends of the windows due, less any ending before a whole window has been captured: the
+    // empty ring ahead of the first samples would be analyzed as part of the signal
+    fn captured_windows_due(&mut self, written: u64) -> impl Iterator<Item = u64> {
+        let captured = (self.fft_size / self.setup.zero_pad) as u64;
+        self.hops.due(written).filter(move |&end| end >= captured)
+    }
 
-        // Render header
-        let header = format!("Gain: {:.1}x | Freq Zoom: {:.1}x | Press 'q' to quit | FPS: {}",
-                           state.gain, state.freq_zoom, TARGET_FPS);
-        self.write_str_at(0, 0, &header);
+    fn set_window(&mut self, window: Window) {
+        self.window_type = window;
+        self.window = self.setup.window(window);
+    }
 
-        let max_freq = sample_rate as f32 / state.freq_zoom / 2.0;
-        let spectrum_header = format!("Spectrum Analysis (0 Hz - {:.0} Hz)", max_freq);
-        self.write_str_at(0, 2, &spectrum_header);
+    // Spectrum of the window in `samples`, into the left or the right channel's buffer
+    fn compute(&mut self, gain: f32, right: bool, times: Option<&mut StageTimes>) -> Result<()> {
+        let out = if right { &mut self.right_spectrum } else { &mut self.spectrum };
+        compute_spectrum_timed(&self.samples, &self.window, &*self.setup.fft, gain, &mut self.setup.buffers, out, times)
+    }
+}
 
-        self.write_str_at(0, 3, "────────────────────────────────");
+// What the analysis thread reads: the rings, and the view settings it follows between hops
+struct Analyzer {
+    sample_rate: u32,
+    main: Arc<AudioBuffer>,
+    right: Option<Arc<AudioBuffer>>,
+    fft: Arc<dyn RealToComplex<f32>>,
+    monitor: Option<f32>,
+    // The size only changes when nothing is logged, so the logs never mix two sizes
+    fixed_size: bool,
+    gain: AtomicU32,
+    fps: AtomicU64,
+    tracks_phase: AtomicBool,
+    settings: Mutex<(usize, Window)>,
+    stop: AtomicBool,
+}
 
-        // Render spectrum chart
-        let spectrum_chart = Chart::new(self.back_buffer.width as u32, 5, 0.0, max_freq)
-            .lineplot(&Shape::Lines(&state.waterfall_data[state.current_line]))
-            .to_string();
-        for (i, line) in spectrum_chart.lines().enumerate() {
-            self.write_str_at(0, 4 + i, line);
+impl Analyzer {
+    fn new(pipeline: &Pipeline, state: &ViewState, logs: &SpectrumLogs) -> Self {
+        Self {
+            sample_rate: pipeline.sample_rate,
+            // In stereo the main view follows the left channel, or the mid under --stereo-mode mid-side
+            main: Arc::clone(pipeline.stereo_buffers.as_ref().map_or(&pipeline.input_buffer, |[left, _]| left)),
+            right: pipeline.stereo_buffers.as_ref().map(|[_, right]| Arc::clone(right)),
+            fft: Arc::clone(&pipeline.fft),
+            monitor: state.monitor,
+            fixed_size: logs.csv.is_some() || logs.session.is_some() || logs.events.is_some(),
+            gain: AtomicU32::new(state.gain.to_bits()),
+            fps: AtomicU64::new(state.fps),
+            tracks_phase: AtomicBool::new(state.tracks_phase()),
+            settings: Mutex::new((state.fft_size, state.window)),
+            stop: AtomicBool::new(false),
         }
+    }
 
-        // Render waterfall
-        let freq_step = (sample_rate as f32) / 2.0 / state.freq_zoom / (self.back_buffer.width as f32);
-        for i in 0..state.history_size {
-            let line = (state.current_line + i) % state.history_size;
-            let points = &state.waterfall_data[line];
+    // Hands the view's current settings to the thread, once a frame
+    fn follow(&self, state: &ViewState) {
+        self.gain.store(state.gain.to_bits(), Ordering::Relaxed);
+        self.fps.store(state.fps, Ordering::Relaxed);
+        self.tracks_phase.store(state.tracks_phase(), Ordering::Relaxed);
+        *self.settings.lock().unwrap() = (state.fft_size, state.window);
+    }
+}
 
-            for j in 0..self.back_buffer.width {
-                let idx = ((j as f32 * freq_step) * FFT_SIZE as f32 / sample_rate as f32) as usize;
-                if idx < points.len() {
-                    let magnitude = points[idx].1;
-                    let normalized = (magnitude * 200.0).min(100.0) as u8;
-                    let color = match normalized {
-                        0..=20 => Color::Blue,
-                        21..=40 => Color::Cyan,
-                        41..=60 => Color::Green,
-                        61..=80 => Color::Yellow,
-                        _ => Color::Red,
-                    };
-                    self.set_cell(j, i + 15, ScreenCell {
-                        char: '█',
-                        fg_color: Some(color),
-                        bg_color: Some(color),
-                    });
-                }
+// One analyzed hop on its way to the view
+enum AnalyzedFrame {
+    Spectrum {
+        fft_size: usize,
+        // The sample its window ends on
+        end: u64,
+        spectrum: Vec<f32>,
+        right: Option<Vec<f32>>,
+        phase: Option<Vec<f32>>,
+        delay: Option<Delay>,
+    },
+    // The monitor needs only its one frequency, so it skips the FFT altogether. The sample
+    // its window ends on comes along to time CW keying.
+    Level(f32, u64),
+}
+
+// Analyzes every hop as audio arrives, however long the view takes to draw, so the logs stay
+// continuous and a slow terminal only loses lines on screen. Without --overlap it keeps to
+// the view's frame rate, one window per frame as before.
+fn run_analysis(analyzer: &Analyzer, logs: &SpectrumLogs, frames: mpsc::SyncSender<AnalyzedFrame>, options: &Options)
+                -> Result<()> {
+    let capacity = analyzer.main.capacity();
+    let (fft_size, window) = *analyzer.settings.lock().unwrap();
+    // A size changed live outlasts a pipeline rebuild
+    let mut fft_cache = FftCache::new(options.zero_pad);
+    fft_cache.store(FftSetup::new(Arc::clone(&analyzer.fft), options.zero_pad));
+    let mut analysis = Analysis::new(fft_cache.take(fft_size), window, options.overlap, capacity);
+    // The left window, kept while the right one is read over it
+    let mut delay = (options.delay && analyzer.right.is_some()).then(|| (DelayEstimator::new(fft_size), Vec::new()));
+
+    let profile = logs.profile.as_ref();
+    while !analyzer.stop.load(Ordering::Relaxed) {
+        let tick = Instant::now();
+        let (fft_size, window) = *analyzer.settings.lock().unwrap();
+        if let Some(profile) = profile {
+            profile.record("analysis;lock", tick.elapsed());
+        }
+        if fft_size != analysis.fft_size {
+            analysis.switch_size(&mut fft_cache, fft_size, window, options.overlap, capacity);
+            if let Some((estimator, _)) = &mut delay {
+                *estimator = DelayEstimator::new(fft_size);
+            }
+        } else if window != analysis.window_type {
+            analysis.set_window(window);
+        }
+        let gain = f32::from_bits(analyzer.gain.load(Ordering::Relaxed));
+
+        for end in analysis.hops.due(analyzer.main.written()) {
+            let reorder_start = profile.map(|_| Instant::now());
+            analyzer.main.window_ending(end, &mut analysis.samples);
+            if let (Some(profile), Some(start)) = (profile, reorder_start) {
+                profile.record("analysis;reorder", start.elapsed());
+            }
+            let mut times = profile.map(|_| StageTimes::default());
+            let frame = if let Some(freq) = analyzer.monitor {
+                let level = goertzel(&analysis.samples, &analysis.window, freq, analyzer.sample_rate, gain);
+                AnalyzedFrame::Level(level, end)
+            } else {
+                analysis.compute(gain, false, times.as_mut())?;
+                let phase = analyzer.tracks_phase.load(Ordering::Relaxed).then(|| {
+                    analysis.setup.buffers.phases_into(&mut analysis.phase);
+                    analysis.phase.clone()
+                });
+                let mut measured = None;
+                let right = match &analyzer.right {
+                    Some(right) => {
+                        if let Some((_, left)) = &mut delay {
+                            left.clone_from(&analysis.samples);
+                        }
+                        right.window_ending(end, &mut analysis.samples);
+                        if let Some((estimator, left)) = &mut delay {
+                            measured = estimator.estimate(left, &analysis.samples)?;
+                        }
+                        analysis.compute(gain, true, times.as_mut())?;
+                        Some(analysis.right_spectrum.clone())
+                    }
+                    None => None,
+                };
+                logs.log(&analysis.spectrum, gain, analysis.window_type);
+                let spectrum = analysis.spectrum.clone();
+                AnalyzedFrame::Spectrum { fft_size, end, spectrum, right, phase, delay: measured }
+            };
+            if let (Some(profile), Some(times)) = (profile, times) {
+                profile.record("analysis;window", times.window);
+                profile.record("analysis;fft", times.fft);
+                profile.record("analysis;magnitude", times.magnitude);
+            }
+            // A full queue means the view has fallen behind, which costs it the line
+            if let Err(mpsc::TrySendError::Disconnected(_)) = frames.try_send(frame) {
+                return Ok(());
             }
         }
 
-        // Update screen with changes
-        let mut current_fg = None;
-        let mut current_bg = None;
+        let pace = match options.overlap {
+            Some(_) => ANALYSIS_POLL_INTERVAL,
+            None => Duration::from_micros(1_000_000 / analyzer.fps.load(Ordering::Relaxed).max(MIN_FPS)),
+        };
+        if let Some(rest) = pace.checked_sub(tick.elapsed()) {
+            thread::sleep(rest);
+        }
+    }
+    Ok(())
+}
 
-        for y in 0..self.back_buffer.height {
-            for x in 0..self.back_buffer.width {
-                let front_cell = &self.front_buffer.cells[y][x];
-                let back_cell = &self.back_buffer.cells[y][x];
+fn run_pipeline(pipeline: &Pipeline, state: &mut ViewState, renderer: &mut Renderer, recorder: &mut Recorder,
+                logs: &SpectrumLogs, device_name: Option<&str>, options: &Options) -> Result<RunOutcome> {
+    pipeline.set_muted(state.muted);
+    state.set_stereo(pipeline.stereo_buffers.is_some());
+    pipeline.set_notch(state.notch_enabled);
 
-                if front_cell != back_cell {
-                    queue!(self.stdout, MoveTo(x as u16, y as u16))?;
+    let analyzer = Analyzer::new(pipeline, state, logs);
+    let (sender, frames) = mpsc::sync_channel(ANALYSIS_QUEUE_FRAMES);
+    thread::scope(|scope| {
+        let analysis = scope.spawn(|| run_analysis(&analyzer, logs, sender, options));
+        let outcome = run_view(pipeline, state, renderer, recorder, (&analyzer, &frames, logs.profile.as_ref()),
+                               device_name, options);
+        analyzer.stop.store(true, Ordering::Relaxed);
+        analysis.join().map_err(|_| anyhow!("Analysis thread panicked"))??;
+        outcome
+    })
+}
 
-                    if current_fg != back_cell.fg_color {
-                        if let Some(color) = back_cell.fg_color {
-                            queue!(self.stdout, SetForegroundColor(color))?;
-                        } else {
-                            queue!(self.stdout, ResetColor)?;
+// The terminal side of `run_pipeline`: keys, the analyzed hops and drawing, at the view's
+// frame rate
+fn run_view(pipeline: &Pipeline, state: &mut ViewState, renderer: &mut Renderer, recorder: &mut Recorder,
+            (analyzer, frames, profile): (&Analyzer, &mpsc::Receiver<AnalyzedFrame>, Option<&ProfileLog>),
+            device_name: Option<&str>,
+            options: &Options) -> Result<RunOutcome> {
+    let capacity = pipeline.input_buffer.capacity();
+    let mut watchdog = Watchdog::default();
+    let mut samples = Vec::new();
+    let mut stepper: Option<Stepper> = None;
+
+    loop {
+        let frame_start = Instant::now();
+        match &mut stepper {
+            _ if !state.paused => stepper = None,
+            Some(stepper) => stepper.follow(analyzer),
+            None => stepper = Some(Stepper::new(analyzer, state)),
+        }
+        if interrupted() {
+            return Ok(RunOutcome::Quit);
+        }
+
+        if event::poll(Duration::from_millis(0))? {
+            match event::read()? {
+                Event::Key(key) => match action_for(&key) {
+                    Some(Action::Quit) => return Ok(RunOutcome::Quit),
+                    Some(Action::ToggleNotch) if state.notch.is_some() => {
+                        state.notch_enabled = !state.notch_enabled;
+                        pipeline.set_notch(state.notch_enabled);
+                    }
+                    Some(Action::ToggleMute) => {
+                        state.muted = !state.muted;
+                        pipeline.set_muted(state.muted);
+                    }
+                    Some(Action::ToggleTone) if !state.output => state.notify("No output to play a tone on".into()),
+                    Some(Action::ToggleTone) => state.tone = !state.tone,
+                    Some(Action::ToggleRecording) => recorder.toggle()?,
+                    Some(Action::SeekForward) if state.paused => {
+                        if let Some(stepper) = &mut stepper {
+                            stepper.step(analyzer, state, options)?;
                         }
-                        current_fg = back_cell.fg_color;
                     }
-
-                    if current_bg != back_cell.bg_color {
-                        if let Some(color) = back_cell.bg_color {
-                            queue!(self.stdout, SetBackgroundColor(color))?;
+                    Some(Action::NextWindow) => state.window = state.window.next(),
+                    Some(action @ (Action::FftSmaller | Action::FftLarger)) => {
+                        let size = if action == Action::FftLarger { state.fft_size * 2 } else { state.fft_size / 2 };
+                        if analyzer.fixed_size {
+                            state.notify("The FFT size is fixed while logging spectra".into());
+                        } else if !(MIN_FFT_SIZE * state.zero_pad..=MAX_FFT_SIZE).contains(&size)
+                                  || size as u64 > capacity {
+                            state.notify(format!("FFT size stays at {}", state.fft_size));
                         } else {
-                            queue!(self.stdout, ResetColor)?;
+                            state.set_fft_size(size);
                         }
-                        current_bg = back_cell.bg_color;
                     }
+                    Some(Action::SaveConfig) => save_config(state, device_name),
+                    Some(Action::ExportState) => dump_state(state, pipeline.sample_rate, device_name),
+                    Some(Action::ToggleScope) => state.scope = !state.scope,
+                    Some(Action::ToggleScopeTrigger) => state.scope_trigger = !state.scope_trigger,
+                    Some(Action::ToggleXy) if pipeline.stereo_buffers.is_none() => {
+                        state.notify("X-Y needs stereo input (--channels stereo)".into())
+                    }
+                    Some(Action::ToggleXy) => state.xy = !state.xy,
+                    Some(Action::NextInput) if options.extra_devices.is_empty() => {
+                        state.notify("Only one input; pass several to --device, like --device 0,2".into())
+                    }
+                    Some(Action::NextInput) => return Ok(RunOutcome::NextInput),
+                    Some(action) => apply_view_action(action, state, renderer, pipeline.sample_rate),
+                    None => (),
+                },
+                Event::Mouse(mouse) => apply_mouse(mouse, state, renderer, pipeline.sample_rate),
+                Event::Resize(width, height) => resize(state, renderer, width, height)?,
+                _ => (),
+            }
+        }
+
+        let frame_time = state.frame_time();
+        if pipeline.health.has_ended() {
+            return Ok(RunOutcome::Quit);
+        }
+        if watchdog.should_restart(&pipeline.health, options) {
+            return Ok(RunOutcome::Restart);
+        }
+        analyzer.follow(state);
 
-                    write!(self.stdout, "{}", back_cell.char)?;
+        // While paused the thread keeps analyzing and logging, but its lines are dropped, so
+        // resuming picks up live audio instead of a backlog. So are lines of a size just left,
+        // and those of a stalled input, which would only repeat the last audio it delivered.
+        let dsp_start = Instant::now();
+        let stalled = pipeline.health.input_age() >= options.stall_timeout;
+        if stalled && !state.paused {
+            state.clear_live_spectrum();
+        }
+        loop {
+            let frame = match frames.try_recv() {
+                Ok(frame) => frame,
+                Err(mpsc::TryRecvError::Empty) => break,
+                // The thread only stops early on an error, which `run_pipeline` reports
+                Err(mpsc::TryRecvError::Disconnected) => return Ok(RunOutcome::Quit),
+            };
+            match frame {
+                _ if state.paused || stalled => (),
+                AnalyzedFrame::Level(level, end) => state.add_monitor_level(level, end, pipeline.sample_rate),
+                AnalyzedFrame::Spectrum { fft_size, .. } if fft_size != state.fft_size => (),
+                AnalyzedFrame::Spectrum { end, spectrum, right, phase, delay, .. } => {
+                    if let Some(phase) = phase {
+                        state.set_phase(&phase);
+                    }
+                    state.delay = delay;
+                    if let Some(right) = right {
+                        state.add_right_spectrum(&right, pipeline.sample_rate);
+                    }
+                    state.add_spectrum(&spectrum, pipeline.sample_rate);
+                    state.frame_end = end;
                 }
             }
         }
+        if !state.paused {
+            // The waveform views only ever show the newest window, so they read it here
+            if state.scope || state.xy {
+                let written = analyzer.main.written();
+                samples.resize(state.fft_size, 0.0);
+                analyzer.main.window_ending(written, &mut samples);
+                state.set_scope_samples(&samples);
+                if let (Some(right), true) = (&analyzer.right, state.xy) {
+                    right.window_ending(written, &mut samples);
+                    state.set_xy_right(&samples);
+                }
+            }
+            if state.monitor.is_none() {
+                state.update_agc(frame_time.as_secs_f32());
+            }
+        }
+        pipeline.set_tone(state.tone, state.find_peak().map(|peak| peak.freq), state.tone_volume);
+        pipeline.set_output_mix(state.output_gain_db, state.balance);
+        state.recording = recorder.is_recording();
+        state.banner = input_banner(&pipeline.health, options);
+        state.overruns = pipeline.health.overruns();
+        state.underruns = pipeline.health.underruns();
+        state.skipped_secs = pipeline.health.skipped_frames() as f32 / pipeline.input_rate as f32;
+        state.input_block = pipeline.health.block_frames().map(|frames| (frames, pipeline.input_rate));
+        state.update_input_meter(pipeline.meter.take(), frame_time.as_secs_f32());
+        let dsp = dsp_start.elapsed();
+        let render_start = Instant::now();
+        renderer.render(state, pipeline.sample_rate)?;
+        let render = render_start.elapsed();
+        if let Some(profile) = profile {
+            profile.record("view;render", render);
+        }
+        state.diagnostics.record(FrameTiming { start: frame_start, dsp, render, busy: frame_start.elapsed() });
 
-        self.stdout.flush()?;
-        std::mem::swap(&mut self.front_buffer, &mut self.back_buffer);
+        let elapsed = frame_start.elapsed();
+        if elapsed < frame_time {
+            thread::sleep(frame_time - elapsed);
+        }
+    }
+}
+
+// Frames stepped through while paused: the audio held since the pause began, as the ring
+// soon overwrites it, and an analysis of its own, as the thread's frames are dropped
+struct Stepper {
+    main: Tape,
+    right: Option<Tape>,
+    analysis: Option<Analysis>,
+}
+
+impl Stepper {
+    // The tapes start a window before the last frame shown, so its first step is all there
+    fn new(analyzer: &Analyzer, state: &ViewState) -> Self {
+        let start = state.frame_end.saturating_sub(state.fft_size as u64);
+        let limit = (analyzer.sample_rate * STEP_TAPE_SECS) as usize;
+        let mut stepper = Self {
+            main: Tape::new(start, limit),
+            right: analyzer.right.as_ref().map(|_| Tape::new(start, limit)),
+            analysis: None,
+        };
+        stepper.follow(analyzer);
+        stepper
+    }
+
+    fn follow(&mut self, analyzer: &Analyzer) {
+        self.main.follow(&analyzer.main);
+        if let (Some(tape), Some(ring)) = (&mut self.right, &analyzer.right) {
+            tape.follow(ring);
+        }
+    }
+
+    // Analyzes the window one hop past the last frame shown and hands it to the view
+    fn step(&mut self, analyzer: &Analyzer, state: &mut ViewState, options: &Options) -> Result<()> {
+        if analyzer.monitor.is_some() {
+            state.notify("The --monitor level has no spectrum to step through".into());
+            return Ok(());
+        }
+        if self.analysis.as_ref().is_none_or(|analysis| analysis.fft_size != state.fft_size) {
+            let setup = FftSetup::new(dsp::plan_fft(state.fft_size), options.zero_pad);
+            let mut analysis = Analysis::new(setup, state.window, options.overlap, u64::MAX);
+            analysis.hops.skip_to(state.frame_end);
+            self.analysis = Some(analysis);
+        }
+        let Some(analysis) = &mut self.analysis else { return Ok(()) };
+        if state.window != analysis.window_type {
+            analysis.set_window(state.window);
+        }
+        let Some(end) = analysis.hops.step(self.main.written()) else {
+            let notice = if self.main.is_full() {
+                format!("Stepped through the {} s of audio kept since the pause", STEP_TAPE_SECS)
+            } else {
+                "No newer audio to step to yet".into()
+            };
+            state.notify(notice);
+            return Ok(());
+        };
+
+        self.main.window_ending(end, &mut analysis.samples);
+        analysis.compute(state.gain, false, None)?;
+        if state.tracks_phase() {
+            analysis.setup.buffers.phases_into(&mut analysis.phase);
+            state.set_phase(&analysis.phase);
+        }
+        if let Some(right) = &self.right {
+            right.window_ending(end, &mut analysis.samples);
+            analysis.compute(state.gain, true, None)?;
+            state.add_right_spectrum(&analysis.right_spectrum, analyzer.sample_rate);
+        }
+        state.add_spectrum(&analysis.spectrum, analyzer.sample_rate);
+        state.frame_end = end;
         Ok(())
     }
 }
 
-impl Drop for Renderer {
-    fn drop(&mut self) {
-        let _ = execute!(self.stdout, Show, LeaveAlternateScreen);
-        let _ = disable_raw_mode();
+// Warning shown while the input is unhealthy; the last frames stay on screen behind it
+fn input_banner(health: &PipelineHealth, options: &Options) -> Option<String> {
+    if health.is_healthy(options) && !health.is_disconnected() {
+        return None;
     }
+    let problem = match health.last_error() {
+        Some(err) if health.is_disconnected() => format!("Device disconnected ({})", err),
+        Some(err) => format!("Stream error ({})", err),
+        None => format!("NO INPUT for {:.0} s", health.input_age().as_secs_f32()),
+    };
+    let action = if options.auto_restart { "restarting shortly" } else { "press q to quit" };
+    Some(format!("{} - {}", problem, action))
 }
 
-fn list_devices() -> Result<Vec<cpal::Device>> {
-    let host = cpal::default_host();
-    let devices = host.input_devices()?;
-    println!("Available input devices:\n----------------------");
+// Headless capture for --stream-udp and --ipc-socket: frames go out at unit gain, leaving
+// gain to the viewer
+fn run_stream(pipeline: &Pipeline, send: &mut dyn FnMut(&[f32]), logs: &SpectrumLogs, options: &Options)
+    -> Result<RunOutcome> {
+    let fft_size = pipeline.fft_size;
+    let window = options.window.padded_coefficients(fft_size, options.zero_pad);
+    let frame_time = Duration::from_micros(1_000_000 / options.fps);
+    let mut watchdog = Watchdog::default();
+    pipeline.set_notch(options.notch.is_some());
 
-    let device_list: Vec<_> = devices.collect();
-    for (idx, device) in device_list.iter().enumerate() {
-        if let Ok(name) = device.name() {
-            if let Ok(config) = device.default_input_config() {
-                println!("{}. {} ({} Hz)", idx, name, config.sample_rate().0);
-            } else {
-                println!("{}. {}", idx, name);
+    let mut samples = vec![0.0; fft_size];
+    let mut fft_buffers = SpectrumBuffers::new(&*pipeline.fft);
+    let mut spectrum = vec![0.0; fft_size / 2];
+    let mut hops = HopCursor::new(fft_size / options.zero_pad, options.overlap, pipeline.input_buffer.capacity());
+
+    loop {
+        let frame_start = Instant::now();
+        if interrupted() || pipeline.health.has_ended() {
+            return Ok(RunOutcome::Quit);
+        }
+        if watchdog.should_restart(&pipeline.health, options) {
+            if let Some(err) = pipeline.health.last_error() {
+                eprintln!("Restarting after {}", err);
             }
+            return Ok(RunOutcome::Restart);
+        }
+
+        let main_buffer = pipeline.stereo_buffers.as_ref().map_or(&pipeline.input_buffer, |[left, _]| left);
+        let written = main_buffer.written();
+        for end in hops.due(written) {
+            main_buffer.window_ending(end, &mut samples);
+            compute_spectrum_into(&samples, &window, &*pipeline.fft, 1.0, &mut fft_buffers, &mut spectrum)?;
+            send(&spectrum);
+            logs.log(&spectrum, 1.0, options.window);
+        }
+
+        let elapsed = frame_start.elapsed();
+        if elapsed < frame_time {
+            thread::sleep(frame_time - elapsed);
         }
     }
-    Ok(device_list)
 }
 
-fn get_user_device_choice(max: usize) -> usize {
+// Headless features for --json-out: one object per analyzed frame on stdout, measured at
+// unit gain so levels read relative to full scale
+fn run_json(pipeline: &Pipeline, state: &mut ViewState, logs: &SpectrumLogs, options: &Options) -> Result<RunOutcome> {
+    // Which the state's levels take back out, whatever --gain was
+    state.gain = 1.0;
+    let setup = FftSetup::new(Arc::clone(&pipeline.fft), options.zero_pad);
+    let mut analysis = Analysis::new(setup, options.window, options.overlap, pipeline.input_buffer.capacity());
+    let frame_time = Duration::from_micros(1_000_000 / options.fps);
+    let mut watchdog = Watchdog::default();
+    pipeline.set_notch(options.notch.is_some());
+    let mut out = stdout().lock();
+
     loop {
-        println!("\nSelect device number (0-{}): ", max - 1);
-        let mut input = String::new();
-        stdin().read_line(&mut input).unwrap();
-        if let Ok(num) = input.trim().parse() {
-            if num < max {
-                return num;
+        let frame_start = Instant::now();
+        if interrupted() || pipeline.health.has_ended() {
+            return Ok(RunOutcome::Quit);
+        }
+        if watchdog.should_restart(&pipeline.health, options) {
+            if let Some(err) = pipeline.health.last_error() {
+                eprintln!("Restarting after {}", err);
             }
+            return Ok(RunOutcome::Restart);
+        }
+
+        let main_buffer = pipeline.stereo_buffers.as_ref().map_or(&pipeline.input_buffer, |[left, _]| left);
+        let written = main_buffer.written();
+        for end in analysis.captured_windows_due(written) {
+            main_buffer.window_ending(end, &mut analysis.samples);
+            analysis.compute(1.0, false, None)?;
+            logs.log(&analysis.spectrum, 1.0, options.window);
+            state.add_spectrum(&analysis.spectrum, pipeline.sample_rate);
+            match writeln!(out, "{}", frame_features(state)) {
+                Ok(()) => (),
+                // The reader going away, as `head` does, ends the run like Ctrl-C
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => return Ok(RunOutcome::Quit),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let elapsed = frame_start.elapsed();
+        if elapsed < frame_time {
+            thread::sleep(frame_time - elapsed);
         }
-        println!("Invalid selection, try again");
     }
 }
 
-fn main() -> Result<()> {
-    let device_list = list_devices()?;
-    if device_list.is_empty() {
-        println!("No input devices found!");
-        return Ok(());
-    }
+// Headless summary for --tape: a line per interval on stdout with no alternate screen, so it
+// can go to a log file or a status bar. Band energy is averaged over the interval at unit
+// gain, so the cells read against full scale whatever the display gain.
+fn run_tape(pipeline: &Pipeline, state: &mut ViewState, logs: &SpectrumLogs, options: &Options) -> Result<RunOutcome> {
+    // Which the state's levels take back out, whatever --gain was
+    state.gain = 1.0;
+    let setup = FftSetup::new(Arc::clone(&pipeline.fft), options.zero_pad);
+    let mut analysis = Analysis::new(setup, options.window, options.overlap, pipeline.input_buffer.capacity());
+    let frame_time = Duration::from_micros(1_000_000 / options.fps);
+    let mut watchdog = Watchdog::default();
+    pipeline.set_notch(options.notch.is_some());
+    let mut out = stdout().lock();
+    let bands = dsp::octave_bands(BandFraction::Octave, pipeline.sample_rate);
+    let noise_bandwidth = options.window.noise_bandwidth() * options.zero_pad as f32;
+    let interval = Duration::from_secs_f32(options.tape_interval);
+    let mut energies: Vec<Option<f32>> = vec![None; bands.len()];
+    let mut frames = 0;
+    let mut line_start = Instant::now();
 
-    let device_idx = get_user_device_choice(device_list.len());
-    let input_device = &device_list[device_idx];
-
-    let input_config = input_device.default_input_config()?;
-    let sample_rate = input_config.sample_rate().0;
-    println!("\nSelected device: {} @ {} Hz", input_device.name()?, sample_rate);
-    
-    // Select output device
-    let host = cpal::default_host();
-    let output_device = host.default_output_device()
-        .expect("No output device available");
-    let output_config = output_device.default_output_config()?;
-    
-    println!("Press Enter to start visualization...");
-    let mut input = String::new();
-    stdin().read_line(&mut input)?;
-
-    let mut planner = FftPlanner::new();
-    let fft = planner.plan_fft_forward(FFT_SIZE);
-
-    let (_, term_height) = size()?;
-    let history_size = (term_height - 15) as usize;
-
-    let mut state = ViewState::new(history_size);
-    
-    // Create shared buffers for input and output
-    let input_buffer = Arc::new(Mutex::new(AudioBuffer {
-        samples: vec![0.0; FFT_SIZE],
-        position: 0,
-    }));
-    let output_buffer = Arc::clone(&input_buffer);
-
-    // Input stream configuration
-    let input_buffer_clone = Arc::clone(&input_buffer);
-    let input_stream = input_device.build_input_stream(
-        &input_config.into(),
-        move |data: &[f32], _: &cpal::InputCallbackInfo| {
-            let mut buffer = input_buffer_clone.lock().unwrap();
-            for &sample in data {
-                let pos = buffer.position;
-                buffer.samples[pos] = sample;
-                buffer.position = (buffer.position + 1) % FFT_SIZE;
+    loop {
+        let frame_start = Instant::now();
+        if interrupted() || pipeline.health.has_ended() {
+            return Ok(RunOutcome::Quit);
+        }
+        if watchdog.should_restart(&pipeline.health, options) {
+            if let Some(err) = pipeline.health.last_error() {
+                eprintln!("Restarting after {}", err);
             }
-        },
-        |err| eprintln!("Error in input stream: {}", err),
-        None,
-    )?;
-
-    // Output stream configuration
-    let output_stream = output_device.build_output_stream(
-        &output_config.config(),
-        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            let mut buffer = output_buffer.lock().unwrap();
-            
-            // Copy samples to output buffer
-            for sample in data.iter_mut() {
-                let pos = buffer.position;
-                *sample = buffer.samples[pos];
-                buffer.position = (buffer.position + 1) % FFT_SIZE;
+            return Ok(RunOutcome::Restart);
+        }
+
+        let main_buffer = pipeline.stereo_buffers.as_ref().map_or(&pipeline.input_buffer, |[left, _]| left);
+        let written = main_buffer.written();
+        for end in analysis.captured_windows_due(written) {
+            main_buffer.window_ending(end, &mut analysis.samples);
+            analysis.compute(1.0, false, None)?;
+            logs.log(&analysis.spectrum, 1.0, options.window);
+            state.add_spectrum(&analysis.spectrum, pipeline.sample_rate);
+            let levels = dsp::band_levels(&analysis.spectrum, pipeline.sample_rate, &bands, noise_bandwidth);
+            for (energy, level) in energies.iter_mut().zip(levels) {
+                *energy = level.map(|level| energy.unwrap_or(0.0) + level * level);
             }
-        },
-        |err| eprintln!("Error in output stream: {}", err),
-        None,
-    )?;
+            frames += 1;
+        }
+
+        if line_start.elapsed() >= interval && frames > 0 {
+            line_start = Instant::now();
+            match write_tape_line(&mut out, state, &energies, frames) {
+                Ok(()) => (),
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => return Ok(RunOutcome::Quit),
+                Err(e) => return Err(e.into()),
+            }
+            energies.fill(None);
+            frames = 0;
+        }
+
+        let elapsed = frame_start.elapsed();
+        if elapsed < frame_time {
+            thread::sleep(frame_time - elapsed);
+        }
+    }
+}
 
-    // Start both streams
-    input_stream.play()?;
-    output_stream.play()?;
+// UTC time, one waterfall cell per octave band from the lowest up, then the peak, all read
+// against full scale. Bands too narrow for the FFT's bins stay blank.
+fn write_tape_line(out: &mut impl Write, state: &ViewState, energies: &[Option<f32>], frames: usize)
+    -> std::io::Result<()> {
+    write!(out, "{} ", utc_timestamp(SystemTime::now()))?;
+    for energy in energies {
+        let Some(energy) = energy else {
+            write!(out, " ")?;
+            continue;
+        };
+        let level = (energy / frames as f32).sqrt();
+        let level_db = state.untilted_full_scale_db(level).max(state.db_floor);
+        let cell = state.waterfall_cell((level_db - state.db_floor) / -state.db_floor);
+        if let Some(color) = cell.fg_color {
+            queue!(out, SetForegroundColor(color))?;
+        }
+        if let Some(color) = cell.bg_color {
+            queue!(out, SetBackgroundColor(color))?;
+        }
+        write!(out, "{}", cell.char)?;
+        if cell.fg_color.is_some() || cell.bg_color.is_some() {
+            queue!(out, ResetColor)?;
+        }
+    }
+    match state.find_peak() {
+        Some(peak) => {
+            let level_db = state.full_scale_db(peak.magnitude, peak.bin).max(state.db_floor) + state.cal_offset_db;
+            writeln!(out, " Peak: {:.1} Hz {:.1} {}", peak.freq, level_db, state.level_unit.label())?
+        }
+        None => writeln!(out, " Peak: --")?,
+    }
+    out.flush()
+}
 
+// Measurements that are unavailable, such as the peak of silence, come out as null
+fn frame_features(state: &ViewState) -> serde_json::Value {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |since| since.as_secs_f64());
+    let peak = state.find_peak();
+    serde_json::json!({
+        "version": JSON_SCHEMA_VERSION,
+        "timestamp": timestamp,
+        "peak_hz": peak.as_ref().map(|peak| peak.freq),
+        "peak_db": peak.as_ref().map(|peak| state.full_scale_db(peak.magnitude, peak.bin) + state.cal_offset_db),
+        "rms_db": state.full_scale_rms_db().map(|rms| rms + state.cal_offset_db),
+        "level_unit": state.level_unit.name(),
+        "snr_db": state.snr_db(),
+        "flatness": state.flatness,
+    })
+}
+
+// Remote display for --view-udp: received frames stand in for a local pipeline, and the
+// sender's header decides how bins map to frequencies
+fn run_viewer(mut options: Options, bind: &str) -> Result<()> {
+    install_interrupt_handler(options.duration)?;
+    let mut receiver = UdpReceiver::bind(bind)?;
+    println!("Waiting for spectrum frames on {}...", bind);
+    let first = loop {
+        if interrupted() {
+            return Ok(());
+        }
+        if let Some(frame) = receiver.latest()? {
+            break frame;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let sample_rate = first.sample_rate;
+    options.fft_size = first.fft_size;
+    println!("Receiving {} Hz, FFT size {}", sample_rate, first.fft_size);
+
+    let mut state = new_view_state(initial_history_rows(options.layout)?, &options, sample_rate, "remote");
     let mut renderer = Renderer::new()?;
+    let mut spectrum = vec![0.0; options.fft_size / 2];
 
-    let frame_time = Duration::from_micros(1_000_000 / TARGET_FPS);
     loop {
         let frame_start = Instant::now();
+        if interrupted() {
+            return Ok(());
+        }
 
         if event::poll(Duration::from_millis(0))? {
-            if let Event::Key(key) = event::read()? {
-                match key.code {
-                    KeyCode::Char('q') => break,
-                    KeyCode::Char('+') => state.gain *= 1.2,
-                    KeyCode::Char('-') => state.gain /= 1.2,
-                    KeyCode::Char('w') => state.freq_zoom *= 1.2,
-                    KeyCode::Char('s') => state.freq_zoom /= 1.2,
-                    _ => (),
-                }
+            match event::read()? {
+                Event::Key(key) => match action_for(&key) {
+                    Some(Action::Quit) => return Ok(()),
+                    Some(Action::SaveConfig) => save_config(&mut state, None),
+                    Some(action) => apply_view_action(action, &mut state, &renderer, sample_rate),
+                    None => (),
+                },
+                Event::Mouse(mouse) => apply_mouse(mouse, &mut state, &renderer, sample_rate),
+                Event::Resize(width, height) => resize(&mut state, &mut renderer, width, height)?,
+                _ => (),
             }
         }
 
-        let spectrum = {
-            let buffer = input_buffer.lock().unwrap();
-            let mut ordered_samples = vec![0.0; FFT_SIZE];
-            let pos = buffer.position;
-
-            for i in 0..FFT_SIZE {
-                let sample_pos = (pos + FFT_SIZE - i) % FFT_SIZE;
-                ordered_samples[FFT_SIZE - 1 - i] = buffer.samples[sample_pos];
+        let frame_time = state.frame_time();
+        // A sender restarted with another rate or size would land on the wrong bins
+        let dsp_start = Instant::now();
+        let frame = receiver.latest()?
+            .filter(|frame| frame.sample_rate == sample_rate && frame.fft_size == options.fft_size);
+        if let Some(frame) = frame.filter(|_| !state.paused) {
+            for (out, &magnitude) in spectrum.iter_mut().zip(&frame.spectrum) {
+                *out = magnitude * state.gain;
             }
+            state.add_spectrum(&spectrum, sample_rate);
+            state.update_agc(frame_time.as_secs_f32());
+        }
+        let dsp = dsp_start.elapsed();
+        let render_start = Instant::now();
+        renderer.render(&state, sample_rate)?;
+        state.diagnostics.record(FrameTiming {
+            start: frame_start,
+            dsp,
+            render: render_start.elapsed(),
+            busy: frame_start.elapsed(),
+        });
 
-            let mut fft_buffer: Vec<Complex<f32>> = ordered_samples.iter()
-                .enumerate()
-                .map(|(i, &sample)| {
-                    let window = 0.5 * (1.0 - (2.0 * std::f32::consts::PI * i as f32 / FFT_SIZE as f32).cos());
-                    Complex::new(sample * window * state.gain * BASE_GAIN, 0.0)
-                })
-                .collect();
+        let elapsed = frame_start.elapsed();
+        if elapsed < frame_time {
+            thread::sleep(frame_time - elapsed);
+        }
+    }
+}
 
-            fft.process(&mut fft_buffer);
-
-            fft_buffer.iter()
-                .take(FFT_SIZE/2)
-                .enumerate()
-                .map(|(i, x)| {
-                    if i == 0 { return 0.0; }
-                    let freq_scale = (1.0 + (i as f32 / 100.0)).log10();
-                    (x.norm_sqr() as f32).sqrt() * freq_scale
-                })
-                .collect()
-        };
+// Playback of a --record-session file: frames are shown once the replay clock passes their
+// timestamps, and a seek refills the waterfall with the frames leading up to the target
+fn run_replay(mut options: Options, path: &Path) -> Result<()> {
+    let mut session = Session::open(path)?;
+    let first = session.frame(0)?;
+    let sample_rate = first.sample_rate;
+    options.fft_size = first.fft_size;
+    let length = session.duration();
+    println!("Replaying {} frames over {:.1} s, {} Hz, FFT size {}",
+             session.len(), length.as_secs_f32(), sample_rate, first.fft_size);
+    install_interrupt_handler(options.duration)?;
 
-        state.add_spectrum(spectrum, sample_rate);
+    let mut state = new_view_state(initial_history_rows(options.layout)?, &options, sample_rate, "replay");
+    let mut renderer = Renderer::new()?;
+    let mut spectrum = vec![0.0; options.fft_size / 2];
+    let mut position = Duration::ZERO;
+    let mut next = 0;
+    let mut last_tick = Instant::now();
+
+    loop {
+        let frame_start = Instant::now();
+        if interrupted() {
+            return Ok(());
+        }
+
+        let mut seek = None;
+        if event::poll(Duration::from_millis(0))? {
+            match event::read()? {
+                Event::Key(key) => match action_for(&key) {
+                    Some(Action::Quit) => return Ok(()),
+                    Some(Action::SaveConfig) => save_config(&mut state, None),
+                    Some(Action::SeekBack) => seek = Some(position.saturating_sub(REPLAY_SEEK_STEP)),
+                    Some(Action::SeekForward) => seek = Some((position + REPLAY_SEEK_STEP).min(length)),
+                    Some(action) => apply_view_action(action, &mut state, &renderer, sample_rate),
+                    None => (),
+                },
+                Event::Mouse(mouse) => apply_mouse(mouse, &mut state, &renderer, sample_rate),
+                Event::Resize(width, height) => resize(&mut state, &mut renderer, width, height)?,
+                _ => (),
+            }
+        }
+
+        // The clock follows wall time, so a slow frame doesn't slow the replay down
+        let frame_time = state.frame_time();
+        let dsp_start = Instant::now();
+        let tick = last_tick.elapsed();
+        last_tick = Instant::now();
+        if let Some(target) = seek {
+            position = target;
+            state.clear_history();
+            next = session.index_after(position).saturating_sub(state.history_size);
+        } else if !state.paused {
+            position = (position + tick).min(length);
+        }
+        let due = session.index_after(position);
+        for index in next..due {
+            // Frames are written by a single pipeline, but a damaged file shouldn't misplace bins
+            let frame = session.frame(index)?;
+            if frame.sample_rate != sample_rate || frame.fft_size != options.fft_size {
+                continue;
+            }
+            for (out, &magnitude) in spectrum.iter_mut().zip(&frame.spectrum) {
+                *out = magnitude * state.gain;
+            }
+            state.add_spectrum(&spectrum, sample_rate);
+        }
+        if due > next {
+            state.update_agc(frame_time.as_secs_f32());
+        }
+        next = due;
+        state.replay = Some((position, length));
+        let dsp = dsp_start.elapsed();
+        let render_start = Instant::now();
         renderer.render(&state, sample_rate)?;
+        state.diagnostics.record(FrameTiming {
+            start: frame_start,
+            dsp,
+            render: render_start.elapsed(),
+            busy: frame_start.elapsed(),
+        });
 
         let elapsed = frame_start.elapsed();
         if elapsed < frame_time {
             thread::sleep(frame_time - elapsed);
         }
     }
+}
 
+// The requested band and --monitor tone have to fit below each input's Nyquist limit
+fn check_nyquist(options: &Options, sample_rate: u32) -> Result<()> {
+    if options.fmin >= sample_rate as f32 / 2.0 {
+        bail!("--fmin {} Hz is at or above the {} Hz Nyquist limit of the input", options.fmin, sample_rate / 2);
+    }
+    if let Some(fmax) = options.fmax.filter(|&fmax| fmax > sample_rate as f32 / 2.0) {
+        bail!("--fmax {} Hz is above the {} Hz Nyquist limit of the input", fmax, sample_rate / 2);
+    }
+    if let Some(freq) = options.monitor.filter(|&freq| freq >= sample_rate as f32 / 2.0) {
+        bail!("--monitor {} Hz is above the {} Hz Nyquist limit of the input", freq, sample_rate / 2);
+    }
     Ok(())
 }
+
+// Streams, a recorder and a view for each input after the first of a comma-separated
+// --device, numbered after it in the header. Each stays muted while off screen, so only the
+// input shown is heard; its output keeps draining the ring so switching to it plays live audio.
+fn open_standby_inputs(sources: Vec<(SampleSource, u32, u16)>, options: &Options) -> Result<Vec<StandbyInput>> {
+    let total = sources.len() + 1;
+    sources.into_iter().enumerate().map(|(i, (source, input_rate, channels))| {
+        let sample_rate = analysis_rate(input_rate, options.decimate)?;
+        let names = OutputNames::new(&options.output_prefix, &source.name(), sample_rate);
+        let kind = format!("capture-{}", i + 2);
+        let recorder = Recorder::new(None, names, &kind, input_rate, channels);
+        let pipeline = build_pipeline(&source, options, &recorder, None)?;
+        pipeline.set_muted(true);
+        let history_rows = initial_history_rows(options.layout)?;
+        let mut state = Box::new(new_view_state(history_rows, options, sample_rate, &source.name()));
+        state.input_label = Some(format!("Input {}/{}: {}", i + 2, total, source.name()));
+        Ok(StandbyInput { device_name: Some(source.name()), source, pipeline, recorder, state, sample_rate })
+    }).collect()
+}
+
+// A view of the input `device`, which names the files saved from it. The band up to --fmax
+// is a zoom and center that depend on the sample rate, so it is set here once that is known;
+// a remote or recorded rate could otherwise leave it past Nyquist.
+fn new_view_state(history_rows: usize, options: &Options, sample_rate: u32, device: &str) -> ViewState {
+    let names = OutputNames::new(&options.output_prefix, device, sample_rate);
+    let mut state = ViewState::new(history_rows, options, names);
+    if let Some(fmax) = options.fmax {
+        state.set_freq_range(options.fmin, fmax.min(sample_rate as f32 / 2.0), sample_rate);
+    }
+    state
+}
+
+fn initial_history_rows(mode: LayoutMode) -> Result<usize> {
+    let (term_width, term_height) = size()?;
+    let layout = Layout::new(term_width as usize, term_height as usize, 1, mode).ok_or_else(|| {
+        anyhow!("Terminal is {} rows tall; at least {} rows are needed", term_height, MIN_TERMINAL_ROWS)
+    })?;
+    // Starting on the spectrum alone still needs a line to commit frames to
+    Ok(layout.history_rows.max(1))
+}
+
+// Sleeps between rebuild attempts while still honoring 'q'
+fn wait_or_quit(duration: Duration) -> Result<bool> {
+    let deadline = Instant::now() + duration;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if interrupted() {
+            return Ok(true);
+        }
+        if event::poll(remaining.min(Duration::from_millis(100)))? {
+            if let Event::Key(key) = event::read()? {
+                if action_for(&key) == Some(Action::Quit) {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+// Headless: no device, no terminal, just the DSP path
+fn run_benchmark(options: &Options) -> Result<()> {
+    let result = dsp::benchmark(options.fft_size, options.window, options.iterations)?;
+    println!("FFT size {}, {} window: {} frames in {:.3} s, {:.0} frames/s, {:.2} us/frame",
+             options.fft_size, options.window.name(), result.frames, result.elapsed.as_secs_f64(),
+             result.frames_per_sec(), result.micros_per_frame());
+    Ok(())
+}
+
+// Prints the input devices for scripts to pick from, without prompting or touching the terminal
+fn run_list_devices(options: &Options) -> Result<()> {
+    let devices = describe_devices(&input_devices()?);
+    if options.json {
+        let list: Vec<_> = devices.iter().map(|info| info.to_json()).collect();
+        println!("{}", serde_json::to_string_pretty(&list)?);
+    } else {
+        for info in devices {
+            println!("{}", info);
+        }
+    }
+    Ok(())
+}
+
+// Errors are printed as their message chain, without anyhow's debug backtrace; by the time
+// `run` returns, the terminal has already been restored
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    install_panic_hook();
+    let mut options = Options::from_args()?;
+    if options.version {
+        println!("qmx-capture {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    if options.help {
+        print!("{}", help_text());
+        return Ok(());
+    }
+    if options.bench {
+        return run_benchmark(&options);
+    }
+    if options.list_devices {
+        return run_list_devices(&options);
+    }
+    if let Some(bind) = options.view_udp.clone() {
+        return run_viewer(options, &bind);
+    }
+    if let Some(path) = options.replay.clone() {
+        return run_replay(options, &path);
+    }
+
+    // With --json-out or --tape stdout carries only frames, so everything else goes to stderr
+    let headless = options.json_out || options.tape;
+    let status = |message: String| if headless { eprintln!("{}", message) } else { println!("{}", message) };
+    let mut source = match (&options.input_file, options.sample_rate) {
+        (Some(path), _) => SampleSource::File(path.clone()),
+        (None, sample_rate) if options.test_signal.is_some() => SampleSource::TestSignal {
+            signal: options.test_signal.clone().unwrap(),
+            sample_rate: sample_rate.unwrap_or(DEFAULT_TEST_SIGNAL_RATE),
+        },
+        (None, Some(sample_rate)) if options.stdin => {
+            SampleSource::Stdin { sample_rate, channels: options.stdin_channels }
+        }
+        _ if options.loopback => loopback_source(options.sample_rate)?,
+        _ if (headless || options.no_wait) && options.device.is_none() => {
+            let flag = if options.json_out { "--json-out" } else if options.tape { "--tape" } else { "--no-wait" };
+            bail!("{} has no prompt to pick a device from; pass --device, --input-file or --stdin", flag);
+        }
+        _ => {
+            let mut device_list = if headless { input_devices()? } else { list_devices()? };
+            if device_list.is_empty() {
+                status("No input devices found!".to_string());
+                return Ok(());
+            }
+
+            let device = match &options.device {
+                Some(selector) => select_device(device_list, selector, "input")?,
+                None => device_list.swap_remove(get_user_device_choice(device_list.len())?),
+            };
+            SampleSource::device(device, options.sample_rate)
+        }
+    };
+    source = source.with_buffer_size(options.buffer_size);
+
+    let (input_rate, channels) = source.format()?;
+    let mut sample_rate = analysis_rate(input_rate, options.decimate)?;
+    check_nyquist(&options, sample_rate)?;
+    // Saved with the settings so the next run opens the same device; a loopback source is
+    // found afresh each time instead
+    let mut device_name = (matches!(source, SampleSource::Device { .. }) && !options.loopback).then(|| source.name());
+    let kind = match source {
+        SampleSource::Device { .. } => "device",
+        SampleSource::File(_) => "file",
+        SampleSource::Stdin { .. } => "input",
+        SampleSource::Loopback(_) => "loopback",
+        SampleSource::TestSignal { .. } => "test signal",
+    };
+    status(format!("\nSelected {}: {} @ {} Hz", kind, source.name(), input_rate));
+    if options.decimate > 1 {
+        status(format!("Decimating by {} to {} Hz", options.decimate, sample_rate));
+    }
+    let mut extra_sources = Vec::new();
+    for selector in &options.extra_devices {
+        let extra = SampleSource::device(select_device(input_devices()?, selector, "input")?, options.sample_rate)
+            .with_buffer_size(options.buffer_size);
+        let (input_rate, channels) = extra.format()?;
+        check_nyquist(&options, analysis_rate(input_rate, options.decimate)?)?;
+        status(format!("Also capturing device: {} @ {} Hz", extra.name(), input_rate));
+        extra_sources.push((extra, input_rate, channels));
+    }
+    // Only the default is forgiven; an --output-device that isn't there is still an error
+    if options.output && options.output_device.is_none() && !has_default_output() {
+        status("Warning: no output device available, continuing without playback".to_string());
+        options.output = false;
+    }
+
+    let names = OutputNames::new(&options.output_prefix, &source.name(), sample_rate);
+    let mut recorder = Recorder::new(options.record.clone(), names.clone(), "capture", input_rate, channels);
+    if options.record.is_some() {
+        recorder.start()?;
+    }
+    // Paths given for the logs may carry the same tokens as --output-prefix
+    let expand = |path: &Option<PathBuf>| path.as_deref().map(|path| names.expand(path));
+    let logs = SpectrumLogs {
+        csv: expand(&options.csv)
+            .map(|path| {
+                CsvLogger::create(&path, sample_rate, options.fft_size / 2, options.fft_size, options.session_max)
+            })
+            .transpose()?,
+        events: expand(&options.event_log)
+            .map(|path| EventLog::create(&path, sample_rate, options.fft_size, options.zero_pad,
+                                         options.event_threshold, Duration::from_secs_f32(options.event_debounce)))
+            .transpose()?,
+        onsets: expand(&options.onset_log).map(|path| OnsetLog::create(&path, options.onset_threshold)).transpose()?,
+        session: expand(&options.record_session)
+            .map(|path| SessionWriter::create(&path, sample_rate, options.fft_size))
+            .transpose()?,
+        profile: expand(&options.profile).map(|path| ProfileLog::create(&path)).transpose()?,
+        skips: expand(&options.skip_silence_log).map(|path| SkipLog::create(&path).map(Arc::new)).transpose()?,
+    };
+
+    let mut frontend = match &options.stream_udp {
+        Some(addr) => {
+            let sender = UdpSender::connect(addr, sample_rate, options.fft_size)?;
+            println!("Streaming spectrum frames to {}", addr);
+            Frontend::Udp(sender)
+        }
+        None if options.ipc_socket.is_some() => {
+            let path = options.ipc_socket.as_deref().unwrap();
+            let sender = IpcSender::bind(path, sample_rate, options.fft_size)?;
+            println!("Serving spectrum frames on {}", path.display());
+            Frontend::Ipc(sender)
+        }
+        None if options.json_out => Frontend::Json(Box::new(new_view_state(1, &options, sample_rate, &source.name()))),
+        None if options.tape => Frontend::Tape(Box::new(new_view_state(1, &options, sample_rate, &source.name()))),
+        None => {
+            // Before the prompt, which nobody would see with stdout redirected
+            check_terminal()?;
+            // Stdin carries samples, so there is no prompt to wait on
+            if !options.stdin && !options.no_wait {
+                println!("Press Enter to start visualization...");
+                let mut input = String::new();
+                stdin().read_line(&mut input)?;
+            }
+            let history_rows = initial_history_rows(options.layout)?;
+            let mut state = Box::new(new_view_state(history_rows, &options, sample_rate, &source.name()));
+            if !extra_sources.is_empty() {
+                state.input_label = Some(format!("Input 1/{}: {}", extra_sources.len() + 1, source.name()));
+            }
+            Frontend::Terminal { state, renderer: Renderer::new()? }
+        }
+    };
+
+    // Only now, so Ctrl-C still aborts the prompts above the usual way
+    install_interrupt_handler(options.duration)?;
+    let mut pipeline = build_pipeline(&source, &options, &recorder, logs.skips.as_ref())?;
+    let mut standby = open_standby_inputs(extra_sources, &options)?;
+
+    // Supervisor: rebuild streams, buffers and planner whenever the watchdog gives up on them.
+    // An error ends it without skipping the shutdown below, which reports it once done.
+    let outcome = (|| -> Result<()> {
+        loop {
+            let outcome = match &mut frontend {
+                Frontend::Terminal { state, renderer } => run_pipeline(&pipeline, state, renderer, &mut recorder, &logs,
+                                                                       device_name.as_deref(), &options)?,
+                Frontend::Udp(sender) => {
+                    run_stream(&pipeline, &mut |spectrum| sender.send(spectrum), &logs, &options)?
+                }
+                Frontend::Ipc(sender) => {
+                    run_stream(&pipeline, &mut |spectrum| sender.send(spectrum), &logs, &options)?
+                }
+                Frontend::Json(state) => run_json(&pipeline, state, &logs, &options)?,
+                Frontend::Tape(state) => run_tape(&pipeline, state, &logs, &options)?,
+            };
+            if let RunOutcome::Quit = outcome {
+                return Ok(());
+            }
+            if let (RunOutcome::NextInput, Frontend::Terminal { state, renderer }) = (&outcome, &mut frontend) {
+                // The others rotate so Tab goes through them in order and comes back to the first
+                let next = &mut standby[0];
+                std::mem::swap(&mut source, &mut next.source);
+                std::mem::swap(&mut pipeline, &mut next.pipeline);
+                std::mem::swap(&mut recorder, &mut next.recorder);
+                std::mem::swap(state, &mut next.state);
+                std::mem::swap(&mut device_name, &mut next.device_name);
+                std::mem::swap(&mut sample_rate, &mut next.sample_rate);
+                // The view's own mute setting goes back on when it is shown again
+                next.pipeline.set_muted(true);
+                standby.rotate_left(1);
+                // The terminal may have been resized while this input was off screen
+                let (width, height) = size()?;
+                fit_history(state, width as usize, height as usize);
+                renderer.invalidate()?;
+                continue;
+            }
+
+            let sample_rate = pipeline.sample_rate;
+            let disconnected = pipeline.health.is_disconnected();
+            pipeline.shutdown();
+            recorder.flush();
+            if let Frontend::Terminal { state, renderer } = &mut frontend {
+                let waiting = if disconnected { "Device disconnected" } else { "Input lost" };
+                state.banner = Some(format!("{} - reconnecting, press q to quit", waiting));
+                renderer.render(state, sample_rate)?;
+            }
+            pipeline = loop {
+                let rebuilt = source.reopen().and_then(|reopened| {
+                    let pipeline = build_pipeline(&reopened, &options, &recorder, logs.skips.as_ref())?;
+                    source = reopened;
+                    Ok(pipeline)
+                });
+                match rebuilt {
+                    Ok(pipeline) => break pipeline,
+                    // Headless modes have no keyboard to quit from, so they just keep retrying
+                    Err(_) if interrupted() => return Ok(()),
+                    Err(_) if !matches!(frontend, Frontend::Terminal { .. }) => thread::sleep(options.restart_after),
+                    Err(_) if !wait_or_quit(options.restart_after)? => continue,
+                    Err(_) => return Ok(()),
+                }
+            };
+            if let Frontend::Terminal { state, renderer } = &mut frontend {
+                state.restarts += 1;
+                renderer.invalidate()?;
+            }
+        }
+    })();
+
+    // Shutdown runs in a fixed order: the streams stop, then the other inputs, the recordings
+    // and logs they fed are finished and their writer threads joined, and the terminal is
+    // restored last, once nothing can draw or fail into it. Every step runs whatever failed
+    // before it, and the first error is the one returned.
+    let mut first_error = outcome.err();
+    let mut keep = |result: Result<()>| {
+        if let Err(e) = result {
+            first_error.get_or_insert(e);
+        }
+    };
+    pipeline.shutdown();
+    for mut input in standby {
+        input.pipeline.shutdown();
+        keep(input.recorder.stop());
+    }
+    keep(recorder.stop());
+    keep(logs.finish());
+    if let Frontend::Terminal { state, renderer } = frontend {
+        drop(renderer);
+        // The history as it stood at exit
+        if let Some(path) = &options.npy_out {
+            let path = state.output_names.expand(path);
+            match export_npy(&state, sample_rate, Some(&path)) {
+                Ok(path) => println!("Saved {}", path.display()),
+                Err(e) => keep(Err(e)),
+            }
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qmx_capture::dsp::{compute_spectrum, plan_fft};
+
+    #[test]
+    fn json_levels_read_a_sine_against_full_scale_at_any_frequency() {
+        let options = Options::defaults();
+        let sample_rate = 48000;
+        let fft = plan_fft(options.fft_size);
+        let window = options.window.coefficients(options.fft_size);
+        for freq in [200.0, 1000.0, 10000.0] {
+            let mut state = new_view_state(20, &options, sample_rate, "test");
+            // Analyzed at unit gain and unaveraged, as in run_json
+            state.gain = 1.0;
+            state.average_alpha = 1.0;
+            // Half of full scale, -6 dBFS
+            let samples: Vec<f32> = (0..options.fft_size)
+                .map(|i| 0.5 * (2.0 * std::f32::consts::PI * freq * i as f32 / sample_rate as f32).sin())
+                .collect();
+            state.add_spectrum(&compute_spectrum(&samples, &window, &*fft, 1.0).unwrap(), sample_rate);
+
+            let features = frame_features(&state);
+            let peak_db = features["peak_db"].as_f64().unwrap();
+            let rms_db = features["rms_db"].as_f64().unwrap();
+            // The peak bin loses up to 1.4 dB to the Hann window when the tone falls between bins
+            assert!((-7.5..=-5.9).contains(&peak_db), "peak {} dB at {} Hz", peak_db, freq);
+            assert!((rms_db + 6.02).abs() < 0.1, "RMS {} dB at {} Hz", rms_db, freq);
+        }
+    }
+}
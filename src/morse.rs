@@ -0,0 +1,167 @@
+// Morse decoding of the --monitor tone: its level thresholded into key down and up, the
+// marks and gaps timed against an estimated dit length, and the symbols mapped to text
+
+// Keying speed assumed until the first dahs and dits are timed, and the range it may adapt to
+const DEFAULT_WPM: f64 = 20.0;
+const MIN_WPM: f64 = 5.0;
+const MAX_WPM: f64 = 60.0;
+// PARIS timing: a dit lasts 1.2 / WPM seconds
+const DIT_SECS_PER_WPM: f64 = 1.2;
+// How quickly the tracked tone and noise levels relax toward each other
+const ENVELOPE_RELAX_DB_PER_S: f32 = 6.0;
+// Tone and noise closer than this are no keying at all, just noise
+const MIN_KEYING_SPAN_DB: f32 = 10.0;
+// Gap between the key down and key up levels, so noise on the threshold doesn't chatter
+const HYSTERESIS_DB: f32 = 3.0;
+// Weight of each new mark in the running dit length
+const DIT_SMOOTHING: f64 = 0.3;
+// Decoded characters kept for the strip
+const MAX_TEXT_CHARS: usize = 512;
+
+const CODE: &[(&str, char)] = &[
+    (".-", 'A'), ("-...", 'B'), ("-.-.", 'C'), ("-..", 'D'), (".", 'E'), ("..-.", 'F'), ("--.", 'G'),
+    ("....", 'H'), ("..", 'I'), (".---", 'J'), ("-.-", 'K'), (".-..", 'L'), ("--", 'M'), ("-.", 'N'),
+    ("---", 'O'), (".--.", 'P'), ("--.-", 'Q'), (".-.", 'R'), ("...", 'S'), ("-", 'T'), ("..-", 'U'),
+    ("...-", 'V'), (".--", 'W'), ("-..-", 'X'), ("-.--", 'Y'), ("--..", 'Z'),
+    ("-----", '0'), (".----", '1'), ("..---", '2'), ("...--", '3'), ("....-", '4'), (".....", '5'),
+    ("-....", '6'), ("--...", '7'), ("---..", '8'), ("----.", '9'),
+    (".-.-.-", '.'), ("--..--", ','), ("..--..", '?'), ("-..-.", '/'), ("-...-", '='), (".-.-.", '+'),
+];
+
+#[derive(Clone)]
+pub struct CwDecoder {
+    // A fixed level to key on in place of the midpoint of the tracked levels
+    fixed_threshold_db: Option<f32>,
+    tone_db: f32,
+    noise_db: f32,
+    key_down: bool,
+    // Audio time in seconds of the last level, and of the last change of key
+    last_time: Option<f64>,
+    since: f64,
+    dit_secs: f64,
+    symbol: String,
+    word_ended: bool,
+    text: String,
+}
+
+impl CwDecoder {
+    pub fn new(fixed_threshold_db: Option<f32>) -> Self {
+        Self {
+            fixed_threshold_db,
+            tone_db: f32::NEG_INFINITY,
+            noise_db: f32::INFINITY,
+            key_down: false,
+            last_time: None,
+            since: 0.0,
+            dit_secs: DIT_SECS_PER_WPM / DEFAULT_WPM,
+            symbol: String::new(),
+            word_ended: true,
+            text: String::new(),
+        }
+    }
+
+    pub fn wpm(&self) -> f64 {
+        DIT_SECS_PER_WPM / self.dit_secs
+    }
+
+    pub fn key_down(&self) -> bool {
+        self.key_down
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    // The level keyed on, or None while tone and noise are too close to call
+    pub fn threshold_db(&self) -> Option<f32> {
+        if let Some(threshold) = self.fixed_threshold_db {
+            return Some(threshold);
+        }
+        (self.tone_db - self.noise_db >= MIN_KEYING_SPAN_DB).then(|| (self.tone_db + self.noise_db) / 2.0)
+    }
+
+    // One monitor level, in dB, for the analysis window ending `time` seconds into the audio
+    pub fn update(&mut self, level_db: f32, time: f64) {
+        let dt = self.last_time.map_or(0.0, |last| (time - last).max(0.0));
+        self.last_time = Some(time);
+        let relax = ENVELOPE_RELAX_DB_PER_S * dt as f32;
+        self.tone_db = level_db.max(self.tone_db - relax);
+        self.noise_db = level_db.min(self.noise_db + relax);
+
+        let down = match self.threshold_db() {
+            Some(threshold) if self.key_down => level_db > threshold - HYSTERESIS_DB / 2.0,
+            Some(threshold) => level_db > threshold + HYSTERESIS_DB / 2.0,
+            None => false,
+        };
+        let held = time - self.since;
+        if down != self.key_down {
+            if self.key_down {
+                self.end_mark(held);
+            }
+            self.key_down = down;
+            self.since = time;
+        } else if !down {
+            // Gaps are only known to be letter or word spaces once they have lasted long enough
+            if held > 2.0 * self.dit_secs && !self.symbol.is_empty() {
+                self.end_letter();
+            }
+            if held > 5.0 * self.dit_secs && !self.word_ended {
+                self.push(' ');
+                self.word_ended = true;
+            }
+        }
+    }
+
+    // A dit is one unit and a dah three, so anything past two units is a dah
+    fn end_mark(&mut self, secs: f64) {
+        let (mark, units) = if secs > 2.0 * self.dit_secs { ('-', 3.0) } else { ('.', 1.0) };
+        self.symbol.push(mark);
+        let dit = self.dit_secs + DIT_SMOOTHING * (secs / units - self.dit_secs);
+        self.dit_secs = dit.clamp(DIT_SECS_PER_WPM / MAX_WPM, DIT_SECS_PER_WPM / MIN_WPM);
+    }
+
+    fn end_letter(&mut self) {
+        let letter = CODE.iter().find(|(code, _)| *code == self.symbol).map_or('*', |&(_, letter)| letter);
+        self.symbol.clear();
+        self.push(letter);
+        self.word_ended = false;
+    }
+
+    fn push(&mut self, c: char) {
+        self.text.push(c);
+        if self.text.len() > MAX_TEXT_CHARS {
+            self.text.drain(..self.text.len() - MAX_TEXT_CHARS);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_keyed_text_and_learns_its_speed() {
+        // 25 WPM keying, a 48 ms dit, read every 4 ms with the tone at -20 dB over noise at -70 dB
+        let mut decoder = CwDecoder::new(None);
+        let mut step = 0;
+        let mut key = |decoder: &mut CwDecoder, down: bool, units: u64| {
+            for _ in 0..units * 12 {
+                step += 1;
+                decoder.update(if down { -20.0 } else { -70.0 }, step as f64 * 0.004);
+            }
+        };
+        key(&mut decoder, false, 10);
+        for word in ["... --- ...", "-.-. --.-"] {
+            for letter in word.split(' ') {
+                for mark in letter.chars() {
+                    key(&mut decoder, true, if mark == '-' { 3 } else { 1 });
+                    key(&mut decoder, false, 1);
+                }
+                key(&mut decoder, false, 2);
+            }
+            key(&mut decoder, false, 4);
+        }
+        assert_eq!(decoder.text(), "SOS CQ ");
+        assert!((decoder.wpm() - 25.0).abs() < 2.0, "{} WPM", decoder.wpm());
+    }
+}
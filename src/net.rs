@@ -0,0 +1,227 @@
+// Spectrum frames over UDP or a Unix socket: a small header carrying the sample rate and
+// FFT size, then the magnitudes as little-endian f32
+
+use anyhow::{anyhow, bail, Result};
+use std::{
+    io::ErrorKind,
+    net::{ToSocketAddrs, UdpSocket},
+    path::Path,
+};
+#[cfg(unix)]
+use std::{
+    io::Write,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    time::Duration,
+};
+
+const FRAME_MAGIC: &[u8; 4] = b"QMXS";
+// Magic, sample rate, FFT size and bin count
+const FRAME_HEADER_LEN: usize = 16;
+// Largest payload a single IPv4 UDP datagram can carry
+const MAX_DATAGRAM_LEN: usize = 65507;
+pub const MAX_FRAME_BINS: usize = (MAX_DATAGRAM_LEN - FRAME_HEADER_LEN) / 4;
+// How long a socket client may hold up a frame before it is dropped
+#[cfg(unix)]
+const IPC_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct Frame {
+    pub sample_rate: u32,
+    pub fft_size: usize,
+    pub spectrum: Vec<f32>,
+}
+
+pub fn encode_frame(sample_rate: u32, fft_size: usize, spectrum: &[f32], out: &mut Vec<u8>) {
+    out.clear();
+    out.extend_from_slice(FRAME_MAGIC);
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(fft_size as u32).to_le_bytes());
+    out.extend_from_slice(&(spectrum.len() as u32).to_le_bytes());
+    for &magnitude in spectrum {
+        out.extend_from_slice(&magnitude.to_le_bytes());
+    }
+}
+
+pub fn decode_frame(bytes: &[u8]) -> Result<Frame> {
+    if bytes.len() < FRAME_HEADER_LEN || &bytes[..4] != FRAME_MAGIC {
+        bail!("Not a spectrum frame");
+    }
+    let word = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    let (sample_rate, fft_size, bins) = (word(4), word(8) as usize, word(12) as usize);
+    if bytes.len() != FRAME_HEADER_LEN + bins * 4 || bins != fft_size / 2 {
+        bail!("Truncated or inconsistent spectrum frame");
+    }
+    let spectrum = bytes[FRAME_HEADER_LEN..].chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect();
+    Ok(Frame { sample_rate, fft_size, spectrum })
+}
+
+// Fire-and-forget: a dropped datagram is just a missing waterfall line
+pub struct UdpSender {
+    socket: UdpSocket,
+    sample_rate: u32,
+    fft_size: usize,
+    buffer: Vec<u8>,
+}
+
+impl UdpSender {
+    pub fn connect(addr: &str, sample_rate: u32, fft_size: usize) -> Result<Self> {
+        if fft_size / 2 > MAX_FRAME_BINS {
+            bail!("FFT size {} does not fit in a UDP datagram; use at most {}",
+                  fft_size, (MAX_FRAME_BINS * 2).next_power_of_two() / 2);
+        }
+        let target = addr.to_socket_addrs()?.next().ok_or_else(|| anyhow!("No address for {}", addr))?;
+        let bind = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(target)?;
+        Ok(Self { socket, sample_rate, fft_size, buffer: Vec::new() })
+    }
+
+    pub fn send(&mut self, spectrum: &[f32]) {
+        encode_frame(self.sample_rate, self.fft_size, spectrum, &mut self.buffer);
+        // Nobody listening yet is fine; the viewer can come and go
+        let _ = self.socket.send(&self.buffer);
+    }
+}
+
+// Reliable, ordered frames for a frontend on the same machine. Any number of clients may
+// connect, each getting every frame from then on; the header gives each frame's length, so
+// the stream needs no framing of its own. A client that goes away or stops reading is
+// dropped, and the socket keeps accepting new ones.
+#[cfg(unix)]
+pub struct IpcSender {
+    listener: UnixListener,
+    path: PathBuf,
+    clients: Vec<UnixStream>,
+    sample_rate: u32,
+    fft_size: usize,
+    buffer: Vec<u8>,
+}
+
+#[cfg(unix)]
+impl IpcSender {
+    pub fn bind(path: &Path, sample_rate: u32, fft_size: usize) -> Result<Self> {
+        // A socket left behind by a run that didn't get to clean up would block the bind
+        if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path).map_err(|e| anyhow!("Failed to bind {}: {}", path.display(), e))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener, path: path.to_path_buf(), clients: Vec::new(), sample_rate, fft_size, buffer: Vec::new() })
+    }
+
+    pub fn send(&mut self, spectrum: &[f32]) {
+        while let Ok((client, _)) = self.listener.accept() {
+            let ready = client.set_nonblocking(false).and_then(|_| client.set_write_timeout(Some(IPC_WRITE_TIMEOUT)));
+            if ready.is_ok() {
+                self.clients.push(client);
+            }
+        }
+        if self.clients.is_empty() {
+            return;
+        }
+        encode_frame(self.sample_rate, self.fft_size, spectrum, &mut self.buffer);
+        // A write cut short leaves the client mid-frame, so any error ends it
+        self.clients.retain_mut(|client| client.write_all(&self.buffer).is_ok());
+    }
+}
+
+#[cfg(unix)]
+impl Drop for IpcSender {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(not(unix))]
+pub struct IpcSender;
+
+#[cfg(not(unix))]
+impl IpcSender {
+    pub fn bind(_path: &Path, _sample_rate: u32, _fft_size: usize) -> Result<Self> {
+        bail!("--ipc-socket needs Unix domain sockets, which this platform lacks");
+    }
+
+    pub fn send(&mut self, _spectrum: &[f32]) {}
+}
+
+pub struct UdpReceiver {
+    socket: UdpSocket,
+    buffer: Vec<u8>,
+}
+
+impl UdpReceiver {
+    pub fn bind(addr: &str) -> Result<Self> {
+        let socket = UdpSocket::bind(addr).map_err(|e| anyhow!("Failed to bind {}: {}", addr, e))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, buffer: vec![0; MAX_DATAGRAM_LEN] })
+    }
+
+    // Drains everything queued and keeps only the newest valid frame, so a slow
+    // viewer skips lines instead of falling behind
+    pub fn latest(&mut self) -> Result<Option<Frame>> {
+        let mut latest = None;
+        loop {
+            match self.socket.recv(&mut self.buffer) {
+                Ok(len) => {
+                    if let Ok(frame) = decode_frame(&self.buffer[..len]) {
+                        latest = Some(frame);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(latest),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip_and_reject_garbage() {
+        let spectrum: Vec<f32> = (0..512).map(|i| i as f32 * 0.25).collect();
+        let mut bytes = Vec::new();
+        encode_frame(48000, 1024, &spectrum, &mut bytes);
+
+        let frame = decode_frame(&bytes).unwrap();
+        assert_eq!(frame.sample_rate, 48000);
+        assert_eq!(frame.fft_size, 1024);
+        assert_eq!(frame.spectrum, spectrum);
+
+        assert!(decode_frame(&bytes[..bytes.len() - 4]).is_err());
+        assert!(decode_frame(b"not a frame at all").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_clients_get_whole_frames_and_can_come_and_go() {
+        use std::io::Read;
+        let path = std::env::temp_dir().join(format!("qmx-ipc-test-{}.sock", std::process::id()));
+        let mut sender = IpcSender::bind(&path, 48000, 8).unwrap();
+        let read_frame = |client: &mut UnixStream| {
+            let mut bytes = vec![0; FRAME_HEADER_LEN + 4 * 4];
+            client.read_exact(&mut bytes).unwrap();
+            decode_frame(&bytes).unwrap().spectrum
+        };
+
+        let mut first = UnixStream::connect(&path).unwrap();
+        sender.send(&[1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(read_frame(&mut first), [1.0, 2.0, 3.0, 4.0]);
+
+        drop(first);
+        sender.send(&[0.0; 4]);
+        let mut second = UnixStream::connect(&path).unwrap();
+        sender.send(&[5.0, 6.0, 7.0, 8.0]);
+        assert_eq!(read_frame(&mut second), [5.0, 6.0, 7.0, 8.0]);
+        assert_eq!(sender.clients.len(), 1);
+
+        drop(sender);
+        assert!(!path.exists());
+    }
+}
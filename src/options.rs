@@ -0,0 +1,1076 @@
+// Command-line options
+
+use crate::audio::{ChannelLayout, ChannelSelect, DeviceSelector, StereoMode, DEFAULT_TEST_SIGNAL_RATE};
+use crate::config;
+use crate::export::DEFAULT_OUTPUT_PREFIX;
+use crate::dsp::{BandFraction, TestSignal, Weighting, Window};
+use crate::view::{
+    Band, ClassicThresholds, ColorMode, Colormap, Compander, DEFAULT_CLASSIC_THRESHOLDS, FreqAxis, LayoutMode, LevelRange,
+    LevelUnit, Quantity, Scale,
+    Scroll, TimeCompression, TraceColor, TraceStyle, WaterfallGlyph, DEFAULT_GAIN_DB, DEFAULT_STEP, MAX_FPS, MAX_GAIN_DB, MAX_OUTPUT_GAIN_DB, MAX_WATERFALL_GAMMA,
+    MAX_WATERFALL_SCALE, MIN_AVERAGE_ALPHA, MIN_FPS, MIN_GAIN_DB, MIN_OUTPUT_GAIN_DB, MIN_WATERFALL_GAMMA,
+    MIN_WATERFALL_SCALE, MAX_TIME_COMPRESS, DEFAULT_ONSET_THRESHOLD, DEFAULT_PHASE_GATE_DB, MAX_WATERFALL_SMOOTH,
+    DEFAULT_TRACK_TOLERANCE_HZ, MAX_TRACKED_PEAKS, DEFAULT_GAIN_STEP_DB,
+};
+use anyhow::{anyhow, bail, Result};
+use std::{fmt, path::{Path, PathBuf}, str::FromStr, time::Duration};
+
+const DEFAULT_FFT_SIZE: usize = 2048;
+pub const MIN_FFT_SIZE: usize = 64;
+pub const MAX_FFT_SIZE: usize = 65536;
+const DEFAULT_FPS: u64 = 30;
+const DEFAULT_DB_FLOOR: f32 = -90.0;
+const DEFAULT_AGC_ATTACK_SECS: f32 = 0.1;
+const DEFAULT_AGC_RELEASE_SECS: f32 = 2.0;
+const DEFAULT_AGC_FLOOR_DB: f32 = -80.0;
+// Shares of a rise and a fall the --envelope follower takes per line: instant up, slow down
+const DEFAULT_ENVELOPE_ATTACK: f32 = 1.0;
+const DEFAULT_ENVELOPE_RELEASE: f32 = 0.1;
+// Share of the way to the peak --follow-peak moves the zoomed band each frame
+const DEFAULT_FOLLOW_SMOOTHING: f32 = 0.2;
+const DEFAULT_PEAK_THRESHOLD_DB: f32 = -60.0;
+// Peak level that starts an --event-log entry, and how long it must stay below it to end one
+const DEFAULT_EVENT_THRESHOLD_DB: f32 = -40.0;
+const DEFAULT_EVENT_DEBOUNCE_SECS: f32 = 2.0;
+const DEFAULT_RESTART_AFTER_SECS: f32 = 10.0;
+const DEFAULT_STALL_TIMEOUT_SECS: f32 = 2.0;
+const DEFAULT_MAX_STREAM_ERRORS: usize = 1;
+const DEFAULT_BENCH_ITERATIONS: usize = 10_000;
+const DEFAULT_TONE_VOLUME: f32 = 0.2;
+const MAX_DECIMATE: usize = 16;
+const MAX_ZERO_PAD: usize = 16;
+// Context kept around each trigger crossing, in waterfall lines
+const DEFAULT_TRIGGER_PRE_LINES: usize = 5;
+const DEFAULT_TRIGGER_POST_LINES: usize = 10;
+// Beyond this the hop shrinks to a handful of samples and the waterfall races
+const MAX_OVERLAP: f32 = 0.95;
+
+pub struct Options {
+    pub device: Option<DeviceSelector>,
+    // Further inputs from a comma-separated --device, captured alongside the first
+    pub extra_devices: Vec<DeviceSelector>,
+    pub fft_size: usize,
+    pub gain: f32,
+    pub zoom: f32,
+    pub fmin: f32,
+    pub fmax: Option<f32>,
+    pub window: Window,
+    pub scale: Scale,
+    pub quantity: Quantity,
+    pub weighting: Weighting,
+    pub db_floor: f32,
+    pub freq_axis: FreqAxis,
+    pub scroll: Scroll,
+    pub layout: LayoutMode,
+    pub colormap: Option<Colormap>,
+    pub invert: bool,
+    pub waterfall_smooth: usize,
+    pub classic_thresholds: ClassicThresholds,
+    pub color_mode: Option<ColorMode>,
+    pub ascii: bool,
+    pub waterfall_glyph: WaterfallGlyph,
+    pub bars: bool,
+    pub braille: bool,
+    pub trace_color: Option<TraceColor>,
+    pub trace_style: TraceStyle,
+    pub level_range: Option<LevelRange>,
+    pub session_max: bool,
+    pub average: f32,
+    pub overlap: Option<f32>,
+    // Frames per input callback, or None for the driver's choice
+    pub buffer_size: Option<u32>,
+    pub waterfall_scale: f32,
+    pub waterfall_gamma: f32,
+    pub auto_contrast: bool,
+    pub auto_range: bool,
+    pub agc_attack: f32,
+    pub agc_release: f32,
+    pub agc_floor: f32,
+    pub envelope: bool,
+    pub follow_peak: bool,
+    pub follow_smoothing: f32,
+    pub phase_panel: bool,
+    pub phase_gate: f32,
+    pub envelope_attack: f32,
+    pub envelope_release: f32,
+    pub peak_decay: f32,
+    pub peak_threshold: f32,
+    pub track_peaks: Option<usize>,
+    pub track_tolerance: f32,
+    pub record: Option<PathBuf>,
+    pub input_file: Option<PathBuf>,
+    pub stdin: bool,
+    pub loopback: bool,
+    pub test_signal: Option<TestSignal>,
+    pub sample_rate: Option<u32>,
+    pub stdin_channels: u16,
+    pub skip_silence: Option<f32>,
+    pub skip_silence_min: f32,
+    pub skip_silence_log: Option<PathBuf>,
+    pub channel: ChannelSelect,
+    pub auto_restart: bool,
+    pub restart_after: Duration,
+    pub stall_timeout: Duration,
+    pub max_stream_errors: usize,
+    pub fps: u64,
+    pub waterfall_fps: Option<u64>,
+    pub csv: Option<PathBuf>,
+    pub event_log: Option<PathBuf>,
+    pub event_threshold: f32,
+    pub event_debounce: f32,
+    pub onsets: bool,
+    pub onset_threshold: f32,
+    pub onset_log: Option<PathBuf>,
+    pub npy_out: Option<PathBuf>,
+    pub output_prefix: String,
+    pub profile: Option<PathBuf>,
+    pub bench: bool,
+    pub list_devices: bool,
+    pub json: bool,
+    pub json_out: bool,
+    pub tape: bool,
+    pub tape_interval: f32,
+    pub no_wait: bool,
+    pub iterations: usize,
+    pub output: bool,
+    pub output_device: Option<DeviceSelector>,
+    pub hpf: Option<f32>,
+    pub notch: Option<f32>,
+    pub decimate: usize,
+    // FFT length over captured samples; --fft-size counts the captured ones
+    pub zero_pad: usize,
+    pub time_compress: usize,
+    pub time_compress_mode: TimeCompression,
+    pub channel_layout: ChannelLayout,
+    pub stereo_mode: StereoMode,
+    pub monitor: Option<f32>,
+    pub cw: bool,
+    pub cw_threshold: Option<f32>,
+    pub tuner: bool,
+    pub thd: bool,
+    pub thd_fundamental: Option<f32>,
+    pub delay: bool,
+    pub stream_udp: Option<String>,
+    pub ipc_socket: Option<PathBuf>,
+    pub view_udp: Option<String>,
+    pub record_session: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    pub tone_volume: f32,
+    pub step: f32,
+    pub gain_step: f32,
+    pub output_gain: f32,
+    pub output_delay: f32,
+    pub balance: f32,
+    pub persistence: Option<f32>,
+    pub compand: Option<Compander>,
+    pub bandwidth: Option<f32>,
+    pub gate: Option<f32>,
+    pub bands: Vec<Band>,
+    pub band_analysis: Option<BandFraction>,
+    pub reference_curve: Vec<(f32, f32)>,
+    pub cal_offset: Option<f32>,
+    pub cal_unit: LevelUnit,
+    pub history: Option<usize>,
+    pub trigger: Option<f32>,
+    pub trigger_pre: usize,
+    pub trigger_post: usize,
+    pub duration: Option<Duration>,
+    pub help: bool,
+    pub version: bool,
+}
+
+impl Options {
+    // The built-in defaults, before the config file and the flags
+    pub fn defaults() -> Self {
+        Self {
+            device: None,
+            extra_devices: Vec::new(),
+            fft_size: DEFAULT_FFT_SIZE,
+            gain: DEFAULT_GAIN_DB,
+            zoom: 1.0,
+            fmin: 0.0,
+            fmax: None,
+            window: Window::Hann,
+            scale: Scale::Linear,
+            quantity: Quantity::Magnitude,
+            weighting: Weighting::None,
+            db_floor: DEFAULT_DB_FLOOR,
+            freq_axis: FreqAxis::Linear,
+            scroll: Scroll::Up,
+            layout: LayoutMode::Both,
+            colormap: None,
+            invert: false,
+            waterfall_smooth: 1,
+            classic_thresholds: DEFAULT_CLASSIC_THRESHOLDS,
+            color_mode: None,
+            ascii: false,
+            waterfall_glyph: WaterfallGlyph::Char('█'),
+            bars: false,
+            braille: false,
+            trace_color: None,
+            trace_style: TraceStyle::Line,
+            level_range: None,
+            session_max: false,
+            average: 1.0,
+            overlap: None,
+            buffer_size: None,
+            waterfall_scale: 1.0,
+            waterfall_gamma: 1.0,
+            auto_contrast: false,
+            auto_range: false,
+            agc_attack: DEFAULT_AGC_ATTACK_SECS,
+            agc_release: DEFAULT_AGC_RELEASE_SECS,
+            agc_floor: DEFAULT_AGC_FLOOR_DB,
+            envelope: false,
+            follow_peak: false,
+            follow_smoothing: DEFAULT_FOLLOW_SMOOTHING,
+            phase_panel: false,
+            phase_gate: DEFAULT_PHASE_GATE_DB,
+            envelope_attack: DEFAULT_ENVELOPE_ATTACK,
+            envelope_release: DEFAULT_ENVELOPE_RELEASE,
+            peak_decay: 0.0,
+            peak_threshold: DEFAULT_PEAK_THRESHOLD_DB,
+            track_peaks: None,
+            track_tolerance: DEFAULT_TRACK_TOLERANCE_HZ,
+            record: None,
+            input_file: None,
+            stdin: false,
+            test_signal: None,
+            loopback: false,
+            sample_rate: None,
+            stdin_channels: 1,
+            skip_silence: None,
+            skip_silence_min: 0.5,
+            skip_silence_log: None,
+            channel: ChannelSelect::Mix,
+            auto_restart: false,
+            restart_after: Duration::from_secs_f32(DEFAULT_RESTART_AFTER_SECS),
+            stall_timeout: Duration::from_secs_f32(DEFAULT_STALL_TIMEOUT_SECS),
+            max_stream_errors: DEFAULT_MAX_STREAM_ERRORS,
+            fps: DEFAULT_FPS,
+            waterfall_fps: None,
+            csv: None,
+            event_log: None,
+            event_threshold: DEFAULT_EVENT_THRESHOLD_DB,
+            event_debounce: DEFAULT_EVENT_DEBOUNCE_SECS,
+            onsets: false,
+            onset_threshold: DEFAULT_ONSET_THRESHOLD,
+            onset_log: None,
+            npy_out: None,
+            output_prefix: DEFAULT_OUTPUT_PREFIX.into(),
+            profile: None,
+            bench: false,
+            list_devices: false,
+            json: false,
+            json_out: false,
+            tape: false,
+            tape_interval: 1.0,
+            no_wait: false,
+            iterations: DEFAULT_BENCH_ITERATIONS,
+            output: true,
+            output_device: None,
+            hpf: None,
+            notch: None,
+            decimate: 1,
+            zero_pad: 1,
+            time_compress: 1,
+            time_compress_mode: TimeCompression::Max,
+            channel_layout: ChannelLayout::Mono,
+            stereo_mode: StereoMode::LeftRight,
+            monitor: None,
+            cw: false,
+            cw_threshold: None,
+            tuner: false,
+            thd: false,
+            thd_fundamental: None,
+            delay: false,
+            stream_udp: None,
+            ipc_socket: None,
+            view_udp: None,
+            record_session: None,
+            replay: None,
+            tone_volume: DEFAULT_TONE_VOLUME,
+            step: DEFAULT_STEP,
+            gain_step: DEFAULT_GAIN_STEP_DB,
+            output_gain: 0.0,
+            output_delay: 0.0,
+            balance: 0.0,
+            persistence: None,
+            compand: None,
+            bandwidth: None,
+            gate: None,
+            bands: Vec::new(),
+            band_analysis: None,
+            reference_curve: Vec::new(),
+            cal_offset: None,
+            cal_unit: LevelUnit::Dbfs,
+            history: None,
+            trigger: None,
+            trigger_pre: DEFAULT_TRIGGER_PRE_LINES,
+            trigger_post: DEFAULT_TRIGGER_POST_LINES,
+            duration: None,
+            help: false,
+            version: false,
+        }
+    }
+
+    pub fn from_args() -> Result<Self> {
+        Self::parse_args(config::load_args(), std::env::args().skip(1))
+    }
+
+    // The config file supplies defaults; a bad entry is reported and skipped
+    fn parse_args(config: Vec<(String, Vec<String>)>, args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut options = Self::defaults();
+        for (flag, values) in config {
+            if let Err(e) = options.apply(&flag, &mut values.into_iter()) {
+                eprintln!("Ignoring config entry {}: {}", flag.trim_start_matches('-'), e);
+            }
+        }
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            options.apply(&arg, &mut args)?;
+        }
+        // Whatever else was passed, these only print and exit
+        if options.help || options.version {
+            return Ok(options);
+        }
+
+        if !options.fft_size.is_power_of_two() || !(MIN_FFT_SIZE..=MAX_FFT_SIZE).contains(&options.fft_size) {
+            bail!("--fft-size must be a power of two between {} and {}, got {}",
+                  MIN_FFT_SIZE, MAX_FFT_SIZE, options.fft_size);
+        }
+        if !(MIN_GAIN_DB..=MAX_GAIN_DB).contains(&options.gain) {
+            bail!("--gain must be between {} and {} dB, got {}", MIN_GAIN_DB, MAX_GAIN_DB, options.gain);
+        }
+        if options.zoom < 1.0 || options.zoom.is_nan() {
+            bail!("--zoom must be at least 1, got {}", options.zoom);
+        }
+        if !(options.fmin >= 0.0 && options.fmin.is_finite()) {
+            bail!("--fmin must be a frequency in Hz at or above 0, got {}", options.fmin);
+        }
+        if options.fmax.is_some_and(|fmax| fmax.is_nan() || fmax <= options.fmin) {
+            bail!("--fmax must be above --fmin ({} Hz), got {}", options.fmin, options.fmax.unwrap());
+        }
+        if !(MIN_AVERAGE_ALPHA..=1.0).contains(&options.average) {
+            bail!("--average must be between {} and 1, got {}", MIN_AVERAGE_ALPHA, options.average);
+        }
+        if !(MIN_WATERFALL_SCALE..=MAX_WATERFALL_SCALE).contains(&options.waterfall_scale) {
+            bail!("--waterfall-scale must be between {} and {}, got {}",
+                  MIN_WATERFALL_SCALE, MAX_WATERFALL_SCALE, options.waterfall_scale);
+        }
+        if !(MIN_WATERFALL_GAMMA..=MAX_WATERFALL_GAMMA).contains(&options.waterfall_gamma) {
+            bail!("--waterfall-gamma must be between {} and {}, got {}",
+                  MIN_WATERFALL_GAMMA, MAX_WATERFALL_GAMMA, options.waterfall_gamma);
+        }
+        if let Some(decay) = options.persistence {
+            if !(decay > 0.0 && decay < 1.0) {
+                bail!("--persistence is the share of a line kept per line, between 0 and 1, got {}", decay);
+            }
+        }
+        if options.json && !options.list_devices {
+            bail!("--json only applies to --list-devices");
+        }
+        if options.json_out && (options.stream_udp.is_some() || options.view_udp.is_some() || options.replay.is_some()) {
+            bail!("--json-out writes frames to stdout and cannot be combined with --stream-udp, --view-udp or --replay");
+        }
+        if options.tape && (options.json_out || options.stream_udp.is_some() || options.ipc_socket.is_some()
+                            || options.view_udp.is_some() || options.replay.is_some()) {
+            bail!("--tape writes lines to stdout and cannot be combined with --json-out, --stream-udp, --ipc-socket, \
+                   --view-udp or --replay");
+        }
+        if options.tape_interval <= 0.0 || !options.tape_interval.is_finite() {
+            bail!("--tape-interval must be a positive number of seconds, got {}", options.tape_interval);
+        }
+        if options.gate.is_some_and(|db| db.is_nan() || db > 0.0) {
+            bail!("--gate is a level in dB at or below 0, got {}", options.gate.unwrap());
+        }
+        if options.cal_offset.is_some_and(|db| !db.is_finite()) {
+            bail!("--cal-offset must be a finite number of dB, got {}", options.cal_offset.unwrap());
+        }
+        if options.cal_unit == LevelUnit::Db {
+            bail!("--cal-unit must be dbfs or spl");
+        }
+        if options.bandwidth.is_some_and(|db| db.is_nan() || db <= 0.0) {
+            bail!("--bandwidth is a drop below the peak in dB, above 0, got {}", options.bandwidth.unwrap());
+        }
+        if let Some(overlap) = options.overlap {
+            if !(0.0..=MAX_OVERLAP).contains(&overlap) {
+                bail!("--overlap must be between 0 and {}, got {}", MAX_OVERLAP, overlap);
+            }
+        }
+        if options.agc_attack <= 0.0 || options.agc_release <= 0.0 {
+            bail!("--agc-attack and --agc-release must be positive");
+        }
+        for (flag, share) in [("--envelope-attack", options.envelope_attack),
+                              ("--envelope-release", options.envelope_release)] {
+            if !(share > 0.0 && share <= 1.0) {
+                bail!("{} is the share of the change taken per line, above 0 and at most 1, got {}", flag, share);
+            }
+        }
+        if !(options.follow_smoothing > 0.0 && options.follow_smoothing <= 1.0) {
+            bail!("--follow-smoothing is the share of the way to the peak taken per frame, above 0 and at most 1, \
+                   got {}", options.follow_smoothing);
+        }
+        if !options.phase_gate.is_finite() || options.phase_gate > 0.0 {
+            bail!("--phase-gate is a level below the strongest bin, 0 dB or less, got {}", options.phase_gate);
+        }
+        if options.track_peaks.is_some_and(|count| !(1..=MAX_TRACKED_PEAKS).contains(&count)) {
+            bail!("--track-peaks must be between 1 and {}, got {}", MAX_TRACKED_PEAKS, options.track_peaks.unwrap());
+        }
+        if options.track_tolerance <= 0.0 || !options.track_tolerance.is_finite() {
+            bail!("--track-tolerance must be a positive number of Hz, got {}", options.track_tolerance);
+        }
+        if !(0.0..=1.0).contains(&options.peak_decay) {
+            bail!("--peak-decay must be between 0 and 1, got {}", options.peak_decay);
+        }
+        if options.db_floor >= 0.0 || options.db_floor.is_nan() {
+            bail!("--db-floor must be negative, got {}", options.db_floor);
+        }
+        if !options.output && options.output_device.is_some() {
+            bail!("--output-device cannot be combined with --no-output");
+        }
+        if options.buffer_size == Some(0) {
+            bail!("--buffer-size must be at least one frame");
+        }
+        if options.buffer_size.is_some()
+           && (options.input_file.is_some() || options.stdin || options.view_udp.is_some() || options.replay.is_some()
+               || options.test_signal.is_some()) {
+            bail!("--buffer-size only applies to capture devices");
+        }
+        if options.loopback {
+            if options.input_file.is_some() || options.stdin || options.view_udp.is_some() || options.replay.is_some() {
+                bail!("--loopback captures the system output and cannot be combined with other inputs");
+            }
+            if options.output_device.is_some() {
+                bail!("--loopback plays nothing back, so --output-device has no effect");
+            }
+            // Playing the capture back would feed it into itself
+            options.output = false;
+        }
+        if options.output_delay < 0.0 || !options.output_delay.is_finite() {
+            bail!("--output-delay must be zero or more milliseconds, got {}", options.output_delay);
+        }
+        if options.output_delay > 0.0 && !options.output {
+            bail!("--output-delay delays playback, and there is none with --no-output or --loopback");
+        }
+        if options.ipc_socket.is_some() && (options.stream_udp.is_some() || options.view_udp.is_some()
+                                            || options.replay.is_some() || options.json_out) {
+            bail!("--ipc-socket serves live frames and cannot be combined with --stream-udp, --view-udp, --replay or --json-out");
+        }
+        if options.view_udp.is_some() && (options.stream_udp.is_some() || options.input_file.is_some()) {
+            bail!("--view-udp takes its frames from the network and cannot be combined with --stream-udp or --input-file");
+        }
+        if options.replay.is_some() && (options.input_file.is_some() || options.stdin || options.view_udp.is_some()
+                                        || options.stream_udp.is_some() || options.record_session.is_some()) {
+            bail!("--replay takes its frames from the session file and cannot be combined with other inputs or outputs");
+        }
+        if !options.extra_devices.is_empty() {
+            if options.input_file.is_some() || options.stdin || options.loopback || options.view_udp.is_some()
+               || options.replay.is_some() {
+                bail!("Several --device indices only apply to live devices");
+            }
+            if options.stream_udp.is_some() || options.ipc_socket.is_some() || options.json_out || options.tape {
+                bail!("Several --device indices need the terminal view to switch between them");
+            }
+            if options.record.is_some() || options.csv.is_some() || options.record_session.is_some()
+               || options.event_log.is_some() || options.onset_log.is_some() {
+                bail!("--record, --csv, --record-session, --event-log and --onset-log follow a single input; \
+                       pass one --device");
+            }
+        }
+        if options.sample_rate == Some(0) {
+            bail!("--sample-rate must be positive");
+        }
+        if options.sample_rate.is_some() && options.input_file.is_some() {
+            bail!("--input-file plays at the file's own sample rate; drop --sample-rate");
+        }
+        if options.stdin {
+            if options.sample_rate.is_none() {
+                bail!("--stdin needs the stream's rate via --sample-rate");
+            }
+            if options.input_file.is_some() || options.view_udp.is_some() {
+                bail!("--stdin cannot be combined with --input-file or --view-udp");
+            }
+            if options.stdin_channels == 0 {
+                bail!("--stdin-channels must be at least 1");
+            }
+        }
+        if let Some(signal) = &options.test_signal {
+            if options.input_file.is_some() || options.stdin || options.loopback || options.view_udp.is_some()
+               || options.replay.is_some() || !options.extra_devices.is_empty() {
+                bail!("--test-signal generates its own input and cannot be combined with other inputs");
+            }
+            let nyquist = options.sample_rate.unwrap_or(DEFAULT_TEST_SIGNAL_RATE) as f32 / 2.0;
+            if let Some(freq) = signal.max_freq().filter(|&freq| freq >= nyquist) {
+                bail!("--test-signal reaches {} Hz, at or above the {} Hz Nyquist limit; raise --sample-rate",
+                      freq, nyquist);
+            }
+        }
+        if let Some(threshold) = options.skip_silence {
+            if threshold >= 0.0 || !threshold.is_finite() {
+                bail!("--skip-silence must be a negative level in dB, got {}", threshold);
+            }
+            // A device has to be listened to as it plays; only files and pipes can be read ahead
+            if options.input_file.is_none() && !options.stdin {
+                bail!("--skip-silence only applies to --input-file and --stdin");
+            }
+        }
+        if options.skip_silence_min < 0.0 || !options.skip_silence_min.is_finite() {
+            bail!("--skip-silence-min must be zero or more seconds, got {}", options.skip_silence_min);
+        }
+        if options.skip_silence_log.is_some() && options.skip_silence.is_none() {
+            bail!("--skip-silence-log needs --skip-silence");
+        }
+        if let Some(cutoff) = options.hpf {
+            if cutoff <= 0.0 || cutoff.is_nan() {
+                bail!("--hpf must be a positive cutoff in Hz, got {}", cutoff);
+            }
+        }
+        if let Some(mains) = options.notch {
+            if mains != 50.0 && mains != 60.0 {
+                bail!("--notch must be 50 or 60, got {}", mains);
+            }
+        }
+        let thresholds = options.classic_thresholds;
+        if !thresholds.is_increasing() || thresholds.0.iter().any(|threshold| !(0.0..=100.0).contains(threshold)) {
+            bail!("--classic-thresholds must be increasing percentages between 0 and 100, got {}", thresholds.name());
+        }
+        if !options.zero_pad.is_power_of_two() || options.zero_pad > MAX_ZERO_PAD {
+            bail!("--zero-pad must be a power of two up to {}, got {}", MAX_ZERO_PAD, options.zero_pad);
+        }
+        if options.waterfall_smooth.is_multiple_of(2) || options.waterfall_smooth > MAX_WATERFALL_SMOOTH {
+            bail!("--waterfall-smooth must be an odd number of bins up to {}, got {}",
+                  MAX_WATERFALL_SMOOTH, options.waterfall_smooth);
+        }
+        if !(1..=MAX_TIME_COMPRESS).contains(&options.time_compress) {
+            bail!("--time-compress must be between 1 and {} frames, got {}", MAX_TIME_COMPRESS, options.time_compress);
+        }
+        if options.zero_pad > 1 {
+            if options.view_udp.is_some() || options.replay.is_some() {
+                bail!("--zero-pad shapes the local analysis and cannot be combined with --view-udp or --replay");
+            }
+            // From here on the FFT size is the padded length, which every bin and frequency follows
+            options.fft_size *= options.zero_pad;
+            if options.fft_size > MAX_FFT_SIZE {
+                bail!("--fft-size times --zero-pad must be at most {}, got {}", MAX_FFT_SIZE, options.fft_size);
+            }
+        }
+        if !(1..=MAX_DECIMATE).contains(&options.decimate) {
+            bail!("--decimate must be between 1 and {}, got {}", MAX_DECIMATE, options.decimate);
+        }
+        if let Some(freq) = options.monitor {
+            if freq <= 0.0 || freq.is_nan() {
+                bail!("--monitor must be a positive frequency in Hz, got {}", freq);
+            }
+        }
+        if !(options.step > 1.0 && options.step.is_finite()) {
+            bail!("--step must be a factor above 1, got {}", options.step);
+        }
+        if !(options.gain_step > 0.0 && options.gain_step.is_finite()) {
+            bail!("--gain-step must be a positive number of dB, got {}", options.gain_step);
+        }
+        if !(0.0..=1.0).contains(&options.tone_volume) {
+            bail!("--tone-volume must be between 0 and 1, got {}", options.tone_volume);
+        }
+        if !(MIN_OUTPUT_GAIN_DB..=MAX_OUTPUT_GAIN_DB).contains(&options.output_gain) {
+            bail!("--output-gain must be between {} and {} dB, got {}",
+                  MIN_OUTPUT_GAIN_DB, MAX_OUTPUT_GAIN_DB, options.output_gain);
+        }
+        if !(-1.0..=1.0).contains(&options.balance) {
+            bail!("--balance must be between -1 (left) and 1 (right), got {}", options.balance);
+        }
+        if options.trigger.is_some_and(|level| level.is_nan() || level > 0.0) {
+            bail!("--trigger is a level in dB at or below 0, got {}", options.trigger.unwrap());
+        }
+        if options.event_threshold.is_nan() || options.event_threshold > 0.0 {
+            bail!("--event-threshold is a level in dB at or below 0, got {}", options.event_threshold);
+        }
+        if !options.event_debounce.is_finite() || options.event_debounce < 0.0 {
+            bail!("--event-debounce must be zero or more seconds, got {}", options.event_debounce);
+        }
+        if !(options.onset_threshold > 0.0 && options.onset_threshold <= 1.0) {
+            bail!("--onset-threshold is a spectral flux above 0 and at most 1, got {}", options.onset_threshold);
+        }
+        if options.delay && options.channel_layout != ChannelLayout::Stereo {
+            bail!("--delay measures one channel against the other and needs --channels stereo");
+        }
+        if options.stereo_mode == StereoMode::MidSide {
+            if options.channel_layout != ChannelLayout::Stereo {
+                bail!("--stereo-mode mid-side splits the stereo view and needs --channels stereo");
+            }
+            if options.delay {
+                bail!("--delay measures left against right and cannot run with --stereo-mode mid-side");
+            }
+        }
+        if options.thd_fundamental.is_some_and(|freq| freq <= 0.0 || freq.is_nan()) {
+            bail!("--thd-fundamental must be a positive frequency in Hz, got {}", options.thd_fundamental.unwrap());
+        }
+        if (options.cw || options.cw_threshold.is_some()) && options.monitor.is_none() {
+            bail!("--cw decodes the tone --monitor follows; give its frequency with --monitor");
+        }
+        if options.tuner && options.monitor.is_some() {
+            bail!("--tuner and --monitor both replace the main view; pick one");
+        }
+        if options.duration == Some(Duration::ZERO) {
+            bail!("--duration must be a positive number of seconds");
+        }
+        if options.iterations == 0 {
+            bail!("--iterations must be at least 1");
+        }
+        if !(MIN_FPS..=MAX_FPS).contains(&options.fps) {
+            bail!("--fps must be between {} and {}, got {}", MIN_FPS, MAX_FPS, options.fps);
+        }
+        if let Some(fps) = options.waterfall_fps.filter(|fps| !(MIN_FPS..=MAX_FPS).contains(fps)) {
+            bail!("--waterfall-fps must be between {} and {}, got {}", MIN_FPS, MAX_FPS, fps);
+        }
+        Ok(options)
+    }
+
+    // Applies one flag, pulling its value from `args` when it takes one
+    fn apply(&mut self, arg: &str, args: &mut impl Iterator<Item = String>) -> Result<()> {
+        let arg = match arg {
+            "-h" => "--help",
+            "-V" => "--version",
+            _ => arg,
+        };
+        let flag = FLAGS.iter().find(|flag| flag.name == arg)
+            .ok_or_else(|| anyhow!("Unknown argument: {}; see --help", arg))?;
+        match flag.set {
+            Set::Switch(set) => set(self),
+            Set::Value(set) => {
+                let value = args.next().ok_or_else(|| anyhow!("{} requires a value", arg))?;
+                set(self, &value).map_err(|e| {
+                    if e.is::<InvalidValue>() { anyhow!("Invalid value for {}: {}", arg, value) } else { e }
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn effective_color_mode(&self) -> ColorMode {
+        self.color_mode.unwrap_or_else(ColorMode::detect)
+    }
+
+    // Gradients need at least 256 colors to look smooth; below that default to the classic five
+    pub fn effective_colormap(&self) -> Colormap {
+        match self.colormap {
+            Some(Colormap::Classic(_)) => Colormap::Classic(self.classic_thresholds),
+            Some(colormap) => colormap,
+            None if self.effective_color_mode() >= ColorMode::Ansi256 => Colormap::Viridis,
+            None => Colormap::Classic(self.classic_thresholds),
+        }
+    }
+}
+
+// One row per flag, holding how it sets the options: `apply` and --help both go by this table alone
+pub struct Flag {
+    pub name: &'static str,
+    // What the flag takes, empty for a switch
+    pub value: &'static str,
+    pub description: &'static str,
+    pub set: Set,
+    // The built-in default, read off the options before any config or flag is applied
+    pub default: Option<fn(&Options) -> String>,
+}
+
+pub enum Set {
+    Switch(fn(&mut Options)),
+    // Handed the value that follows the flag
+    Value(fn(&mut Options, &str) -> Result<()>),
+}
+
+const fn switch(name: &'static str, description: &'static str, set: fn(&mut Options)) -> Flag {
+    Flag { name, value: "", description, set: Set::Switch(set), default: None }
+}
+
+const fn flag(name: &'static str, value: &'static str, description: &'static str,
+              set: fn(&mut Options, &str) -> Result<()>) -> Flag {
+    Flag { name, value, description, set: Set::Value(set), default: None }
+}
+
+const fn defaulted(name: &'static str, value: &'static str, description: &'static str,
+                   set: fn(&mut Options, &str) -> Result<()>, default: fn(&Options) -> String) -> Flag {
+    Flag { name, value, description, set: Set::Value(set), default: Some(default) }
+}
+
+pub const FLAGS: &[Flag] = &[
+    switch("--help", "Print these options and exit (or -h)", |o| o.help = true),
+    switch("--version", "Print the version and exit (or -V)", |o| o.version = true),
+    flag("--device", "N[,N...]", "Input device by number; several show side by side, Tab switches",
+         |o, v| {
+             let mut indices = v.split(',').map(|index| parse(index.trim()).map(DeviceSelector::Index));
+             o.device = indices.next().transpose()?;
+             o.extra_devices = indices.collect::<Result<_>>()?;
+             Ok(())
+         }),
+    flag("--device-name", "NAME", "Input device by name",
+         |o, v| {
+             o.device = Some(DeviceSelector::Name(v.to_string()));
+             o.extra_devices.clear();
+             Ok(())
+         }),
+    flag("--input-file", "PATH", "Analyze a WAV file instead of a device",
+         |o, v| parse(v).map(|v| o.input_file = Some(v))),
+    switch("--stdin", "Read raw little-endian f32 samples from stdin (needs --sample-rate)", |o| o.stdin = true),
+    switch("--loopback", "Capture what the system is playing", |o| o.loopback = true),
+    flag("--test-signal", "SPEC", "Generate the input: sine:F[+F...], sweep:START-END[:SECS] or noise:white|pink",
+         |o, v| parse(v).map(|v| o.test_signal = Some(v))),
+    flag("--sample-rate", "HZ", "Sample rate of --stdin or --test-signal, or to open the device at",
+         |o, v| parse(v).map(|v| o.sample_rate = Some(v))),
+    defaulted("--stdin-channels", "N", "Interleaved channels on stdin",
+              |o, v| parse(v).map(|v| o.stdin_channels = v), |o| o.stdin_channels.to_string()),
+    flag("--skip-silence", "DB", "Pass over file or stdin input that stays below this RMS level",
+         |o, v| parse(v).map(|v| o.skip_silence = Some(v))),
+    defaulted("--skip-silence-min", "SECS", "Quiet that plays through before skipping starts",
+              |o, v| parse(v).map(|v| o.skip_silence_min = v), |o| o.skip_silence_min.to_string()),
+    flag("--skip-silence-log", "PATH", "Append where each skipped stretch starts and how long it ran",
+         |o, v| parse(v).map(|v| o.skip_silence_log = Some(v))),
+    flag("--channel", "mix|left|right|N", "Channel to analyze (default mix)",
+         |o, v| parse(v).map(|v| o.channel = v)),
+    flag("--channels", "mono|stereo", "Analyze the channels together or side by side (default mono)",
+         |o, v| parse(v).map(|v| o.channel_layout = v)),
+    flag("--stereo-mode", "mid-side|lr", "Show left and right, or their mid (L+R) and side (L-R) (default lr)",
+         |o, v| parse(v).map(|v| o.stereo_mode = v)),
+    flag("--buffer-size", "FRAMES", "Fixed input block size, for lower latency (default: the driver's)",
+         |o, v| parse(v).map(|v| o.buffer_size = Some(v))),
+    switch("--list-devices", "Print the input devices and exit", |o| o.list_devices = true),
+    switch("--json", "With --list-devices, print them as JSON", |o| o.json = true),
+    switch("--no-wait", "Start without waiting for Enter", |o| o.no_wait = true),
+    switch("--auto-restart", "Reopen the device after it fails", |o| o.auto_restart = true),
+    defaulted("--restart-after", "SECS", "Wait before reopening the device",
+              |o, v| seconds(v, "--restart-after must be zero or more seconds").map(|secs| o.restart_after = secs),
+              |o| o.restart_after.as_secs_f32().to_string()),
+    defaulted("--stall-timeout", "SECS", "Input silence that counts as a lost device",
+              |o, v| seconds(v, "--stall-timeout must be zero or more seconds").map(|secs| o.stall_timeout = secs),
+              |o| o.stall_timeout.as_secs_f32().to_string()),
+    defaulted("--max-stream-errors", "N", "Stream errors tolerated before restarting",
+              |o, v| parse(v).map(|v| o.max_stream_errors = v), |o| o.max_stream_errors.to_string()),
+    defaulted("--fft-size", "N", "Samples per FFT, a power of two",
+              |o, v| parse(v).map(|v| o.fft_size = v), |o| o.fft_size.to_string()),
+    defaulted("--zero-pad", "N", "Pad each FFT to N times its samples, a power of two",
+              |o, v| parse(v).map(|v| o.zero_pad = v), |o| o.zero_pad.to_string()),
+    flag("--overlap", "SHARE", "Share of each FFT's samples the next one reuses (default: as the frame rate needs)",
+         |o, v| parse(v).map(|v| o.overlap = Some(v))),
+    defaulted("--window", "NAME", "FFT window: hann, hamming, blackman or rectangular",
+              |o, v| parse(v).map(|v| o.window = v), |o| o.window.name().to_lowercase()),
+    defaulted("--decimate", "N", "Keep every Nth sample after low-pass filtering",
+              |o, v| parse(v).map(|v| o.decimate = v), |o| o.decimate.to_string()),
+    flag("--hpf", "HZ", "High-pass filter the input at this cutoff",
+         |o, v| parse(v).map(|v| o.hpf = Some(v))),
+    flag("--notch", "50|60", "Notch out mains hum and its harmonics",
+         |o, v| parse(v).map(|v| o.notch = Some(v))),
+    defaulted("--gain", "DB", "Display gain",
+              |o, v| parse(v).map(|v| o.gain = v), |o| o.gain.to_string()),
+    defaulted("--gain-step", "DB", "Decibels each gain key press adds or takes away",
+              |o, v| parse(v).map(|v| o.gain_step = v), |o| o.gain_step.to_string()),
+    defaulted("--step", "X", "Factor each zoom key press applies",
+              |o, v| parse(v).map(|v| o.step = v), |o| o.step.to_string()),
+    defaulted("--zoom", "X", "Frequency zoom",
+              |o, v| parse(v).map(|v| o.zoom = v), |o| o.zoom.to_string()),
+    defaulted("--fmin", "HZ", "Bottom of the band zoom and pan work within",
+              |o, v| parse(v).map(|v| o.fmin = v), |o| o.fmin.to_string()),
+    flag("--fmax", "HZ", "Top of the band (default: half the sample rate)",
+         |o, v| parse(v).map(|v| o.fmax = Some(v))),
+    switch("--follow-peak", "Keep the zoomed band centered on the strongest peak", |o| o.follow_peak = true),
+    defaulted("--follow-smoothing", "SHARE", "Share of the way to the peak the band moves per frame",
+              |o, v| parse(v).map(|v| o.follow_smoothing = v), |o| o.follow_smoothing.to_string()),
+    defaulted("--freq-axis", "linear|log", "Frequency axis",
+              |o, v| parse(v).map(|v| o.freq_axis = v), |o| o.freq_axis.name().into()),
+    defaulted("--scale", "linear|db", "Level scale",
+              |o, v| parse(v).map(|v| o.scale = v), |o| o.scale.name().into()),
+    defaulted("--quantity", "NAME", "Level shown: magnitude, power or psd",
+              |o, v| parse(v).map(|v| o.quantity = v), |o| o.quantity.name().to_lowercase()),
+    defaulted("--weighting", "a|c|none", "Frequency weighting",
+              |o, v| parse(v).map(|v| o.weighting = v), |o| o.weighting.name().to_lowercase()),
+    defaulted("--db-floor", "DB", "Bottom of the dB scale",
+              |o, v| parse(v).map(|v| o.db_floor = v), |o| o.db_floor.to_string()),
+    flag("--cal-offset", "DB", "Calibration added to power and PSD, --json-out and --tape levels (not magnitude)",
+         |o, v| parse(v).map(|v| o.cal_offset = Some(v))),
+    defaulted("--cal-unit", "dbfs|spl", "Unit the calibrated levels read in",
+              |o, v| parse(v).map(|v| o.cal_unit = v), |o| o.cal_unit.name().into()),
+    flag("--level-range", "BOTTOM,TOP", "Lock the chart's level range",
+         |o, v| parse(v).map(|v| o.level_range = Some(v))),
+    defaulted("--average", "ALPHA", "Weight of each new frame in the running average",
+              |o, v| parse(v).map(|v| o.average = v), |o| o.average.to_string()),
+    switch("--envelope", "Follow each bin's envelope ahead of the average", |o| o.envelope = true),
+    defaulted("--envelope-attack", "SHARE", "Share of a rise the envelope takes per line",
+              |o, v| parse(v).map(|v| o.envelope_attack = v), |o| o.envelope_attack.to_string()),
+    defaulted("--envelope-release", "SHARE", "Share of a fall the envelope takes per line",
+              |o, v| parse(v).map(|v| o.envelope_release = v), |o| o.envelope_release.to_string()),
+    defaulted("--peak-decay", "SHARE", "Share of a held peak lost per frame",
+              |o, v| parse(v).map(|v| o.peak_decay = v), |o| o.peak_decay.to_string()),
+    defaulted("--peak-threshold", "DB", "Weakest level counted as a peak",
+              |o, v| parse(v).map(|v| o.peak_threshold = v), |o| o.peak_threshold.to_string()),
+    flag("--track-peaks", "N", "Follow the N strongest peaks with numbered markers on the spectrum",
+         |o, v| parse(v).map(|v| o.track_peaks = Some(v))),
+    defaulted("--track-tolerance", "HZ", "Farthest a tracked peak may move between frames",
+              |o, v| parse(v).map(|v| o.track_tolerance = v), |o| o.track_tolerance.to_string()),
+    switch("--session-max", "Show the loudest level each bin has reached", |o| o.session_max = true),
+    flag("--bandwidth", "DB", "Mark the peak's bandwidth this far below it",
+         |o, v| parse(v).map(|v| o.bandwidth = Some(v))),
+    switch("--thd", "Show total harmonic distortion", |o| o.thd = true),
+    flag("--thd-fundamental", "HZ", "Fundamental for --thd (default: the peak)",
+         |o, v| parse(v).map(|v| o.thd_fundamental = Some(v))),
+    switch("--delay", "Measure the delay between the channels (needs --channels stereo)", |o| o.delay = true),
+    switch("--phase-panel", "Show the unwrapped phase under the magnitude", |o| o.phase_panel = true),
+    defaulted("--phase-gate", "DB", "Weakest bin, below the strongest, the phase panel shows",
+              |o, v| parse(v).map(|v| o.phase_gate = v), |o| o.phase_gate.to_string()),
+    defaulted("--agc-attack", "SECS", "How quickly automatic gain turns down",
+              |o, v| parse(v).map(|v| o.agc_attack = v), |o| o.agc_attack.to_string()),
+    defaulted("--agc-release", "SECS", "How quickly automatic gain turns back up",
+              |o, v| parse(v).map(|v| o.agc_release = v), |o| o.agc_release.to_string()),
+    defaulted("--agc-floor", "DB", "Level below which automatic gain stops rising",
+              |o, v| parse(v).map(|v| o.agc_floor = v), |o| o.agc_floor.to_string()),
+    switch("--auto-range", "Set gain and contrast from the first 2 s of signal", |o| o.auto_range = true),
+    defaulted("--layout", "NAME", "Show both, spectrum or waterfall",
+              |o, v| parse(v).map(|v| o.layout = v), |o| o.layout.name().into()),
+    switch("--bars", "Draw the spectrum as block bars", |o| o.bars = true),
+    switch("--braille", "Draw the spectrum as a braille trace", |o| o.braille = true),
+    flag("--trace-color", "COLOR", "Color of the spectrum trace, #rrggbb or a name",
+         |o, v| parse(v).map(|v| o.trace_color = Some(v))),
+    defaulted("--trace-style", "NAME", "Spectrum trace: line, filled or stepped",
+              |o, v| parse(v).map(|v| o.trace_style = v), |o| o.trace_style.name().into()),
+    flag("--colormap", "NAME", "Waterfall colors: classic, viridis, inferno or phase",
+         |o, v| parse(v).map(|v| o.colormap = Some(v))),
+    switch("--invert", "Run the waterfall colors backward, strong signals dark on light", |o| o.invert = true),
+    defaulted("--waterfall-smooth", "BINS", "Blur each waterfall line across this many bins, an odd count",
+              |o, v| parse(v).map(|v| o.waterfall_smooth = v), |o| o.waterfall_smooth.to_string()),
+    flag("--classic-thresholds", "A,B,C,D", "Percentages where the classic colormap changes color",
+         |o, v| parse(v).map(|v| o.classic_thresholds = v)),
+    flag("--color-mode", "NAME", "Colors the terminal takes: mono, 16, 256 or truecolor (default: detected)",
+         |o, v| parse(v).map(|v| o.color_mode = Some(v))),
+    switch("--ascii", "Draw with ASCII characters only", |o| o.ascii = true),
+    defaulted("--waterfall-char", "CHAR|half", "Character of each waterfall cell",
+              |o, v| parse(v).map(|v| o.waterfall_glyph = v), |o| o.waterfall_glyph.name()),
+    defaulted("--waterfall-scale", "X", "Waterfall contrast",
+              |o, v| parse(v).map(|v| o.waterfall_scale = v), |o| o.waterfall_scale.to_string()),
+    defaulted("--waterfall-gamma", "X", "Waterfall brightness curve",
+              |o, v| parse(v).map(|v| o.waterfall_gamma = v), |o| o.waterfall_gamma.to_string()),
+    switch("--auto-contrast", "Set the waterfall contrast from the signal", |o| o.auto_contrast = true),
+    flag("--persistence", "SHARE", "Keep this share of each waterfall line in the next",
+         |o, v| parse(v).map(|v| o.persistence = Some(v))),
+    flag("--compand", "mu-law[:MU]|power[:EXP]", "Lift weak levels on screen without the dB scale",
+         |o, v| parse(v).map(|v| o.compand = Some(v))),
+    flag("--gate", "DB", "Draw waterfall bins below this level as background",
+         |o, v| parse(v).map(|v| o.gate = Some(v))),
+    defaulted("--scroll", "up|down", "Waterfall direction",
+              |o, v| parse(v).map(|v| o.scroll = v), |o| o.scroll.name().into()),
+    flag("--history", "LINES", "Waterfall lines to keep for scrolling back (default: a screenful)",
+         |o, v| parse(v).map(|v| o.history = Some(v))),
+    defaulted("--time-compress", "N", "Frames folded into each waterfall line",
+              |o, v| parse(v).map(|v| o.time_compress = v), |o| o.time_compress.to_string()),
+    defaulted("--time-compress-mode", "max|mean", "How folded frames combine",
+              |o, v| parse(v).map(|v| o.time_compress_mode = v), |o| o.time_compress_mode.name().into()),
+    defaulted("--fps", "N", "Frames drawn per second",
+              |o, v| parse(v).map(|v| o.fps = v), |o| o.fps.to_string()),
+    flag("--waterfall-fps", "N", "Redraw the waterfall at most this often, for slow links (default: every frame)",
+         |o, v| parse(v).map(|v| o.waterfall_fps = Some(v))),
+    flag("--trigger", "DB", "Only record waterfall lines around a peak above this level",
+         |o, v| parse(v).map(|v| o.trigger = Some(v))),
+    defaulted("--trigger-pre", "LINES", "Lines kept before each trigger",
+              |o, v| parse(v).map(|v| o.trigger_pre = v), |o| o.trigger_pre.to_string()),
+    defaulted("--trigger-post", "LINES", "Lines kept after each trigger",
+              |o, v| parse(v).map(|v| o.trigger_post = v), |o| o.trigger_post.to_string()),
+    flag("--bands", "PATH|octave|third-octave", "Mark the bands of a TOML band plan, or show band levels as bars",
+         // A band fraction by name, or else the path of a band plan
+         |o, v| match v.parse() {
+             Ok(fraction) => {
+                 o.band_analysis = Some(fraction);
+                 Ok(())
+             }
+             Err(_) => config::load_bands(Path::new(v)).map(|bands| o.bands = bands),
+         }),
+    flag("--reference-curve", "PATH", "Overlay a target response, one frequency,magnitude pair per line",
+         |o, v| config::load_reference_curve(Path::new(v)).map(|curve| o.reference_curve = curve)),
+    flag("--monitor", "HZ", "Show the level of the tone at this frequency",
+         |o, v| parse(v).map(|v| o.monitor = Some(v))),
+    switch("--cw", "Decode Morse from the --monitor tone", |o| o.cw = true),
+    flag("--cw-threshold", "DB", "Fixed level to key the decoder on (default: follows the signal)",
+         |o, v| parse(v).map(|v| o.cw_threshold = Some(v))),
+    switch("--tuner", "Show the nearest note to the peak in large type", |o| o.tuner = true),
+    switch("--no-output", "Don't play the input back", |o| o.output = false),
+    flag("--output-device", "N|NAME", "Play back on this device",
+         |o, v| parse(v).map(|v| o.output_device = Some(v))),
+    defaulted("--output-gain", "DB", "Playback gain",
+              |o, v| parse(v).map(|v| o.output_gain = v), |o| o.output_gain.to_string()),
+    defaulted("--output-delay", "MS", "Play the input back this much later",
+              |o, v| parse(v).map(|v| o.output_delay = v), |o| o.output_delay.to_string()),
+    defaulted("--balance", "-1..1", "Playback balance, left to right",
+              |o, v| parse(v).map(|v| o.balance = v), |o| o.balance.to_string()),
+    defaulted("--tone-volume", "0..1", "Volume of the tone played at the peak",
+              |o, v| parse(v).map(|v| o.tone_volume = v), |o| o.tone_volume.to_string()),
+    flag("--record", "PATH", "Record the input to a WAV file",
+         |o, v| parse(v).map(|v| o.record = Some(v))),
+    flag("--record-session", "PATH", "Record the analyzed frames for --replay",
+         |o, v| parse(v).map(|v| o.record_session = Some(v))),
+    flag("--replay", "PATH", "Replay a recorded session",
+         |o, v| parse(v).map(|v| o.replay = Some(v))),
+    flag("--csv", "PATH", "Write each spectrum to a CSV file",
+         |o, v| parse(v).map(|v| o.csv = Some(v))),
+    flag("--npy-out", "PATH", "Save the waterfall history as a NumPy array on exit",
+         |o, v| parse(v).map(|v| o.npy_out = Some(v))),
+    defaulted("--output-prefix", "TEMPLATE", "Name of saved files, from {kind}, {timestamp}, {device} and {samplerate}",
+              |o, v| parse(v).map(|v| o.output_prefix = v), |o| o.output_prefix.clone()),
+    flag("--event-log", "PATH", "Append peaks above --event-threshold with UTC timestamps",
+         |o, v| parse(v).map(|v| o.event_log = Some(v))),
+    defaulted("--event-threshold", "DB", "Peak level that starts an event",
+              |o, v| parse(v).map(|v| o.event_threshold = v), |o| o.event_threshold.to_string()),
+    defaulted("--event-debounce", "SECS", "Time below the threshold that ends an event",
+              |o, v| parse(v).map(|v| o.event_debounce = v), |o| o.event_debounce.to_string()),
+    switch("--onsets", "Mark onsets, where the spectral flux rises past --onset-threshold, on the waterfall",
+           |o| o.onsets = true),
+    defaulted("--onset-threshold", "FLUX", "Spectral flux that marks an onset, above 0 and at most 1",
+              |o, v| parse(v).map(|v| o.onset_threshold = v), |o| o.onset_threshold.to_string()),
+    flag("--onset-log", "PATH", "Append onsets with UTC timestamps",
+         |o, v| parse(v).map(|v| o.onset_log = Some(v))),
+    switch("--json-out", "Write each frame's peak, RMS, SNR and flatness to stdout as JSON instead of drawing",
+           |o| o.json_out = true),
+    switch("--tape", "Write a line of octave band levels and the peak to stdout instead of drawing", |o| o.tape = true),
+    defaulted("--tape-interval", "SECS", "Time each --tape line covers",
+              |o, v| parse(v).map(|v| o.tape_interval = v), |o| o.tape_interval.to_string()),
+    flag("--stream-udp", "HOST:PORT", "Send each spectrum over UDP",
+         |o, v| parse(v).map(|v| o.stream_udp = Some(v))),
+    flag("--ipc-socket", "PATH", "Serve each spectrum on a Unix socket",
+         |o, v| parse(v).map(|v| o.ipc_socket = Some(v))),
+    flag("--view-udp", "ADDR:PORT", "Show spectra received over UDP",
+         |o, v| parse(v).map(|v| o.view_udp = Some(v))),
+    flag("--profile", "PATH", "Write stage timings as folded stacks",
+         |o, v| parse(v).map(|v| o.profile = Some(v))),
+    flag("--duration", "SECS", "Quit after this long",
+         |o, v| seconds(v, "--duration must be a positive number of seconds").map(|secs| o.duration = Some(secs))),
+    switch("--bench", "Time the FFT and exit", |o| o.bench = true),
+    defaulted("--iterations", "N", "FFTs --bench times",
+              |o, v| parse(v).map(|v| o.iterations = v), |o| o.iterations.to_string()),
+];
+
+// Every flag with what it takes, a description and its default, for --help
+pub fn help_text() -> String {
+    let defaults = Options::defaults();
+    let usage = |flag: &Flag| match flag.value {
+        "" => flag.name.to_string(),
+        value => format!("{} {}", flag.name, value),
+    };
+    let width = FLAGS.iter().map(|flag| usage(flag).len()).max().unwrap_or(0);
+    let mut text = format!("qmx-capture {}\nTerminal spectrum analyzer and waterfall.\n\n\
+                            Usage: qmx-capture [OPTIONS]\n\n\
+                            Without an input option it lists the devices to pick one from.\n\n\
+                            Options:\n", env!("CARGO_PKG_VERSION"));
+    for flag in FLAGS {
+        let default = flag.default.map_or(String::new(), |default| format!(" (default {})", default(&defaults)));
+        text.push_str(&format!("  {:<width$}  {}{}\n", usage(flag), flag.description, default));
+    }
+    let config = config::config_path().map_or("the config file".into(), |path| path.display().to_string());
+    text.push_str(&format!("\nEntries in {} stand in for flags, as in `fft-size = 4096`.\n\
+                            Press ? while running for the keys.\n", config));
+    text
+}
+
+// A flag's value that doesn't parse as what it takes; `apply` reports it along with the flag
+#[derive(Debug)]
+struct InvalidValue;
+
+impl fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid value")
+    }
+}
+
+impl std::error::Error for InvalidValue {}
+
+fn parse<T: FromStr>(value: &str) -> Result<T> {
+    value.parse().map_err(|_| InvalidValue.into())
+}
+
+// Seconds as a Duration, failing with `error` on a negative or non-finite count
+fn seconds(value: &str, error: &str) -> Result<Duration> {
+    Duration::try_from_secs_f32(parse(value)?).map_err(|_| anyhow!("{}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(args: &[&str]) -> Result<Options> {
+        Options::parse_args(Vec::new(), args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn no_flags_leave_the_defaults_and_each_default_parses_back() {
+        let options = parsed(&[]).unwrap();
+        assert_eq!(options.fft_size, DEFAULT_FFT_SIZE);
+        assert_eq!(options.gain, DEFAULT_GAIN_DB);
+        assert_eq!(options.restart_after, Duration::from_secs_f32(DEFAULT_RESTART_AFTER_SECS));
+        assert!(options.output && !options.help && !options.version);
+        assert!(options.device.is_none() && options.fmax.is_none());
+
+        // What --help shows as a default is what the flag would take to set it
+        let defaults = Options::defaults();
+        for flag in FLAGS {
+            if let Some(default) = flag.default {
+                let text = default(&defaults);
+                let mut options = Options::defaults();
+                options.apply(flag.name, &mut std::iter::once(text.clone())).unwrap();
+                assert_eq!(default(&options), text, "{}", flag.name);
+            }
+        }
+    }
+
+    #[test]
+    fn flags_set_values_and_switches_and_config_gives_way_to_the_command_line() {
+        let options = parsed(&["--fft-size", "4096", "--no-output", "--fmax", "8000", "--device", "2, 5",
+                               "--restart-after", "0.5", "--window", "blackman"]).unwrap();
+        assert_eq!(options.fft_size, 4096);
+        assert!(!options.output);
+        assert_eq!(options.fmax, Some(8000.0));
+        assert!(matches!(options.device, Some(DeviceSelector::Index(2))));
+        assert!(matches!(options.extra_devices[..], [DeviceSelector::Index(5)]));
+        assert_eq!(options.restart_after, Duration::from_millis(500));
+        assert_eq!(options.window.name(), Window::Blackman.name());
+
+        let config = vec![("--fft-size".to_string(), vec!["1024".to_string()]),
+                          ("--zoom".to_string(), vec!["2".to_string()]),
+                          ("--no-such-entry".to_string(), Vec::new())];
+        let options = Options::parse_args(config, ["--fft-size".to_string(), "512".to_string()]).unwrap();
+        assert_eq!((options.fft_size, options.zoom), (512, 2.0));
+    }
+
+    #[test]
+    fn bands_take_a_fraction_by_name_or_else_a_band_plan() {
+        let options = parsed(&["--bands", "third-octave"]).unwrap();
+        assert_eq!(options.band_analysis, Some(BandFraction::ThirdOctave));
+        assert!(options.bands.is_empty());
+        assert_eq!(parsed(&["--bands", "octave"]).unwrap().band_analysis, Some(BandFraction::Octave));
+
+        let path = std::env::temp_dir().join(format!("qmx-bands-test-{}.toml", std::process::id()));
+        std::fs::write(&path, "[[band]]\nname = \"40m\"\nstart = 7000\nend = 7300\n").unwrap();
+        let options = parsed(&["--bands", path.to_str().unwrap()]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(options.bands, [Band { name: "40m".into(), start: 7000.0, end: 7300.0 }]);
+        assert!(options.band_analysis.is_none());
+        assert!(parsed(&["--bands", "no-such-plan.toml"]).is_err());
+    }
+
+    #[test]
+    fn bad_flags_name_what_went_wrong() {
+        let error = |args: &[&str]| parsed(args).err().unwrap().to_string();
+        assert_eq!(error(&["--no-such-flag"]), "Unknown argument: --no-such-flag; see --help");
+        assert_eq!(error(&["--fft-size"]), "--fft-size requires a value");
+        assert_eq!(error(&["--fft-size", "lots"]), "Invalid value for --fft-size: lots");
+        assert_eq!(error(&["--device", "1,x"]), "Invalid value for --device: 1,x");
+        assert_eq!(error(&["--restart-after", "-1"]), "--restart-after must be zero or more seconds");
+        assert_eq!(error(&["--fft-size", "1000"]),
+                   format!("--fft-size must be a power of two between {} and {}, got 1000",
+                           MIN_FFT_SIZE, MAX_FFT_SIZE));
+    }
+
+    #[test]
+    fn version_and_help_skip_the_checks_on_everything_else() {
+        for flag in ["--version", "-V"] {
+            let options = parsed(&["--fft-size", "1000", flag]).unwrap();
+            assert!(options.version && !options.help);
+        }
+        assert!(parsed(&["-h", "--stdin"]).unwrap().help);
+        assert!(help_text().contains(&format!("qmx-capture {}", env!("CARGO_PKG_VERSION"))));
+    }
+}
@@ -0,0 +1,1709 @@
+// Terminal frontend: a double-buffered screen and the layout of the header, chart and waterfall
+
+use crate::dsp::{Note, Weighting};
+use crate::keys::BINDINGS;
+use crate::view::{format_freq_tick, nice_step, Comparison, FreqAxis, GateMode, LayoutMode, LevelRange, TraceStyle, ViewState};
+use anyhow::{bail, Result};
+use crossterm::{
+    cursor::{Hide, MoveTo, Show},
+    event::{DisableMouseCapture, EnableMouseCapture},
+    execute, queue,
+    style::{Color, ResetColor, SetBackgroundColor, SetForegroundColor},
+    terminal::{disable_raw_mode, enable_raw_mode, size, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use std::{
+    io::{stdout, IsTerminal, Stdout, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+use textplots::{Chart, Plot, Shape};
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ScreenCell {
+    pub char: char,
+    pub fg_color: Option<Color>,
+    pub bg_color: Option<Color>,
+}
+
+impl Default for ScreenCell {
+    fn default() -> Self {
+        Self {
+            char: ' ',
+            fg_color: None,
+            bg_color: None,
+        }
+    }
+}
+
+pub struct ScreenBuffer {
+    pub cells: Vec<Vec<ScreenCell>>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl ScreenBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            cells: vec![vec![ScreenCell::default(); width]; height],
+            width,
+            height,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.fill(ScreenCell::default());
+    }
+
+    pub fn fill(&mut self, fill: ScreenCell) {
+        for row in &mut self.cells {
+            for cell in row {
+                *cell = fill.clone();
+            }
+        }
+    }
+}
+
+// Draws into any sink; the terminal one also owns the terminal setup and undoes it on drop
+pub struct Renderer<W: Write = Stdout> {
+    out: W,
+    terminal: bool,
+    front_buffer: ScreenBuffer,
+    back_buffer: ScreenBuffer,
+    // Level range the chart last fitted itself to, for locking it there
+    fitted_range: Option<LevelRange>,
+    // Escapes and text of a frame, gathered to go out in one write, and how long the last one was
+    output: Vec<u8>,
+    frame_bytes: usize,
+    // Waterfall rows as last drawn under --waterfall-fps, with when and from which row they start
+    waterfall_cache: Vec<Vec<ScreenCell>>,
+    waterfall_drawn: Option<(Instant, usize)>,
+}
+
+impl Renderer {
+    pub fn new() -> Result<Self> {
+        check_terminal()?;
+        let mut stdout = stdout();
+        execute!(stdout, EnterAlternateScreen, Hide, EnableMouseCapture)?;
+        // Half set up is worse than not at all, so a failure here undoes the above
+        let (term_width, term_height) = match enable_raw_mode().and_then(|_| size()) {
+            Ok(size) => size,
+            Err(e) => {
+                restore_terminal();
+                return Err(e.into());
+            }
+        };
+
+        let mut renderer = Self::with_output(stdout, term_width as usize, term_height as usize);
+        renderer.terminal = true;
+        Ok(renderer)
+    }
+}
+
+impl<W: Write> Renderer<W> {
+    // A fixed-size screen written to `out`, leaving the terminal alone; for tests and captures
+    pub fn with_output(out: W, width: usize, height: usize) -> Self {
+        Self {
+            out,
+            terminal: false,
+            front_buffer: ScreenBuffer::new(width, height),
+            back_buffer: ScreenBuffer::new(width, height),
+            fitted_range: None,
+            output: Vec::new(),
+            frame_bytes: 0,
+            waterfall_cache: Vec::new(),
+            waterfall_drawn: None,
+        }
+    }
+
+    // The frame being drawn; after `compose` and before `present` it holds the whole next screen
+    pub fn back_buffer(&self) -> &ScreenBuffer {
+        &self.back_buffer
+    }
+
+    // Forces every cell to be redrawn on the next frame
+    pub fn invalidate(&mut self) -> Result<()> {
+        queue!(self.out, ResetColor, Clear(ClearType::All))?;
+        self.front_buffer.fill(ScreenCell { char: '\0', fg_color: None, bg_color: None });
+        Ok(())
+    }
+
+    pub fn fitted_range(&self) -> Option<LevelRange> {
+        self.fitted_range
+    }
+
+    // Columns available to the waterfall, which is what the cursor and zoom work in
+    pub fn waterfall_width(&self, panels: usize) -> usize {
+        Layout::new(self.back_buffer.width, self.back_buffer.height, panels, LayoutMode::Both)
+            .map_or(self.back_buffer.width, |layout| layout.waterfall_width)
+    }
+
+    // Waterfall column under a screen cell below the header, in whichever panel it falls
+    pub fn panel_column(&self, panels: usize, x: usize, y: usize) -> Option<usize> {
+        let layout = Layout::new(self.back_buffer.width, self.back_buffer.height, panels, LayoutMode::Both)?;
+        if !(HEADER_ROWS..self.back_buffer.height).contains(&y) {
+            return None;
+        }
+        (0..layout.panels).map(|panel| layout.panel_left(panel))
+            .find(|&left| (left..left + layout.waterfall_width).contains(&x))
+            .map(|left| x - left)
+    }
+
+    // Marquee column under a screen cell, when the marquee is showing
+    pub fn marquee_column(&self, state: &ViewState, x: usize, y: usize) -> Option<usize> {
+        let layout = Layout::new(self.back_buffer.width, self.back_buffer.height, state.panels(), state.layout)?;
+        let column = x.checked_sub(layout.waterfall_left).filter(|&column| column < layout.waterfall_width)?;
+        (layout.marquee_row(state) == Some(y)).then_some(column)
+    }
+
+    // Waterfall row under a screen row, counted from the top of the waterfall
+    pub fn waterfall_row(&self, panels: usize, mode: LayoutMode, y: usize) -> Option<usize> {
+        let layout = Layout::new(self.back_buffer.width, self.back_buffer.height, panels, mode)?;
+        y.checked_sub(layout.waterfall_top).filter(|&row| row < layout.history_rows)
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.back_buffer.width, self.back_buffer.height)
+    }
+
+    pub fn resize(&mut self, width: usize, height: usize) -> Result<()> {
+        self.front_buffer = ScreenBuffer::new(width, height);
+        self.back_buffer = ScreenBuffer::new(width, height);
+        self.waterfall_drawn = None;
+        self.invalidate()
+    }
+
+    pub fn write_str_at(&mut self, x: usize, y: usize, s: &str) {
+        if y >= self.back_buffer.height {
+            return;
+        }
+        let cells = &mut self.back_buffer.cells[y];
+        for (i, c) in s.chars().enumerate() {
+            if x + i >= self.back_buffer.width {
+                break;
+            }
+            cells[x + i].char = c;
+        }
+    }
+
+    // Like write_str_at, but also sets the colors; None is the terminal default
+    pub fn write_str_colored(&mut self, x: usize, y: usize, s: &str, fg: Option<Color>, bg: Option<Color>) {
+        if y >= self.back_buffer.height {
+            return;
+        }
+        let cells = &mut self.back_buffer.cells[y];
+        for (i, c) in s.chars().enumerate() {
+            if x + i >= self.back_buffer.width {
+                break;
+            }
+            cells[x + i] = ScreenCell { char: c, fg_color: fg, bg_color: bg };
+        }
+    }
+
+    pub fn set_cell(&mut self, x: usize, y: usize, cell: ScreenCell) {
+        if x < self.back_buffer.width && y < self.back_buffer.height {
+            self.back_buffer.cells[y][x] = cell;
+        }
+    }
+
+    pub fn render(&mut self, state: &ViewState, sample_rate: u32) -> Result<()> {
+        self.compose(state, sample_rate);
+        self.present()
+    }
+
+    // Draws the next frame into the back buffer without writing anything out
+    pub fn compose(&mut self, state: &ViewState, sample_rate: u32) {
+        self.back_buffer.clear();
+        match Layout::new(self.back_buffer.width, self.back_buffer.height, state.panels(), state.layout) {
+            Some(layout) => {
+                self.draw(state, sample_rate, layout);
+                if let Some(banner) = &state.banner {
+                    self.draw_banner(state, banner, layout);
+                }
+                if state.diagnostics.enabled {
+                    self.draw_diagnostics(state, layout);
+                }
+                if state.show_help {
+                    self.draw_help(state);
+                }
+            }
+            None => self.write_str_at(0, 0, &format!("Terminal too small: need at least {} rows", MIN_TERMINAL_ROWS)),
+        }
+    }
+
+    pub fn draw(&mut self, state: &ViewState, sample_rate: u32, layout: Layout) {
+        let width = layout.waterfall_width;
+        let left = layout.waterfall_left;
+
+        // Render header
+        let mut header = format!("Gain: {:+.1} dB | Freq Zoom: {:.1}x | Window: {} | FFT: {} | Scale: {} | Press 'q' to quit, '?' for help | FPS: {}",
+                           state.gain_db(), state.freq_zoom, state.window.name(), state.fft_size, state.scale_label(),
+                           state.fps);
+        if state.zero_pad > 1 {
+            header.push_str(&format!(" | Zero-pad: {}x of {}", state.zero_pad, state.fft_size / state.zero_pad));
+        }
+        if state.time_compress > 1 {
+            header.push_str(&format!(" | {} frames/line ({})", state.time_compress, state.time_compress_mode.name()));
+        }
+        if let Some(label) = &state.input_label {
+            header.push_str(&format!(" | {}", label));
+        }
+        if state.weighting != Weighting::None {
+            header.push_str(&format!(" | {}-weighted", state.weighting.name()));
+        }
+        if state.agc.enabled {
+            header.push_str(" | AGC");
+        }
+        if state.follow_peak {
+            header.push_str(" | Following peak");
+        }
+        if state.envelope.enabled {
+            header.push_str(" | Envelope");
+        }
+        if state.auto_contrast {
+            header.push_str(" | Contrast: auto");
+        } else if state.waterfall_scale != 1.0 {
+            header.push_str(&format!(" | Contrast: {:.2}x", state.waterfall_scale));
+        }
+        if state.waterfall_gamma != 1.0 {
+            header.push_str(&format!(" | Gamma: {:.2}", state.waterfall_gamma));
+        }
+        if state.compand {
+            header.push_str(&format!(" | Compand: {}", state.compander.name()));
+        }
+        if state.average_alpha < 1.0 {
+            header.push_str(&format!(" | Avg: {:.2}", state.average_alpha));
+        }
+        match (state.comparison, &state.reference, &state.reference_b) {
+            (_, None, None) => (),
+            (Comparison::LiveMinusA, Some(_), None) => header.push_str(" | Ref subtracted"),
+            (comparison, _, _) => header.push_str(&format!(" | Showing {}", comparison.name())),
+        }
+        match (state.gate, &state.reference) {
+            (GateMode::Off, _) => (),
+            (GateMode::Level, _) => header.push_str(&format!(" | Gate: {:.0} dB", state.gate_db)),
+            (GateMode::Reference, Some(_)) => header.push_str(&format!(" | Gate: ref +{:.0} dB", state.gate_margin_db)),
+            (GateMode::Reference, None) => header.push_str(" | Gate: no reference"),
+        }
+        if state.peak_hold_enabled {
+            header.push_str(" | Peak hold");
+        }
+        if state.session_max_enabled {
+            header.push_str(" | Session max");
+        }
+        if state.persistence {
+            header.push_str(&format!(" | Persistence {:.2}", state.persistence_decay));
+        }
+        if let Some(back) = state.cursor_line_back() {
+            header.push_str(&format!(" | Spectrum at -{:.1}s", back as f32 * state.line_time().as_secs_f32()));
+        }
+        if state.history_offset > 0 {
+            let back = state.history_offset as f32 * state.line_time().as_secs_f32();
+            header.push_str(&format!(" | History -{:.1}s", back));
+        }
+        if state.bandwidth {
+            match state.peak_bandwidth() {
+                Some((low, high)) => header.push_str(&format!(" | -{} dB BW: {:.1} Hz", state.bandwidth_db, high - low)),
+                None => header.push_str(&format!(" | -{} dB BW: --", state.bandwidth_db)),
+            }
+        }
+        let mut rec_column = None;
+        if state.recording {
+            header.push_str(" | ");
+            rec_column = Some(header.chars().count());
+            header.push_str("REC");
+        }
+        if state.restarts > 0 {
+            header.push_str(&format!(" | Restarts: {}", state.restarts));
+        }
+        if state.overruns > 0 {
+            header.push_str(&format!(" | Overruns: {}", state.overruns));
+        }
+        if state.underruns > 0 {
+            header.push_str(&format!(" | Underruns: {}", state.underruns));
+        }
+        if state.skipped_secs > 0.0 {
+            header.push_str(&format!(" | Skipped: {:.1} s", state.skipped_secs));
+        }
+        if state.right.is_some() {
+            header.push_str(&format!(" | Stereo {}", state.stereo_mode.label()));
+        }
+        if let Some(mains) = state.notch.filter(|_| state.notch_enabled) {
+            header.push_str(&format!(" | Notch {} Hz", mains));
+        }
+        if !state.output {
+            header.push_str(" | No output");
+        } else if state.muted {
+            header.push_str(" | MUTED");
+        }
+        if state.tone && state.output {
+            header.push_str(&format!(" | Tone: {:.0}%", state.tone_volume * 100.0));
+        }
+        if state.output && state.output_gain_db != 0.0 {
+            header.push_str(&format!(" | Out: {:+.0} dB", state.output_gain_db));
+        }
+        if state.output && state.output_delay_ms > 0.0 {
+            header.push_str(&format!(" | Delay: {:.0} ms", state.output_delay_ms));
+        }
+        if state.output && state.balance != 0.0 {
+            let side = if state.balance < 0.0 { "L" } else { "R" };
+            header.push_str(&format!(" | Balance: {} {:.0}%", side, state.balance.abs() * 100.0));
+        }
+        if let Some(trigger) = &state.trigger {
+            let status = if trigger.is_triggered() { "TRIGGERED" } else { "armed" };
+            header.push_str(&format!(" | Trigger {:.0} dB: {}", trigger.level_db, status));
+        }
+        if let Some((position, length)) = state.replay {
+            header.push_str(&format!(" | Replay: {:.1} / {:.1} s", position.as_secs_f32(), length.as_secs_f32()));
+        }
+        if state.paused {
+            header.push_str(&format!(" | PAUSED at frame {}", state.frame_count));
+            if state.frame_end > 0 {
+                header.push_str(&format!(", {:.3} s", state.frame_end as f64 / sample_rate as f64));
+            }
+        }
+        if state.waterfall_held {
+            header.push_str(" | WATERFALL HELD");
+        }
+        if state.calibration.is_some() {
+            header.push_str(" | Calibrating...");
+        }
+        if let Some(notice) = state.current_notice() {
+            header.push_str(&format!(" | {}", notice));
+        }
+        self.write_str_at(0, 0, &header);
+        if let Some(x) = rec_column {
+            self.write_str_colored(x, 0, "REC", state.color_mode.quantize(Color::Red), None);
+        }
+
+        if state.input_metered {
+            self.draw_input_meter(state);
+        }
+
+        if let Some(freq) = state.monitor {
+            self.draw_monitor(state, freq, layout);
+            return;
+        }
+
+        // Measurement readouts
+        let peak = match state.find_peak() {
+            Some(peak) => format!("Peak: {:.1} Hz {} ({})",
+                                  peak.freq, state.note_label(peak.freq),
+                                  state.format_quantity(peak.magnitude, peak.freq, sample_rate)),
+            None => "Peak: --".to_string(),
+        };
+        self.write_str_colored(0, 1, &peak, state.color_mode.quantize(Color::Yellow), None);
+        let mut readouts = Vec::new();
+        if let Some(snr) = state.snr_db() {
+            readouts.push(format!("SNR: {:.1} dB", snr));
+        }
+        if let Some(cursor) = state.cursor_column(width) {
+            let freq = state.column_freq(cursor, width, sample_rate);
+            let magnitude = state.column_magnitude(state.shown_line(), cursor, width, sample_rate).unwrap_or(0.0);
+            readouts.push(format!("Cursor: {:.1} Hz ({})", freq, state.format_quantity(magnitude, freq, sample_rate)));
+        }
+        if state.thd {
+            readouts.push(match state.thd_ratio {
+                Some(ratio) => format!("THD: {:.3}%", ratio * 100.0),
+                None => "THD: --".to_string(),
+            });
+        }
+        if let Some(flatness) = state.flatness {
+            readouts.push(format!("Flatness: {:.3}", flatness));
+        }
+        if state.delay_enabled && state.right.is_some() {
+            readouts.push(match state.delay {
+                Some(delay) => {
+                    let millis = delay.samples as f32 * 1000.0 / sample_rate as f32;
+                    let polarity = if delay.correlation < 0.0 { ", inverted" } else { "" };
+                    format!("Delay R: {:+.2} ms ({:+} samples, r {:.2}{})",
+                            millis, delay.samples, delay.correlation.abs(), polarity)
+                }
+                None => "Delay R: no correlation".to_string(),
+            });
+        }
+        if !readouts.is_empty() {
+            self.write_str_at(peak.chars().count(), 1, &format!(" | {}", readouts.join(" | ")));
+        }
+
+        if let Some(stats) = state.spectrum_stats() {
+            self.write_str_at(0, 2, &format!("Min: {} | Max: {} @ {:.1} Hz | Mean: {} | RMS: {}",
+                                             state.format_level(stats.min), state.format_level(stats.max), stats.max_freq,
+                                             state.format_level(stats.mean), state.format_level(stats.rms)));
+        }
+
+        let (min_freq, max_freq) = state.freq_range(sample_rate);
+        let axis_label = match state.freq_axis {
+            FreqAxis::Linear => "",
+            FreqAxis::Log => ", log",
+        };
+        let spectrum_header = if state.xy && state.right.is_some() {
+            "X-Y (left across, right up)".to_string()
+        } else if state.scope {
+            let millis = state.scope_trace().len() as f32 * 1000.0 / sample_rate as f32;
+            format!("Scope ({:.1} ms{})", millis, if state.scope_trigger { ", triggered" } else { "" })
+        } else if let Some(fraction) = state.band_analysis {
+            format!("Band Analysis ({}, {:.0} Hz - {:.0} Hz)", fraction.name(), min_freq, max_freq)
+        } else {
+            let locked = if state.level_range.is_some() { ", levels locked" } else { "" };
+            format!("Spectrum Analysis ({:.0} Hz - {:.0} Hz{}{})", min_freq, max_freq, axis_label, locked)
+        };
+        self.write_str_at(0, 4, &spectrum_header);
+
+        let separator = if state.ascii { "-" } else { "─" };
+        self.write_str_at(0, 5, &separator.repeat(32));
+
+        if state.tuner {
+            self.draw_tuner(state);
+            return;
+        }
+
+        // Render spectrum, as a braille trace, block bars or a textplots chart, or the scope in its
+        // place. Without braille glyphs the braille trace falls back to ASCII bars. The marquee
+        // takes the row over the waterfall, and the phase panel the lower half of the rest from
+        // the magnitude.
+        let marquee_row = layout.marquee_row(state);
+        let chart_bottom = marquee_row.unwrap_or(layout.waterfall_top);
+        let spectrum_rows = chart_bottom - HEADER_ROWS;
+        let phase_rows = if state.phase_panel && !state.scope && !state.xy && spectrum_rows >= 2 * MIN_PHASE_ROWS {
+            spectrum_rows / 2
+        } else {
+            0
+        };
+        let magnitude_layout = Layout { waterfall_top: chart_bottom - phase_rows, ..layout };
+        match state.layout {
+            LayoutMode::Waterfall => (),
+            _ if state.xy && state.right.is_some() => self.draw_xy(state, layout),
+            _ if state.scope => self.draw_scope(state, layout),
+            _ if state.band_analysis.is_some() => self.draw_band_analysis(state, sample_rate, magnitude_layout),
+            _ if state.braille && !state.ascii => self.draw_braille(state, sample_rate, magnitude_layout),
+            _ if state.bars || state.braille => self.draw_bars(state, sample_rate, magnitude_layout),
+            _ => self.draw_chart(state, sample_rate, min_freq, max_freq, magnitude_layout),
+        }
+        if phase_rows > 0 {
+            self.draw_phase(state, sample_rate, magnitude_layout.waterfall_top, phase_rows, layout);
+        }
+
+        // Render waterfalls, left channel first in stereo, or under --waterfall-fps put back
+        // the rows last drawn until they are due again
+        let cursor = state.cursor_column(width);
+        let rows = layout.waterfall_top..layout.waterfall_top + layout.history_rows;
+        let due = match (state.waterfall_fps, self.waterfall_drawn) {
+            (Some(fps), Some((drawn, top))) => top != layout.waterfall_top
+                || self.waterfall_cache.len() != layout.history_rows
+                || drawn.elapsed() >= Duration::from_micros(1_000_000 / fps),
+            _ => true,
+        };
+        if due {
+            self.draw_waterfall(state, &state.waterfall_data, &state.waterfall_phase, left, sample_rate, layout);
+            if let Some(right) = &state.right {
+                self.draw_waterfall(state, &right.waterfall_data, &[], layout.panel_left(1), sample_rate, layout);
+            }
+            if state.waterfall_fps.is_some() {
+                self.waterfall_cache = self.back_buffer.cells[rows].to_vec();
+                self.waterfall_drawn = Some((Instant::now(), layout.waterfall_top));
+            }
+        } else {
+            self.back_buffer.cells[rows].clone_from_slice(&self.waterfall_cache);
+        }
+
+        self.draw_axes(state, sample_rate, layout);
+        self.draw_legend(state, layout);
+        self.draw_annotations(state, sample_rate, layout);
+
+        // Bandwidth edges, a mouse selection and the cursor as lines through the blank parts of the chart
+        let edges = state.bandwidth.then(|| state.peak_bandwidth()).flatten();
+        let markers: Vec<_> = edges.into_iter()
+            .flat_map(|(low, high)| [low, high])
+            .filter_map(|freq| state.freq_column(freq, width, sample_rate))
+            .chain(state.selection.into_iter().flat_map(|(start, end)| [start, end]))
+            .map(|column| (column, state.marker_color()))
+            .chain(cursor.map(|column| (column, state.highlight_color())))
+            .collect();
+        for (column, fg_color) in markers.into_iter().filter(|_| !state.scope && !state.xy) {
+            let x = left + column;
+            for y in HEADER_ROWS..layout.waterfall_top {
+                if self.back_buffer.cells[y][x].char == ' ' {
+                    self.set_cell(x, y, ScreenCell { char: state.cursor_char(), fg_color, bg_color: None });
+                }
+            }
+        }
+        if !state.scope && !state.xy {
+            self.draw_bands(state, sample_rate, layout);
+            self.draw_peak_tracks(state, sample_rate, magnitude_layout);
+        }
+        // Last, so no line through the chart crosses it
+        if let Some(y) = marquee_row {
+            self.draw_marquee(state, sample_rate, y, layout);
+        }
+    }
+
+    // The whole band as one row of bars in the spectrum's colors, with the part on screen on a
+    // dim background between brackets
+    fn draw_marquee(&mut self, state: &ViewState, sample_rate: u32, y: usize, layout: Layout) {
+        let width = layout.waterfall_width;
+        let (first, last) = state.marquee_window(width, sample_rate);
+        let window_bg = state.dim_color();
+        for column in 0..width {
+            let x = layout.waterfall_left + column;
+            let magnitude = state.marquee_magnitude(column, width, sample_rate);
+            let t = magnitude.map_or(0.0, |magnitude| state.intensity(magnitude));
+            let eighths = (t * 8.0).round() as usize;
+            let mut cell = if eighths > 0 { state.bar_cell(t, eighths) } else { ScreenCell::default() };
+            let inside = (first..=last).contains(&column);
+            if window_bg.is_none() && (column == first || column == last) {
+                cell.char = if column == first { '[' } else { ']' };
+            }
+            if inside {
+                cell.bg_color = window_bg;
+            }
+            self.set_cell(x, y, cell);
+        }
+    }
+
+    // Each tracked peak as a line through the blank parts of the chart, with its number and
+    // frequency along the separator row; a label that would run into the one before it is left out
+    fn draw_peak_tracks(&mut self, state: &ViewState, sample_rate: u32, layout: Layout) {
+        let (width, left) = (layout.waterfall_width, layout.waterfall_left);
+        if layout.waterfall_top <= HEADER_ROWS {
+            return;
+        }
+        let mut tracks: Vec<_> = state.peak_tracks.iter()
+            .filter_map(|track| Some((state.freq_column(track.freq, width, sample_rate)?, track)))
+            .collect();
+        tracks.sort_by_key(|&(column, _)| column);
+        let mut next_free = 0;
+        for (column, track) in tracks {
+            // A track waiting out a missed frame is drawn dim where it was last seen
+            let fg_color = if track.missed > 0 { state.dim_color() } else { state.marker_color() };
+            for y in HEADER_ROWS..layout.waterfall_top {
+                if self.back_buffer.cells[y][left + column].char == ' ' {
+                    self.set_cell(left + column, y, ScreenCell { char: state.cursor_char(), fg_color, bg_color: None });
+                }
+            }
+            let label = format!("{} {:.1} Hz", track.number, track.freq);
+            if column >= next_free && column + label.len() <= width {
+                self.write_str_colored(left + column, HEADER_ROWS - 1, &label, fg_color, None);
+                next_free = column + label.len() + 1;
+            }
+        }
+    }
+
+    // Numbered markers over the left waterfall, each with its frequency and age beside it,
+    // on the right unless that runs off the panel
+    fn draw_annotations(&mut self, state: &ViewState, sample_rate: u32, layout: Layout) {
+        let (width, left) = (layout.waterfall_width, layout.waterfall_left);
+        let line_secs = state.line_time().as_secs_f32();
+        for (i, annotation) in state.annotations.iter().enumerate() {
+            let Some(row) = state.annotation_row(annotation).filter(|&row| row < layout.history_rows) else { continue };
+            let Some(column) = state.freq_column(annotation.freq, width, sample_rate) else { continue };
+            let y = layout.waterfall_top + row;
+            let number = (i + 1).to_string();
+            let age = state.annotation_age(annotation) as f32 * line_secs;
+            let detail = format!("{:.1} Hz -{:.1}s", annotation.freq, age);
+            let column = column.min(width - number.len());
+            let after = column + number.len() + 1;
+            let x = if after + detail.len() <= width { after } else { column.saturating_sub(detail.len() + 1) };
+            self.write_str_colored(left + x, y, &detail, state.highlight_color(), None);
+            self.write_str_colored(left + column, y, &number, state.highlight_color(), None);
+        }
+    }
+
+    // Band edges as faint lines through the blank parts of the chart, and band names along
+    // the separator row. A band cut off by the view is labeled from the left edge; a label
+    // crowded past the end of its band by the one before it is left out.
+    fn draw_bands(&mut self, state: &ViewState, sample_rate: u32, layout: Layout) {
+        let (width, left) = (layout.waterfall_width, layout.waterfall_left);
+        let (min_freq, max_freq) = state.freq_range(sample_rate);
+        let mut visible: Vec<_> = state.bands.iter()
+            .filter(|band| band.end > min_freq && band.start < max_freq)
+            .map(|band| {
+                let start = state.freq_column(band.start, width, sample_rate);
+                let end = state.freq_column(band.end, width, sample_rate);
+                (start, end, band)
+            })
+            .collect();
+        visible.sort_by_key(|&(start, _, _)| start.unwrap_or(0));
+
+        let fg_color = state.dim_color();
+        let edge = if state.ascii { ':' } else { '┆' };
+        let mut next_free = 0;
+        for (start, end, band) in visible {
+            for x in start.into_iter().chain(end).map(|column| left + column) {
+                for y in HEADER_ROWS..layout.waterfall_top {
+                    if self.back_buffer.cells[y][x].char == ' ' {
+                        self.set_cell(x, y, ScreenCell { char: edge, fg_color, bg_color: None });
+                    }
+                }
+            }
+            let wanted = start.map_or(0, |start| start + 1);
+            let column = wanted.max(next_free);
+            if column >= width || (column > wanted && column > end.unwrap_or(width)) {
+                continue;
+            }
+            let label: String = band.name.chars().take(width - column).collect();
+            self.write_str_colored(left + column, HEADER_ROWS - 1, &label, fg_color, None);
+            next_free = column + label.chars().count() + 1;
+        }
+    }
+
+    // Boxed list of every binding over the middle of the screen, split into as many
+    // columns as the height requires
+    fn draw_help(&mut self, state: &ViewState) {
+        let entries: Vec<String> = BINDINGS.iter()
+            .map(|binding| format!("{:<HELP_KEY_COLS$}{}", binding.key_label(), binding.description))
+            .collect();
+        let max_rows = self.back_buffer.height.saturating_sub(2).max(1);
+        let columns = entries.len().div_ceil(max_rows);
+        let rows = entries.len().div_ceil(columns);
+        let column_width = entries.iter().map(|entry| entry.chars().count()).max().unwrap_or(0) + HELP_GAP_COLS;
+        let box_width = (columns * column_width + HELP_GAP_COLS + 1).min(self.back_buffer.width);
+        let box_height = (rows + 2).min(self.back_buffer.height);
+        let left = (self.back_buffer.width - box_width) / 2;
+        let top = (self.back_buffer.height - box_height) / 2;
+
+        self.draw_box(state, left, top, box_width, box_height);
+        let title = format!(" Key bindings ('?' to close) | Gain/zoom step: {} dB, {:.2}x ",
+                            state.gain_step_db, state.step);
+        self.write_str_at(left + HELP_GAP_COLS, top, &title);
+
+        for (i, entry) in entries.iter().enumerate() {
+            let x = left + HELP_GAP_COLS + (i / rows) * column_width;
+            let y = top + 1 + i % rows;
+            let room = (left + box_width - 1).saturating_sub(x);
+            let entry: String = entry.chars().take(room).collect();
+            self.write_str_at(x, y, &entry);
+        }
+    }
+
+    // Opaque bordered box; overlays write their text inside it afterwards
+    fn draw_box(&mut self, state: &ViewState, left: usize, top: usize, width: usize, height: usize) {
+        let (horizontal, vertical, corners) = if state.ascii { ('-', '|', ['+'; 4]) } else { ('─', '│', ['┌', '┐', '└', '┘']) };
+        for y in top..top + height {
+            for x in left..left + width {
+                let last_x = x + 1 == left + width;
+                let last_y = y + 1 == top + height;
+                let char = match (x == left, last_x, y == top, last_y) {
+                    (true, _, true, _) => corners[0],
+                    (_, true, true, _) => corners[1],
+                    (true, _, _, true) => corners[2],
+                    (_, true, _, true) => corners[3],
+                    (_, _, true, _) | (_, _, _, true) => horizontal,
+                    (true, _, _, _) | (_, true, _, _) => vertical,
+                    _ => ' ',
+                };
+                self.set_cell(x, y, ScreenCell { char, fg_color: None, bg_color: None });
+            }
+        }
+    }
+
+    // One boxed line centered on the waterfall
+    fn draw_banner(&mut self, state: &ViewState, message: &str, layout: Layout) {
+        let width = (message.chars().count() + 2 * HELP_GAP_COLS).min(self.back_buffer.width);
+        let left = (self.back_buffer.width - width) / 2;
+        let top = layout.waterfall_top + layout.history_rows.saturating_sub(3) / 2;
+        self.draw_box(state, left, top, width, 3);
+        let message: String = message.chars().take(width.saturating_sub(2 * HELP_GAP_COLS)).collect();
+        self.write_str_at(left + HELP_GAP_COLS, top + 1, &message);
+    }
+
+    // Timing overlay in the top right corner of the waterfall
+    fn draw_diagnostics(&mut self, state: &ViewState, layout: Layout) {
+        let Some(summary) = state.diagnostics.summary() else { return };
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let mut lines = vec![
+            format!("Frame:  {:6.2} ms of {:.2} ms", ms(summary.busy), ms(state.frame_time())),
+            format!("FFT:    {:6.2} ms", ms(summary.dsp)),
+            format!("Render: {:6.2} ms", ms(summary.render)),
+            format!("FPS:    {:6.1} of {}", summary.fps, state.fps),
+        ];
+        lines.push(format!("Output: {:6} bytes/frame", self.frame_bytes));
+        if let Some(flux) = state.flux {
+            let onset = state.onset_threshold.map_or(String::new(), |threshold| format!(", onset at {:.2}", threshold));
+            lines.push(format!("Flux:   {:6.2}{}", flux, onset));
+        }
+        // One input block is the least the capture can lag behind the sound
+        if let Some((frames, rate)) = state.input_block {
+            let fixed = if state.buffer_size.is_some() { ", fixed" } else { "" };
+            lines.push(format!("Input:  {:6} frames, {:.1} ms{}", frames, frames as f64 * 1000.0 / rate as f64, fixed));
+        }
+        let width = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0) + 2 * HELP_GAP_COLS;
+        let height = lines.len() + 2;
+        let right = layout.legend_left.unwrap_or(self.back_buffer.width);
+        let Some(left) = right.checked_sub(width) else { return };
+        let top = layout.waterfall_top;
+        self.draw_box(state, left, top, width, height);
+        self.write_str_at(left + HELP_GAP_COLS, top, " Diagnostics ");
+        for (i, line) in lines.iter().enumerate() {
+            self.write_str_at(left + HELP_GAP_COLS, top + 1 + i, line);
+        }
+    }
+
+    // One point per screen column, each the strongest of the bins under it
+    fn draw_chart(&mut self, state: &ViewState, sample_rate: u32, min_freq: f32, max_freq: f32, layout: Layout) {
+        let columns = self.back_buffer.width;
+        let line = state.shown_line();
+        let mut points = Vec::with_capacity(columns);
+        let mut peaks = Vec::with_capacity(columns);
+        let mut envelope = Vec::new();
+        let mut curve = Vec::new();
+        for j in 0..columns {
+            let freq = state.column_freq(j, columns, sample_rate);
+            let x = state.axis_position(freq);
+            if let Some(magnitude) = state.column_magnitude(line, j, columns, sample_rate) {
+                points.push((x, state.chart_value(magnitude, freq, sample_rate)));
+            }
+            if let Some(held) = state.column_peak_hold(j, columns, sample_rate) {
+                peaks.push((x, state.chart_value(held, freq, sample_rate)));
+            }
+            if let Some(max) = state.column_session_max(j, columns, sample_rate).filter(|_| state.session_max_enabled) {
+                envelope.push((x, state.chart_value(max, freq, sample_rate)));
+            }
+            if let Some(level) = state.column_reference_curve(j, columns, sample_rate) {
+                curve.push((x, state.chart_value(level, freq, sample_rate)));
+            }
+        }
+        // Alone the chart fills its rows; beside the waterfall it keeps to a compact strip
+        let rows = layout.waterfall_top - HEADER_ROWS;
+        let height = if layout.history_rows == 0 { (rows.saturating_sub(1) * 4).max(5) } else { 5 };
+        let (width, height) = (self.back_buffer.width as u32, height as u32);
+        let (left, right) = (state.axis_position(min_freq.max(f32::MIN_POSITIVE)), state.axis_position(max_freq));
+        // The session envelope and reference curve are plotted on their own so they can take
+        // colors of their own, and the grid goes behind all of them, so they share one level
+        // range fitted to everything shown
+        let held: &[(f32, f32)] = if state.peak_hold_enabled { &peaks } else { &[] };
+        let (bottom, top) = points.iter().chain(held).chain(&envelope).chain(&curve)
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(bottom, top), &(_, y)| (bottom.min(y), top.max(y)));
+        let (bottom, top) = if bottom <= top { (bottom, top) } else { (0.0, 0.0) };
+        self.fitted_range = (bottom < top).then_some(LevelRange { bottom, top });
+        // A locked range clips whatever falls outside it to its edges
+        let (bottom, top) = match state.level_range {
+            Some(range) => {
+                for (_, y) in points.iter_mut().chain(&mut peaks).chain(&mut envelope).chain(&mut curve) {
+                    *y = y.clamp(range.bottom, range.top);
+                }
+                (range.bottom, range.top)
+            }
+            None => (bottom, top),
+        };
+        let mut chart = Chart::new_with_y_range(width, height, left, right, bottom, top);
+        let spectrum_shape = match state.trace_style {
+            TraceStyle::Line => Shape::Lines(&points),
+            TraceStyle::Filled => Shape::Bars(&points),
+            TraceStyle::Stepped => Shape::Steps(&points),
+        };
+        let peak_shape = Shape::Points(&peaks);
+        let chart = chart.lineplot(&spectrum_shape);
+        let chart = if state.peak_hold_enabled { chart.lineplot(&peak_shape) } else { chart };
+        // Shapes only reach the canvas through figures(); Display prints the canvas as it is
+        chart.figures();
+        let spectrum_chart = chart.to_string();
+        for (i, line) in spectrum_chart.lines().take(rows).enumerate() {
+            self.write_str_at(0, HEADER_ROWS + i, line);
+        }
+        // The canvas comes out uncolored, so a chosen color goes onto the dots it drew
+        if let Some(fg_color) = state.trace_color.and_then(|_| state.trace_fg(0.0)) {
+            for y in HEADER_ROWS..HEADER_ROWS + spectrum_chart.lines().take(rows).count() {
+                for x in 0..columns {
+                    let cell = &mut self.back_buffer.cells[y][x];
+                    if ('\u{2801}'..='\u{28ff}').contains(&cell.char) {
+                        cell.fg_color = Some(fg_color);
+                    }
+                }
+            }
+        }
+
+        let dims = ((width, height), (left, right), (bottom, top), rows);
+        self.draw_chart_overlay(&curve, dims, state.curve_color());
+        self.draw_chart_overlay(&envelope, dims, state.dim_color());
+        self.draw_chart_grid(state, sample_rate, (width, height), (left, right), (bottom, top), rows);
+    }
+
+    // A line plotted on the chart's canvas whose dots fill only the cells the trace left blank
+    fn draw_chart_overlay(&mut self, points: &[(f32, f32)], dims: ChartDims, fg_color: Option<Color>) {
+        if points.is_empty() {
+            return;
+        }
+        let ((width, height), (left, right), (bottom, top), rows) = dims;
+        let mut chart = Chart::new_with_y_range(width, height, left, right, bottom, top);
+        let shape = Shape::Lines(points);
+        let chart = chart.lineplot(&shape);
+        chart.figures();
+        for (i, line) in chart.to_string().lines().take(rows).enumerate() {
+            let y = HEADER_ROWS + i;
+            for (x, char) in line.chars().enumerate() {
+                let blank = self.back_buffer.cells[y].get(x).is_some_and(|cell| cell.char == '\u{2800}');
+                if blank && ('\u{2801}'..='\u{28ff}').contains(&char) {
+                    self.set_cell(x, y, ScreenCell { char, fg_color, bg_color: None });
+                }
+            }
+        }
+    }
+
+    // Faint lines at round levels and frequencies through the cells the plots left blank,
+    // spaced for the range on screen. Levels are labeled beside the chart's own end labels
+    // and frequencies along its bottom line, wherever they fit.
+    fn draw_chart_grid(&mut self, state: &ViewState, sample_rate: u32, (width, height): (u32, u32),
+                       (left, right): (f32, f32), (bottom, top): (f32, f32), rows: usize) {
+        // Braille cells are two dots wide and four tall, and the plots reach both edge dots
+        let (columns, canvas_rows) = (width as usize / 2 + 1, height as usize / 4 + 1);
+        if right <= left || top <= bottom || canvas_rows < 3 {
+            return;
+        }
+        let (horizontal, vertical, crossing) = if state.ascii { ('-', ':', '+') } else { ('┈', '┊', '┼') };
+        let fg_color = state.dim_color();
+
+        let target = (canvas_rows / CHART_GRID_ROWS).max(1);
+        let step = nice_step((top - bottom) / target as f32);
+        let decimals = (-step.log10().floor()).max(0.0) as usize;
+        let mut levels = Vec::new();
+        for level in ((bottom / step).ceil() as i32..).map(|k| k as f32 * step).take_while(|&level| level <= top) {
+            let dot = height as f32 - ((level - bottom) / (top - bottom) * height as f32).round();
+            let row = dot as usize / 4;
+            // The first and last rows already carry the chart's own labels
+            if row > 0 && row + 1 < canvas_rows.min(rows) && levels.last().is_none_or(|&(last, _)| last != row) {
+                levels.push((row, format!("{:.*}", decimals, level)));
+            }
+        }
+        let mut freqs = Vec::new();
+        for freq in state.freq_ticks(sample_rate, (columns / FREQ_TICK_COLS).max(1)) {
+            let dot = ((state.axis_position(freq) - left) / (right - left) * width as f32).round();
+            let column = dot as usize / 2;
+            // A line on the left edge would only hide the lowest bins
+            if (1..columns).contains(&column) {
+                freqs.push((column, format_freq_tick(freq)));
+            }
+        }
+
+        for i in 0..canvas_rows.min(rows) {
+            let y = HEADER_ROWS + i;
+            let on_level = levels.iter().any(|&(row, _)| row == i);
+            for x in 0..columns {
+                let on_freq = freqs.iter().any(|&(column, _)| column == x);
+                let char = match (on_level, on_freq) {
+                    (true, true) => crossing,
+                    (true, false) => horizontal,
+                    (false, true) => vertical,
+                    (false, false) => continue,
+                };
+                if self.back_buffer.cells[y].get(x).is_some_and(|cell| cell.char == '\u{2800}') {
+                    self.set_cell(x, y, ScreenCell { char, fg_color, bg_color: None });
+                }
+            }
+        }
+        for (row, label) in levels {
+            self.write_label_if_clear(columns + 1, HEADER_ROWS + row, &label, fg_color);
+        }
+        // Below the chart if its own axis line has room, else along its bottom row
+        let label_row = HEADER_ROWS + canvas_rows.min(rows - 1);
+        for (column, label) in freqs {
+            self.write_label_if_clear(column + 1, label_row, &label, fg_color);
+        }
+    }
+
+    // Writes `label` only over blank cells, with a blank cell after it
+    fn write_label_if_clear(&mut self, x: usize, y: usize, label: &str, fg_color: Option<Color>) {
+        let Some(cells) = self.back_buffer.cells.get(y) else { return };
+        let end = x + label.chars().count();
+        let blank = |cell: &ScreenCell| cell.char == ' ' || cell.char == '\u{2800}';
+        if end > cells.len() || !cells[x..(end + 1).min(cells.len())].iter().all(blank) {
+            return;
+        }
+        self.write_str_colored(x, y, label, fg_color, None);
+    }
+
+    // The trace across the full width, scaled to its own peak
+    fn draw_scope(&mut self, state: &ViewState, layout: Layout) {
+        let trace = state.scope_trace();
+        if trace.len() < 2 {
+            return;
+        }
+        let points: Vec<(f32, f32)> = trace.iter().enumerate().map(|(i, &sample)| (i as f32, sample)).collect();
+        let peak = trace.iter().fold(f32::MIN_POSITIVE, |peak, sample| peak.max(sample.abs()));
+        let rows = layout.waterfall_top - HEADER_ROWS;
+        let height = (rows.saturating_sub(1) * 4).max(5);
+        let width = (self.back_buffer.width * 2).max(32);
+        let mut chart = Chart::new_with_y_range(width as u32, height as u32, 0.0, (trace.len() - 1) as f32, -peak, peak);
+        let shape = Shape::Lines(&points);
+        let chart = chart.lineplot(&shape);
+        chart.figures();
+        let scope_chart = chart.to_string();
+        for (i, line) in scope_chart.lines().take(rows).enumerate() {
+            self.write_str_at(0, HEADER_ROWS + i, line);
+        }
+    }
+
+    // Sample pairs as braille dots in a square twice as many columns wide as it is rows tall,
+    // scaled to the louder channel; a mono signal lies on the rising diagonal
+    fn draw_xy(&mut self, state: &ViewState, layout: Layout) {
+        let rows = layout.waterfall_top - HEADER_ROWS;
+        let cols = (rows * 2).min(self.back_buffer.width);
+        let (dots_x, dots_y) = (cols * 2, rows * 4);
+        if rows == 0 || cols == 0 {
+            return;
+        }
+        let peak = state.xy_pairs().fold(f32::MIN_POSITIVE, |peak, (l, r)| peak.max(l.abs()).max(r.abs()));
+        let mut cells = vec![0u8; rows * cols];
+        for (left, right) in state.xy_pairs() {
+            let x = (((left / peak + 1.0) / 2.0 * (dots_x - 1) as f32).round() as usize).min(dots_x - 1);
+            let y = (((1.0 - right / peak) / 2.0 * (dots_y - 1) as f32).round() as usize).min(dots_y - 1);
+            cells[(y / 4) * cols + x / 2] |= BRAILLE_DOTS[x % 2][y % 4];
+        }
+        let offset = (self.back_buffer.width - cols) / 2;
+        for (i, &bits) in cells.iter().enumerate() {
+            let char = match bits {
+                0 => continue,
+                _ if state.ascii => '*',
+                _ => char::from_u32(0x2800 + bits as u32).unwrap_or('*'),
+            };
+            self.back_buffer.cells[HEADER_ROWS + i / cols][offset + i % cols].char = char;
+        }
+    }
+
+    // One bar per waterfall column, aligned with it; each row holds eight steps of height
+    fn draw_bars(&mut self, state: &ViewState, sample_rate: u32, layout: Layout) {
+        let width = layout.waterfall_width;
+        let rows = layout.waterfall_top - HEADER_ROWS;
+        let line = state.shown_line();
+        for j in 0..width {
+            let Some(magnitude) = state.column_magnitude(line, j, width, sample_rate) else { continue };
+            let x = layout.waterfall_left + j;
+            let eighths = self.draw_bar(state, x, layout.waterfall_top, rows, state.intensity(magnitude));
+
+            // The session maximum sits as a dim line at the row it reached, under any held peak
+            if state.session_max_enabled {
+                let max = state.column_session_max(j, width, sample_rate).unwrap_or(0.0);
+                let max_row = (state.intensity(max) * rows as f32) as usize;
+                if max_row > 0 && max_row * 8 > eighths {
+                    let y = layout.waterfall_top - max_row.min(rows);
+                    let char = if state.ascii { '-' } else { '▔' };
+                    self.set_cell(x, y, ScreenCell { char, fg_color: state.dim_color(), bg_color: None });
+                }
+            }
+
+            // The reference curve the same way, in its own color
+            if let Some(level) = state.column_reference_curve(j, width, sample_rate) {
+                let curve_row = (state.intensity(level) * rows as f32) as usize;
+                if curve_row > 0 && curve_row * 8 > eighths {
+                    let y = layout.waterfall_top - curve_row.min(rows);
+                    let char = if state.ascii { '-' } else { '▔' };
+                    self.set_cell(x, y, ScreenCell { char, fg_color: state.curve_color(), bg_color: None });
+                }
+            }
+
+            // Held peaks sit as a thin line at the row they reached
+            if state.peak_hold_enabled {
+                let held = state.column_peak_hold(j, width, sample_rate).unwrap_or(0.0);
+                let held_row = (state.intensity(held) * rows as f32) as usize;
+                if held_row > 0 && held_row * 8 > eighths {
+                    let y = layout.waterfall_top - held_row.min(rows);
+                    let char = if state.ascii { '-' } else { '▔' };
+                    self.set_cell(x, y, ScreenCell { char, fg_color: state.highlight_color(), bg_color: None });
+                }
+            }
+        }
+    }
+
+    // --bands octave or third-octave: a bar per band, evenly spaced across the chart whatever
+    // the frequency axis, over its nominal center on the bottom row. Labels that would run
+    // into the one before are left out.
+    fn draw_band_analysis(&mut self, state: &ViewState, sample_rate: u32, layout: Layout) {
+        let bands = state.band_analysis_levels(sample_rate);
+        let label_row = layout.waterfall_top - 1;
+        let rows = label_row.saturating_sub(HEADER_ROWS);
+        let slot = layout.waterfall_width / bands.len().max(1);
+        if slot == 0 || rows == 0 {
+            return;
+        }
+        let bar_width = slot.saturating_sub(1).max(1);
+        let fg_color = state.dim_color();
+        let mut next_free = 0;
+        for (i, (band, level)) in bands.iter().enumerate() {
+            let x = layout.waterfall_left + i * slot;
+            if let Some(level) = level {
+                for column in x..x + bar_width {
+                    self.draw_bar(state, column, label_row, rows, state.intensity(*level));
+                }
+            }
+            let label = if band.center >= 1000.0 {
+                format!("{}k", band.center / 1000.0)
+            } else {
+                format!("{}", band.center)
+            };
+            let start = (x + bar_width / 2).saturating_sub(label.len() / 2).max(layout.waterfall_left);
+            if start >= next_free && start + label.len() <= layout.waterfall_left + layout.waterfall_width {
+                self.write_str_colored(start, label_row, &label, fg_color, None);
+                next_free = start + label.len() + 1;
+            }
+        }
+    }
+
+    // The spectrum as a line of braille dots, two across and four down per cell over the
+    // waterfall's columns, each cell colored by the strongest dot in it. Held peaks and the
+    // reference curve are single dots, in their own colors where the trace leaves a cell empty.
+    fn draw_braille(&mut self, state: &ViewState, sample_rate: u32, layout: Layout) {
+        let rows = layout.waterfall_top - HEADER_ROWS;
+        let (columns, dot_rows) = (layout.waterfall_width * 2, rows * 4);
+        if columns == 0 || dot_rows == 0 {
+            return;
+        }
+        let line = state.shown_line();
+        // Trace dots, held peak dots, reference curve dots and the strongest trace level, per cell
+        let mut cells = vec![(0u8, 0u8, 0u8, 0.0f32); layout.waterfall_width * rows];
+        let dot_row = |t: f32| dot_rows - 1 - (t * (dot_rows - 1) as f32).round() as usize;
+        enum Dot {
+            Trace(f32),
+            Held,
+            Curve,
+        }
+        let set = |cells: &mut [(u8, u8, u8, f32)], column: usize, row: usize, dot: Dot| {
+            let cell = &mut cells[row / 4 * layout.waterfall_width + column / 2];
+            let bit = BRAILLE_DOTS[column % 2][row % 4];
+            match dot {
+                Dot::Trace(t) => {
+                    cell.0 |= bit;
+                    cell.3 = cell.3.max(t);
+                }
+                Dot::Held => cell.1 |= bit,
+                Dot::Curve => cell.2 |= bit,
+            }
+        };
+
+        // Each column's dots run from the previous column's level to its own, so the trace stays joined
+        let mut previous = None;
+        for column in 0..columns {
+            let Some(magnitude) = state.column_magnitude(line, column, columns, sample_rate) else { continue };
+            let t = state.intensity(magnitude);
+            let row = dot_row(t);
+            let (from, to) = match previous {
+                _ if state.trace_style == TraceStyle::Filled => (row, dot_rows - 1),
+                Some(last) if last < row => (last + 1, row),
+                Some(last) if last > row => (row, last - 1),
+                _ => (row, row),
+            };
+            for row in from..=to {
+                set(&mut cells, column, row, Dot::Trace(t));
+            }
+            previous = Some(row);
+            if state.peak_hold_enabled {
+                let held = state.column_peak_hold(column, columns, sample_rate).unwrap_or(0.0);
+                set(&mut cells, column, dot_row(state.intensity(held)), Dot::Held);
+            }
+            if let Some(level) = state.column_reference_curve(column, columns, sample_rate) {
+                set(&mut cells, column, dot_row(state.intensity(level)), Dot::Curve);
+            }
+        }
+
+        for (i, &(trace, held, curve, t)) in cells.iter().enumerate() {
+            let bits = trace | held | curve;
+            if bits == 0 {
+                continue;
+            }
+            let fg_color = match (trace, held) {
+                (0, 0) => state.curve_color(),
+                (0, _) => state.highlight_color(),
+                _ => state.trace_fg(t),
+            };
+            let char = char::from_u32(0x2800 + bits as u32).unwrap();
+            let (x, y) = (layout.waterfall_left + i % layout.waterfall_width, HEADER_ROWS + i / layout.waterfall_width);
+            self.set_cell(x, y, ScreenCell { char, fg_color, bg_color: None });
+        }
+    }
+
+    // Unwrapped phase of the latest frame as braille dots over the waterfall's columns, under a
+    // row giving its range; each column shows the strongest bin under it that passes the gate
+    fn draw_phase(&mut self, state: &ViewState, sample_rate: u32, top: usize, rows: usize, layout: Layout) {
+        let (width, left) = (layout.waterfall_width, layout.waterfall_left);
+        let unwrapped = state.unwrapped_phase();
+        let columns = width * 2;
+        let values: Vec<Option<f32>> = (0..columns)
+            .map(|column| state.column_unwrapped_phase(&unwrapped, column, columns, sample_rate))
+            .collect();
+        let (low, high) = values.iter().flatten()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(low, high), &phase| (low.min(phase), high.max(phase)));
+        let fg_color = state.dim_color();
+        if low > high {
+            let label = format!("Phase: no bins within {:.0} dB of the peak", -state.phase_gate_db);
+            self.write_str_colored(left, top, &label, fg_color, None);
+            return;
+        }
+        let degrees = if state.ascii { " deg" } else { "°" };
+        let label = format!("Phase, unwrapped ({:.0}{} to {:.0}{}, bins within {:.0} dB of the peak)",
+                            low.to_degrees(), degrees, high.to_degrees(), degrees, -state.phase_gate_db);
+        self.write_str_colored(left, top, &label, fg_color, None);
+
+        // A flat phase still needs some span to sit in the middle of
+        let (low, high) = if high - low < 1e-3 { (low - 1.0, high + 1.0) } else { (low, high) };
+        let dot_rows = (rows - 1) * 4;
+        let mut cells = vec![0u8; width * (rows - 1)];
+        for (column, phase) in values.iter().enumerate() {
+            let Some(phase) = phase else { continue };
+            let row = ((high - phase) / (high - low) * (dot_rows - 1) as f32).round() as usize;
+            cells[row / 4 * width + column / 2] |= BRAILLE_DOTS[column % 2][row % 4];
+        }
+        let fg_color = state.trace_fg(1.0);
+        for (i, &bits) in cells.iter().enumerate() {
+            let char = match bits {
+                0 => continue,
+                _ if state.ascii => '*',
+                _ => char::from_u32(0x2800 + bits as u32).unwrap_or('*'),
+            };
+            self.set_cell(left + i % width, top + 1 + i / width, ScreenCell { char, fg_color, bg_color: None });
+        }
+    }
+
+    // Vertical bar of height `t` (0..1 of `rows`) standing on the row above `bottom`;
+    // returns its height in eighths of a row
+    fn draw_bar(&mut self, state: &ViewState, x: usize, bottom: usize, rows: usize, t: f32) -> usize {
+        let eighths = (t * (rows * 8) as f32).round() as usize;
+        for row in 0..rows {
+            let fill = eighths.saturating_sub(row * 8).min(8);
+            if fill > 0 {
+                self.set_cell(x, bottom - 1 - row, state.bar_cell(t, fill));
+            }
+        }
+        eighths
+    }
+
+    // Raw input level: the RMS as a bar with the peak as a tick past it, and a clip warning
+    // that stays until reset
+    fn draw_input_meter(&mut self, state: &ViewState) {
+        let dbfs = |level: f32| 20.0 * level.max(1e-6).log10();
+        let column = |level: f32| {
+            let t = (dbfs(level) - INPUT_METER_FLOOR_DB) / -INPUT_METER_FLOOR_DB;
+            (t.clamp(0.0, 1.0) * INPUT_METER_COLS as f32).round() as usize
+        };
+        let (rms, peak) = (column(state.input_rms), column(state.input_peak));
+        let (fill, tick, empty) = if state.ascii { ('#', '|', '.') } else { ('█', '┃', '·') };
+
+        let label = "Input: ";
+        self.write_str_at(0, 3, label);
+        let left = label.len();
+        for i in 0..INPUT_METER_COLS {
+            let db = INPUT_METER_FLOOR_DB * (1.0 - (i + 1) as f32 / INPUT_METER_COLS as f32);
+            let color = match db {
+                _ if state.ascii => None,
+                db if db > INPUT_METER_RED_DB => state.color_mode.quantize(Color::Red),
+                db if db > INPUT_METER_YELLOW_DB => state.color_mode.quantize(Color::Yellow),
+                _ => state.color_mode.quantize(Color::Green),
+            };
+            let cell = match i {
+                i if i < rms => ScreenCell { char: fill, fg_color: color, bg_color: None },
+                i if i + 1 == peak => ScreenCell { char: tick, fg_color: color, bg_color: None },
+                _ => ScreenCell { char: empty, fg_color: state.dim_color(), bg_color: None },
+            };
+            self.set_cell(left + i, 3, cell);
+        }
+
+        let readout = format!(" Peak: {:.1} dBFS | RMS: {:.1} dBFS",
+                              dbfs(state.input_peak), dbfs(state.input_rms));
+        self.write_str_at(left + INPUT_METER_COLS, 3, &readout);
+        if state.input_clipped {
+            let x = left + INPUT_METER_COLS + readout.chars().count();
+            self.write_str_at(x, 3, " | ");
+            self.write_str_colored(x + 3, 3, "CLIP", state.color_mode.quantize(Color::Red), None);
+        }
+    }
+
+    // Goertzel monitor: a horizontal meter in place of the spectrum and a strip chart of
+    // recent levels, newest on the right, in place of the waterfall
+    fn draw_monitor(&mut self, state: &ViewState, freq: f32, layout: Layout) {
+        let level = state.monitor_levels.back().copied().unwrap_or(0.0);
+        self.write_str_at(0, 1, &format!("Monitor: {:.1} Hz {} ({})", freq, Note::nearest(freq).name(), state.format_level(level)));
+
+        let left = layout.waterfall_left;
+        let width = layout.waterfall_width;
+        let t = state.intensity(level);
+        let eighths = (t * (width * 8) as f32).round() as usize;
+        for y in HEADER_ROWS..layout.waterfall_top {
+            for column in 0..width {
+                let fill = eighths.saturating_sub(column * 8).min(8);
+                if fill > 0 {
+                    self.set_cell(left + column, y, state.meter_cell(t, fill));
+                }
+            }
+        }
+
+        let bottom = layout.waterfall_top + layout.history_rows;
+        let recent = state.monitor_levels.iter().rev().take(width);
+        for (age, &level) in recent.enumerate() {
+            self.draw_bar(state, left + width - 1 - age, bottom, layout.history_rows, state.intensity(level));
+        }
+
+        // Decoded CW takes the row the frequency axis would, newest text at the right
+        if let Some(cw) = &state.cw {
+            let threshold = cw.threshold_db().map_or("--".to_string(), |db| format!("{:.0} dB", db));
+            let key = if cw.key_down() { "DOWN" } else { "up" };
+            let label = format!("CW {:.0} WPM, key {} at {}: ", cw.wpm(), key, threshold);
+            let room = self.back_buffer.width.saturating_sub(label.chars().count());
+            let text: Vec<char> = cw.text().chars().collect();
+            let shown: String = text[text.len().saturating_sub(room)..].iter().collect();
+            self.write_str_at(0, layout.freq_axis_row, &format!("{}{}", label, shown));
+        }
+    }
+
+    // Tuner: the nearest note in large type over a meter of how far off it is, centered
+    // in everything below the header; short terminals get only the meter
+    fn draw_tuner(&mut self, state: &ViewState) {
+        let width = self.back_buffer.width;
+        let rows = self.back_buffer.height.saturating_sub(HEADER_ROWS);
+        let note = state.find_peak().map(|peak| (peak.freq, Note::nearest(peak.freq)));
+        let big = rows >= TUNER_ROWS;
+        let used = if big { TUNER_ROWS } else { TUNER_ROWS - BIG_GLYPH_ROWS - 1 };
+        let mut y = HEADER_ROWS + rows.saturating_sub(used) / 2;
+
+        if big {
+            let text = note.map_or("--".to_string(), |(_, note)| note.name());
+            self.draw_big_text(state, &text, y);
+            y += BIG_GLYPH_ROWS + 1;
+        }
+        let caption = match note {
+            Some((freq, _)) => format!("{} at {:.1} Hz", state.note_label(freq), freq),
+            None => "No clear pitch".to_string(),
+        };
+        self.write_str_at(width.saturating_sub(caption.chars().count()) / 2, y, &caption);
+        y += 2;
+
+        // -50 to +50 cents across the meter, in tune at its center mark
+        let half = (TUNER_METER_COLS / 2).min(width.saturating_sub(2) / 2);
+        let left = width.saturating_sub(2 * half + 1) / 2;
+        let (line, center, marker) = if state.ascii { ('-', '+', '#') } else { ('─', '┼', '█') };
+        for x in 0..=2 * half {
+            let char = if x == half { center } else { line };
+            self.set_cell(left + x, y, ScreenCell { char, ..Default::default() });
+        }
+        if let Some((_, note)) = note {
+            let offset = (note.cents / 50.0 * half as f32).round() as isize;
+            let x = half.saturating_add_signed(offset).min(2 * half);
+            self.set_cell(left + x, y, ScreenCell { char: marker, fg_color: state.highlight_color(), bg_color: None });
+        }
+        self.write_str_at(left, y + 1, "-50");
+        self.write_str_at(left + half, y + 1, "0");
+        self.write_str_at((left + 2 * half + 1).saturating_sub(3), y + 1, "+50");
+    }
+
+    // Text in the 3x5 block font, centered across the screen starting at row `top`
+    fn draw_big_text(&mut self, state: &ViewState, text: &str, top: usize) {
+        let fill = if state.ascii { '#' } else { '█' };
+        let glyphs: Vec<_> = text.chars()
+            .filter_map(|c| BIG_FONT.iter().find(|(glyph, _)| *glyph == c).map(|(_, rows)| rows))
+            .collect();
+        let text_cols = (glyphs.len() * (BIG_GLYPH_COLS + 1)).saturating_sub(1);
+        let left = self.back_buffer.width.saturating_sub(text_cols) / 2;
+        for (i, rows) in glyphs.iter().enumerate() {
+            let x = left + i * (BIG_GLYPH_COLS + 1);
+            for (row, pattern) in rows.iter().enumerate() {
+                let line: String = pattern.chars().map(|c| if c == '#' { fill } else { ' ' }).collect();
+                self.write_str_at(x, top + row, &line);
+            }
+        }
+    }
+
+    // `phases` holds the lines' bin phases under the phase colormap, and is empty otherwise
+    fn draw_waterfall(&mut self, state: &ViewState, data: &[Vec<(f32, f32)>], phases: &[Vec<f32>], left: usize,
+                      sample_rate: u32, layout: Layout) {
+        let width = layout.waterfall_width;
+        let cursor = state.cursor_column(width);
+        let lines = state.lines_per_row();
+        let gate = state.gate_thresholds(width, sample_rate);
+        // Gated bins have no intensity at all, so they never reach the colormap
+        let intensity = |magnitude: f32, column: usize| {
+            gate.as_ref().is_none_or(|gate| magnitude >= gate[column]).then(|| state.waterfall_intensity(magnitude))
+        };
+        let phase = |index: usize, column: usize| {
+            phases.get(index).and_then(|line| state.column_phase(&data[index], line, column, width, sample_rate))
+        };
+        for i in 0..(state.visible_lines / lines).min(layout.history_rows) {
+            let index = state.history_index(i * lines);
+            let points = &state.waterfall_line(&data[index]);
+            let lower = (lines == 2).then(|| state.history_index(i * lines + 1))
+                .map(|lower_index| (lower_index, state.waterfall_line(&data[lower_index])));
+
+            for j in 0..width {
+                if let Some(magnitude) = state.column_magnitude(points, j, width, sample_rate) {
+                    let mut cell = if let Some((lower_index, lower_points)) = &lower {
+                        let lower = state.column_magnitude(lower_points, j, width, sample_rate).unwrap_or(0.0);
+                        state.packed_waterfall_cell(intensity(magnitude, j).map(|t| (t, phase(index, j))),
+                                                    intensity(lower, j).map(|t| (t, phase(*lower_index, j))))
+                    } else {
+                        intensity(magnitude, j)
+                            .map_or_else(ScreenCell::default, |t| state.phase_waterfall_cell(t, phase(index, j)))
+                    };
+                    if cursor == Some(j) {
+                        cell.char = state.cursor_char();
+                        cell.fg_color = state.highlight_color();
+                    }
+                    self.set_cell(left + j, layout.waterfall_top + i, cell);
+                }
+            }
+        }
+    }
+
+    // Colormap strip beside the waterfall, brightest at the top, built from the same
+    // intensity-to-cell mapping so the two always agree
+    pub fn draw_legend(&mut self, state: &ViewState, layout: Layout) {
+        let Some(left) = layout.legend_left else { return };
+        let rows = layout.history_rows;
+        for row in 0..rows {
+            let t = 1.0 - row as f32 / (rows - 1).max(1) as f32;
+            let cell = state.waterfall_cell(t);
+            for x in 0..LEGEND_SWATCH_COLS {
+                self.set_cell(left + 1 + x, layout.waterfall_top + row, cell.clone());
+            }
+            if row % TIME_TICK_ROWS == 0 || row == rows - 1 {
+                let label = state.format_level(state.waterfall_magnitude(t));
+                self.write_str_at(left + 2 + LEGEND_SWATCH_COLS, layout.waterfall_top + row, &label);
+            }
+        }
+    }
+
+    // Frequency ticks under the waterfall and frame ages down its left side
+    pub fn draw_axes(&mut self, state: &ViewState, sample_rate: u32, layout: Layout) {
+        let width = layout.waterfall_width;
+        let ticks = state.freq_ticks(sample_rate, (width / FREQ_TICK_COLS).max(1));
+        for panel in 0..layout.panels {
+            let mut next_free = 0;
+            for &freq in &ticks {
+                let Some(column) = state.freq_column(freq, width, sample_rate) else { continue };
+                let label = format!("|{}", format_freq_tick(freq));
+                if column >= next_free && column + label.len() <= width {
+                    self.write_str_at(layout.panel_left(panel) + column, layout.freq_axis_row, &label);
+                    next_free = column + label.len() + 1;
+                }
+            }
+        }
+
+        if layout.waterfall_left == 0 {
+            return;
+        }
+        let line_secs = state.line_time().as_secs_f32();
+        let lines = state.lines_per_row();
+        let rows = (state.visible_lines / lines).min(layout.history_rows);
+        for row in (0..rows).filter(|&row| (state.row_age(row) / lines).is_multiple_of(TIME_TICK_ROWS)) {
+            let age = state.row_age(row) as f32 * line_secs;
+            let label = if age >= 100.0 { format!("-{:.0}s", age) } else { format!("-{:.1}s", age) };
+            let label = format!("{:>width$}", label, width = TIME_AXIS_COLS - 1);
+            self.write_str_at(0, layout.waterfall_top + row, &label);
+        }
+        // Onsets beside the rows they begin on, under the cursor's pointer
+        let marker = if state.ascii { "*" } else { "•" };
+        for row in state.onset_rows().filter(|&row| row < rows) {
+            self.write_str_colored(TIME_AXIS_COLS - 1, layout.waterfall_top + row, marker, state.marker_color(), None);
+        }
+        // The row the spectrum shows, pointed at from the axis
+        if let Some(row) = state.cursor_row.filter(|&row| row < rows && state.cursor_line_back().is_some()) {
+            let marker = if state.ascii { ">" } else { "▶" };
+            let y = layout.waterfall_top + row;
+            self.write_str_colored(TIME_AXIS_COLS - 1, y, marker, state.highlight_color(), None);
+        }
+    }
+
+    // Writes the changed cells. A run of them on a row needs only the one cursor move, as the
+    // terminal steps the cursor along after each character.
+    pub fn present(&mut self) -> Result<()> {
+        let out = &mut self.output;
+        out.clear();
+        let mut current_fg = None;
+        let mut current_bg = None;
+        let mut cursor = None;
+
+        for y in 0..self.back_buffer.height {
+            for x in 0..self.back_buffer.width {
+                let front_cell = &self.front_buffer.cells[y][x];
+                let back_cell = &self.back_buffer.cells[y][x];
+
+                if front_cell != back_cell {
+                    if cursor != Some((x, y)) {
+                        queue!(out, MoveTo(x as u16, y as u16))?;
+                    }
+
+                    // ResetColor clears both colors, so the other one may need setting again
+                    if (current_fg != back_cell.fg_color && back_cell.fg_color.is_none())
+                        || (current_bg != back_cell.bg_color && back_cell.bg_color.is_none()) {
+                        queue!(out, ResetColor)?;
+                        current_fg = None;
+                        current_bg = None;
+                    }
+                    if current_fg != back_cell.fg_color {
+                        if let Some(color) = back_cell.fg_color {
+                            queue!(out, SetForegroundColor(color))?;
+                        }
+                        current_fg = back_cell.fg_color;
+                    }
+                    if current_bg != back_cell.bg_color {
+                        if let Some(color) = back_cell.bg_color {
+                            queue!(out, SetBackgroundColor(color))?;
+                        }
+                        current_bg = back_cell.bg_color;
+                    }
+
+                    write!(out, "{}", back_cell.char)?;
+                    cursor = Some((x + 1, y));
+                }
+            }
+        }
+
+        // The next frame starts out assuming the default colors
+        if current_fg.is_some() || current_bg.is_some() {
+            queue!(out, ResetColor)?;
+        }
+        self.frame_bytes = out.len();
+        self.out.write_all(out)?;
+        self.out.flush()?;
+        std::mem::swap(&mut self.front_buffer, &mut self.back_buffer);
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for Renderer<W> {
+    fn drop(&mut self) {
+        if self.terminal {
+            restore_terminal();
+        }
+    }
+}
+
+// The full-screen view needs stdout on a terminal that can move the cursor; redirected into a
+// file it would only fill it with escape codes
+pub fn check_terminal() -> Result<()> {
+    if !stdout().is_terminal() {
+        bail!("stdout is not a terminal; use --json-out, --stream-udp or --ipc-socket to run without one");
+    }
+    if std::env::var("TERM").is_ok_and(|term| term == "dumb") {
+        bail!("TERM=dumb cannot show the full-screen view; use a real terminal, or --json-out to run without one");
+    }
+    Ok(())
+}
+
+// Undoes everything `Renderer::new` did to the terminal; safe to call more than once
+pub fn restore_terminal() {
+    let _ = execute!(stdout(), ResetColor, Show, DisableMouseCapture, LeaveAlternateScreen);
+    let _ = disable_raw_mode();
+}
+
+// Restores the terminal before the default hook prints the panic message and backtrace
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        default_hook(info);
+    }));
+}
+
+// Set on SIGINT or SIGTERM. Loops poll it and leave the way 'q' does, so the terminal is
+// restored by the same `Drop` as a normal exit rather than from the handler
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// A run time, as from --duration, ends the run the same way Ctrl-C does once it is up, so
+// every loop stops and tears down through the same path
+pub fn install_interrupt_handler(run_for: Option<Duration>) -> Result<()> {
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::Relaxed))
+        .map_err(|e| anyhow::anyhow!("Failed to install the interrupt handler: {}", e))?;
+    if let Some(run_for) = run_for {
+        thread::spawn(move || {
+            thread::sleep(run_for);
+            INTERRUPTED.store(true, Ordering::Relaxed);
+        });
+    }
+    Ok(())
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+// Status, readouts, statistics, input meter, spectrum title and separator
+const HEADER_ROWS: usize = 6;
+// A textplots chart's canvas size in dots, its frequency and level ranges, and the rows it may fill
+type ChartDims = ((u32, u32), (f32, f32), (f32, f32), usize);
+const SPECTRUM_ROWS: usize = 11;
+const MIN_SPECTRUM_ROWS: usize = 2;
+// Label and dots of the phase panel, which only appears where the magnitude keeps as many
+const MIN_PHASE_ROWS: usize = 3;
+const MIN_WATERFALL_ROWS: usize = 3;
+const FREQ_AXIS_ROWS: usize = 1;
+pub const MIN_TERMINAL_ROWS: usize = HEADER_ROWS + MIN_SPECTRUM_ROWS + MIN_WATERFALL_ROWS + FREQ_AXIS_ROWS;
+const TIME_AXIS_COLS: usize = 7;
+const MIN_WATERFALL_COLS: usize = 32;
+// The input meter spans -60..0 dBFS, turning yellow and then red toward full scale
+const INPUT_METER_COLS: usize = 30;
+const INPUT_METER_FLOOR_DB: f32 = -60.0;
+const INPUT_METER_YELLOW_DB: f32 = -12.0;
+const INPUT_METER_RED_DB: f32 = -3.0;
+// Gap, two-cell swatch, gap and a label as wide as "-90.0 dB"
+const LEGEND_COLS: usize = 12;
+const LEGEND_SWATCH_COLS: usize = 2;
+const PANEL_GAP_COLS: usize = 1;
+// Rough spacing between labeled ticks on the waterfall axes
+const FREQ_TICK_COLS: usize = 12;
+// Rough spacing between level gridlines on the spectrum chart
+const CHART_GRID_ROWS: usize = 3;
+// Bit of each dot in a braille cell, by column then row
+const BRAILLE_DOTS: [[u8; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+const TIME_TICK_ROWS: usize = 4;
+// Width of the key column in the help overlay, enough for "Shift+Right "
+const HELP_KEY_COLS: usize = 12;
+const HELP_GAP_COLS: usize = 2;
+// Big note, gap, caption, gap, meter and its scale
+const TUNER_ROWS: usize = BIG_GLYPH_ROWS + 5;
+const TUNER_METER_COLS: usize = 61;
+const BIG_GLYPH_ROWS: usize = 5;
+const BIG_GLYPH_COLS: usize = 3;
+// Just what note names and octaves need
+const BIG_FONT: [(char, [&str; BIG_GLYPH_ROWS]); 19] = [
+    ('A', [" # ", "# #", "###", "# #", "# #"]),
+    ('B', ["## ", "# #", "## ", "# #", "## "]),
+    ('C', [" ##", "#  ", "#  ", "#  ", " ##"]),
+    ('D', ["## ", "# #", "# #", "# #", "## "]),
+    ('E', ["###", "#  ", "## ", "#  ", "###"]),
+    ('F', ["###", "#  ", "## ", "#  ", "#  "]),
+    ('G', [" ##", "#  ", "# #", "# #", " ##"]),
+    ('#', ["# #", "###", "# #", "###", "# #"]),
+    ('-', ["   ", "   ", "###", "   ", "   "]),
+    ('0', ["###", "# #", "# #", "# #", "###"]),
+    ('1', [" # ", "## ", " # ", " # ", "###"]),
+    ('2', ["###", "  #", "###", "#  ", "###"]),
+    ('3', ["###", "  #", " ##", "  #", "###"]),
+    ('4', ["# #", "# #", "###", "  #", "  #"]),
+    ('5', ["###", "#  ", "###", "  #", "###"]),
+    ('6', ["###", "#  ", "###", "# #", "###"]),
+    ('7', ["###", "  #", "  #", "  #", "  #"]),
+    ('8', ["###", "# #", "###", "# #", "###"]),
+    ('9', ["###", "# #", "###", "  #", "###"]),
+];
+
+// Split of the screen; short terminals shrink the spectrum before the waterfall, and
+// narrow ones drop the legend and then the time axis
+#[derive(Clone, Copy)]
+pub struct Layout {
+    pub waterfall_top: usize,
+    pub history_rows: usize,
+    pub waterfall_left: usize,
+    pub waterfall_width: usize,
+    pub freq_axis_row: usize,
+    pub legend_left: Option<usize>,
+    pub panels: usize,
+}
+
+impl Layout {
+    // `panels` waterfalls share the width, each `waterfall_width` wide with a gap between.
+    // With only one of spectrum and waterfall shown the other gets no rows at all.
+    pub fn new(width: usize, height: usize, panels: usize, mode: LayoutMode) -> Option<Self> {
+        let spare = height.checked_sub(MIN_TERMINAL_ROWS)?;
+        let spectrum_rows = match mode {
+            LayoutMode::Both => MIN_SPECTRUM_ROWS + spare.min(SPECTRUM_ROWS - MIN_SPECTRUM_ROWS),
+            LayoutMode::Spectrum => height - HEADER_ROWS - FREQ_AXIS_ROWS,
+            LayoutMode::Waterfall => 0,
+        };
+        let waterfall_top = HEADER_ROWS + spectrum_rows;
+        let waterfall_left = if width >= TIME_AXIS_COLS + MIN_WATERFALL_COLS { TIME_AXIS_COLS } else { 0 };
+        let legend_cols = if width >= waterfall_left + MIN_WATERFALL_COLS + LEGEND_COLS { LEGEND_COLS } else { 0 };
+        let gaps = (panels - 1) * PANEL_GAP_COLS;
+        let waterfall_width = width.saturating_sub(waterfall_left + legend_cols + gaps) / panels;
+        Some(Self {
+            waterfall_top,
+            history_rows: height - waterfall_top - FREQ_AXIS_ROWS,
+            waterfall_left,
+            waterfall_width,
+            freq_axis_row: height - FREQ_AXIS_ROWS,
+            legend_left: (legend_cols > 0).then_some(width - legend_cols),
+            panels,
+        })
+    }
+
+    // The row over the waterfall, taken from the spectrum for the marquee while zoomed in
+    // and with rows to spare
+    pub fn marquee_row(&self, state: &ViewState) -> Option<usize> {
+        let shown = state.marquee && state.freq_zoom > 1.0 && !state.scope && !state.tuner
+            && !(state.xy && state.right.is_some());
+        (shown && self.waterfall_top > HEADER_ROWS + MIN_SPECTRUM_ROWS).then(|| self.waterfall_top - 1)
+    }
+
+    pub fn panel_left(&self, panel: usize) -> usize {
+        self.waterfall_left + panel * (self.waterfall_width + PANEL_GAP_COLS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{export::OutputNames, options::Options};
+
+    const WIDTH: usize = 160;
+    const HEIGHT: usize = 40;
+
+    fn view_state() -> ViewState {
+        let options = Options::defaults();
+        let names = OutputNames::new(&options.output_prefix, "test", 48000);
+        let history_rows = Layout::new(WIDTH, HEIGHT, 1, LayoutMode::Both).unwrap().history_rows;
+        ViewState::new(history_rows, &options, names)
+    }
+
+    fn row_text(buffer: &ScreenBuffer, y: usize) -> String {
+        buffer.cells[y].iter().map(|cell| cell.char).collect()
+    }
+
+    #[test]
+    fn layout_splits_the_screen_between_spectrum_waterfall_and_legend() {
+        let layout = Layout::new(WIDTH, HEIGHT, 1, LayoutMode::Both).unwrap();
+        assert_eq!((layout.waterfall_top, layout.history_rows, layout.freq_axis_row), (17, 22, 39));
+        assert_eq!((layout.waterfall_left, layout.waterfall_width, layout.legend_left), (7, 141, Some(148)));
+
+        let stereo = Layout::new(WIDTH, HEIGHT, 2, LayoutMode::Both).unwrap();
+        assert_eq!((stereo.waterfall_width, stereo.panel_left(1)), (70, 78));
+        assert_eq!(Layout::new(WIDTH, HEIGHT, 1, LayoutMode::Waterfall).unwrap().waterfall_top, HEADER_ROWS);
+        assert!(Layout::new(WIDTH, MIN_TERMINAL_ROWS - 1, 1, LayoutMode::Both).is_none());
+
+        let renderer = Renderer::with_output(Vec::new(), WIDTH, HEIGHT);
+        assert_eq!(renderer.panel_column(2, 80, 20), Some(2));
+        assert_eq!(renderer.panel_column(2, 80, 2), None);
+    }
+
+    #[test]
+    fn header_leads_with_the_main_settings() {
+        let mut state = view_state();
+        let mut renderer = Renderer::with_output(Vec::new(), WIDTH, HEIGHT);
+        renderer.compose(&state, 48000);
+        let header = row_text(renderer.back_buffer(), 0);
+        assert!(header.starts_with(&format!("Gain: +14.0 dB | Freq Zoom: 1.0x | Window: Hann | FFT: {} | Scale: linear",
+                                            state.fft_size)), "{}", header);
+
+        state.paused = true;
+        state.frame_count = 12;
+        state.frame_end = 24000;
+        renderer.compose(&state, 48000);
+        assert!(row_text(renderer.back_buffer(), 0).contains(" | PAUSED at frame 12, 0.500 s"));
+    }
+
+    #[test]
+    fn waterfall_cells_take_the_colormap_at_their_intensity() {
+        let mut state = view_state();
+        let magnitude = 0.5;
+        // Unaveraged, so the line holds the frame as given
+        state.average_alpha = 1.0;
+        state.add_spectrum(&vec![magnitude; state.fft_size / 2], 48000);
+        let mut renderer = Renderer::with_output(Vec::new(), WIDTH, HEIGHT);
+        renderer.compose(&state, 48000);
+
+        let layout = Layout::new(WIDTH, HEIGHT, 1, LayoutMode::Both).unwrap();
+        let expected = state.waterfall_cell(state.waterfall_intensity(magnitude));
+        // The newest line scrolls in at the bottom, over history that is still silent
+        let newest = layout.waterfall_top + layout.history_rows - 1;
+        let cells = &renderer.back_buffer().cells;
+        assert!(cells[newest][layout.waterfall_left..][..layout.waterfall_width].iter().all(|cell| *cell == expected));
+        assert_eq!(cells[newest - 1][layout.waterfall_left], state.waterfall_cell(0.0));
+        assert_ne!(state.waterfall_cell(0.0), expected);
+    }
+
+    #[test]
+    fn only_changed_cells_are_written_out() {
+        let state = view_state();
+        let mut renderer = Renderer::with_output(Vec::new(), WIDTH, HEIGHT);
+        renderer.render(&state, 48000).unwrap();
+        assert!(String::from_utf8_lossy(&renderer.out).contains("Gain:"));
+
+        renderer.out.clear();
+        renderer.render(&state, 48000).unwrap();
+        assert!(renderer.out.is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+// Session files: every analyzed spectrum with its time, for scrubbing through later.
+// After a short file header each record is the elapsed time in microseconds (u64 LE), the
+// frame length (u32 LE) and a spectrum frame as sent over UDP, so every record carries its
+// own sample rate and FFT size.
+
+use crate::net::{decode_frame, encode_frame, Frame};
+use anyhow::{anyhow, bail, Result};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+const SESSION_MAGIC: &[u8; 4] = b"QMXR";
+const SESSION_VERSION: u32 = 1;
+const RECORD_HEADER_LEN: usize = 12;
+// Frames queued for the writer thread before new ones are dropped
+const SESSION_QUEUE_FRAMES: usize = 64;
+
+// Spectra are stored at unit gain so a replay can apply its own
+pub struct SessionWriter {
+    sender: mpsc::SyncSender<(Duration, Vec<f32>)>,
+    writer: thread::JoinHandle<Result<()>>,
+    started: Instant,
+}
+
+impl SessionWriter {
+    pub fn create(path: &Path, sample_rate: u32, fft_size: usize) -> Result<Self> {
+        let file = File::create(path).map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e))?;
+        let mut out = BufWriter::new(file);
+        out.write_all(SESSION_MAGIC)?;
+        out.write_all(&SESSION_VERSION.to_le_bytes())?;
+
+        let (sender, receiver) = mpsc::sync_channel::<(Duration, Vec<f32>)>(SESSION_QUEUE_FRAMES);
+        let writer = thread::spawn(move || -> Result<()> {
+            let mut frame = Vec::new();
+            for (time, spectrum) in receiver {
+                encode_frame(sample_rate, fft_size, &spectrum, &mut frame);
+                out.write_all(&(time.as_micros() as u64).to_le_bytes())?;
+                out.write_all(&(frame.len() as u32).to_le_bytes())?;
+                out.write_all(&frame)?;
+            }
+            out.flush()?;
+            Ok(())
+        });
+        Ok(Self { sender, writer, started: Instant::now() })
+    }
+
+    pub fn log(&self, spectrum: &[f32], gain: f32) {
+        let unit = spectrum.iter().map(|mag| mag / gain).collect();
+        let _ = self.sender.try_send((self.started.elapsed(), unit));
+    }
+
+    // Drains the queue and reports any write error from the thread
+    pub fn finish(self) -> Result<()> {
+        drop(self.sender);
+        self.writer.join().map_err(|_| anyhow!("Session writer thread panicked"))?
+    }
+}
+
+struct Record {
+    time: Duration,
+    offset: u64,
+    len: usize,
+}
+
+// An indexed session file; frames are read from disk as they are needed
+pub struct Session {
+    reader: BufReader<File>,
+    records: Vec<Record>,
+    buffer: Vec<u8>,
+}
+
+impl Session {
+    // A record cut short, as by a crash mid-write, ends the session there
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+        let mut reader = BufReader::new(file);
+        let mut header = [0; 8];
+        reader.read_exact(&mut header).map_err(|_| anyhow!("{} is not a session file", path.display()))?;
+        if &header[..4] != SESSION_MAGIC {
+            bail!("{} is not a session file", path.display());
+        }
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if version != SESSION_VERSION {
+            bail!("{} is session format version {}, expected {}", path.display(), version, SESSION_VERSION);
+        }
+
+        let mut records = Vec::new();
+        let mut offset = header.len() as u64;
+        let end = reader.get_ref().metadata()?.len();
+        let mut record_header = [0; RECORD_HEADER_LEN];
+        loop {
+            match reader.read_exact(&mut record_header) {
+                Ok(()) => (),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            let time = Duration::from_micros(u64::from_le_bytes(record_header[..8].try_into().unwrap()));
+            let len = u32::from_le_bytes(record_header[8..].try_into().unwrap()) as usize;
+            offset += RECORD_HEADER_LEN as u64;
+            if offset + len as u64 > end {
+                break;
+            }
+            records.push(Record { time, offset, len });
+            reader.seek_relative(len as i64)?;
+            offset += len as u64;
+        }
+        if records.is_empty() {
+            bail!("{} holds no frames", path.display());
+        }
+        Ok(Self { reader, records, buffer: Vec::new() })
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn time(&self, index: usize) -> Duration {
+        self.records[index].time
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.records.last().map_or(Duration::ZERO, |record| record.time)
+    }
+
+    // Index of the first frame later than `time`, so frames before it are due by then
+    pub fn index_after(&self, time: Duration) -> usize {
+        self.records.partition_point(|record| record.time <= time)
+    }
+
+    pub fn frame(&mut self, index: usize) -> Result<Frame> {
+        let record = &self.records[index];
+        self.buffer.resize(record.len, 0);
+        self.reader.seek(SeekFrom::Start(record.offset))?;
+        self.reader.read_exact(&mut self.buffer)?;
+        decode_frame(&self.buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_round_trip_and_survive_a_cut_off_record() {
+        let path = std::env::temp_dir().join(format!("qmx-session-test-{}.qmxr", std::process::id()));
+        let writer = SessionWriter::create(&path, 48000, 8).unwrap();
+        writer.log(&[2.0, 4.0, 6.0, 8.0], 2.0);
+        writer.log(&[0.0, 0.0, 0.0, 1.0], 1.0);
+        writer.finish().unwrap();
+
+        let mut session = Session::open(&path).unwrap();
+        assert_eq!(session.len(), 2);
+        let frame = session.frame(0).unwrap();
+        assert_eq!((frame.sample_rate, frame.fft_size), (48000, 8));
+        assert_eq!(frame.spectrum, [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(session.index_after(session.time(1)), 2);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(Session::open(&path).unwrap().len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
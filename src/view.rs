@@ -0,0 +1,2957 @@
+// Display state shared by every frontend: scaling, zoom, history and color mapping
+
+use crate::audio::StereoMode;
+use crate::dsp::{
+    band_levels, bin_sine_amplitude, local_peaks, octave_bands, parabolic_offset, spectral_flatness, spectral_flux, thd,
+    sine_amplitude, triangular_smooth, BandFraction, Delay, Note, OctaveBand, Weighting, Window, BASE_GAIN,
+};
+use crate::export::OutputNames;
+use crate::morse::CwDecoder;
+use crate::options::Options;
+use crate::render::ScreenCell;
+use anyhow::{bail, Result};
+use crossterm::style::Color;
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    ops::Range,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+pub const MIN_FPS: u64 = 1;
+pub const MAX_FPS: u64 = 120;
+// Most analysis frames --time-compress may fold into one waterfall line
+pub const MAX_TIME_COMPRESS: usize = 64;
+// Widest --waterfall-smooth kernel, in bins
+pub const MAX_WATERFALL_SMOOTH: usize = 31;
+const NOTICE_DURATION: Duration = Duration::from_secs(3);
+// Display gain in dB, and its range as the linear factor the spectrum is scaled by
+pub const DEFAULT_GAIN_DB: f32 = 14.0;
+pub const MIN_GAIN_DB: f32 = -40.0;
+pub const MAX_GAIN_DB: f32 = 60.0;
+const MIN_GAIN: f32 = 0.01;
+const MAX_GAIN: f32 = 1000.0;
+// Factor each zoom key press applies, and the fine/normal/coarse steps 'F' cycles through
+pub const DEFAULT_STEP: f32 = 1.2;
+const STEP_PRESETS: [f32; 3] = [1.05, DEFAULT_STEP, 2.0];
+// Decibels each gain key press adds or takes away, with presets cycled alongside the zoom step's
+pub const DEFAULT_GAIN_STEP_DB: f32 = 1.0;
+const GAIN_STEP_PRESETS_DB: [f32; 3] = [0.5, DEFAULT_GAIN_STEP_DB, 3.0];
+// How far below the strongest bin the phase panel still shows a bin's phase
+pub const DEFAULT_PHASE_GATE_DB: f32 = -50.0;
+// Curve ^ switches companding to without --compand: mu-law that draws a level 24 dB down at
+// half height
+pub const DEFAULT_COMPANDER: Compander = Compander::MuLaw(255.0);
+// Spectral flux that marks an onset: half the frame's level new since the one before
+pub const DEFAULT_ONSET_THRESHOLD: f32 = 0.5;
+// Playback volume range; the top leaves a little room to bring up a quiet input
+pub const MIN_OUTPUT_GAIN_DB: f32 = -60.0;
+pub const MAX_OUTPUT_GAIN_DB: f32 = 12.0;
+// Most peaks --track-peaks may follow, so each marker keeps a one-digit number
+pub const MAX_TRACKED_PEAKS: usize = 9;
+pub const DEFAULT_TRACK_TOLERANCE_HZ: f32 = 50.0;
+// A tracked peak has to be the strongest bin this far either side, which keeps a tone's
+// leakage from starting tracks of its own
+const TRACK_PEAK_REACH_BINS: usize = 3;
+// Frames a track may go unmatched before it is dropped
+const TRACK_MAX_MISSED: usize = 8;
+// Deepest zoom still shows this many FFT bins across the screen
+const MIN_VISIBLE_BINS: f32 = 16.0;
+const AGC_TARGET_INTENSITY: f32 = 0.85;
+pub const MIN_AVERAGE_ALPHA: f32 = 0.01;
+pub const MIN_WATERFALL_SCALE: f32 = 0.01;
+pub const MAX_WATERFALL_SCALE: f32 = 100.0;
+// Gamma below 1 lifts weak signals, above 1 pushes them back into the floor
+pub const MIN_WATERFALL_GAMMA: f32 = 0.2;
+pub const MAX_WATERFALL_GAMMA: f32 = 5.0;
+// Auto contrast puts this share of a line's bins below the target intensity, and follows
+// changes over a few dozen lines
+const AUTO_CONTRAST_PERCENTILE: f32 = 0.99;
+const AUTO_CONTRAST_TARGET: f32 = 0.9;
+const AUTO_CONTRAST_SMOOTHING: f32 = 0.05;
+// Share of the previous waterfall line a new one keeps when persistence is switched on
+// without --persistence
+const DEFAULT_PERSISTENCE_DECAY: f32 = 0.85;
+// SNR takes its noise floor as the median of the bins this far either side of the peak,
+// leaving out the few closest, which still hold the peak's own leakage
+const SNR_NOISE_SPAN_BINS: usize = 64;
+const SNR_GUARD_BINS: usize = 3;
+const DEFAULT_BANDWIDTH_DB: f32 = 3.0;
+const DEFAULT_GATE_DB: f32 = -70.0;
+const DEFAULT_GATE_MARGIN_DB: f32 = 6.0;
+const MAX_GATE_MARGIN_DB: f32 = 60.0;
+// Auto range watches this long, then puts the typical peak where AGC would and the
+// typical noise floor low in the waterfall
+const CALIBRATION_TIME: Duration = Duration::from_secs(2);
+const CALIBRATION_FLOOR_INTENSITY: f32 = 0.15;
+// Frames averaged by the diagnostics overlay, about two seconds at the default rate
+const DIAGNOSTIC_FRAMES: usize = 60;
+// Monitor levels kept for the strip chart, more than any terminal is wide
+const MONITOR_HISTORY: usize = 1024;
+// How fast the input meter's peak reading falls back
+const INPUT_PEAK_FALL_DB_PER_S: f32 = 20.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Scale {
+    Linear,
+    Db,
+}
+
+impl Scale {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Linear => "linear",
+            Self::Db => "db",
+        }
+    }
+}
+
+impl FromStr for Scale {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "linear" | "lin" => Ok(Self::Linear),
+            "db" => Ok(Self::Db),
+            _ => bail!("Unknown scale '{}', expected linear or db", s),
+        }
+    }
+}
+
+// What the spectrum's level axis measures. Magnitude is the display magnitude, gain and treble
+// tilt included. Power takes those back out along with the window's coherent gain and squares
+// what is left, so a full-scale sine reads 1, or 0 dB, at any gain, window or FFT size. PSD
+// then divides by the window's equivalent noise bandwidth in Hz, (ENBW in bins) x (sample
+// rate) / (captured samples), so broadband noise reads the same density at any of them.
+// Only those two take --cal-offset and read in its unit; magnitude stays plain dB.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Quantity {
+    Magnitude,
+    Power,
+    Psd,
+}
+
+impl Quantity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Magnitude => "Magnitude",
+            Self::Power => "Power",
+            Self::Psd => "PSD",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            Self::Magnitude => Self::Power,
+            Self::Power => Self::Psd,
+            Self::Psd => Self::Magnitude,
+        }
+    }
+}
+
+impl FromStr for Quantity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "magnitude" | "mag" => Ok(Self::Magnitude),
+            "power" => Ok(Self::Power),
+            "psd" => Ok(Self::Psd),
+            _ => bail!("Unknown quantity '{}', expected magnitude, power or psd", s),
+        }
+    }
+}
+
+// What calibrated dB levels are referenced to; plain dB until --cal-offset is given
+#[derive(Clone, Copy, PartialEq)]
+pub enum LevelUnit {
+    Db,
+    Dbfs,
+    Spl,
+}
+
+impl LevelUnit {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Db => "db",
+            Self::Dbfs => "dbfs",
+            Self::Spl => "spl",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Db => "dB",
+            Self::Dbfs => "dBFS",
+            Self::Spl => "dB SPL",
+        }
+    }
+}
+
+impl FromStr for LevelUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "db" => Ok(Self::Db),
+            "dbfs" => Ok(Self::Dbfs),
+            "spl" | "db-spl" => Ok(Self::Spl),
+            _ => bail!("Unknown level unit '{}', expected dbfs or spl", s),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FreqAxis {
+    Linear,
+    Log,
+}
+
+impl FreqAxis {
+    pub fn toggle(self) -> Self {
+        match self {
+            Self::Linear => Self::Log,
+            Self::Log => Self::Linear,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Linear => "linear",
+            Self::Log => "log",
+        }
+    }
+}
+
+impl FromStr for FreqAxis {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "linear" | "lin" => Ok(Self::Linear),
+            "log" => Ok(Self::Log),
+            _ => bail!("Unknown frequency axis '{}', expected linear or log", s),
+        }
+    }
+}
+
+// Which way the waterfall moves: up puts the newest line at the bottom, down at the top
+#[derive(Clone, Copy, PartialEq)]
+pub enum Scroll {
+    Up,
+    Down,
+}
+
+impl Scroll {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Up => "up",
+            Self::Down => "down",
+        }
+    }
+}
+
+impl FromStr for Scroll {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "up" => Ok(Self::Up),
+            "down" => Ok(Self::Down),
+            _ => bail!("Unknown scroll direction '{}', expected up or down", s),
+        }
+    }
+}
+
+// Waterfall noise gate: bins below a fixed level, or less than a margin above the
+// captured reference, are drawn as background
+#[derive(Clone, Copy, PartialEq)]
+pub enum GateMode {
+    Off,
+    Level,
+    Reference,
+}
+
+// What the spectrum shows once references are captured: the live spectrum less reference A,
+// the live spectrum as it is, or reference B less reference A for a before/after comparison
+#[derive(Clone, Copy, PartialEq)]
+pub enum Comparison {
+    LiveMinusA,
+    Live,
+    BMinusA,
+}
+
+impl Comparison {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::LiveMinusA => "live - ref A",
+            Self::Live => "live",
+            Self::BMinusA => "ref B - ref A",
+        }
+    }
+}
+
+// How the frames collapsed into one waterfall line by --time-compress are combined: the
+// loudest of each bin, which keeps short bursts visible, or their mean, which calms the noise
+#[derive(Clone, Copy, PartialEq)]
+pub enum TimeCompression {
+    Max,
+    Mean,
+}
+
+impl TimeCompression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Max => "max",
+            Self::Mean => "mean",
+        }
+    }
+}
+
+impl FromStr for TimeCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "max" => Ok(Self::Max),
+            "mean" => Ok(Self::Mean),
+            _ => bail!("Unknown time compression '{}', expected max or mean", s),
+        }
+    }
+}
+
+// Which parts of the screen below the header are shown; either one alone gets the full height
+#[derive(Clone, Copy, PartialEq)]
+pub enum LayoutMode {
+    Both,
+    Spectrum,
+    Waterfall,
+}
+
+impl LayoutMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Both => "both",
+            Self::Spectrum => "spectrum",
+            Self::Waterfall => "waterfall",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            Self::Both => Self::Spectrum,
+            Self::Spectrum => Self::Waterfall,
+            Self::Waterfall => Self::Both,
+        }
+    }
+}
+
+impl FromStr for LayoutMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "both" => Ok(Self::Both),
+            "spectrum" => Ok(Self::Spectrum),
+            "waterfall" => Ok(Self::Waterfall),
+            _ => bail!("Unknown layout '{}', expected both, spectrum or waterfall", s),
+        }
+    }
+}
+
+// Glyph a waterfall cell is drawn with; half blocks pack two history lines into each row,
+// the upper one in the foreground color and the lower one in the background
+#[derive(Clone, Copy, PartialEq)]
+pub enum WaterfallGlyph {
+    Char(char),
+    HalfBlocks,
+}
+
+impl WaterfallGlyph {
+    pub fn name(self) -> String {
+        match self {
+            Self::Char(c) => c.to_string(),
+            Self::HalfBlocks => "half".to_string(),
+        }
+    }
+}
+
+impl FromStr for WaterfallGlyph {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            _ if s.eq_ignore_ascii_case("half") => Ok(Self::HalfBlocks),
+            (Some(c), None) => Ok(Self::Char(c)),
+            _ => bail!("Unknown waterfall character '{}', expected a single character or half", s),
+        }
+    }
+}
+
+// Color stops for the gradient maps, evenly spaced over 0..1
+const VIRIDIS: [(u8, u8, u8); 9] = [
+    (68, 1, 84), (71, 44, 122), (59, 81, 139), (44, 113, 142), (33, 144, 141),
+    (39, 173, 129), (92, 200, 99), (170, 220, 50), (253, 231, 37),
+];
+const INFERNO: [(u8, u8, u8); 9] = [
+    (0, 0, 4), (31, 12, 72), (85, 15, 109), (136, 34, 106), (186, 54, 85),
+    (227, 89, 51), (249, 140, 10), (249, 201, 50), (252, 255, 164),
+];
+
+// Where the classic map steps from blue to cyan, green, yellow and red, in percent of
+// full intensity; each level runs up to and including its threshold
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ClassicThresholds(pub [f32; 4]);
+
+pub const DEFAULT_CLASSIC_THRESHOLDS: ClassicThresholds = ClassicThresholds([20.0, 40.0, 60.0, 80.0]);
+
+impl ClassicThresholds {
+    pub fn is_increasing(&self) -> bool {
+        self.0.windows(2).all(|pair| pair[0] < pair[1])
+    }
+
+    pub fn name(&self) -> String {
+        self.0.map(|threshold| threshold.to_string()).join(",")
+    }
+}
+
+impl FromStr for ClassicThresholds {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let values = s.split(',').map(|part| part.trim().parse::<f32>()).collect::<Result<Vec<_>, _>>()?;
+        match values.try_into() {
+            Ok(thresholds) => Ok(Self(thresholds)),
+            Err(_) => bail!("Expected four comma-separated thresholds, got '{}'", s),
+        }
+    }
+}
+
+// Fixed bottom and top of the spectrum chart, in the units of its scale
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LevelRange {
+    pub bottom: f32,
+    pub top: f32,
+}
+
+impl FromStr for LevelRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parsed = s.split_once(',').and_then(|(bottom, top)| Some((bottom.trim().parse().ok()?, top.trim().parse().ok()?)));
+        match parsed {
+            Some((bottom, top)) if bottom < top => Ok(Self { bottom, top }),
+            _ => bail!("Expected a level range as BOTTOM,TOP with BOTTOM below TOP, got '{}'", s),
+        }
+    }
+}
+
+impl LevelRange {
+    pub fn name(&self) -> String {
+        format!("{},{}", self.bottom, self.top)
+    }
+}
+
+// Display curve on the 0..1 level that lifts weak signals without the dB scale: mu-law with
+// its mu, or a power law with an exponent below 1. Only drawing goes through it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Compander {
+    MuLaw(f32),
+    Power(f32),
+}
+
+impl Compander {
+    pub fn name(&self) -> String {
+        match self {
+            Self::MuLaw(mu) => format!("mu-law:{}", mu),
+            Self::Power(exponent) => format!("power:{}", exponent),
+        }
+    }
+
+    pub fn apply(self, t: f32) -> f32 {
+        match self {
+            Self::MuLaw(mu) => (1.0 + mu * t).ln() / (1.0 + mu).ln(),
+            Self::Power(exponent) => t.powf(exponent),
+        }
+    }
+
+    // Inverse of `apply`, for the levels behind labels and targets
+    pub fn expand(self, t: f32) -> f32 {
+        match self {
+            Self::MuLaw(mu) => ((1.0 + mu).powf(t) - 1.0) / mu,
+            Self::Power(exponent) => t.powf(1.0 / exponent),
+        }
+    }
+}
+
+impl FromStr for Compander {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (curve, parameter) = match s.split_once(':') {
+            Some((curve, parameter)) => (curve, Some(parameter.trim().parse::<f32>()?)),
+            None => (s, None),
+        };
+        match (curve.trim().to_lowercase().as_str(), parameter) {
+            ("mu-law" | "mulaw" | "mu", mu) => match mu.unwrap_or(255.0) {
+                mu if mu.is_finite() && mu > 0.0 => Ok(Self::MuLaw(mu)),
+                mu => bail!("Mu-law needs a mu above 0, got {}", mu),
+            },
+            ("power", exponent) => match exponent.unwrap_or(0.3) {
+                exponent if exponent > 0.0 && exponent <= 1.0 => Ok(Self::Power(exponent)),
+                exponent => bail!("A power-law compander needs an exponent above 0 and at most 1, got {}", exponent),
+            },
+            _ => bail!("Unknown compander '{}', expected mu-law[:MU] or power[:EXPONENT]", s),
+        }
+    }
+}
+
+// A fixed color for the spectrum trace: one of the terminal's named colors, or #rrggbb
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TraceColor(pub Color);
+
+const NAMED_COLORS: &[(&str, Color)] = &[
+    ("black", Color::Black), ("dark_grey", Color::DarkGrey), ("red", Color::Red), ("dark_red", Color::DarkRed),
+    ("green", Color::Green), ("dark_green", Color::DarkGreen), ("yellow", Color::Yellow),
+    ("dark_yellow", Color::DarkYellow), ("blue", Color::Blue), ("dark_blue", Color::DarkBlue),
+    ("magenta", Color::Magenta), ("dark_magenta", Color::DarkMagenta), ("cyan", Color::Cyan),
+    ("dark_cyan", Color::DarkCyan), ("white", Color::White), ("grey", Color::Grey),
+];
+
+impl FromStr for TraceColor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let lower = s.to_lowercase();
+        if let Some(hex) = lower.strip_prefix('#').filter(|hex| hex.len() == 6) {
+            if let Ok(rgb) = u32::from_str_radix(hex, 16) {
+                return Ok(Self(Color::Rgb { r: (rgb >> 16) as u8, g: (rgb >> 8) as u8, b: rgb as u8 }));
+            }
+        }
+        match NAMED_COLORS.iter().find(|(name, _)| *name == lower) {
+            Some(&(_, color)) => Ok(Self(color)),
+            None => bail!("Unknown color '{}', expected #rrggbb or one of {}", s,
+                          NAMED_COLORS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")),
+        }
+    }
+}
+
+impl TraceColor {
+    pub fn name(self) -> String {
+        match self.0 {
+            Color::Rgb { r, g, b } => format!("#{:02x}{:02x}{:02x}", r, g, b),
+            color => NAMED_COLORS.iter().find(|&&(_, named)| named == color).map_or("white", |&(name, _)| name).into(),
+        }
+    }
+}
+
+// How the spectrum trace meets the chart: a line, the area under it filled, or stairs
+// holding each column's level until the next
+#[derive(Clone, Copy, PartialEq)]
+pub enum TraceStyle {
+    Line,
+    Filled,
+    Stepped,
+}
+
+impl TraceStyle {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Line => "line",
+            Self::Filled => "filled",
+            Self::Stepped => "stepped",
+        }
+    }
+}
+
+impl FromStr for TraceStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "line" => Ok(Self::Line),
+            "filled" => Ok(Self::Filled),
+            "stepped" => Ok(Self::Stepped),
+            _ => bail!("Unknown trace style '{}', expected line, filled or stepped", s),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Colormap {
+    Classic(ClassicThresholds),
+    Viridis,
+    Inferno,
+    // Hue from each bin's phase, brightness from its level; gray wherever phase is unknown
+    Phase,
+}
+
+impl Colormap {
+    // Terminal color; the classic map keeps named colors so it works on any palette
+    pub fn color(self, t: f32) -> Color {
+        match self {
+            Self::Classic(thresholds) => classic_color(t, thresholds),
+            _ => {
+                let (r, g, b) = self.rgb(t);
+                Color::Rgb { r, g, b }
+            }
+        }
+    }
+
+    // Plain RGB for output that has no terminal, such as image export
+    pub fn rgb(self, t: f32) -> (u8, u8, u8) {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Self::Classic(thresholds) => {
+                let named = classic_color(t, thresholds);
+                ANSI16.iter().find(|(color, _)| *color == named).map_or((0, 0, 0), |&(_, rgb)| rgb)
+            }
+            Self::Viridis => gradient(&VIRIDIS, t),
+            Self::Inferno => gradient(&INFERNO, t),
+            Self::Phase => {
+                let level = (t * 255.0).round() as u8;
+                (level, level, level)
+            }
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Classic(_) => "classic",
+            Self::Viridis => "viridis",
+            Self::Inferno => "inferno",
+            Self::Phase => "phase",
+        }
+    }
+}
+
+fn classic_color(t: f32, thresholds: ClassicThresholds) -> Color {
+    const LEVELS: [Color; 5] = [Color::Blue, Color::Cyan, Color::Green, Color::Yellow, Color::Red];
+    let percent = (t.clamp(0.0, 1.0) * 100.0).floor();
+    let level = thresholds.0.iter().position(|&threshold| percent <= threshold).unwrap_or(4);
+    LEVELS[level]
+}
+
+impl FromStr for Colormap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "classic" => Ok(Self::Classic(DEFAULT_CLASSIC_THRESHOLDS)),
+            "viridis" => Ok(Self::Viridis),
+            "inferno" => Ok(Self::Inferno),
+            "phase" => Ok(Self::Phase),
+            _ => bail!("Unknown colormap '{}', expected classic, viridis, inferno or phase", s),
+        }
+    }
+}
+
+// Fully saturated hue going once around the color wheel per turn of phase, at brightness t
+pub fn phase_rgb(phase: f32, t: f32) -> (u8, u8, u8) {
+    let hue = (phase / std::f32::consts::TAU).rem_euclid(1.0) * 6.0;
+    let (value, frac) = (t.clamp(0.0, 1.0) * 255.0, hue.fract());
+    let (rising, falling) = (value * frac, value * (1.0 - frac));
+    let (r, g, b) = match hue as usize {
+        0 => (value, rising, 0.0),
+        1 => (falling, value, 0.0),
+        2 => (0.0, value, rising),
+        3 => (0.0, falling, value),
+        4 => (rising, 0.0, value),
+        _ => (value, 0.0, falling),
+    };
+    (r.round() as u8, g.round() as u8, b.round() as u8)
+}
+
+fn gradient(stops: &[(u8, u8, u8)], t: f32) -> (u8, u8, u8) {
+    let pos = t * (stops.len() - 1) as f32;
+    let idx = (pos as usize).min(stops.len() - 2);
+    let frac = pos - idx as f32;
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * frac).round() as u8;
+    let (from, to) = (stops[idx], stops[idx + 1]);
+    (lerp(from.0, to.0), lerp(from.1, to.1), lerp(from.2, to.2))
+}
+
+// Approximate RGB of the 16 basic ANSI colors, used to quantize gradients
+const ANSI16: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)), (Color::DarkRed, (128, 0, 0)), (Color::DarkGreen, (0, 128, 0)),
+    (Color::DarkYellow, (128, 128, 0)), (Color::DarkBlue, (0, 0, 128)), (Color::DarkMagenta, (128, 0, 128)),
+    (Color::DarkCyan, (0, 128, 128)), (Color::Grey, (192, 192, 192)), (Color::DarkGrey, (128, 128, 128)),
+    (Color::Red, (255, 0, 0)), (Color::Green, (0, 255, 0)), (Color::Yellow, (255, 255, 0)),
+    (Color::Blue, (0, 0, 255)), (Color::Magenta, (255, 0, 255)), (Color::Cyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+const SHADE_RAMP: [char; 5] = [' ', '░', '▒', '▓', '█'];
+const ASCII_RAMP: [char; 10] = [' ', '.', ':', '-', '=', '+', '*', '#', '%', '@'];
+// Lower eighths of a cell, used to give spectrum bars sub-row height
+const BAR_BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+// Left eighths, the same trick for horizontal meters
+const METER_BLOCKS: [char; 8] = ['▏', '▎', '▍', '▌', '▋', '▊', '▉', '█'];
+
+// How many colors the terminal can show, from nothing up to 24-bit
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum ColorMode {
+    Mono,
+    Ansi16,
+    Ansi256,
+    TrueColor,
+}
+
+impl ColorMode {
+    pub fn detect() -> Self {
+        if std::env::var_os("NO_COLOR").is_some() {
+            return Self::Mono;
+        }
+        let colorterm = std::env::var("COLORTERM").unwrap_or_default().to_lowercase();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            return Self::TrueColor;
+        }
+        let term = std::env::var("TERM").unwrap_or_default().to_lowercase();
+        if term.is_empty() || term == "dumb" {
+            Self::Mono
+        } else if term.contains("256color") {
+            Self::Ansi256
+        } else {
+            Self::Ansi16
+        }
+    }
+
+    // Closest color this terminal can display, or None when colors are off
+    pub fn quantize(self, color: Color) -> Option<Color> {
+        let Color::Rgb { r, g, b } = color else {
+            return (self != Self::Mono).then_some(color);
+        };
+        match self {
+            Self::Mono => None,
+            Self::Ansi16 => ANSI16.iter()
+                .min_by_key(|(_, (cr, cg, cb))| {
+                    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+                    d(r, *cr) + d(g, *cg) + d(b, *cb)
+                })
+                .map(|&(named, _)| named),
+            Self::Ansi256 => {
+                let level = |v: u8| (v as u16 * 5 / 255) as u8;
+                Some(Color::AnsiValue(16 + 36 * level(r) + 6 * level(g) + level(b)))
+            }
+            Self::TrueColor => Some(color),
+        }
+    }
+}
+
+impl FromStr for ColorMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "mono" | "none" => Ok(Self::Mono),
+            "16" | "ansi16" => Ok(Self::Ansi16),
+            "256" | "ansi256" => Ok(Self::Ansi256),
+            "truecolor" | "24bit" => Ok(Self::TrueColor),
+            _ => bail!("Unknown color mode '{}', expected mono, 16, 256 or truecolor", s),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Peak {
+    pub freq: f32,
+    pub magnitude: f32,
+    // The loudest bin, which `magnitude` is read from
+    pub bin: usize,
+}
+
+// Summary of the latest displayed spectrum, DC excluded
+pub struct SpectrumStats {
+    pub min: f32,
+    pub max: f32,
+    pub max_freq: f32,
+    pub mean: f32,
+    pub rms: f32,
+}
+
+// Where one frame's time went, for the diagnostics overlay
+#[derive(Clone, Copy)]
+pub struct FrameTiming {
+    pub start: Instant,
+    pub dsp: Duration,
+    pub render: Duration,
+    pub busy: Duration,
+}
+
+// Averages over a rolling window of recent frames
+pub struct TimingSummary {
+    pub dsp: Duration,
+    pub render: Duration,
+    pub busy: Duration,
+    pub fps: f32,
+}
+
+#[derive(Clone, Default)]
+pub struct Diagnostics {
+    pub enabled: bool,
+    frames: VecDeque<FrameTiming>,
+}
+
+impl Diagnostics {
+    pub fn record(&mut self, timing: FrameTiming) {
+        if self.frames.len() == DIAGNOSTIC_FRAMES {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(timing);
+    }
+
+    // Achieved FPS comes from the spacing of frame starts, so it includes the sleeps
+    pub fn summary(&self) -> Option<TimingSummary> {
+        let (first, last) = (self.frames.front()?, self.frames.back()?);
+        let count = self.frames.len() as u32;
+        let average = |field: fn(&FrameTiming) -> Duration| self.frames.iter().map(field).sum::<Duration>() / count;
+        let span = last.start.duration_since(first.start).as_secs_f32();
+        Some(TimingSummary {
+            dsp: average(|frame| frame.dsp),
+            render: average(|frame| frame.render),
+            busy: average(|frame| frame.busy),
+            fps: if span > 0.0 { (count - 1) as f32 / span } else { 0.0 },
+        })
+    }
+}
+
+// Automatic gain control: rides `gain` so the loudest bin sits near the top of the display
+#[derive(Clone)]
+pub struct Agc {
+    pub enabled: bool,
+    pub attack: f32,
+    pub release: f32,
+    pub floor_db: f32,
+}
+
+// Envelope follower on each bin ahead of the average: rises are taken at the attack share
+// per line and falls at the release share, so brief peaks linger as they scroll away
+#[derive(Clone)]
+pub struct Envelope {
+    pub enabled: bool,
+    pub attack: f32,
+    pub release: f32,
+}
+
+impl Envelope {
+    fn follow(&self, levels: &mut [f32], spectrum: &[f32], weights: &[f32]) {
+        for ((level, &mag), &weight) in levels.iter_mut().zip(spectrum).zip(weights) {
+            let mag = mag * weight;
+            let share = if mag > *level { self.attack } else { self.release };
+            *level += (mag - *level) * share;
+        }
+    }
+}
+
+// Re-lays a history ring oldest-first, keeping the newest lines and zero-filling new rows
+fn relay_history<T: Clone>(data: &mut Vec<Vec<T>>, current_line: usize, history_size: usize, blank: &[T]) {
+    let old_size = data.len();
+    let mut lines: Vec<Vec<T>> = (0..old_size)
+        .map(|i| std::mem::take(&mut data[(current_line + i) % old_size]))
+        .collect();
+    if lines.len() > history_size {
+        lines.drain(..lines.len() - history_size);
+    } else {
+        let missing = history_size - lines.len();
+        lines.splice(0..0, std::iter::repeat_n(blank.to_vec(), missing));
+    }
+    *data = lines;
+}
+
+// Phosphor-style persistence: each bin of the line at `current` holds at least the decayed
+// value of the line before it, so brief signals fade out over the following lines
+fn persist(lines: &mut [Vec<(f32, f32)>], current: usize, previous: usize, decay: f32) {
+    if current == previous {
+        return;
+    }
+    let (low, high) = lines.split_at_mut(current.max(previous));
+    let (line, before) = if current > previous { (&mut high[0], &low[previous]) } else { (&mut low[current], &high[0]) };
+    for (point, &(_, held)) in line.iter_mut().zip(before.iter()) {
+        point.1 = point.1.max(held * decay);
+    }
+}
+
+// Compact tick label such as "440" or "2.5k"
+pub fn format_freq_tick(freq: f32) -> String {
+    if freq >= 1000.0 {
+        let khz = format!("{:.1}", freq / 1000.0);
+        format!("{}k", khz.trim_end_matches(".0"))
+    } else {
+        format!("{:.0}", freq)
+    }
+}
+
+// Combines one frame into a line being gathered; the first frame starts it over
+fn fold_frame(gathered: &mut Vec<(f32, f32)>, frame: &[(f32, f32)], first: bool, mode: TimeCompression) {
+    if first || gathered.len() != frame.len() {
+        gathered.clear();
+        gathered.extend_from_slice(frame);
+        return;
+    }
+    for (point, &(_, mag)) in gathered.iter_mut().zip(frame) {
+        point.1 = match mode {
+            TimeCompression::Max => point.1.max(mag),
+            TimeCompression::Mean => point.1 + mag,
+        };
+    }
+}
+
+// The linear factor for a display gain in dB, held to the safe range
+pub fn gain_factor(gain_db: f32) -> f32 {
+    10f32.powf(gain_db / 20.0).clamp(MIN_GAIN, MAX_GAIN)
+}
+
+// Smallest 1-2-5 step of at least `raw`
+pub fn nice_step(raw: f32) -> f32 {
+    let magnitude = 10f32.powf(raw.log10().floor());
+    [1.0, 2.0, 5.0, 10.0].into_iter().map(|m| m * magnitude).find(|&step| step >= raw).unwrap_or(10.0 * magnitude)
+}
+
+// Right channel of the stereo view; it shares the history position of the main waterfall,
+// while reference subtraction and peak hold stay with the left
+#[derive(Clone)]
+pub struct StereoChannel {
+    pub waterfall_data: Vec<Vec<(f32, f32)>>,
+    pub average: Vec<f32>,
+    envelope_levels: Vec<f32>,
+    live_line: Vec<(f32, f32)>,
+    compressed: Vec<(f32, f32)>,
+}
+
+// Lines held back while a trigger is armed: the left line and, in stereo, the right one
+type PendingLine = (Vec<(f32, f32)>, Option<Vec<(f32, f32)>>);
+
+// Keeps waterfall history for threshold crossings only, like a scope trigger: a frame whose
+// peak reaches `level_db` is recorded along with up to `pre` lines before it and `post` after
+#[derive(Clone)]
+pub struct Trigger {
+    pub level_db: f32,
+    pre: usize,
+    post: usize,
+    pending: VecDeque<PendingLine>,
+    remaining: usize,
+}
+
+impl Trigger {
+    pub fn new(level_db: f32, pre: usize, post: usize) -> Self {
+        Self { level_db, pre, post, pending: VecDeque::with_capacity(pre + 1), remaining: 0 }
+    }
+
+    // Still recording the lines after a crossing
+    pub fn is_triggered(&self) -> bool {
+        self.remaining > 0
+    }
+}
+
+// A named stretch of spectrum from --bands, marked on the display
+#[derive(Clone, Debug, PartialEq)]
+pub struct Band {
+    pub name: String,
+    pub start: f32,
+    pub end: f32,
+}
+
+// A marker dropped on the paused waterfall, tied to the history line it sits on so it
+// scrolls away with it once the display runs again
+#[derive(Clone)]
+pub struct Annotation {
+    pub freq: f32,
+    line: u64,
+}
+
+// One of the --track-peaks peaks, followed from frame to frame by nearest frequency
+#[derive(Clone)]
+pub struct PeakTrack {
+    // The lowest number free when the track started, kept for its life
+    pub number: usize,
+    pub freq: f32,
+    pub magnitude: f32,
+    // Frames in a row without a peak close enough to match
+    pub missed: usize,
+}
+
+// Per-frame peak and median levels at unit gain, gathered for a one-shot auto range
+#[derive(Clone, Default)]
+pub struct Calibration {
+    started: Option<Instant>,
+    peaks: Vec<f32>,
+    floors: Vec<f32>,
+}
+
+fn median(values: &mut [f32]) -> f32 {
+    let middle = values.len() / 2;
+    *values.select_nth_unstable_by(middle, f32::total_cmp).1
+}
+
+#[derive(Clone)]
+pub struct ViewState {
+    pub gain: f32,
+    pub freq_zoom: f32,
+    pub center_freq: Option<f32>,
+    // Keeps the zoomed band centered on the peak, moving this share of the way each frame
+    pub follow_peak: bool,
+    pub follow_smoothing: f32,
+    // A strip of the whole band while zoomed, with the part on screen marked
+    pub marquee: bool,
+    // Bottom of the band zoom and pan work within, hiding DC and rumble below it
+    pub fmin: f32,
+    pub waterfall_data: Vec<Vec<(f32, f32)>>,
+    // Bin phases of each waterfall line for the phase colormap, and of the latest frame; a
+    // line without any, as from a remote or replayed source, is left empty. Not kept at
+    // all under the other colormaps.
+    pub waterfall_phase: Vec<Vec<f32>>,
+    phase: Vec<f32>,
+    // Unwrapped phase under the magnitude, for bins no more than `phase_gate_db` below the peak
+    pub phase_panel: bool,
+    pub phase_gate_db: f32,
+    pub current_line: usize,
+    // Lines kept, of which the newest `visible_lines` are shown, or as many as
+    // `history_offset` lines further back when scrolled
+    pub history_size: usize,
+    history_depth: usize,
+    pub visible_lines: usize,
+    pub history_offset: usize,
+    pub fft_size: usize,
+    // How many times the captured samples fft_size spans, the rest being zeros
+    pub zero_pad: usize,
+    pub window: Window,
+    pub scale: Scale,
+    pub quantity: Quantity,
+    pub db_floor: f32,
+    pub freq_axis: FreqAxis,
+    pub scroll: Scroll,
+    pub layout: LayoutMode,
+    pub colormap: Colormap,
+    // Strong signals take the low end of the colormap and the ramps, for light backgrounds and print
+    pub invert: bool,
+    // Bins each waterfall line is blurred over before it is drawn; 1 leaves it sharp
+    pub waterfall_smooth: usize,
+    pub color_mode: ColorMode,
+    pub ascii: bool,
+    pub waterfall_glyph: WaterfallGlyph,
+    pub cursor: Option<usize>,
+    // Waterfall row whose line the spectrum shows in place of the live one
+    pub cursor_row: Option<usize>,
+    // Columns where a mouse drag started and where it is now
+    pub selection: Option<(usize, usize)>,
+    // Numbered from 1 in the order they were placed
+    pub annotations: Vec<Annotation>,
+    // Up to `track_peaks` strongest peaks, matched to the last frame's within the tolerance in Hz
+    pub track_peaks: usize,
+    pub track_tolerance: f32,
+    pub peak_tracks: Vec<PeakTrack>,
+    // History lines committed since the start, to place annotations by
+    lines_committed: u64,
+    // Rows of the history written since it was last cleared, at most its size
+    filled_lines: usize,
+    pub average: Vec<f32>,
+    pub average_alpha: f32,
+    // Reference A, subtracted from the live spectrum, and B, compared against it
+    pub reference: Option<Vec<f32>>,
+    pub reference_b: Option<Vec<f32>>,
+    pub comparison: Comparison,
+    pub gate: GateMode,
+    pub gate_db: f32,
+    pub gate_margin_db: f32,
+    pub bands: Vec<Band>,
+    // --bands octave or third-octave, drawn in place of the spectrum chart
+    pub band_analysis: Option<BandFraction>,
+    // Target response from --reference-curve, as frequency and magnitude points
+    pub reference_curve: Vec<(f32, f32)>,
+    // Added to the power and PSD levels and to the full-scale readouts so they read in
+    // level_unit. Magnitude levels carry the display tilt, which no one offset can take out,
+    // so they stay plain dB.
+    pub cal_offset_db: f32,
+    pub level_unit: LevelUnit,
+    pub agc: Agc,
+    pub envelope: Envelope,
+    envelope_levels: Vec<f32>,
+    pub peak_hold: Vec<f32>,
+    pub peak_hold_enabled: bool,
+    pub peak_decay: f32,
+    // Strongest level each bin has reached since the start or the last reset, never decayed
+    pub session_max: Vec<f32>,
+    pub session_max_enabled: bool,
+    pub peak_threshold: f32,
+    pub recording: bool,
+    pub restarts: usize,
+    // Which of several --device inputs this view belongs to, for the header
+    pub input_label: Option<String>,
+    // How the files saved from this view are named
+    pub output_names: OutputNames,
+    // Samples lost on the playback ring since the pipeline started
+    pub overruns: u64,
+    pub underruns: u64,
+    // --skip-silence's level and minimum run, and the input it has passed over in seconds
+    pub skip_silence: Option<(f32, f32)>,
+    pub skipped_secs: f32,
+    // Frames per input block and the rate they arrive at, and the --buffer-size asked for
+    pub input_block: Option<(usize, u32)>,
+    pub buffer_size: Option<u32>,
+    // Raw input levels for the meter row, which only a live pipeline feeds
+    pub input_metered: bool,
+    pub input_peak: f32,
+    pub input_rms: f32,
+    // Set by any sample at full scale and kept until reset
+    pub input_clipped: bool,
+    pub paused: bool,
+    // Spectra taken in so far, and the sample the newest live one ended on, where stepping
+    // a paused view goes on from
+    pub frame_count: u64,
+    pub frame_end: u64,
+    // Keeps the waterfall as a snapshot while the spectrum goes on updating
+    pub waterfall_held: bool,
+    pub fps: u64,
+    // Lower rate for redrawing the waterfall, which changes nearly every cell each time
+    pub waterfall_fps: Option<u64>,
+    pub notice: Option<(String, Instant)>,
+    // Persistent warning drawn over the waterfall, such as a lost input device
+    pub banner: Option<String>,
+    pub output: bool,
+    pub muted: bool,
+    pub notch: Option<f32>,
+    pub notch_enabled: bool,
+    // The second panel's channel, and whether the pair is left and right or mid and side
+    pub right: Option<StereoChannel>,
+    pub stereo_mode: StereoMode,
+    pub bars: bool,
+    pub braille: bool,
+    pub trace_color: Option<TraceColor>,
+    pub trace_style: TraceStyle,
+    // Chart levels held in place instead of fitted to each frame
+    pub level_range: Option<LevelRange>,
+    pub show_help: bool,
+    pub diagnostics: Diagnostics,
+    pub weighting: Weighting,
+    // Goertzel monitor frequency, and its recent levels oldest first
+    pub monitor: Option<f32>,
+    pub monitor_levels: VecDeque<f32>,
+    pub cw: Option<CwDecoder>,
+    pub tuner: bool,
+    // THD of the latest frame, measured on the given fundamental or else the peak
+    pub thd: bool,
+    thd_fundamental: Option<f32>,
+    pub thd_ratio: Option<f32>,
+    // Spectral flatness of the latest frame, 0 for a tone to 1 for noise
+    pub flatness: Option<f32>,
+    // Spectral flux of the latest frame against the frame before, kept for the next one
+    pub flux: Option<f32>,
+    previous_spectrum: Vec<f32>,
+    // With --onsets, the flux that marks an onset, whether the last frame was over it, an
+    // onset waiting for the next line into the history, and the lines marked so far
+    pub onset_threshold: Option<f32>,
+    onset_above: bool,
+    onset_pending: bool,
+    onsets: Vec<u64>,
+    // Right channel's delay behind the left, from the latest stereo frame
+    pub delay_enabled: bool,
+    pub delay: Option<Delay>,
+    // Raw waveform in place of the spectrum, from the newest analysis window
+    pub scope: bool,
+    pub scope_trigger: bool,
+    scope_samples: Vec<f32>,
+    // Left against right from the same window, for stereo phase and width
+    pub xy: bool,
+    xy_right: Vec<f32>,
+    // Markers where the peak has fallen this many dB
+    pub bandwidth: bool,
+    pub bandwidth_db: f32,
+    // Peak-locked sine on the output in place of the input, and its level out of 1
+    pub tone: bool,
+    pub tone_volume: f32,
+    pub step: f32,
+    pub gain_step_db: f32,
+    // Playback only: volume in dB, balance from -1 (left) to 1 (right) and delay in ms
+    pub output_gain_db: f32,
+    pub balance: f32,
+    pub output_delay_ms: f32,
+    pub trigger: Option<Trigger>,
+    live_line: Vec<(f32, f32)>,
+    // Frames per waterfall line, how they are combined, and the line gathered so far
+    pub time_compress: usize,
+    pub time_compress_mode: TimeCompression,
+    compressed: Vec<(f32, f32)>,
+    compressed_frames: usize,
+    // Replay position and session length, shown in the header
+    pub replay: Option<(Duration, Duration)>,
+    // Waterfall-only gain on top of `gain`, set by hand or tracked from recent lines
+    pub waterfall_scale: f32,
+    pub auto_contrast: bool,
+    pub calibration: Option<Calibration>,
+    // Curve on the normalized intensity, display only
+    pub waterfall_gamma: f32,
+    pub persistence: bool,
+    pub persistence_decay: f32,
+    // Companding of every drawn level, the spectrum's and the waterfall's
+    pub compand: bool,
+    pub compander: Compander,
+    contrast_level: f32,
+    contrast_scratch: Vec<f32>,
+    // Per-bin weighting factors and the sample rate they were computed for
+    weights: Vec<f32>,
+    weights_rate: u32,
+}
+
+impl ViewState {
+    // `history_rows` screen rows of waterfall, which packed half blocks fill with twice the lines
+    pub fn new(history_rows: usize, options: &Options, output_names: OutputNames) -> Self {
+        let history_depth = options.history.unwrap_or(0);
+        let mut state = Self {
+            gain: gain_factor(options.gain),
+            freq_zoom: 1.0,
+            center_freq: None,
+            follow_peak: options.follow_peak,
+            marquee: true,
+            follow_smoothing: options.follow_smoothing,
+            fmin: options.fmin,
+            waterfall_data: vec![vec![(0.0, 0.0); options.fft_size/2]; history_rows.max(history_depth)],
+            waterfall_phase: match options.effective_colormap() {
+                Colormap::Phase => vec![Vec::new(); history_rows.max(history_depth)],
+                _ => Vec::new(),
+            },
+            phase: Vec::new(),
+            phase_panel: options.phase_panel,
+            phase_gate_db: options.phase_gate,
+            current_line: 0,
+            history_size: history_rows.max(history_depth),
+            history_depth,
+            visible_lines: history_rows,
+            history_offset: 0,
+            fft_size: options.fft_size,
+            zero_pad: options.zero_pad,
+            window: options.window,
+            scale: options.scale,
+            quantity: options.quantity,
+            db_floor: options.db_floor,
+            freq_axis: options.freq_axis,
+            scroll: options.scroll,
+            layout: options.layout,
+            colormap: options.effective_colormap(),
+            invert: options.invert,
+            waterfall_smooth: options.waterfall_smooth,
+            color_mode: options.effective_color_mode(),
+            ascii: options.ascii,
+            waterfall_glyph: options.waterfall_glyph,
+            cursor: None,
+            cursor_row: None,
+            selection: None,
+            annotations: Vec::new(),
+            track_peaks: options.track_peaks.unwrap_or(0),
+            track_tolerance: options.track_tolerance,
+            peak_tracks: Vec::new(),
+            lines_committed: 0,
+            filled_lines: 0,
+            average: vec![0.0; options.fft_size/2],
+            average_alpha: options.average,
+            reference: None,
+            reference_b: None,
+            comparison: Comparison::LiveMinusA,
+            gate: if options.gate.is_some() { GateMode::Level } else { GateMode::Off },
+            gate_db: options.gate.unwrap_or(DEFAULT_GATE_DB),
+            gate_margin_db: DEFAULT_GATE_MARGIN_DB,
+            bands: options.bands.clone(),
+            band_analysis: options.band_analysis,
+            reference_curve: options.reference_curve.clone(),
+            cal_offset_db: options.cal_offset.unwrap_or(0.0),
+            level_unit: if options.cal_offset.is_some() { options.cal_unit } else { LevelUnit::Db },
+            agc: Agc {
+                enabled: false,
+                attack: options.agc_attack,
+                release: options.agc_release,
+                floor_db: options.agc_floor,
+            },
+            envelope: Envelope {
+                enabled: options.envelope,
+                attack: options.envelope_attack,
+                release: options.envelope_release,
+            },
+            envelope_levels: vec![0.0; options.fft_size/2],
+            peak_hold: vec![0.0; options.fft_size/2],
+            peak_hold_enabled: false,
+            peak_decay: options.peak_decay,
+            session_max: vec![0.0; options.fft_size/2],
+            session_max_enabled: options.session_max,
+            peak_threshold: options.peak_threshold,
+            recording: false,
+            restarts: 0,
+            input_label: None,
+            output_names,
+            overruns: 0,
+            input_block: None,
+            buffer_size: options.buffer_size,
+            underruns: 0,
+            skip_silence: options.skip_silence.map(|threshold| (threshold, options.skip_silence_min)),
+            skipped_secs: 0.0,
+            input_metered: false,
+            input_peak: 0.0,
+            input_rms: 0.0,
+            input_clipped: false,
+            paused: false,
+            frame_count: 0,
+            frame_end: 0,
+            waterfall_held: false,
+            fps: options.fps,
+            waterfall_fps: options.waterfall_fps,
+            notice: None,
+            banner: None,
+            output: options.output,
+            muted: false,
+            notch: options.notch,
+            notch_enabled: options.notch.is_some(),
+            right: None,
+            stereo_mode: options.stereo_mode,
+            bars: options.bars,
+            braille: options.braille,
+            trace_color: options.trace_color,
+            trace_style: options.trace_style,
+            level_range: options.level_range,
+            show_help: false,
+            diagnostics: Diagnostics::default(),
+            weighting: options.weighting,
+            monitor: options.monitor,
+            monitor_levels: VecDeque::with_capacity(MONITOR_HISTORY),
+            cw: (options.cw || options.cw_threshold.is_some()).then(|| CwDecoder::new(options.cw_threshold)),
+            tuner: options.tuner,
+            thd: options.thd || options.thd_fundamental.is_some(),
+            thd_fundamental: options.thd_fundamental,
+            thd_ratio: None,
+            flatness: None,
+            flux: None,
+            previous_spectrum: Vec::new(),
+            onset_threshold: options.onsets.then_some(options.onset_threshold),
+            onset_above: false,
+            onset_pending: false,
+            onsets: Vec::new(),
+            delay_enabled: options.delay,
+            delay: None,
+            scope: false,
+            scope_trigger: false,
+            scope_samples: Vec::with_capacity(options.fft_size),
+            xy: false,
+            xy_right: Vec::with_capacity(options.fft_size),
+            bandwidth: options.bandwidth.is_some(),
+            bandwidth_db: options.bandwidth.unwrap_or(DEFAULT_BANDWIDTH_DB),
+            tone: false,
+            tone_volume: options.tone_volume,
+            step: options.step,
+            gain_step_db: options.gain_step,
+            output_gain_db: options.output_gain,
+            output_delay_ms: options.output_delay,
+            balance: options.balance,
+            replay: None,
+            trigger: options.trigger.map(|level| Trigger::new(level, options.trigger_pre, options.trigger_post)),
+            live_line: vec![(0.0, 0.0); options.fft_size/2],
+            time_compress: options.time_compress,
+            time_compress_mode: options.time_compress_mode,
+            compressed: Vec::new(),
+            compressed_frames: 0,
+            waterfall_scale: options.waterfall_scale,
+            auto_contrast: options.auto_contrast,
+            calibration: options.auto_range.then(Calibration::default),
+            waterfall_gamma: options.waterfall_gamma,
+            persistence: options.persistence.is_some(),
+            persistence_decay: options.persistence.unwrap_or(DEFAULT_PERSISTENCE_DECAY),
+            compand: options.compand.is_some(),
+            compander: options.compand.unwrap_or(DEFAULT_COMPANDER),
+            contrast_level: 0.0,
+            contrast_scratch: Vec::with_capacity(options.fft_size / 2),
+            weights: Vec::new(),
+            weights_rate: 0,
+        };
+        state.freq_zoom = options.zoom.min(state.max_freq_zoom());
+        if state.lines_per_row() > 1 {
+            state.fit_history_rows(history_rows);
+        }
+        state
+    }
+
+    pub fn set_stereo(&mut self, stereo: bool) {
+        if stereo == self.right.is_some() {
+            return;
+        }
+        self.right = stereo.then(|| StereoChannel {
+            waterfall_data: vec![vec![(0.0, 0.0); self.fft_size / 2]; self.history_size],
+            average: vec![0.0; self.fft_size / 2],
+            envelope_levels: vec![0.0; self.fft_size / 2],
+            live_line: vec![(0.0, 0.0); self.fft_size / 2],
+            compressed: Vec::new(),
+        });
+    }
+
+    // Waterfalls shown side by side
+    pub fn panels(&self) -> usize {
+        if self.right.is_some() { 2 } else { 1 }
+    }
+
+    // Must come before `add_spectrum` for the same frame, which advances the shared line
+    fn update_weights(&mut self, sample_rate: u32) {
+        if self.weights_rate != sample_rate {
+            self.weights = self.weighting.table(sample_rate, self.fft_size);
+            self.weights_rate = sample_rate;
+        }
+    }
+
+    pub fn add_right_spectrum(&mut self, spectrum: &[f32], sample_rate: u32) {
+        self.update_weights(sample_rate);
+        let Some(right) = &mut self.right else { return };
+        let alpha = self.average_alpha;
+        if self.envelope.enabled {
+            self.envelope.follow(&mut right.envelope_levels, spectrum, &self.weights);
+        }
+        for (i, (avg, &mag)) in right.average.iter_mut().zip(spectrum).enumerate() {
+            let level = if self.envelope.enabled { right.envelope_levels[i] } else { mag * self.weights[i] };
+            *avg = alpha * level + (1.0 - alpha) * *avg;
+        }
+        let bin_width = sample_rate as f32 / self.fft_size as f32;
+        for (i, (point, &mag)) in right.live_line.iter_mut().zip(&right.average).enumerate() {
+            *point = (i as f32 * bin_width, mag);
+        }
+    }
+
+    // Zeroes the live spectrum and its average, so a stalled input reads as silence rather
+    // than as whatever it last carried. The history stays as it was.
+    pub fn clear_live_spectrum(&mut self) {
+        self.average.fill(0.0);
+        self.envelope_levels.fill(0.0);
+        for (_, mag) in &mut self.live_line {
+            *mag = 0.0;
+        }
+        if let Some(right) = &mut self.right {
+            right.average.fill(0.0);
+            right.envelope_levels.fill(0.0);
+            for (_, mag) in &mut right.live_line {
+                *mag = 0.0;
+            }
+        }
+        self.thd_ratio = None;
+        self.flatness = None;
+        self.delay = None;
+    }
+
+    // Builds the live line, then overwrites the oldest history line with it unless a
+    // trigger holds it back
+    pub fn add_spectrum(&mut self, spectrum: &[f32], sample_rate: u32) {
+        self.frame_count += 1;
+        self.update_weights(sample_rate);
+        for ((held, &mag), &weight) in self.peak_hold.iter_mut().zip(spectrum).zip(&self.weights) {
+            *held = (*held * (1.0 - self.peak_decay)).max(mag * weight);
+        }
+        for ((max, &mag), &weight) in self.session_max.iter_mut().zip(spectrum).zip(&self.weights) {
+            *max = max.max(mag * weight);
+        }
+
+        // Exponential moving average, of the envelope when it is on; alpha 1.0 passes the
+        // frame through
+        if self.envelope.enabled {
+            self.envelope.follow(&mut self.envelope_levels, spectrum, &self.weights);
+        }
+        let alpha = self.average_alpha;
+        for (i, (avg, &mag)) in self.average.iter_mut().zip(spectrum).enumerate() {
+            let level = if self.envelope.enabled { self.envelope_levels[i] } else { mag * self.weights[i] };
+            *avg = alpha * level + (1.0 - alpha) * *avg;
+        }
+
+        let bin_width = sample_rate as f32 / self.fft_size as f32;
+        for (i, (point, &mag)) in self.live_line.iter_mut().zip(&self.average).enumerate() {
+            let level = match (self.comparison, &self.reference, &self.reference_b) {
+                (Comparison::Live, _, _) | (_, None, _) => mag,
+                (Comparison::BMinusA, Some(a), Some(b)) => (b[i] - a[i]).max(0.0),
+                (_, Some(a), _) => (mag - a[i]).max(0.0),
+            };
+            *point = (i as f32 * bin_width, level);
+        }
+        if self.thd {
+            let fundamental = self.thd_fundamental.or_else(|| self.peak_of(&self.live_line).map(|(_, peak)| peak.freq));
+            self.thd_ratio = fundamental.and_then(|freq| thd(spectrum, freq, sample_rate));
+        }
+        self.flatness = spectral_flatness(spectrum);
+        self.flux = spectral_flux(&self.previous_spectrum, spectrum);
+        self.previous_spectrum.clear();
+        self.previous_spectrum.extend_from_slice(spectrum);
+        // An onset is a rise past the threshold, marked on the next line the history takes
+        if let Some(threshold) = self.onset_threshold {
+            let above = self.flux.is_some_and(|flux| flux >= threshold);
+            self.onset_pending |= above && !self.onset_above;
+            self.onset_above = above;
+        }
+        self.calibrate();
+        if self.follow_peak {
+            self.follow_peak_center(sample_rate);
+        }
+        if self.track_peaks > 0 {
+            self.update_peak_tracks(sample_rate);
+        }
+
+        if self.waterfall_held {
+            return;
+        }
+        if self.time_compress > 1 {
+            if !self.compress_live_line() {
+                return;
+            }
+            // The gathered line stands in for the live one while it is committed
+            self.swap_compressed();
+            self.record_live_line();
+            self.swap_compressed();
+        } else {
+            self.record_live_line();
+        }
+    }
+
+    // Folds the live line into the one being gathered; true once it holds `time_compress` frames
+    fn compress_live_line(&mut self) -> bool {
+        let first = self.compressed_frames == 0;
+        let mode = self.time_compress_mode;
+        fold_frame(&mut self.compressed, &self.live_line, first, mode);
+        if let Some(channel) = &mut self.right {
+            fold_frame(&mut channel.compressed, &channel.live_line, first, mode);
+        }
+        self.compressed_frames += 1;
+        if self.compressed_frames < self.time_compress {
+            return false;
+        }
+        if mode == TimeCompression::Mean {
+            let frames = self.compressed_frames as f32;
+            self.compressed.iter_mut().for_each(|(_, mag)| *mag /= frames);
+            if let Some(channel) = &mut self.right {
+                channel.compressed.iter_mut().for_each(|(_, mag)| *mag /= frames);
+            }
+        }
+        self.compressed_frames = 0;
+        true
+    }
+
+    fn swap_compressed(&mut self) {
+        std::mem::swap(&mut self.live_line, &mut self.compressed);
+        if let Some(channel) = &mut self.right {
+            std::mem::swap(&mut channel.live_line, &mut channel.compressed);
+        }
+    }
+
+    // Lines the trigger holds back or lets through, or every line without one
+    fn record_live_line(&mut self) {
+        let Some(mut trigger) = self.trigger.take() else {
+            self.commit_live_line();
+            return;
+        };
+        let peak = self.live_line.iter().skip(1).map(|&(_, mag)| mag).fold(0.0, f32::max);
+        if peak > 0.0 && self.level_db(peak) >= trigger.level_db {
+            for (left, right) in trigger.pending.drain(..) {
+                self.commit_line(&left, right.as_deref());
+            }
+            self.commit_live_line();
+            trigger.remaining = trigger.post;
+        } else if trigger.remaining > 0 {
+            self.commit_live_line();
+            trigger.remaining -= 1;
+        } else if trigger.pre > 0 {
+            // Reuse the oldest held line's buffers once the context is full
+            let (mut left, mut right) = if trigger.pending.len() == trigger.pre {
+                trigger.pending.pop_front().unwrap()
+            } else {
+                (Vec::new(), None)
+            };
+            left.clone_from(&self.live_line);
+            let right = self.right.as_ref().map(|channel| {
+                let mut line = right.take().unwrap_or_default();
+                line.clone_from(&channel.live_line);
+                line
+            });
+            trigger.pending.push_back((left, right));
+        }
+        self.trigger = Some(trigger);
+    }
+
+    fn commit_live_line(&mut self) {
+        let left = std::mem::take(&mut self.live_line);
+        let right = self.right.as_mut().map(|channel| std::mem::take(&mut channel.live_line));
+        self.commit_line(&left, right.as_deref());
+        self.live_line = left;
+        if let (Some(channel), Some(line)) = (&mut self.right, right) {
+            channel.live_line = line;
+        }
+    }
+
+    fn commit_line(&mut self, left: &[(f32, f32)], right: Option<&[(f32, f32)]>) {
+        self.waterfall_data[self.current_line].copy_from_slice(left);
+        // Lines held back by a trigger take the phase of the frame that lets them through
+        if let Some(line) = self.waterfall_phase.get_mut(self.current_line) {
+            line.clone_from(&self.phase);
+        }
+        if let (Some(channel), Some(line)) = (&mut self.right, right) {
+            channel.waterfall_data[self.current_line].copy_from_slice(line);
+        }
+        if self.persistence {
+            let previous = (self.current_line + self.history_size - 1) % self.history_size;
+            persist(&mut self.waterfall_data, self.current_line, previous, self.persistence_decay);
+            if let Some(channel) = &mut self.right {
+                persist(&mut channel.waterfall_data, self.current_line, previous, self.persistence_decay);
+            }
+        }
+        self.track_contrast();
+        if std::mem::take(&mut self.onset_pending) {
+            self.onsets.push(self.lines_committed);
+        }
+        self.current_line = (self.current_line + 1) % self.history_size;
+        self.lines_committed += 1;
+        self.filled_lines = (self.filled_lines + 1).min(self.history_size);
+        let (committed, kept) = (self.lines_committed, self.history_size as u64);
+        self.annotations.retain(|annotation| committed - annotation.line <= kept);
+        self.onsets.retain(|&line| committed - line <= kept);
+        // Scrolled back, the view stays on the same lines as new ones arrive
+        if self.history_offset > 0 {
+            self.scroll_history(1);
+        }
+    }
+
+    // Follows a high percentile of the newest line, skipping DC, for auto contrast
+    fn track_contrast(&mut self) {
+        self.contrast_scratch.clear();
+        self.contrast_scratch.extend(self.waterfall_data[self.current_line].iter().skip(1).map(|&(_, mag)| mag));
+        if self.contrast_scratch.is_empty() {
+            return;
+        }
+        let rank = ((self.contrast_scratch.len() - 1) as f32 * AUTO_CONTRAST_PERCENTILE) as usize;
+        let (_, &mut level, _) = self.contrast_scratch.select_nth_unstable_by(rank, f32::total_cmp);
+        self.contrast_level = if self.contrast_level > 0.0 {
+            self.contrast_level + (level - self.contrast_level) * AUTO_CONTRAST_SMOOTHING
+        } else {
+            level
+        };
+    }
+
+    // Factor applied to magnitudes before the waterfall colormap; silence leaves auto
+    // contrast on the manual scale rather than amplifying nothing
+    pub fn waterfall_gain(&self) -> f32 {
+        if self.auto_contrast && self.contrast_level > 0.0 {
+            (self.magnitude_for_intensity(AUTO_CONTRAST_TARGET) / self.contrast_level)
+                .clamp(MIN_WATERFALL_SCALE, MAX_WATERFALL_SCALE)
+        } else {
+            self.waterfall_scale
+        }
+    }
+
+    pub fn waterfall_intensity(&self, magnitude: f32) -> f32 {
+        self.intensity(magnitude * self.waterfall_gain()).powf(self.waterfall_gamma)
+    }
+
+    // Inverse of waterfall_intensity, for labeling the legend
+    pub fn waterfall_magnitude(&self, t: f32) -> f32 {
+        self.magnitude_for_intensity(t.powf(1.0 / self.waterfall_gamma)) / self.waterfall_gain()
+    }
+
+    pub fn adjust_waterfall_gamma(&mut self, factor: f32) {
+        self.waterfall_gamma = (self.waterfall_gamma * factor).clamp(MIN_WATERFALL_GAMMA, MAX_WATERFALL_GAMMA);
+    }
+
+    // Records the live line's peak and median, then applies the range once enough time has
+    // passed since the first frame; gain and contrast are left for the user to adjust after
+    fn calibrate(&mut self) {
+        let Some(calibration) = &mut self.calibration else { return };
+        let mut levels: Vec<f32> = self.live_line.iter().skip(1).map(|&(_, mag)| mag / self.gain).collect();
+        if levels.is_empty() {
+            return;
+        }
+        calibration.peaks.push(levels.iter().copied().fold(0.0, f32::max));
+        calibration.floors.push(median(&mut levels));
+        if calibration.started.get_or_insert_with(Instant::now).elapsed() < CALIBRATION_TIME {
+            return;
+        }
+
+        let mut calibration = self.calibration.take().unwrap();
+        let peak = median(&mut calibration.peaks);
+        let floor = median(&mut calibration.floors);
+        if peak <= 0.0 {
+            self.notify("Auto range found no signal".to_string());
+            return;
+        }
+        self.agc.enabled = false;
+        self.auto_contrast = false;
+        self.gain = (self.magnitude_for_intensity(AGC_TARGET_INTENSITY) / peak).clamp(MIN_GAIN, MAX_GAIN);
+        // The floor rises as far as it can without pushing the peak off the top
+        let peak_limit = self.magnitude_for_intensity(1.0) / (peak * self.gain);
+        let floor_scale = if floor > 0.0 {
+            self.magnitude_for_intensity(CALIBRATION_FLOOR_INTENSITY) / (floor * self.gain)
+        } else {
+            peak_limit
+        };
+        self.waterfall_scale = floor_scale.min(peak_limit).clamp(MIN_WATERFALL_SCALE, MAX_WATERFALL_SCALE);
+        self.notify(format!("Auto range: gain {:+.1} dB, contrast {:.2}x", self.gain_db(), self.waterfall_scale));
+    }
+
+    // Starts from whatever auto contrast had settled on, then leaves it to the user
+    pub fn adjust_waterfall_scale(&mut self, factor: f32) {
+        self.waterfall_scale = (self.waterfall_gain() * factor).clamp(MIN_WATERFALL_SCALE, MAX_WATERFALL_SCALE);
+        self.auto_contrast = false;
+    }
+
+    pub fn tracks_phase(&self) -> bool {
+        self.phase_panel || !self.waterfall_phase.is_empty()
+    }
+
+    // Phases of the frame about to be added, for the phase colormap and the phase panel
+    pub fn set_phase(&mut self, phase: &[f32]) {
+        self.phase.clear();
+        self.phase.extend_from_slice(phase);
+    }
+
+    // `end` is the sample the level's window ends on, which times the CW decoder's keying
+    pub fn add_monitor_level(&mut self, level: f32, end: u64, sample_rate: u32) {
+        if self.monitor_levels.len() == MONITOR_HISTORY {
+            self.monitor_levels.pop_front();
+        }
+        self.monitor_levels.push_back(level);
+        let level_db = self.to_db(level);
+        if let Some(cw) = &mut self.cw {
+            cw.update(level_db, end as f64 / sample_rate as f64);
+        }
+    }
+
+    // The peak reading falls back slowly so short transients stay readable
+    pub fn update_input_meter(&mut self, (peak, rms, clipped): (f32, f32, bool), dt: f32) {
+        self.input_metered = true;
+        let fall = 10f32.powf(-INPUT_PEAK_FALL_DB_PER_S * dt / 20.0);
+        self.input_peak = peak.max(self.input_peak * fall);
+        self.input_rms = rms;
+        self.input_clipped |= clipped;
+    }
+
+    // Blank waterfall and averages, as before the first frame
+    pub fn clear_history(&mut self) {
+        for line in &mut self.waterfall_data {
+            line.fill((0.0, 0.0));
+        }
+        for line in &mut self.waterfall_phase {
+            line.clear();
+        }
+        self.phase.clear();
+        self.average.fill(0.0);
+        self.envelope_levels.fill(0.0);
+        self.peak_hold.fill(0.0);
+        self.live_line.fill((0.0, 0.0));
+        self.compressed_frames = 0;
+        if let Some(right) = &mut self.right {
+            for line in &mut right.waterfall_data {
+                line.fill((0.0, 0.0));
+            }
+            right.average.fill(0.0);
+            right.envelope_levels.fill(0.0);
+            right.live_line.fill((0.0, 0.0));
+        }
+        if let Some(trigger) = &mut self.trigger {
+            trigger.pending.clear();
+            trigger.remaining = 0;
+        }
+        self.annotations.clear();
+        self.onsets.clear();
+        self.current_line = 0;
+        self.filled_lines = 0;
+    }
+
+    pub fn filled_lines(&self) -> usize {
+        self.filled_lines
+    }
+
+    // Per-bin buffers start over at the new size, taking the history and reference with them
+    pub fn set_fft_size(&mut self, fft_size: usize) {
+        let bins = fft_size / 2;
+        self.fft_size = fft_size;
+        self.waterfall_data = vec![vec![(0.0, 0.0); bins]; self.history_size];
+        self.average = vec![0.0; bins];
+        self.envelope_levels = vec![0.0; bins];
+        self.peak_hold = vec![0.0; bins];
+        self.session_max = vec![0.0; bins];
+        self.live_line = vec![(0.0, 0.0); bins];
+        let stereo = self.right.take().is_some();
+        self.set_stereo(stereo);
+        self.clear_references();
+        self.weights_rate = 0;
+        self.freq_zoom = self.freq_zoom.min(self.max_freq_zoom());
+        self.history_offset = 0;
+        self.clear_history();
+    }
+
+    pub fn resize_history(&mut self, history_size: usize) {
+        let columns = self.fft_size / 2;
+        let blank = vec![(0.0, 0.0); columns];
+        relay_history(&mut self.waterfall_data, self.current_line, history_size, &blank);
+        if self.tracks_phase() {
+            relay_history(&mut self.waterfall_phase, self.current_line, history_size, &[]);
+        }
+        if let Some(right) = &mut self.right {
+            relay_history(&mut right.waterfall_data, self.current_line, history_size, &blank);
+        }
+        self.history_size = history_size;
+        self.filled_lines = self.filled_lines.min(history_size);
+        self.current_line = 0;
+        self.scroll_history(0);
+    }
+
+    // Positive deltas look further back; the offset stops at the oldest kept line
+    pub fn scroll_history(&mut self, delta: isize) {
+        let max_offset = self.history_size.saturating_sub(self.visible_lines);
+        self.history_offset = self.history_offset.saturating_add_signed(delta).min(max_offset);
+    }
+
+    // History lines drawn in each waterfall row; half blocks need colors to tell the two apart
+    pub fn lines_per_row(&self) -> usize {
+        let packed = self.waterfall_glyph == WaterfallGlyph::HalfBlocks && !self.ascii
+            && self.color_mode != ColorMode::Mono;
+        if packed { 2 } else { 1 }
+    }
+
+    // Only grows or shrinks the kept history when --history doesn't already cover the rows
+    pub fn fit_history_rows(&mut self, rows: usize) {
+        self.visible_lines = rows * self.lines_per_row();
+        let history_size = self.visible_lines.max(self.history_depth);
+        if history_size != self.history_size {
+            self.resize_history(history_size);
+        } else {
+            self.scroll_history(0);
+        }
+    }
+
+    // Visible history line at a position oldest first, or newest first when scrolling down
+    pub fn history_index(&self, row: usize) -> usize {
+        (self.current_line + self.history_size - 1 - self.line_back(row) % self.history_size) % self.history_size
+    }
+
+    // Lines back from the newest to the one drawn at a screen line; `line_row` undoes it
+    fn line_back(&self, row: usize) -> usize {
+        self.history_offset + match self.scroll {
+            Scroll::Up => self.visible_lines.saturating_sub(row + 1),
+            Scroll::Down => row,
+        }
+    }
+
+    // Waterfall row a line this far back is drawn on, if it is on screen
+    fn line_row(&self, back: usize) -> Option<usize> {
+        let back = back.checked_sub(self.history_offset).filter(|&back| back < self.visible_lines)?;
+        let line = match self.scroll {
+            Scroll::Up => self.visible_lines - 1 - back,
+            Scroll::Down => back,
+        };
+        Some(line / self.lines_per_row())
+    }
+
+    // Drops a marker at the strongest bin under a column of a waterfall row, if the row
+    // holds a line yet
+    pub fn annotate(&mut self, column: usize, row: usize, width: usize, sample_rate: u32) {
+        let back = self.line_back(row * self.lines_per_row());
+        if back as u64 >= self.lines_committed.min(self.history_size as u64) {
+            return;
+        }
+        let line = &self.waterfall_data[self.history_index(row * self.lines_per_row())];
+        let strongest = self.column_bins(column, width, sample_rate)
+            .filter(|&i| i < line.len())
+            .max_by(|&a, &b| line[a].1.total_cmp(&line[b].1));
+        let freq = strongest.map_or_else(|| self.column_freq(column, width, sample_rate), |i| line[i].0);
+        self.annotations.push(Annotation { freq, line: self.lines_committed - 1 - back as u64 });
+    }
+
+    // Marks the cursor column on the visible row where it is loudest
+    pub fn annotate_cursor(&mut self, width: usize, sample_rate: u32) {
+        let Some(column) = self.cursor_column(width) else {
+            self.notify("Place the cursor first".into());
+            return;
+        };
+        let lines = self.lines_per_row();
+        let loudest = (0..self.visible_lines / lines)
+            .filter_map(|row| {
+                let line = &self.waterfall_data[self.history_index(row * lines)];
+                Some((row, self.column_magnitude(line, column, width, sample_rate)?))
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((row, _)) = loudest {
+            self.annotate(column, row, width, sample_rate);
+        }
+    }
+
+    // Frames between an annotation's line and the newest one
+    pub fn annotation_age(&self, annotation: &Annotation) -> usize {
+        (self.lines_committed - 1 - annotation.line) as usize
+    }
+
+    pub fn annotation_row(&self, annotation: &Annotation) -> Option<usize> {
+        self.line_row(self.annotation_age(annotation))
+    }
+
+    // Waterfall rows of the onsets on screen
+    pub fn onset_rows(&self) -> impl Iterator<Item = usize> + '_ {
+        self.onsets.iter().filter_map(|&line| self.line_row((self.lines_committed - 1 - line) as usize))
+    }
+
+    // Level of the annotated bin in the line it was placed on
+    pub fn annotation_magnitude(&self, annotation: &Annotation, sample_rate: u32) -> f32 {
+        let age = self.annotation_age(annotation);
+        let line = &self.waterfall_data[(self.current_line + self.history_size - 1 - age) % self.history_size];
+        let bin = (annotation.freq * self.fft_size as f32 / sample_rate as f32).round() as usize;
+        line.get(bin).map_or(0.0, |&(_, mag)| mag)
+    }
+
+    // Frames since the newest line on a screen row was captured
+    pub fn row_age(&self, row: usize) -> usize {
+        let lines = self.lines_per_row();
+        self.history_offset + match self.scroll {
+            Scroll::Up => self.visible_lines.saturating_sub(lines * (row + 1)),
+            Scroll::Down => row * lines,
+        }
+    }
+
+    // Newest analyzed spectrum, whether or not a trigger let it into the history
+    pub fn latest_line(&self) -> &[(f32, f32)] {
+        &self.live_line
+    }
+
+    // What the spectrum and its readouts show: the history line under the row cursor, or
+    // the newest spectrum without one
+    pub fn shown_line(&self) -> &[(f32, f32)] {
+        match self.cursor_line_back() {
+            Some(back) => &self.waterfall_data[(self.current_line + self.history_size - 1 - back) % self.history_size],
+            None => &self.live_line,
+        }
+    }
+
+    // Lines back to the newest line of the cursor row, if the row is on screen and holds one
+    pub fn cursor_line_back(&self) -> Option<usize> {
+        let row = self.cursor_row.filter(|&row| row < self.visible_lines / self.lines_per_row())?;
+        let back = self.row_age(row);
+        ((back as u64) < self.lines_committed.min(self.history_size as u64)).then_some(back)
+    }
+
+    // Up and down the screen; the first move lands on the newest row, and stepping past it
+    // returns the spectrum to the live line
+    pub fn move_cursor_row(&mut self, delta: isize) {
+        let rows = (self.visible_lines / self.lines_per_row()) as isize;
+        let newest = match self.scroll {
+            Scroll::Up => rows - 1,
+            Scroll::Down => 0,
+        };
+        self.cursor_row = match self.cursor_row {
+            None => Some(newest.max(0) as usize),
+            Some(row) => {
+                let row = row as isize + delta;
+                (0..rows).contains(&row).then_some(row as usize)
+            }
+        };
+    }
+
+    // Strongest non-DC bin of the shown spectrum, if it clears the noise threshold
+    pub fn find_peak(&self) -> Option<Peak> {
+        self.peak_bin().map(|(_, peak)| peak)
+    }
+
+    fn peak_bin(&self) -> Option<(usize, Peak)> {
+        self.peak_of(self.shown_line())
+    }
+
+    // The loudest bin, with its frequency refined between the bins around it
+    fn peak_of(&self, line: &[(f32, f32)]) -> Option<(usize, Peak)> {
+        line.iter()
+            .enumerate()
+            .skip(1)
+            .max_by(|a, b| a.1.1.total_cmp(&b.1.1))
+            .filter(|&(_, &(_, mag))| mag > 0.0 && self.to_db(mag) >= self.peak_threshold)
+            .map(|(bin, &(freq, magnitude))| {
+                let offset = match line.get(bin + 1) {
+                    Some(&(_, right)) if bin > 1 => parabolic_offset(line[bin - 1].1, magnitude, right),
+                    _ => 0.0,
+                };
+                (bin, Peak { freq: freq + offset * freq / bin as f32, magnitude, bin })
+            })
+    }
+
+    // Peak over the median of the bins around it, in dB
+    pub fn snr_db(&self) -> Option<f32> {
+        let (bin, peak) = self.peak_bin()?;
+        let line = self.shown_line();
+        let mut noise: Vec<f32> = (bin.saturating_sub(SNR_NOISE_SPAN_BINS).max(1)..line.len())
+            .take_while(|&i| i <= bin + SNR_NOISE_SPAN_BINS)
+            .filter(|&i| i.abs_diff(bin) > SNR_GUARD_BINS)
+            .map(|i| line[i].1)
+            .collect();
+        if noise.is_empty() {
+            return None;
+        }
+        let middle = noise.len() / 2;
+        let (_, &mut floor, _) = noise.select_nth_unstable_by(middle, f32::total_cmp);
+        (floor > 0.0).then(|| 20.0 * (peak.magnitude / floor).log10())
+    }
+
+    pub fn set_scope_samples(&mut self, samples: &[f32]) {
+        self.scope_samples.clear();
+        self.scope_samples.extend_from_slice(samples);
+    }
+
+    pub fn set_xy_right(&mut self, samples: &[f32]) {
+        self.xy_right.clear();
+        self.xy_right.extend_from_slice(samples);
+    }
+
+    pub fn xy_pairs(&self) -> impl Iterator<Item = (f32, f32)> + '_ {
+        self.scope_samples.iter().copied().zip(self.xy_right.iter().copied())
+    }
+
+    // Half the window, the newest half unless the trigger finds a rising zero crossing to
+    // start on, which holds a steady tone still
+    pub fn scope_trace(&self) -> &[f32] {
+        let samples = &self.scope_samples;
+        let len = samples.len() / 2;
+        let newest = samples.len() - len;
+        let start = if self.scope_trigger {
+            (1..=newest).find(|&i| samples[i - 1] < 0.0 && samples[i] >= 0.0).unwrap_or(newest)
+        } else {
+            newest
+        };
+        &samples[start..start + len]
+    }
+
+    // Where the level first falls bandwidth_db below the peak on either side, interpolated
+    // between the two bins straddling the drop
+    pub fn peak_bandwidth(&self) -> Option<(f32, f32)> {
+        let (bin, peak) = self.peak_bin()?;
+        let line = self.shown_line();
+        let threshold = peak.magnitude * 10f32.powf(-self.bandwidth_db / 20.0);
+        let edge = |inner: usize, outer: usize| {
+            let ((inner_freq, inner_mag), (outer_freq, outer_mag)) = (line[inner], line[outer]);
+            inner_freq + (outer_freq - inner_freq) * (inner_mag - threshold) / (inner_mag - outer_mag)
+        };
+        let low = (1..bin).rev().find(|&i| line[i].1 < threshold).map(|i| edge(i + 1, i))?;
+        let high = (bin + 1..line.len()).find(|&i| line[i].1 < threshold).map(|i| edge(i - 1, i))?;
+        Some((low, high))
+    }
+
+    pub fn spectrum_stats(&self) -> Option<SpectrumStats> {
+        let bins = self.shown_line().get(1..).filter(|bins| !bins.is_empty())?;
+        let (max_freq, max) = bins.iter().copied().max_by(|a, b| a.1.total_cmp(&b.1))?;
+        let min = bins.iter().map(|&(_, mag)| mag).fold(f32::INFINITY, f32::min);
+        let mean = bins.iter().map(|&(_, mag)| mag).sum::<f32>() / bins.len() as f32;
+        let rms = (bins.iter().map(|&(_, mag)| mag * mag).sum::<f32>() / bins.len() as f32).sqrt();
+        Some(SpectrumStats { min, max, max_freq, mean, rms })
+    }
+
+    // Later frames show only what rises above the spectrum captured here
+    pub fn capture_reference(&mut self) {
+        self.reference = Some(self.average.clone());
+    }
+
+    pub fn capture_reference_b(&mut self) {
+        self.reference_b = Some(self.average.clone());
+    }
+
+    pub fn clear_references(&mut self) {
+        self.reference = None;
+        self.reference_b = None;
+        self.comparison = Comparison::LiveMinusA;
+    }
+
+    // Through the comparisons the captured references allow; B less A needs both
+    pub fn cycle_comparison(&mut self) {
+        self.comparison = match self.comparison {
+            Comparison::LiveMinusA => Comparison::Live,
+            Comparison::Live if self.reference_b.is_some() => Comparison::BMinusA,
+            _ => Comparison::LiveMinusA,
+        };
+    }
+
+    // Whether lines come out with reference A taken off
+    fn reference_subtracted(&self) -> bool {
+        self.reference.is_some() && self.comparison != Comparison::Live
+    }
+
+    // Off, then a fixed level, then relative to the reference when one is captured
+    pub fn cycle_gate(&mut self) {
+        self.gate = match self.gate {
+            GateMode::Off => GateMode::Level,
+            GateMode::Level if self.reference.is_some() => GateMode::Reference,
+            _ => GateMode::Off,
+        };
+    }
+
+    // Moves whichever threshold the gate is using
+    pub fn adjust_gate(&mut self, delta_db: f32) {
+        match self.gate {
+            GateMode::Off => (),
+            GateMode::Level => self.gate_db = (self.gate_db + delta_db).clamp(self.db_floor, 0.0),
+            GateMode::Reference => {
+                self.gate_margin_db = (self.gate_margin_db + delta_db).clamp(0.0, MAX_GATE_MARGIN_DB);
+            }
+        }
+    }
+
+    // Magnitude each waterfall column must reach to be drawn, or None with the gate open. A
+    // line usually has the reference already subtracted, so above it by the margin means
+    // above the reference times the margin less one.
+    pub fn gate_thresholds(&self, width: usize, sample_rate: u32) -> Option<Vec<f32>> {
+        match (self.gate, &self.reference) {
+            (GateMode::Level, _) => {
+                let reference = self.fft_size as f32 / 2.0 * BASE_GAIN;
+                Some(vec![reference * 10f32.powf(self.gate_db / 20.0); width])
+            }
+            (GateMode::Reference, Some(floor)) => {
+                let subtracted = if self.reference_subtracted() { 1.0 } else { 0.0 };
+                let factor = 10f32.powf(self.gate_margin_db / 20.0) - subtracted;
+                Some((0..width).map(|j| self.column_max(floor, j, width, sample_rate).unwrap_or(0.0) * factor).collect())
+            }
+            _ => None,
+        }
+    }
+
+    // Inverse of `intensity`: the magnitude that lands at a given display level
+    pub fn magnitude_for_intensity(&self, t: f32) -> f32 {
+        let t = if self.compand { self.compander.expand(t) } else { t };
+        match self.scale {
+            Scale::Linear => t / 2.0,
+            Scale::Db => {
+                let reference = self.fft_size as f32 / 2.0 * BASE_GAIN;
+                reference * 10f32.powf((self.db_floor * (1.0 - t)) / 20.0)
+            }
+        }
+    }
+
+    // Moves gain toward the level that puts the peak at the AGC target, using the attack
+    // time constant when turning down and the release one when turning up
+    pub fn update_agc(&mut self, dt: f32) {
+        if !self.agc.enabled {
+            return;
+        }
+        let peak = self.latest_line().iter().skip(1).map(|&(_, mag)| mag).fold(0.0, f32::max);
+        let input_level = self.level_db(peak / self.gain);
+        let desired = if peak > 0.0 {
+            self.gain * self.magnitude_for_intensity(AGC_TARGET_INTENSITY) / peak
+        } else {
+            self.gain
+        };
+        // Silence must never be amplified into noise
+        if desired > self.gain && input_level < self.agc.floor_db {
+            return;
+        }
+        let tau = if desired < self.gain { self.agc.attack } else { self.agc.release };
+        let coefficient = 1.0 - (-dt / tau).exp();
+        self.gain = (self.gain + (desired - self.gain) * coefficient).clamp(MIN_GAIN, MAX_GAIN);
+    }
+
+    // The next preset above the current step, wrapping around to the finest
+    pub fn cycle_step(&mut self) {
+        self.step = STEP_PRESETS.into_iter().find(|&step| step > self.step).unwrap_or(STEP_PRESETS[0]);
+        self.gain_step_db = GAIN_STEP_PRESETS_DB.into_iter()
+            .find(|&step| step > self.gain_step_db)
+            .unwrap_or(GAIN_STEP_PRESETS_DB[0]);
+    }
+
+    pub fn gain_db(&self) -> f32 {
+        20.0 * self.gain.log10()
+    }
+
+    // Steps that would leave the safe range are ignored rather than clipped. The level is
+    // rounded to a hundredth of a dB so whole steps keep landing on whole values.
+    pub fn adjust_gain(&mut self, step_db: f32) {
+        self.agc.enabled = false;
+        let gain_db = ((self.gain_db() + step_db) * 100.0).round() / 100.0;
+        if (MIN_GAIN_DB..=MAX_GAIN_DB).contains(&gain_db) {
+            self.gain = gain_factor(gain_db);
+        }
+    }
+
+    pub fn max_freq_zoom(&self) -> f32 {
+        (self.fft_size as f32 / 2.0 / MIN_VISIBLE_BINS).max(1.0)
+    }
+
+    // Zooms around the cursor when there is one, keeping it on the same frequency
+    pub fn adjust_zoom(&mut self, factor: f32, width: usize, sample_rate: u32) {
+        let zoom = self.freq_zoom * factor;
+        let zoom = if (1.0..=self.max_freq_zoom()).contains(&zoom) {
+            zoom
+        } else if factor < 1.0 {
+            1.0
+        } else {
+            return;
+        };
+
+        let cursor_freq = self.cursor_column(width).map(|c| self.column_freq(c, width, sample_rate));
+        if let Some(freq) = cursor_freq {
+            self.center_freq = Some(freq);
+        }
+        self.freq_zoom = zoom;
+        if let Some(freq) = cursor_freq {
+            self.cursor = self.freq_column(freq, width, sample_rate).or(self.cursor);
+        }
+    }
+
+    pub fn adjust_tone_volume(&mut self, delta: f32) {
+        self.tone_volume = (self.tone_volume + delta).clamp(0.0, 1.0);
+    }
+
+    pub fn adjust_output_gain(&mut self, delta_db: f32) {
+        self.output_gain_db = (self.output_gain_db + delta_db).clamp(MIN_OUTPUT_GAIN_DB, MAX_OUTPUT_GAIN_DB);
+    }
+
+    // Rounded so stepping back lands exactly on center
+    pub fn adjust_balance(&mut self, delta: f32) {
+        self.balance = ((self.balance + delta) * 100.0).round().clamp(-100.0, 100.0) / 100.0;
+    }
+
+    pub fn adjust_fps(&mut self, delta: i64) {
+        self.fps = self.fps.saturating_add_signed(delta).clamp(MIN_FPS, MAX_FPS);
+    }
+
+    pub fn frame_time(&self) -> Duration {
+        Duration::from_micros(1_000_000 / self.fps.max(MIN_FPS))
+    }
+
+    // Time each waterfall line covers, for the time axis and history readouts
+    pub fn line_time(&self) -> Duration {
+        self.frame_time() * self.time_compress as u32
+    }
+
+    // Starts the next line afresh, since the frames gathered so far were meant for the old count
+    pub fn adjust_time_compress(&mut self, delta: isize) {
+        self.time_compress = self.time_compress.saturating_add_signed(delta).clamp(1, MAX_TIME_COMPRESS);
+        self.compressed_frames = 0;
+        self.notify(format!("{} frame(s) per waterfall line, {:.2} s each", self.time_compress,
+                            self.line_time().as_secs_f32()));
+    }
+
+    // Short message shown in the header for a few seconds
+    pub fn notify(&mut self, message: String) {
+        self.notice = Some((message, Instant::now()));
+    }
+
+    pub fn current_notice(&self) -> Option<&str> {
+        self.notice.as_ref()
+            .filter(|(_, shown)| shown.elapsed() < NOTICE_DURATION)
+            .map(|(message, _)| message.as_str())
+    }
+
+    pub fn reset_view(&mut self) {
+        self.gain = gain_factor(DEFAULT_GAIN_DB);
+        self.zoom_out();
+        self.agc.enabled = false;
+    }
+
+    pub fn zoom_out(&mut self) {
+        self.freq_zoom = 1.0;
+        self.center_freq = None;
+    }
+
+    // Shows the band between two frequencies as the zoom and center that give it, as far
+    // as the zoom limit allows
+    pub fn set_freq_range(&mut self, min_freq: f32, max_freq: f32, sample_rate: u32) {
+        let low = self.band_position(min_freq, sample_rate);
+        let high = self.band_position(max_freq, sample_rate);
+        self.freq_zoom = (1.0 / (high - low)).clamp(1.0, self.max_freq_zoom());
+        self.center_freq = Some(self.band_freq((low + high) / 2.0, sample_rate));
+    }
+
+    // Fills the width with the band between two columns
+    pub fn zoom_to_columns(&mut self, first: usize, last: usize, width: usize, sample_rate: u32) {
+        let min_freq = self.column_freq(first.min(last), width, sample_rate);
+        let max_freq = self.column_freq(first.max(last) + 1, width, sample_rate);
+        self.set_freq_range(min_freq, max_freq, sample_rate);
+        self.cursor = None;
+    }
+
+    pub fn adjust_average(&mut self, factor: f32) {
+        self.average_alpha = (self.average_alpha * factor).clamp(MIN_AVERAGE_ALPHA, 1.0);
+    }
+
+    pub fn reset_peak_hold(&mut self) {
+        self.peak_hold.fill(0.0);
+    }
+
+    pub fn reset_session_max(&mut self) {
+        self.session_max.fill(0.0);
+    }
+
+    // 0 dB corresponds to a full-scale sine at unity gain
+    pub fn level_db(&self, magnitude: f32) -> f32 {
+        let reference = self.fft_size as f32 / 2.0 * BASE_GAIN;
+        20.0 * (magnitude / reference).log10()
+    }
+
+    pub fn to_db(&self, magnitude: f32) -> f32 {
+        self.level_db(magnitude).max(self.db_floor)
+    }
+
+    // A bin's level over full scale, whatever the display does to it: with the gain, display
+    // tilt and window's coherent gain out, a full-scale sine reads 0 dB at any frequency,
+    // window or FFT size
+    pub fn full_scale_db(&self, magnitude: f32, bin: usize) -> f32 {
+        20.0 * bin_sine_amplitude(magnitude, bin as f32, self.captured(), self.window, self.gain).log10()
+    }
+
+    // `full_scale_db` of a level the display tilt is already out of, such as a band's
+    pub fn untilted_full_scale_db(&self, level: f32) -> f32 {
+        20.0 * sine_amplitude(level, self.captured(), self.window, self.gain).log10()
+    }
+
+    // The signal's RMS over a full-scale sine's, from the power of every bin past DC less the
+    // noise bandwidth the window spreads it over
+    pub fn full_scale_rms_db(&self) -> Option<f32> {
+        let bins = self.shown_line().get(1..).filter(|bins| !bins.is_empty())?;
+        let power: f32 = bins.iter().enumerate()
+            .map(|(i, &(_, mag))| bin_sine_amplitude(mag, (i + 1) as f32, self.captured(), self.window, self.gain))
+            .map(|amplitude| amplitude * amplitude)
+            .sum();
+        Some(10.0 * (power / (self.window.noise_bandwidth() * self.zero_pad as f32)).log10())
+    }
+
+    // Samples each frame's window spans, without the zero padding
+    fn captured(&self) -> usize {
+        self.fft_size / self.zero_pad
+    }
+
+    // A level on the spectrum chart: the magnitude at `freq` as `quantity` measures it
+    pub fn display_value(&self, magnitude: f32, freq: f32, sample_rate: u32) -> f32 {
+        match (self.scale, self.quantity) {
+            (Scale::Linear, _) => self.quantity_level(magnitude, freq, sample_rate),
+            (Scale::Db, Quantity::Magnitude) => self.to_db(magnitude),
+            (Scale::Db, _) => self.power_db(self.quantity_level(magnitude, freq, sample_rate), sample_rate),
+        }
+    }
+
+    // See `Quantity` for what each one takes out of the magnitude
+    fn quantity_level(&self, magnitude: f32, freq: f32, sample_rate: u32) -> f32 {
+        if self.quantity == Quantity::Magnitude {
+            return magnitude;
+        }
+        let bin = freq * self.fft_size as f32 / sample_rate as f32;
+        let amplitude = bin_sine_amplitude(magnitude, bin, self.captured(), self.window, self.gain);
+        match self.quantity {
+            Quantity::Psd => amplitude * amplitude / self.noise_bandwidth_hz(sample_rate),
+            _ => amplitude * amplitude,
+        }
+    }
+
+    fn noise_bandwidth_hz(&self, sample_rate: u32) -> f32 {
+        self.window.noise_bandwidth() * sample_rate as f32 / self.captured() as f32
+    }
+
+    // Lowest level the chart shows: the dB floor, calibrated for power and moved down by the
+    // noise bandwidth for PSD
+    pub fn display_floor_db(&self, sample_rate: u32) -> f32 {
+        match self.quantity {
+            Quantity::Magnitude => self.db_floor,
+            Quantity::Power => self.db_floor + self.cal_offset_db,
+            Quantity::Psd => self.db_floor - 10.0 * self.noise_bandwidth_hz(sample_rate).log10() + self.cal_offset_db,
+        }
+    }
+
+    fn power_db(&self, power: f32, sample_rate: u32) -> f32 {
+        (10.0 * power.log10() + self.cal_offset_db).max(self.display_floor_db(sample_rate))
+    }
+
+    // A readout at one frequency, in the chart's quantity and unit
+    pub fn format_quantity(&self, magnitude: f32, freq: f32, sample_rate: u32) -> String {
+        match (self.scale, self.quantity) {
+            (_, Quantity::Magnitude) => self.format_level(magnitude),
+            (Scale::Linear, _) => format!("{:.3e}", self.quantity_level(magnitude, freq, sample_rate)),
+            (Scale::Db, _) => {
+                format!("{:.1} {}", self.display_value(magnitude, freq, sample_rate), self.quantity_unit())
+            }
+        }
+    }
+
+    // The level unit, per Hz for PSD and uncalibrated for magnitude
+    pub fn quantity_unit(&self) -> String {
+        match self.quantity {
+            Quantity::Magnitude => "dB".to_string(),
+            Quantity::Power => self.level_unit.label().to_string(),
+            Quantity::Psd => format!("{}/Hz", self.level_unit.label()),
+        }
+    }
+
+    // A locked level range was in the old quantity's units, so it is fitted again
+    pub fn cycle_quantity(&mut self) {
+        self.quantity = self.quantity.next();
+        self.level_range = None;
+        self.notify(format!("Spectrum level: {}", self.quantity.name()));
+    }
+
+    // Maps a magnitude onto the 0..1 range fed to the colormap
+    pub fn intensity(&self, magnitude: f32) -> f32 {
+        let t = match self.scale {
+            Scale::Linear => (magnitude * 2.0).min(1.0),
+            Scale::Db => ((self.to_db(magnitude) - self.db_floor) / -self.db_floor).clamp(0.0, 1.0),
+        };
+        if self.compand { self.compander.apply(t) } else { t }
+    }
+
+    // A level on the spectrum chart as drawn: `display_value`, or the companded 0..1 level
+    // while companding, which has no units of its own
+    pub fn chart_value(&self, magnitude: f32, freq: f32, sample_rate: u32) -> f32 {
+        if self.compand { self.intensity(magnitude) } else { self.display_value(magnitude, freq, sample_rate) }
+    }
+
+    // Lowest frequency on the axis: `fmin`, or on the log axis at least the first non-DC
+    // bin. A remote sender's rate can leave `fmin` past Nyquist, so a bin's width is kept.
+    fn band_floor(&self, sample_rate: u32) -> f32 {
+        let bin_width = sample_rate as f32 / self.fft_size as f32;
+        let floor = match self.freq_axis {
+            FreqAxis::Linear => self.fmin,
+            FreqAxis::Log => self.fmin.max(bin_width),
+        };
+        floor.min(sample_rate as f32 / 2.0 - bin_width)
+    }
+
+    // Position of a frequency across the full band, 0 at the band floor and 1 at Nyquist
+    pub fn band_position(&self, freq: f32, sample_rate: u32) -> f32 {
+        let nyquist = sample_rate as f32 / 2.0;
+        let floor = self.band_floor(sample_rate);
+        match self.freq_axis {
+            FreqAxis::Linear => (freq - floor) / (nyquist - floor),
+            FreqAxis::Log => (freq.max(floor) / floor).ln() / (nyquist / floor).ln(),
+        }
+    }
+
+    pub fn band_freq(&self, position: f32, sample_rate: u32) -> f32 {
+        let nyquist = sample_rate as f32 / 2.0;
+        let floor = self.band_floor(sample_rate);
+        match self.freq_axis {
+            FreqAxis::Linear => floor + position * (nyquist - floor),
+            FreqAxis::Log => floor * (nyquist / floor).powf(position),
+        }
+    }
+
+    // Visible frequency band: 1/zoom of the full band around `center_freq`, or from the bottom
+    pub fn freq_range(&self, sample_rate: u32) -> (f32, f32) {
+        let span = 1.0 / self.freq_zoom;
+        let low = self.center_freq.map_or(0.0, |center| {
+            (self.band_position(center, sample_rate) - span / 2.0).clamp(0.0, 1.0 - span)
+        });
+        (self.band_freq(low, sample_rate), self.band_freq(low + span, sample_rate))
+    }
+
+    pub fn freq_column(&self, freq: f32, width: usize, sample_rate: u32) -> Option<usize> {
+        let (min_freq, max_freq) = self.freq_range(sample_rate);
+        if !(min_freq..max_freq).contains(&freq) {
+            return None;
+        }
+        let low = self.band_position(min_freq, sample_rate);
+        let high = self.band_position(max_freq, sample_rate);
+        let t = (self.band_position(freq, sample_rate) - low) / (high - low);
+        Some(((t * width as f32) as usize).min(width.saturating_sub(1)))
+    }
+
+    // Each track, oldest first, takes the nearest of this frame's peaks within the tolerance;
+    // peaks left over start tracks while there is room for them
+    fn update_peak_tracks(&mut self, sample_rate: u32) {
+        let bin_width = sample_rate as f32 / self.fft_size as f32;
+        let magnitudes: Vec<f32> = self.live_line.iter().map(|&(_, mag)| mag).collect();
+        let mut peaks: Vec<Peak> = local_peaks(&magnitudes, TRACK_PEAK_REACH_BINS).into_iter()
+            .filter(|&bin| self.to_db(magnitudes[bin]) >= self.peak_threshold)
+            .take(self.track_peaks)
+            .map(|bin| {
+                let offset = match magnitudes.get(bin + 1) {
+                    Some(&right) if bin > 1 => parabolic_offset(magnitudes[bin - 1], magnitudes[bin], right),
+                    _ => 0.0,
+                };
+                Peak { freq: (bin as f32 + offset) * bin_width, magnitude: magnitudes[bin], bin }
+            })
+            .collect();
+
+        let tolerance = self.track_tolerance;
+        for track in &mut self.peak_tracks {
+            let nearest = peaks.iter().enumerate()
+                .map(|(i, peak)| (i, (peak.freq - track.freq).abs()))
+                .filter(|&(_, distance)| distance <= tolerance)
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+            match nearest {
+                Some((i, _)) => {
+                    let peak = peaks.remove(i);
+                    (track.freq, track.magnitude, track.missed) = (peak.freq, peak.magnitude, 0);
+                }
+                None => track.missed += 1,
+            }
+        }
+        self.peak_tracks.retain(|track| track.missed <= TRACK_MAX_MISSED);
+        for peak in peaks {
+            if self.peak_tracks.len() >= self.track_peaks {
+                break;
+            }
+            let number = (1..).find(|&n| self.peak_tracks.iter().all(|track| track.number != n)).unwrap();
+            self.peak_tracks.push(PeakTrack { number, freq: peak.freq, magnitude: peak.magnitude, missed: 0 });
+        }
+    }
+
+    // Slews the band center toward the live peak along the axis, so the log axis moves evenly
+    // in octaves; without a peak over the threshold the band stays where it is
+    fn follow_peak_center(&mut self, sample_rate: u32) {
+        let Some((_, peak)) = self.peak_of(&self.live_line) else { return };
+        let span = 1.0 / self.freq_zoom;
+        let (min_freq, _) = self.freq_range(sample_rate);
+        let center = self.band_position(min_freq, sample_rate) + span / 2.0;
+        let target = self.band_position(peak.freq, sample_rate);
+        let center = (center + (target - center) * self.follow_smoothing).clamp(span / 2.0, 1.0 - span / 2.0);
+        self.center_freq = Some(self.band_freq(center, sample_rate));
+    }
+
+    pub fn toggle_phase_panel(&mut self) {
+        self.phase_panel = !self.phase_panel;
+        if self.phase_panel && self.layout == LayoutMode::Waterfall {
+            self.notify("The phase panel shares the spectrum's rows; press L to show them".into());
+        }
+    }
+
+    // Phase of each bin of the latest frame, in radians from the middle of the window so a
+    // tone's phase doesn't turn over from bin to bin. Bins more than `phase_gate_db` below
+    // the strongest are None, and the rest are unwrapped across the gaps between them.
+    pub fn unwrapped_phase(&self) -> Vec<Option<f32>> {
+        use std::f32::consts::{PI, TAU};
+        let peak = self.live_line.iter().skip(1).map(|&(_, mag)| mag).fold(0.0, f32::max);
+        let gate = peak * 10f32.powf(self.phase_gate_db / 20.0);
+        let mut previous: Option<f32> = None;
+        self.phase.iter().zip(&self.live_line).enumerate().map(|(i, (&phase, &(_, mag)))| {
+            if i == 0 || peak <= 0.0 || mag < gate {
+                return None;
+            }
+            let centered = phase + PI * i as f32 / self.zero_pad as f32;
+            let unwrapped = match previous {
+                Some(last) => centered - TAU * ((centered - last) / TAU).round(),
+                None => (centered + PI).rem_euclid(TAU) - PI,
+            };
+            previous = Some(unwrapped);
+            Some(unwrapped)
+        }).collect()
+    }
+
+    pub fn toggle_follow_peak(&mut self) {
+        self.follow_peak = !self.follow_peak;
+        if self.follow_peak && self.freq_zoom <= 1.0 {
+            self.notify("Following the peak; zoom in to see the band move".into());
+        }
+    }
+
+    // Centers the zoomed band on a column of the marquee, which spans the whole band
+    pub fn center_on_marquee(&mut self, column: usize, width: usize, sample_rate: u32) {
+        let span = 1.0 / self.freq_zoom;
+        let center = ((column as f32 + 0.5) / width as f32).clamp(span / 2.0, 1.0 - span / 2.0);
+        self.center_freq = Some(self.band_freq(center, sample_rate));
+        self.follow_peak = false;
+    }
+
+    // Shifts the visible band by a fraction of its own width
+    pub fn pan(&mut self, fraction: f32, sample_rate: u32) {
+        let span = 1.0 / self.freq_zoom;
+        let (min_freq, _) = self.freq_range(sample_rate);
+        let center = self.band_position(min_freq, sample_rate) + span * (0.5 + fraction);
+        let center = center.clamp(span / 2.0, 1.0 - span / 2.0);
+        self.center_freq = Some(self.band_freq(center, sample_rate));
+    }
+
+    pub fn column_freq(&self, column: usize, width: usize, sample_rate: u32) -> f32 {
+        let (min_freq, max_freq) = self.freq_range(sample_rate);
+        let t = column as f32 / width as f32;
+        match self.freq_axis {
+            FreqAxis::Linear => min_freq + t * (max_freq - min_freq),
+            FreqAxis::Log => min_freq * (max_freq / min_freq).powf(t),
+        }
+    }
+
+    // Round frequencies to label the visible range with: 1-2-5 steps for about `count` ticks on
+    // the linear axis, 1-2-5 per decade on the log axis
+    pub fn freq_ticks(&self, sample_rate: u32, count: usize) -> Vec<f32> {
+        let (min_freq, max_freq) = self.freq_range(sample_rate);
+        match self.freq_axis {
+            FreqAxis::Linear => {
+                let step = nice_step((max_freq - min_freq) / count as f32);
+                let first = (min_freq / step).ceil() as usize;
+                (first..).map(|k| k as f32 * step).take_while(|&f| f < max_freq).collect()
+            }
+            FreqAxis::Log => (min_freq.log10().floor() as i32..=max_freq.log10().ceil() as i32)
+                .flat_map(|exp| [1.0, 2.0, 5.0].map(|m| m * 10f32.powi(exp)))
+                .filter(|f| (min_freq..max_freq).contains(f))
+                .collect(),
+        }
+    }
+
+    pub fn column_bin(&self, column: usize, width: usize, sample_rate: u32) -> usize {
+        (self.column_freq(column, width, sample_rate) * self.fft_size as f32 / sample_rate as f32) as usize
+    }
+
+    // Bins from this column's own up to the next column's, at least one, so a column that
+    // spans many bins summarizes all of them instead of sampling the first. DC is left out,
+    // as it is from the peak and statistics.
+    pub fn column_bins(&self, column: usize, width: usize, sample_rate: u32) -> Range<usize> {
+        let start = self.column_bin(column, width, sample_rate).max(1);
+        start..self.column_bin(column + 1, width, sample_rate).max(start + 1).min(self.fft_size / 2)
+    }
+
+    // The shown line's energy in each --bands octave or third-octave band within the zoomed
+    // range, at display gain
+    pub fn band_analysis_levels(&self, sample_rate: u32) -> Vec<(OctaveBand, Option<f32>)> {
+        let Some(fraction) = self.band_analysis else { return Vec::new() };
+        let (min_freq, max_freq) = self.freq_range(sample_rate);
+        let bands: Vec<OctaveBand> = octave_bands(fraction, sample_rate).into_iter()
+            .filter(|band| band.high > min_freq && band.low < max_freq)
+            .collect();
+        let magnitudes: Vec<f32> = self.shown_line().iter().map(|&(_, mag)| mag).collect();
+        let noise_bandwidth = self.window.noise_bandwidth() * self.zero_pad as f32;
+        let levels = band_levels(&magnitudes, sample_rate, &bands, noise_bandwidth);
+        bands.into_iter().zip(levels).collect()
+    }
+
+    // Strongest bin of the shown line under a column of the marquee, zoom aside
+    pub fn marquee_magnitude(&self, column: usize, width: usize, sample_rate: u32) -> Option<f32> {
+        let bin = |column: usize| {
+            let freq = self.band_freq(column as f32 / width as f32, sample_rate);
+            (freq * self.fft_size as f32 / sample_rate as f32) as usize
+        };
+        let start = bin(column).max(1);
+        let end = bin(column + 1).max(start + 1).min(self.fft_size / 2);
+        self.shown_line().get(start..end)?.iter().map(|&(_, mag)| mag).reduce(f32::max)
+    }
+
+    // First and last marquee columns of the band on screen
+    pub fn marquee_window(&self, width: usize, sample_rate: u32) -> (usize, usize) {
+        let (min_freq, max_freq) = self.freq_range(sample_rate);
+        let column = |freq: f32| {
+            ((self.band_position(freq, sample_rate) * width as f32) as usize).min(width.saturating_sub(1))
+        };
+        (column(min_freq), column(max_freq).saturating_sub(1).max(column(min_freq)))
+    }
+
+    // Strongest bin under a column; the max keeps a narrow tone visible however far out the
+    // view is zoomed
+    pub fn column_magnitude(&self, line: &[(f32, f32)], column: usize, width: usize, sample_rate: u32) -> Option<f32> {
+        let bins = self.column_bins(column, width, sample_rate);
+        line.get(bins.start..bins.end.min(line.len()))?.iter().map(|&(_, mag)| mag).reduce(f32::max)
+    }
+
+    // A history line as the waterfall draws it, smoothed across bins when --waterfall-smooth
+    // asks for it. Only the picture is blurred; readouts and exports of the data keep the bins.
+    pub fn waterfall_line<'a>(&self, line: &'a [(f32, f32)]) -> Cow<'a, [(f32, f32)]> {
+        if self.waterfall_smooth <= 1 {
+            return Cow::Borrowed(line);
+        }
+        let magnitudes: Vec<f32> = line.iter().map(|&(_, mag)| mag).collect();
+        let smoothed = triangular_smooth(&magnitudes, self.waterfall_smooth);
+        Cow::Owned(line.iter().zip(smoothed).map(|(&(freq, _), mag)| (freq, mag)).collect())
+    }
+
+    pub fn column_peak_hold(&self, column: usize, width: usize, sample_rate: u32) -> Option<f32> {
+        self.column_max(&self.peak_hold, column, width, sample_rate)
+    }
+
+    pub fn column_session_max(&self, column: usize, width: usize, sample_rate: u32) -> Option<f32> {
+        self.column_max(&self.session_max, column, width, sample_rate)
+    }
+
+    // The reference curve at the column's frequency, so it follows zoom and the log axis
+    pub fn column_reference_curve(&self, column: usize, width: usize, sample_rate: u32) -> Option<f32> {
+        self.reference_curve_at(self.column_freq(column, width, sample_rate))
+    }
+
+    // Straight lines between the curve's points; nothing beyond its first and last
+    fn reference_curve_at(&self, freq: f32) -> Option<f32> {
+        let curve = &self.reference_curve;
+        let next = curve.partition_point(|&(point, _)| point <= freq);
+        let (f0, m0) = *curve.get(next.checked_sub(1)?)?;
+        if f0 == freq {
+            return Some(m0);
+        }
+        let (f1, m1) = *curve.get(next)?;
+        Some(m0 + (m1 - m0) * (freq - f0) / (f1 - f0))
+    }
+
+    // Phase of the bin `column_magnitude` picks, when the line carries phases
+    pub fn column_phase(&self, line: &[(f32, f32)], phases: &[f32], column: usize, width: usize,
+                        sample_rate: u32) -> Option<f32> {
+        let bins = self.column_bins(column, width, sample_rate);
+        let strongest = bins.filter(|&i| i < line.len()).max_by(|&a, &b| line[a].1.total_cmp(&line[b].1))?;
+        phases.get(strongest).copied()
+    }
+
+    // Unwrapped phase of the strongest bin under a column that has one
+    pub fn column_unwrapped_phase(&self, unwrapped: &[Option<f32>], column: usize, width: usize,
+                                  sample_rate: u32) -> Option<f32> {
+        self.column_bins(column, width, sample_rate)
+            .filter_map(|i| Some((self.live_line.get(i)?.1, unwrapped.get(i).copied().flatten()?)))
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, phase)| phase)
+    }
+
+    fn column_max(&self, levels: &[f32], column: usize, width: usize, sample_rate: u32) -> Option<f32> {
+        let bins = self.column_bins(column, width, sample_rate);
+        levels.get(bins.start..bins.end.min(levels.len()))?.iter().copied().reduce(f32::max)
+    }
+
+    // The cursor appears in the middle of the screen on the first move
+    pub fn move_cursor(&mut self, delta: isize, width: usize) {
+        let column = self.cursor.map_or(width as isize / 2, |c| c as isize + delta);
+        self.cursor = Some(column.clamp(0, width.saturating_sub(1) as isize) as usize);
+    }
+
+    pub fn cursor_column(&self, width: usize) -> Option<usize> {
+        self.cursor.filter(|_| width > 0).map(|c| c.min(width - 1))
+    }
+
+    pub fn toggle_invert(&mut self) {
+        self.invert = !self.invert;
+    }
+
+    // Where a waterfall intensity lands on the colormap, after contrast and brightness
+    fn colormap_level(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        if self.invert { 1.0 - t } else { t }
+    }
+
+    // Colormap color of a waterfall cell, tinted by its phase under the phase colormap
+    fn waterfall_color(&self, t: f32, phase: Option<f32>) -> Option<Color> {
+        let t = self.colormap_level(t);
+        let color = match phase.filter(|_| self.colormap == Colormap::Phase) {
+            Some(phase) => {
+                let (r, g, b) = phase_rgb(phase, t);
+                Color::Rgb { r, g, b }
+            }
+            None => self.colormap.color(t),
+        };
+        self.color_mode.quantize(color)
+    }
+
+    pub fn waterfall_rgb(&self, t: f32, phase: Option<f32>) -> (u8, u8, u8) {
+        let t = self.colormap_level(t);
+        match phase.filter(|_| self.colormap == Colormap::Phase) {
+            Some(phase) => phase_rgb(phase, t),
+            None => self.colormap.rgb(t),
+        }
+    }
+
+    // Waterfall cell for a 0..1 intensity; without colors the glyph carries the level
+    pub fn waterfall_cell(&self, t: f32) -> ScreenCell {
+        self.phase_waterfall_cell(t, None)
+    }
+
+    pub fn phase_waterfall_cell(&self, t: f32, phase: Option<f32>) -> ScreenCell {
+        if self.ascii {
+            let level = (self.colormap_level(t) * (ASCII_RAMP.len() - 1) as f32).round() as usize;
+            return ScreenCell { char: ASCII_RAMP[level], fg_color: None, bg_color: None };
+        }
+        match self.waterfall_color(t, phase) {
+            // A full block leaves no gaps, but other glyphs sit on the terminal background
+            Some(color) => match self.waterfall_glyph {
+                WaterfallGlyph::Char(c) if c != '█' => ScreenCell { char: c, fg_color: Some(color), bg_color: None },
+                _ => ScreenCell { char: '█', fg_color: Some(color), bg_color: Some(color) },
+            },
+            None => {
+                let shade = (self.colormap_level(t) * (SHADE_RAMP.len() - 1) as f32).round() as usize;
+                ScreenCell { char: SHADE_RAMP[shade], fg_color: None, bg_color: None }
+            }
+        }
+    }
+
+    // Two packed history lines, the older or newer on top following the scroll direction,
+    // each an intensity and maybe a phase; a gated half is left as background
+    pub fn packed_waterfall_cell(&self, top: Option<(f32, Option<f32>)>,
+                                 bottom: Option<(f32, Option<f32>)>) -> ScreenCell {
+        let color = |(t, phase): (f32, Option<f32>)| self.waterfall_color(t, phase);
+        match (top, bottom) {
+            (Some(top), bottom) => ScreenCell { char: '▀', fg_color: color(top), bg_color: bottom.and_then(color) },
+            (None, Some(bottom)) => ScreenCell { char: '▄', fg_color: color(bottom), bg_color: None },
+            (None, None) => ScreenCell::default(),
+        }
+    }
+
+    // Cell of a spectrum bar filled `eighths` (1..=8) of the way up, colored by the bar's intensity
+    pub fn bar_cell(&self, t: f32, eighths: usize) -> ScreenCell {
+        if self.ascii {
+            let char = if eighths >= BAR_BLOCKS.len() / 2 { '#' } else { '_' };
+            return ScreenCell { char, fg_color: None, bg_color: None };
+        }
+        let char = BAR_BLOCKS[eighths.clamp(1, BAR_BLOCKS.len()) - 1];
+        ScreenCell { char, fg_color: self.trace_fg(t), bg_color: None }
+    }
+
+    // Cell of a horizontal meter filled `eighths` (1..=8) of the way across
+    pub fn meter_cell(&self, t: f32, eighths: usize) -> ScreenCell {
+        if self.ascii {
+            let char = if eighths >= METER_BLOCKS.len() / 2 { '#' } else { ' ' };
+            return ScreenCell { char, fg_color: None, bg_color: None };
+        }
+        let char = METER_BLOCKS[eighths.clamp(1, METER_BLOCKS.len()) - 1];
+        ScreenCell { char, fg_color: self.color_mode.quantize(self.colormap.color(t)), bg_color: None }
+    }
+
+    pub fn highlight_color(&self) -> Option<Color> {
+        if self.ascii { None } else { self.color_mode.quantize(Color::White) }
+    }
+
+    pub fn marker_color(&self) -> Option<Color> {
+        if self.ascii { None } else { self.color_mode.quantize(Color::Cyan) }
+    }
+
+    // The spectrum trace at level `t`: the --trace-color if one was given, else the colormap's
+    pub fn trace_fg(&self, t: f32) -> Option<Color> {
+        if self.ascii {
+            return None;
+        }
+        self.color_mode.quantize(self.trace_color.map_or_else(|| self.colormap.color(t), |color| color.0))
+    }
+
+    pub fn curve_color(&self) -> Option<Color> {
+        if self.ascii { None } else { self.color_mode.quantize(Color::Magenta) }
+    }
+
+    // Background layers such as the session maximum, kept behind the live trace
+    pub fn dim_color(&self) -> Option<Color> {
+        if self.ascii { None } else { self.color_mode.quantize(Color::DarkGrey) }
+    }
+
+    pub fn cursor_char(&self) -> char {
+        if self.ascii { '|' } else { '│' }
+    }
+
+    // Nearest note and how far off it the frequency is, e.g. "A4 +12¢"
+    pub fn note_label(&self, freq: f32) -> String {
+        let note = Note::nearest(freq);
+        let unit = if self.ascii { " cents" } else { "¢" };
+        format!("{} {:+}{}", note.name(), note.cents.round() as i32, unit)
+    }
+
+    pub fn format_level(&self, magnitude: f32) -> String {
+        match self.scale {
+            Scale::Linear => format!("{:.2}", magnitude),
+            Scale::Db => format!("{:.1} dB", self.to_db(magnitude)),
+        }
+    }
+
+    // X coordinate of a frequency on the spectrum chart
+    pub fn axis_position(&self, freq: f32) -> f32 {
+        match self.freq_axis {
+            FreqAxis::Linear => freq,
+            FreqAxis::Log => freq.log10(),
+        }
+    }
+
+    pub fn scale_label(&self) -> String {
+        if self.quantity != Quantity::Magnitude {
+            let unit = if self.scale == Scale::Db { self.quantity_unit() } else { "linear".to_string() };
+            return format!("{}, {}", self.quantity.name(), unit);
+        }
+        match self.scale {
+            Scale::Linear => "linear".to_string(),
+            Scale::Db => format!("dB ({:.0}..0 dB)", self.db_floor),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+    const HISTORY_ROWS: usize = 20;
+
+    // Unaveraged, so each line holds its frame as given
+    fn view_state() -> ViewState {
+        let options = Options::defaults();
+        let names = OutputNames::new(&options.output_prefix, "test", SAMPLE_RATE);
+        let mut state = ViewState::new(HISTORY_ROWS, &options, names);
+        state.average_alpha = 1.0;
+        state
+    }
+
+    // A flat frame at `db` relative to full scale
+    fn flat_frame(state: &ViewState, db: f32) -> Vec<f32> {
+        let full_scale = state.fft_size as f32 / 2.0 * BASE_GAIN;
+        vec![full_scale * 10f32.powf(db / 20.0); state.fft_size / 2]
+    }
+
+    // The level of each history line's first bin above DC, oldest first
+    fn history_levels(state: &ViewState) -> Vec<f32> {
+        let size = state.history_size;
+        (0..state.filled_lines())
+            .map(|i| state.waterfall_data[(state.current_line + size - state.filled_lines() + i) % size][1].1)
+            .map(|mag| (state.level_db(mag) * 10.0).round() / 10.0)
+            .collect()
+    }
+
+    #[test]
+    fn trigger_keeps_the_lines_just_before_and_after_a_crossing() {
+        let mut state = view_state();
+        state.trigger = Some(Trigger::new(-20.0, 2, 1));
+        // Quiet frames each a dB apart, so the lines kept show which they were
+        for db in [-80.0, -79.0, -78.0, -77.0, 0.0, -76.0] {
+            state.add_spectrum(&flat_frame(&state, db), SAMPLE_RATE);
+            assert_eq!(state.trigger.as_ref().unwrap().is_triggered(), db == 0.0);
+        }
+        for db in [-75.0, -74.0] {
+            state.add_spectrum(&flat_frame(&state, db), SAMPLE_RATE);
+        }
+        assert_eq!(history_levels(&state), [-78.0, -77.0, 0.0, -76.0]);
+
+        // A second crossing brings only the two lines held since
+        state.add_spectrum(&flat_frame(&state, -5.0), SAMPLE_RATE);
+        assert_eq!(history_levels(&state), [-78.0, -77.0, 0.0, -76.0, -75.0, -74.0, -5.0]);
+    }
+
+    #[test]
+    fn time_compression_folds_each_run_of_frames_into_one_line() {
+        let mut state = view_state();
+        state.time_compress = 3;
+        // Each bin takes its own run of levels, so the fold is checked bin by bin
+        let frames: Vec<Vec<f32>> = [[0.2, 0.8, 0.5], [0.9, 0.1, 0.2], [0.3, 0.3, 0.6]].iter().map(|levels| {
+            (0..state.fft_size / 2).map(|bin| levels[bin % 3] * (bin % 5 + 1) as f32).collect()
+        }).collect();
+        let fold = |fold: fn(&[f32]) -> f32| -> Vec<f32> {
+            (0..state.fft_size / 2).map(|bin| fold(&[frames[0][bin], frames[1][bin], frames[2][bin]])).collect()
+        };
+        let expected_max = fold(|run| run.iter().copied().fold(0.0, f32::max));
+        let expected_mean = fold(|run| run.iter().sum::<f32>() / run.len() as f32);
+
+        for (mode, expected) in [(TimeCompression::Max, expected_max), (TimeCompression::Mean, expected_mean)] {
+            state.time_compress_mode = mode;
+            state.clear_history();
+            for (i, frame) in frames.iter().enumerate() {
+                assert_eq!(state.filled_lines(), 0, "line committed after {} frames", i);
+                state.add_spectrum(frame, SAMPLE_RATE);
+            }
+            assert_eq!(state.filled_lines(), 1);
+            let line = &state.waterfall_data[(state.current_line + state.history_size - 1) % state.history_size];
+            for (&(_, mag), &want) in line.iter().zip(&expected) {
+                assert!((mag - want).abs() < 1e-4, "{}: {} against {}", mode.name(), mag, want);
+            }
+        }
+    }
+
+    // Tones a few bins wide, centered on fractional bins, at -6 dB over a -100 dB floor
+    fn tone_frame(state: &ViewState, bins: &[f32]) -> Vec<f32> {
+        let full_scale = state.fft_size as f32 / 2.0 * BASE_GAIN;
+        (0..state.fft_size / 2).map(|bin| {
+            let tones = bins.iter().map(|&center| 0.5 * (-(bin as f32 - center).powi(2) / 2.0).exp()).sum::<f32>();
+            full_scale * (tones + 1e-5)
+        }).collect()
+    }
+
+    #[test]
+    fn peak_tracks_follow_a_moving_tone_and_keep_their_numbers() {
+        let mut state = view_state();
+        state.track_peaks = 2;
+        let bin_width = SAMPLE_RATE as f32 / state.fft_size as f32;
+
+        // Under half a bin per frame, well inside the tolerance
+        for step in 0..25 {
+            let center = 200.0 + 0.4 * step as f32;
+            state.add_spectrum(&tone_frame(&state, &[center]), SAMPLE_RATE);
+            assert_eq!(state.peak_tracks.len(), 1);
+            let track = &state.peak_tracks[0];
+            assert_eq!((track.number, track.missed), (1, 0));
+            assert!((track.freq - center * bin_width).abs() < 0.1 * bin_width, "{} at bin {}", track.freq, center);
+        }
+
+        // A second tone takes the next number; once the first is gone long enough its track ends,
+        // and the number it leaves free goes to the next tone to appear
+        state.add_spectrum(&tone_frame(&state, &[210.0, 600.0]), SAMPLE_RATE);
+        let numbers = |state: &ViewState| state.peak_tracks.iter().map(|track| track.number).collect::<Vec<_>>();
+        assert_eq!(numbers(&state), [1, 2]);
+        for missed in 1..=TRACK_MAX_MISSED + 1 {
+            state.add_spectrum(&tone_frame(&state, &[600.0]), SAMPLE_RATE);
+            let expected: &[usize] = if missed > TRACK_MAX_MISSED { &[2] } else { &[1, 2] };
+            assert_eq!(numbers(&state), expected);
+        }
+        state.add_spectrum(&tone_frame(&state, &[300.0, 600.0]), SAMPLE_RATE);
+        assert_eq!(numbers(&state), [2, 1]);
+        assert!((state.peak_tracks[1].freq - 300.0 * bin_width).abs() < 0.1 * bin_width);
+    }
+}